@@ -0,0 +1,63 @@
+// Times decoding of gossip messages, the work every peer does for every message in the
+// room. Run with `cargo bench -p messaging`. "signed" is the full receive path; "unsigned"
+// is the encoding alone, to tell parsing apart from checking the signature. Each case
+// runs once as JSON and once as postcard.
+
+use iroh::SecretKey;
+use messaging::{Codec, Message, MessageBody, privacy};
+use std::{hint::black_box, time::Instant};
+
+const ROUNDS: u32 = 20_000;
+const TOPIC: [u8; 32] = [1; 32];
+
+fn bench(name: &str, bytes: &[u8], decode: fn(&[u8]) -> anyhow::Result<Message>) {
+    // Warm up caches and the allocator before timing.
+    for _ in 0..ROUNDS / 10 {
+        black_box(decode(black_box(bytes)).unwrap());
+    }
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        black_box(decode(black_box(bytes)).unwrap());
+    }
+    let each = start.elapsed() / ROUNDS;
+    println!("{name:<28} {:>6} bytes {:>10?}/message", bytes.len(), each);
+}
+
+fn main() {
+    let secret_key = SecretKey::from_bytes(&[7; 32]);
+    let from = secret_key.public();
+
+    let text = Message::new(MessageBody::Message {
+        from,
+        text: "see you all at the standup in ten minutes".to_string(),
+        id: 1,
+        seq: 1,
+    });
+    let long = Message::new(MessageBody::Message {
+        from,
+        text: "lorem ipsum dolor sit amet ".repeat(200),
+        id: 2,
+        seq: 2,
+    });
+    let cover = Message::new(MessageBody::Cover { from });
+
+    for codec in [Codec::Json, Codec::Postcard] {
+        for (name, message) in [("text", &text), ("long text", &long), ("cover", &cover)] {
+            let name = format!("{name} ({codec:?})");
+            let signed = message.to_signed_vec_with(&secret_key, &TOPIC, codec);
+            bench(
+                &format!("{name}, unsigned"),
+                &message.encode(codec),
+                Message::from_bytes,
+            );
+            bench(&format!("{name}, signed"), &signed, |bytes| {
+                Message::from_signed_bytes(bytes, &TOPIC)
+            });
+            bench(
+                &format!("{name}, signed and padded"),
+                &privacy::pad(&signed).unwrap(),
+                |bytes| Message::from_signed_bytes(bytes, &TOPIC),
+            );
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Bad network on demand: delays, drops, duplicates and hard disconnects, driven by a
+//! seeded rng so a failing run can be replayed.
+//!
+//! The bot takes a [`Chaos`] per bot. The client and the server read one from
+//! [`CHAOS_VAR`] when built with the `chaos` feature and run their gossip frames and
+//! direct connections through [`stream`], [`copies`] and [`direct`].
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use futures_lite::{Stream, ready};
+use n0_future::FuturesUnordered;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use std::{
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    sync::{Mutex, OnceLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// Turns on chaos for the client and the server, e.g.
+/// `CHAST_CHAOS=latency=200ms,jitter=100ms,loss=0.05,duplicate=0.02,seed=7`.
+pub const CHAOS_VAR: &str = "CHAST_CHAOS";
+
+#[derive(Debug, Clone, Default)]
+pub struct Chaos {
+    /// Added to every delivered message.
+    pub latency: Duration,
+    /// Up to this much extra delay, picked per message.
+    pub jitter: Duration,
+    /// Chance in `0.0..=1.0` that a message is dropped.
+    pub loss: f64,
+    /// Chance that a message arrives twice.
+    pub duplicate: f64,
+    /// Chance per message that the node drops off the network for good.
+    pub disconnect: f64,
+    pub seed: u64,
+}
+
+impl FromStr for Chaos {
+    type Err = anyhow::Error;
+
+    // Comma separated `key=value` pairs; durations in milliseconds, with or without `ms`.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut chaos = Chaos::default();
+        for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected key=value, got {}", pair))?;
+            let millis = || -> Result<Duration> {
+                let millis = value.trim_end_matches("ms").parse()?;
+                Ok(Duration::from_millis(millis))
+            };
+            let chance = || -> Result<f64> { Ok(value.parse()?) };
+            match key {
+                "latency" => chaos.latency = millis()?,
+                "jitter" => chaos.jitter = millis()?,
+                "loss" => chaos.loss = chance()?,
+                "duplicate" => chaos.duplicate = chance()?,
+                "disconnect" => chaos.disconnect = chance()?,
+                "seed" => chaos.seed = value.parse()?,
+                _ => bail!("Unknown chaos setting {}", key),
+            }
+        }
+        Ok(chaos)
+    }
+}
+
+pub enum Fate {
+    Deliver(Duration),
+    /// Deliver after the delay, then once more.
+    Duplicate(Duration),
+    Drop,
+    Disconnect,
+}
+
+pub struct ChaosState {
+    chaos: Chaos,
+    rng: StdRng,
+}
+
+impl ChaosState {
+    pub fn new(chaos: Chaos) -> Self {
+        let rng = StdRng::seed_from_u64(chaos.seed);
+        Self { chaos, rng }
+    }
+
+    pub fn roll(&mut self) -> Fate {
+        if self.rng.random_bool(self.chaos.disconnect.clamp(0.0, 1.0)) {
+            return Fate::Disconnect;
+        }
+        if self.rng.random_bool(self.chaos.loss.clamp(0.0, 1.0)) {
+            return Fate::Drop;
+        }
+        let jitter = self.chaos.jitter.mul_f64(self.rng.random::<f64>());
+        let delay = self.chaos.latency + jitter;
+        if self.rng.random_bool(self.chaos.duplicate.clamp(0.0, 1.0)) {
+            Fate::Duplicate(delay)
+        } else {
+            Fate::Deliver(delay)
+        }
+    }
+}
+
+// Shared by everything in the process, set up from CHAOS_VAR on first use.
+static GLOBAL: OnceLock<Option<Mutex<ChaosState>>> = OnceLock::new();
+
+/// The next fate from [`CHAOS_VAR`], or None when it isn't set.
+pub fn roll() -> Option<Fate> {
+    let state = GLOBAL.get_or_init(|| {
+        let value = std::env::var(CHAOS_VAR).ok()?;
+        match value
+            .parse()
+            .with_context(|| format!("Ignoring {}", CHAOS_VAR))
+        {
+            Ok(chaos) => {
+                eprintln!("Chaos on: {:?}", chaos);
+                Some(Mutex::new(ChaosState::new(chaos)))
+            }
+            Err(e) => {
+                eprintln!("{:#}", e);
+                None
+            }
+        }
+    });
+    Some(state.as_ref()?.lock().unwrap().roll())
+}
+
+/// Runs incoming gossip through [`roll`]: events come late, twice or not at all, and a
+/// disconnect ends the stream as if the subscription had died. Errors pass straight
+/// through.
+pub fn stream<S, T, E>(incoming: S) -> Delayed<S, T, E>
+where
+    S: Stream<Item = Result<T, E>> + Unpin,
+    T: Clone,
+{
+    Delayed::new(incoming, Rolls::Global)
+}
+
+/// Incoming gossip run through chaos, see [`stream`]. Each event waits out its delay on
+/// its own timer, so a burst sent together arrives together, one latency later.
+pub struct Delayed<S, T, E> {
+    // None once the stream has ended or chaos disconnected it.
+    incoming: Option<S>,
+    rolls: Rolls,
+    pending: FuturesUnordered<Timed<Result<T, E>>>,
+    disconnected: bool,
+}
+
+enum Rolls {
+    Off,
+    Global,
+    Own(Box<ChaosState>),
+}
+
+impl<S, T, E> Delayed<S, T, E>
+where
+    S: Stream<Item = Result<T, E>> + Unpin,
+    T: Clone,
+{
+    fn new(incoming: S, rolls: Rolls) -> Self {
+        Self {
+            incoming: Some(incoming),
+            rolls,
+            pending: FuturesUnordered::new(),
+            disconnected: false,
+        }
+    }
+
+    /// Passes `incoming` through untouched until [`Delayed::set_chaos`] is called.
+    pub fn off(incoming: S) -> Self {
+        Self::new(incoming, Rolls::Off)
+    }
+
+    /// Runs everything received from now on through `chaos`, with its own rng.
+    pub fn set_chaos(&mut self, chaos: Chaos) {
+        self.rolls = Rolls::Own(Box::new(ChaosState::new(chaos)));
+    }
+
+    /// Whether the stream ended because chaos pulled the plug rather than because
+    /// `incoming` closed.
+    pub fn disconnected(&self) -> bool {
+        self.disconnected
+    }
+
+    fn roll(&mut self) -> Option<Fate> {
+        match &mut self.rolls {
+            Rolls::Off => None,
+            Rolls::Global => roll(),
+            Rolls::Own(state) => Some(state.roll()),
+        }
+    }
+
+    fn delay(&mut self, delay: Duration, event: Result<T, E>) {
+        self.pending.push(Timed {
+            sleep: Box::pin(n0_future::time::sleep(delay)),
+            item: Some(event),
+        });
+    }
+}
+
+impl<S, T, E> Stream for Delayed<S, T, E>
+where
+    S: Stream<Item = Result<T, E>> + Unpin,
+    T: Clone,
+{
+    type Item = Result<T, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        // Take in everything that has arrived, so each starts its timer right away.
+        while let Some(incoming) = &mut this.incoming {
+            let event = match Pin::new(incoming).poll_next(cx) {
+                Poll::Ready(Some(Ok(event))) => event,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => {
+                    this.incoming = None;
+                    break;
+                }
+                Poll::Pending => break,
+            };
+            match this.roll() {
+                None => return Poll::Ready(Some(Ok(event))),
+                Some(Fate::Deliver(delay)) => this.delay(delay, Ok(event)),
+                Some(Fate::Duplicate(delay)) => {
+                    this.delay(delay, Ok(event.clone()));
+                    this.delay(delay, Ok(event));
+                }
+                Some(Fate::Drop) => {}
+                Some(Fate::Disconnect) => {
+                    this.incoming = None;
+                    this.pending = FuturesUnordered::new();
+                    this.disconnected = true;
+                }
+            }
+        }
+        match Pin::new(&mut this.pending).poll_next(cx) {
+            Poll::Ready(Some(event)) => Poll::Ready(Some(event)),
+            _ if this.incoming.is_none() && this.pending.is_empty() => Poll::Ready(None),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+// An event held back until its sleep is over.
+struct Timed<T> {
+    sleep: Pin<Box<n0_future::time::Sleep>>,
+    item: Option<T>,
+}
+
+// The item is never pinned; only the sleep is, and that's boxed.
+impl<T> Unpin for Timed<T> {}
+
+impl<T> Future for Timed<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let this = self.get_mut();
+        ready!(this.sleep.as_mut().poll(cx));
+        Poll::Ready(this.item.take().expect("Timed polled after it finished"))
+    }
+}
+
+/// How many copies of an outgoing gossip frame to send, after waiting out its delay.
+pub async fn copies() -> usize {
+    match roll() {
+        None => 1,
+        Some(Fate::Deliver(delay)) => {
+            n0_future::time::sleep(delay).await;
+            1
+        }
+        Some(Fate::Duplicate(delay)) => {
+            n0_future::time::sleep(delay).await;
+            2
+        }
+        Some(Fate::Drop | Fate::Disconnect) => 0,
+    }
+}
+
+/// Call before a direct exchange (history, resends, admission). Delays it, or fails it
+/// the way a lost connection would. A duplicate is just delivered; asking twice over
+/// the same stream isn't something a real network does.
+pub async fn direct() -> Result<()> {
+    match roll() {
+        None => Ok(()),
+        Some(Fate::Deliver(delay) | Fate::Duplicate(delay)) => {
+            n0_future::time::sleep(delay).await;
+            Ok(())
+        }
+        Some(Fate::Drop) => bail!("Connection dropped by chaos"),
+        Some(Fate::Disconnect) => bail!("Disconnected by chaos"),
+    }
+}
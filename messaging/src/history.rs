@@ -0,0 +1,123 @@
+//! Room history handed to one peer over a direct connection, so a newcomer's archive
+//! isn't gossiped to everyone who already has it.
+//!
+//! The archive goes out as a series of `MessageHistory` chunks, each a length-prefixed
+//! JSON message, so a big room never has to fit into one blob and the receiver can show
+//! how far along it is.
+
+use crate::{Message, MessageBody, StoredMessage};
+use anyhow::{Result, anyhow, bail};
+use iroh::{
+    Endpoint, NodeId,
+    endpoint::{RecvStream, SendStream},
+};
+
+pub const HISTORY_ALPN: &[u8] = b"chast/history/0";
+/// Roughly how much JSON goes into one chunk.
+pub const CHUNK_BYTES: usize = 256 * 1024;
+// A single stored message bigger than CHUNK_BYTES still gets a chunk of its own, up to this.
+const MAX_FRAME_BYTES: usize = 16 * 1024 * 1024;
+const MAX_REQUEST_BYTES: usize = 4096;
+
+/// Splits an archive into chunks of about [`CHUNK_BYTES`] each, oldest first. An empty
+/// archive is still one (empty) chunk, so the receiver always hears back.
+pub fn chunk_history(messages: Vec<StoredMessage>) -> Vec<Vec<StoredMessage>> {
+    let mut chunks = vec![Vec::new()];
+    let mut size = 0;
+    for message in messages {
+        let message_size = serde_json::to_vec(&message).map_or(0, |bytes| bytes.len());
+        let current = chunks.last_mut().expect("there is always a chunk");
+        if !current.is_empty() && size + message_size > CHUNK_BYTES {
+            chunks.push(vec![message]);
+            size = message_size;
+        } else {
+            current.push(message);
+            size += message_size;
+        }
+    }
+    chunks
+}
+
+/// Writes the archive to `send` as numbered `MessageHistory` chunks and returns how
+/// many there were.
+pub async fn send_history(
+    send: &mut SendStream,
+    from: NodeId,
+    messages: Vec<StoredMessage>,
+) -> Result<u32> {
+    let chunks = chunk_history(messages);
+    let total = chunks.len() as u32;
+    for (chunk, messages) in chunks.into_iter().enumerate() {
+        let message = Message::new(MessageBody::MessageHistory {
+            from: Some(from),
+            messages,
+            chunk: chunk as u32,
+            chunks: total,
+        });
+        let bytes = message.to_vec();
+        send.write_all(&(bytes.len() as u32).to_be_bytes()).await?;
+        send.write_all(&bytes).await?;
+    }
+    send.finish()?;
+    Ok(total)
+}
+
+/// Reads the history request a peer opened the stream with.
+pub async fn read_request(recv: &mut RecvStream) -> Result<NodeId> {
+    let bytes = recv.read_to_end(MAX_REQUEST_BYTES).await?;
+    match Message::from_bytes(&bytes)?.body {
+        MessageBody::HistoryRequest { from } => Ok(from),
+        _ => Err(anyhow!("Expected a history request")),
+    }
+}
+
+/// Asks the history node `from` for the room's archive. `progress` is called with the
+/// number of chunks received so far and the total after each one arrives.
+pub async fn fetch_history(
+    endpoint: &Endpoint,
+    from: NodeId,
+    mut progress: impl FnMut(u32, u32),
+) -> Result<Vec<StoredMessage>> {
+    #[cfg(feature = "chaos")]
+    crate::chaos::direct().await?;
+    let request = Message::new(MessageBody::HistoryRequest {
+        from: endpoint.node_id(),
+    });
+    let connection = endpoint.connect(from, HISTORY_ALPN).await?;
+    let (mut send, mut recv) = connection.open_bi().await?;
+    send.write_all(&request.to_vec()).await?;
+    send.finish()?;
+
+    let mut archive = Vec::new();
+    let mut expected = 0;
+    loop {
+        let mut length = [0u8; 4];
+        recv.read_exact(&mut length).await?;
+        let length = u32::from_be_bytes(length) as usize;
+        if length > MAX_FRAME_BYTES {
+            bail!("History chunk of {} bytes is too big", length);
+        }
+        let mut bytes = vec![0u8; length];
+        recv.read_exact(&mut bytes).await?;
+        let MessageBody::MessageHistory {
+            from: Some(replier),
+            messages,
+            chunk,
+            chunks,
+        } = Message::from_bytes(&bytes)?.body
+        else {
+            bail!("Unexpected reply to a history request");
+        };
+        if replier != from || chunk != expected || chunk >= chunks {
+            bail!("History chunk {} of {} arrived out of order", chunk, chunks);
+        }
+        archive.extend(messages);
+        expected += 1;
+        progress(expected, chunks);
+        if expected == chunks {
+            break;
+        }
+    }
+    connection.close(0u32.into(), b"history done");
+    Ok(archive)
+}
@@ -1,19 +1,485 @@
-use anyhow::Result;
-use iroh::NodeId;
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Local, NaiveDateTime, TimeDelta, TimeZone, Utc};
+use iroh::{NodeId, SecretKey};
+use iroh_base::Signature;
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::collections::{HashSet, VecDeque};
+
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod history;
+pub mod privacy;
+pub mod receipts;
+
+/// The version of the message format this build speaks. Bump it whenever a
+/// `MessageBody` variant is added or a variant's fields change, so older peers know to
+/// skip what they can't read.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// How far from our clock a kick, ban, invite revocation or presence change may be
+/// stamped before it's refused as a replay.
+pub const REPLAY_WINDOW: TimeDelta = TimeDelta::minutes(10);
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Message {
+    /// [`PROTOCOL_VERSION`] of the sender; 0 from peers older than versioning. Comes
+    /// first so it can be read on its own, whatever follows.
+    #[serde(default)]
+    version: u32,
     pub body: MessageBody,
     nonce: [u8; 16],
+    /// When the sender made the message; the epoch from peers older than version 2.
+    #[serde(default, with = "utc_timestamp")]
+    sent: DateTime<Utc>,
+}
+
+// Just the version of an encoded Message, for when the rest can't be read.
+#[derive(Deserialize)]
+struct Header {
+    #[serde(default)]
+    version: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum MessageBody {
-    AboutMe { from: NodeId, name: String },
-    Message { from: NodeId, text: String },
-    Disconnect { from: NodeId, name: String },
-    MessageHistory { messages: Vec<StoredMessage> },
+    AboutMe {
+        from: NodeId,
+        name: String,
+        #[serde(default)]
+        client: ClientInfo,
+        /// The invite from the ticket this node joined with, if it had one.
+        #[serde(default)]
+        invite: Option<Invite>,
+    },
+    Message {
+        from: NodeId,
+        text: String,
+        #[serde(default)]
+        id: u64,
+        /// Per-sender sequence number starting at 1; 0 means the sender doesn't number
+        /// its messages and gaps can't be detected.
+        #[serde(default)]
+        seq: u64,
+    },
+    Disconnect {
+        from: NodeId,
+        name: String,
+    },
+    MessageHistory {
+        /// The history node sending the archive; older servers leave it out.
+        #[serde(default)]
+        from: Option<NodeId>,
+        messages: Vec<StoredMessage>,
+        /// Which part of the archive this is, counting from 0.
+        #[serde(default)]
+        chunk: u32,
+        /// How many parts the archive was split into; 0 when it was sent whole.
+        #[serde(default)]
+        chunks: u32,
+    },
+    CallRequest {
+        from: NodeId,
+        to: NodeId,
+        call_id: u64,
+    },
+    CallRinging {
+        from: NodeId,
+        to: NodeId,
+        call_id: u64,
+    },
+    CallAccept {
+        from: NodeId,
+        to: NodeId,
+        call_id: u64,
+    },
+    CallHangUp {
+        from: NodeId,
+        to: NodeId,
+        call_id: u64,
+    },
+    ScreenShareStarted {
+        from: NodeId,
+    },
+    ScreenShareStopped {
+        from: NodeId,
+    },
+    RoomInvite {
+        from: NodeId,
+        name: String,
+        ticket: String,
+    },
+    Ack {
+        from: NodeId,
+        to: NodeId,
+        id: u64,
+    },
+    RoomInfo {
+        from: NodeId,
+        info: RoomInfo,
+    },
+    JoinRequest {
+        from: NodeId,
+        name: String,
+    },
+    KnockRequest {
+        from: NodeId,
+        name: String,
+    },
+    JoinReply {
+        from: NodeId,
+        to: NodeId,
+        verdict: JoinVerdict,
+    },
+    ResendRequest {
+        from: NodeId,
+        seqs: Vec<u64>,
+    },
+    Resend {
+        from: NodeId,
+        messages: Vec<SentMessage>,
+    },
+    Presence {
+        from: NodeId,
+        status: PresenceStatus,
+    },
+    /// The room owner or a moderator asking `target` to leave.
+    Kick {
+        from: NodeId,
+        target: NodeId,
+    },
+    /// Like Kick, but everyone also ignores `target` from then on.
+    Ban {
+        from: NodeId,
+        target: NodeId,
+    },
+    /// A notice from a server operator, shown apart from the conversation.
+    Announcement {
+        from: NodeId,
+        text: String,
+        id: u64,
+    },
+    /// The room owner withdrawing an invite; anyone joining with it from then on is
+    /// turned away.
+    RevokeInvite {
+        from: NodeId,
+        invite: u64,
+    },
+    /// A picture `from` posted. Only this notice goes through gossip; the image itself
+    /// is fetched from `from` directly, `size` bytes of PNG or JPEG.
+    Image {
+        from: NodeId,
+        id: u64,
+        width: u32,
+        height: u32,
+        size: u32,
+    },
+    /// A file `from` is offering. Like `Image`, only the offer is gossiped; whoever
+    /// accepts it fetches the `size` bytes from `from` directly.
+    File {
+        from: NodeId,
+        id: u64,
+        name: String,
+        size: u64,
+    },
+    /// A `/me` message: `text` is what `from` did, shown after their name.
+    Action {
+        from: NodeId,
+        text: String,
+        id: u64,
+    },
+    /// Sent over [`history::HISTORY_ALPN`], never gossiped; the history node replies
+    /// with a `MessageHistory` on the same stream.
+    HistoryRequest {
+        from: NodeId,
+    },
+    /// Dummy traffic from a peer with cover traffic turned on; everyone drops it.
+    Cover {
+        from: NodeId,
+    },
+    /// Something from a newer protocol version this build doesn't know. Never sent;
+    /// [`Message::from_bytes`] gives it in place of an error so it can be skipped.
+    #[serde(skip)]
+    Unknown,
+}
+
+impl MessageBody {
+    pub fn sender(&self) -> Option<NodeId> {
+        match self {
+            MessageBody::AboutMe { from, .. }
+            | MessageBody::Message { from, .. }
+            | MessageBody::Disconnect { from, .. }
+            | MessageBody::CallRequest { from, .. }
+            | MessageBody::CallRinging { from, .. }
+            | MessageBody::CallAccept { from, .. }
+            | MessageBody::CallHangUp { from, .. }
+            | MessageBody::ScreenShareStarted { from }
+            | MessageBody::ScreenShareStopped { from }
+            | MessageBody::RoomInvite { from, .. }
+            | MessageBody::Ack { from, .. }
+            | MessageBody::RoomInfo { from, .. }
+            | MessageBody::JoinRequest { from, .. }
+            | MessageBody::KnockRequest { from, .. }
+            | MessageBody::JoinReply { from, .. }
+            | MessageBody::ResendRequest { from, .. }
+            | MessageBody::Resend { from, .. }
+            | MessageBody::Presence { from, .. }
+            | MessageBody::Kick { from, .. }
+            | MessageBody::Ban { from, .. }
+            | MessageBody::Announcement { from, .. }
+            | MessageBody::RevokeInvite { from, .. }
+            | MessageBody::Image { from, .. }
+            | MessageBody::File { from, .. }
+            | MessageBody::Action { from, .. }
+            | MessageBody::HistoryRequest { from }
+            | MessageBody::Cover { from } => Some(*from),
+            MessageBody::MessageHistory { from, .. } => *from,
+            MessageBody::Unknown => None,
+        }
+    }
+
+    /// The deterministic id of a chat message, action or announcement, or None for every
+    /// other kind of body.
+    pub fn content_id(&self) -> Option<MessageId> {
+        match self {
+            MessageBody::Message {
+                from,
+                text,
+                id,
+                seq,
+            } => Some(message_id(from, *seq, *id, text)),
+            MessageBody::Announcement { from, text, id }
+            | MessageBody::Action { from, text, id } => Some(message_id(from, 0, *id, text)),
+            _ => None,
+        }
+    }
+
+    // Kinds that act on the room rather than add to it, which a replay could repeat.
+    fn replayable(&self) -> bool {
+        matches!(
+            self,
+            MessageBody::Kick { .. }
+                | MessageBody::Ban { .. }
+                | MessageBody::RevokeInvite { .. }
+                | MessageBody::Presence { .. }
+                | MessageBody::JoinReply { .. }
+        )
+    }
+}
+
+/// An invite the room owner signed into a ticket. The signature covers the room's
+/// topic too, so an invite can't be moved to another room or given a fresh id by
+/// whoever ends up holding a leaked ticket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Invite {
+    pub id: u64,
+    signature: Signature,
+}
+
+impl Invite {
+    pub fn new(owner_key: &SecretKey, topic: &[u8; 32]) -> Self {
+        let id = rand::random();
+        Self {
+            id,
+            signature: owner_key.sign(&invite_payload(topic, id)),
+        }
+    }
+
+    pub fn verify(&self, owner: &NodeId, topic: &[u8; 32]) -> bool {
+        owner
+            .verify(&invite_payload(topic, self.id), &self.signature)
+            .is_ok()
+    }
+}
+
+fn invite_payload(topic: &[u8; 32], id: u64) -> Vec<u8> {
+    [&b"chast invite"[..], topic, &id.to_le_bytes()].concat()
+}
+
+/// Hash of a chat message's sender, sequence number and content. Every peer derives
+/// the same id for the same message, unlike the random nonce on the envelope.
+pub type MessageId = [u8; 32];
+
+pub fn message_id(from: &NodeId, seq: u64, id: u64, text: &str) -> MessageId {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(from.as_bytes());
+    hasher.update(&seq.to_le_bytes());
+    hasher.update(&id.to_le_bytes());
+    hasher.update(text.as_bytes());
+    *hasher.finalize().as_bytes()
+}
+
+const SEEN_WINDOW: usize = 4096;
+
+/// The most recent message ids we've handled, used to drop replays and duplicates.
+#[derive(Debug, Clone)]
+pub struct SeenWindow {
+    capacity: usize,
+    order: VecDeque<MessageId>,
+    ids: HashSet<MessageId>,
+}
+
+impl Default for SeenWindow {
+    fn default() -> Self {
+        Self::new(SEEN_WINDOW)
+    }
+}
+
+impl SeenWindow {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::new(),
+            ids: HashSet::new(),
+        }
+    }
+
+    /// Records `message`, returning false if it's a duplicate or a replay. Chat messages,
+    /// actions and announcements are told apart by their content; kicks, bans, invite
+    /// revocations and presence changes by their signed nonce, and those are also refused
+    /// once they're further than [`REPLAY_WINDOW`] from now, past what the window holds.
+    pub fn admit(&mut self, message: &Message) -> bool {
+        if let Some(id) = message.body.content_id() {
+            return self.insert(id);
+        }
+        if !message.body.replayable() {
+            return true;
+        }
+        if (Utc::now() - message.sent).abs() > REPLAY_WINDOW {
+            return false;
+        }
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(b"chast replay");
+        hasher.update(&message.nonce);
+        self.insert(*hasher.finalize().as_bytes())
+    }
+
+    /// Records `id`, returning false if it's already in the window.
+    pub fn insert(&mut self, id: MessageId) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.ids.remove(&oldest);
+        }
+        true
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct RoomInfo {
+    pub name: String,
+    pub description: String,
+    pub topic: String,
+}
+
+impl RoomInfo {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_empty() && self.description.is_empty() && self.topic.is_empty()
+    }
+}
+
+/// Which client a peer runs and what it can do, so others can avoid offering features
+/// it would just ignore.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct ClientInfo {
+    pub version: String,
+    pub capabilities: Capabilities,
+    /// The [`PROTOCOL_VERSION`] it speaks; 0 from clients older than versioning.
+    pub protocol: u32,
+}
+
+impl ClientInfo {
+    /// Clients from before capabilities were advertised send no version; we don't know
+    /// what they support, so assume they can.
+    pub fn supports(&self, capability: Capabilities) -> bool {
+        self.version.is_empty() || self.capabilities.contains(capability)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const ENCRYPTION: Self = Self(1 << 0);
+    pub const FILES: Self = Self(1 << 1);
+    pub const REACTIONS: Self = Self(1 << 2);
+    pub const VOICE: Self = Self(1 << 3);
+    /// Keeps the room's message history and shares it with other history nodes.
+    pub const HISTORY: Self = Self(1 << 4);
+    /// Reads messages sent with [`Codec::Postcard`].
+    pub const COMPACT: Self = Self(1 << 5);
+    /// Takes delivery acks over [`receipts::ACK_ALPN`] instead of from the gossip.
+    pub const ACKS: Self = Self(1 << 6);
+
+    const NAMES: [(Self, &'static str); 7] = [
+        (Self::ENCRYPTION, "encryption"),
+        (Self::FILES, "files"),
+        (Self::REACTIONS, "reactions"),
+        (Self::VOICE, "voice"),
+        (Self::HISTORY, "history"),
+        (Self::COMPACT, "compact"),
+        (Self::ACKS, "acks"),
+    ];
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Human-readable names of the known capabilities that are set.
+    pub fn names(self) -> Vec<&'static str> {
+        Self::NAMES
+            .iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PresenceStatus {
+    #[default]
+    Online,
+    Away,
+    Busy,
+}
+
+impl PresenceStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PresenceStatus::Online => "online",
+            PresenceStatus::Away => "away",
+            PresenceStatus::Busy => "busy",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum JoinVerdict {
+    Admitted,
+    RoomFull { capacity: u32 },
+    Denied,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SentMessage {
+    pub seq: u64,
+    pub id: u64,
+    pub text: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -21,22 +487,280 @@ pub struct StoredMessage {
     pub from: NodeId,
     pub sender_name: String,
     pub text: String,
-    pub timestamp: String,
+    /// When the history node stored it. Clients show it in their own timezone.
+    #[serde(with = "utc_timestamp")]
+    pub timestamp: DateTime<Utc>,
+    /// Lets history nodes line up their archives; missing in history from older servers.
+    #[serde(default)]
+    pub id: Option<MessageId>,
+    /// Set when `text` came from a `/me` action rather than a normal message.
+    #[serde(default)]
+    pub action: bool,
+}
+
+/// How history was stamped before timestamps went out in UTC: the server's local time.
+pub const LEGACY_TIMESTAMP_FORMAT: &str = "%d/%m/%Y %H:%M:%S";
+
+/// Reads a stored message's timestamp: RFC 3339, or the legacy format taken as local
+/// time on this machine. Anything unreadable comes back as the epoch, so one bad entry
+/// doesn't cost the whole archive.
+pub fn parse_timestamp(raw: &str) -> DateTime<Utc> {
+    if let Ok(at) = DateTime::parse_from_rfc3339(raw) {
+        return at.with_timezone(&Utc);
+    }
+    NaiveDateTime::parse_from_str(raw, LEGACY_TIMESTAMP_FORMAT)
+        .ok()
+        .and_then(|at| Local.from_local_datetime(&at).earliest())
+        .map_or(DateTime::UNIX_EPOCH, |at| at.with_timezone(&Utc))
+}
+
+// Timestamps travel as RFC 3339 in UTC, which sorts and converts the same everywhere.
+mod utc_timestamp {
+    use chrono::{DateTime, SecondsFormat, Utc};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(at: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&at.to_rfc3339_opts(SecondsFormat::Millis, true))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Utc>, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        Ok(super::parse_timestamp(&raw))
+    }
+}
+
+/// How a message is turned into bytes. Every version reads JSON; postcard is a lot
+/// smaller, but only peers advertising [`Capabilities::COMPACT`] can read it.
+///
+/// Postcard leaves field names out and can't skip fields, so nothing that goes on the
+/// wire may use `skip_serializing_if`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Json,
+    Postcard,
+}
+
+/// The first byte of a postcard-encoded message. JSON always starts with `{`, so this
+/// one byte is enough to tell the two apart. Bumped whenever the postcard layout of the
+/// envelope changes; 0xB1 was the layout from before [`PROTOCOL_VERSION`], 0xB2 the
+/// one from before `sent`.
+pub const POSTCARD_MARKER: u8 = 0xB3;
+
+impl Codec {
+    /// Which codec encoded `bytes`.
+    pub fn of(bytes: &[u8]) -> Self {
+        match bytes.first() {
+            Some(&POSTCARD_MARKER) => Codec::Postcard,
+            _ => Codec::Json,
+        }
+    }
+
+    fn encode<T: Serialize>(self, value: &T) -> Vec<u8> {
+        match self {
+            Codec::Json => serde_json::to_vec(value).expect("serde_json::to_vec is infallible"),
+            Codec::Postcard => {
+                let mut bytes = vec![POSTCARD_MARKER];
+                bytes.extend(
+                    postcard::to_allocvec(value).expect("postcard::to_allocvec is infallible"),
+                );
+                bytes
+            }
+        }
+    }
+
+    // Bytes after a postcard value are ignored, which is what lets padding be tacked on.
+    fn decode<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Result<T> {
+        match bytes.split_first() {
+            Some((&POSTCARD_MARKER, rest)) => Ok(postcard::from_bytes(rest)?),
+            _ => Ok(serde_json::from_slice(bytes)?),
+        }
+    }
 }
 
 impl Message {
+    /// Reads a message in either codec. A message from a newer protocol version that
+    /// doesn't fit this build's `MessageBody`, or any postcard one from a newer version,
+    /// comes back as [`MessageBody::Unknown`] rather than an error.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        serde_json::from_slice(bytes).map_err(Into::into)
+        let header: Header = Codec::decode(bytes)?;
+        if header.version <= PROTOCOL_VERSION {
+            return Codec::decode(bytes);
+        }
+        // Postcard has no field names to go by, so a variant whose fields grew in a newer
+        // layout could read as garbage rather than fail. Only JSON from a newer peer is
+        // worth trying.
+        let known = match Codec::of(bytes) {
+            Codec::Json => Codec::decode::<Self>(bytes).ok(),
+            Codec::Postcard => None,
+        };
+        Ok(known.unwrap_or(Self {
+            version: header.version,
+            body: MessageBody::Unknown,
+            nonce: [0; 16],
+            sent: DateTime::UNIX_EPOCH,
+        }))
+    }
+
+    /// The sender's protocol version.
+    pub fn version(&self) -> u32 {
+        self.version
     }
 
     pub fn new(body: MessageBody) -> Self {
         Self {
+            version: PROTOCOL_VERSION,
             body,
             nonce: rand::random(),
+            sent: Utc::now(),
         }
     }
 
     pub fn to_vec(&self) -> Vec<u8> {
-        serde_json::to_vec(self).expect("serde_json::to_vec is infallible")
+        self.encode(Codec::Json)
+    }
+
+    pub fn encode(&self, codec: Codec) -> Vec<u8> {
+        codec.encode(self)
+    }
+
+    /// Encodes the message for gossip as JSON, signed with the sender's node key. The
+    /// signature covers the room's `topic`, so the message can't be replayed elsewhere.
+    pub fn to_signed_vec(&self, secret_key: &SecretKey, topic: &[u8; 32]) -> Vec<u8> {
+        self.to_signed_vec_with(secret_key, topic, Codec::Json)
+    }
+
+    /// Like [`Message::to_signed_vec`], in the given codec.
+    pub fn to_signed_vec_with(
+        &self,
+        secret_key: &SecretKey,
+        topic: &[u8; 32],
+        codec: Codec,
+    ) -> Vec<u8> {
+        match codec {
+            Codec::Json => {
+                let data =
+                    serde_json::to_string(self).expect("serde_json::to_string is infallible");
+                let signed = SignedMessage {
+                    signer: secret_key.public(),
+                    signature: secret_key.sign(&signed_payload(topic, data.as_bytes())),
+                    data: RawValue::from_string(data).expect("a Message is valid JSON"),
+                    padding: String::new(),
+                };
+                codec.encode(&signed)
+            }
+            Codec::Postcard => {
+                let data = self.encode(Codec::Postcard);
+                let signed = SignedBinary {
+                    signer: secret_key.public(),
+                    signature: secret_key.sign(&signed_payload(topic, &data)),
+                    data,
+                };
+                codec.encode(&signed)
+            }
+        }
+    }
+
+    /// Decodes a gossip message from the room with `topic` in either codec, rejecting it
+    /// unless the signature is valid and the signer is the node the body claims to be
+    /// from. Bodies that name no sender can't be checked, so they're rejected too, except
+    /// for [`MessageBody::Unknown`]: a newer kind this build can't read, vouched for by
+    /// the signature alone.
+    pub fn from_signed_bytes(bytes: &[u8], topic: &[u8; 32]) -> Result<Self> {
+        let (signer, message) = match Codec::of(bytes) {
+            Codec::Json => {
+                let signed: SignedView = Codec::decode(bytes)?;
+                // Older peers sent the data as a string of JSON rather than the JSON itself.
+                let legacy: String;
+                let data = match signed.data.get() {
+                    text if text.starts_with('"') => {
+                        legacy = serde_json::from_str(text)?;
+                        &legacy
+                    }
+                    text => text,
+                };
+                signed
+                    .signer
+                    .verify(&signed_payload(topic, data.as_bytes()), &signed.signature)
+                    .map_err(|_| anyhow!("Bad signature from {}", signed.signer.fmt_short()))?;
+                (signed.signer, Self::from_bytes(data.as_bytes())?)
+            }
+            Codec::Postcard => {
+                let signed: SignedBinaryView = Codec::decode(bytes)?;
+                signed
+                    .signer
+                    .verify(&signed_payload(topic, signed.data), &signed.signature)
+                    .map_err(|_| anyhow!("Bad signature from {}", signed.signer.fmt_short()))?;
+                (signed.signer, Self::from_bytes(signed.data)?)
+            }
+        };
+        match message.body.sender() {
+            Some(from) if from == signer => Ok(message),
+            Some(from) => Err(anyhow!(
+                "{} signed a message claiming to be from {}",
+                signer.fmt_short(),
+                from.fmt_short()
+            )),
+            None if matches!(message.body, MessageBody::Unknown) => Ok(message),
+            None => Err(anyhow!(
+                "{} signed a message that names no sender",
+                signer.fmt_short()
+            )),
+        }
     }
 }
+
+// What the sender's key signs: a hash of the room's topic and the encoded message, so
+// the message never has to be copied next to the topic to be checked.
+fn signed_payload(topic: &[u8; 32], data: &[u8]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"chast message");
+    hasher.update(topic);
+    hasher.update(data);
+    *hasher.finalize().as_bytes()
+}
+
+// Gossip relays messages through other peers, so unlike a direct connection the
+// transport doesn't tell us who wrote them.
+#[derive(Debug, Serialize, Deserialize)]
+struct SignedMessage {
+    signer: NodeId,
+    // The inner message, written into the envelope as the exact JSON that was signed.
+    data: Box<RawValue>,
+    signature: Signature,
+    // Filler from privacy::pad. Not signed, and ignored when reading.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    padding: String,
+}
+
+// The receiving side of SignedMessage, borrowing from the received frame. The padding is
+// left out so serde skips over it in the buffer instead of copying it out, and `data` is
+// a slice of the frame, checked and decoded where it lies.
+#[derive(Deserialize)]
+struct SignedView<'a> {
+    signer: NodeId,
+    #[serde(borrow)]
+    data: &'a RawValue,
+    signature: Signature,
+}
+
+// SignedMessage in postcard. `data` is the inner message, marker byte included, and
+// padding is whatever follows the envelope, so it needs no field of its own.
+#[derive(Debug, Serialize, Deserialize)]
+struct SignedBinary {
+    signer: NodeId,
+    signature: Signature,
+    data: Vec<u8>,
+}
+
+// The receiving side of SignedBinary. Postcard lays bytes out as they are, so `data` is
+// checked and decoded straight from the frame, and only the finished Message owns
+// anything.
+#[derive(Deserialize)]
+struct SignedBinaryView<'a> {
+    signer: NodeId,
+    signature: Signature,
+    data: &'a [u8],
+}
@@ -0,0 +1,33 @@
+//! Padding for signed gossip messages, so relays and other peers on the path can't tell
+//! what kind of message went by from its size.
+
+use crate::{Codec, SignedMessage};
+use anyhow::Result;
+
+/// Sizes padded messages are rounded up to. Anything bigger than the last is sent as is.
+///
+/// iroh-gossip refuses frames over 4096 bytes and wraps each message in a header of its
+/// own, so the last bucket leaves room for that.
+pub const BUCKETS: [usize; 4] = [512, 1024, 2048, 3584];
+
+/// Pads an encoded message from [`crate::Message::to_signed_vec`] up to the next bucket.
+/// The padding sits outside the signed data, and peers that don't know about it skip it.
+pub fn pad(signed: &[u8]) -> Result<Vec<u8>> {
+    // Postcard stops reading at the end of the envelope, so zeros after it do the job.
+    if Codec::of(signed) == Codec::Postcard {
+        let mut padded = signed.to_vec();
+        if let Some(&bucket) = BUCKETS.iter().find(|&&bucket| bucket >= signed.len()) {
+            padded.resize(bucket, 0);
+        }
+        return Ok(padded);
+    }
+    let mut message: SignedMessage = serde_json::from_slice(signed)?;
+    // An empty padding field still costs `,"padding":""`.
+    message.padding = " ".to_string();
+    let base = serde_json::to_vec(&message)?.len() - 1;
+    let Some(&bucket) = BUCKETS.iter().find(|&&bucket| bucket >= base) else {
+        return Ok(signed.to_vec());
+    };
+    message.padding = " ".repeat(bucket - base);
+    Ok(serde_json::to_vec(&message)?)
+}
@@ -0,0 +1,34 @@
+//! Delivery acks sent straight back to a message's author over a direct connection.
+//! Gossiped, every member's ack for every message reached every other member, and the
+//! room's traffic grew with the square of its size.
+//!
+//! Only peers advertising [`Capabilities::ACKS`](crate::Capabilities::ACKS) accept
+//! these; older ones still expect a gossiped `Ack`.
+
+use anyhow::Result;
+use iroh::{Endpoint, NodeId, endpoint::Connection};
+
+pub const ACK_ALPN: &[u8] = b"chast/ack/0";
+// Plenty for the ids of a burst of messages.
+const MAX_ACK_BYTES: usize = 64 * 1024;
+
+/// Tells `to` that its messages `ids` reached us, all over one connection.
+pub async fn send_acks(endpoint: &Endpoint, to: NodeId, ids: &[u64]) -> Result<()> {
+    #[cfg(feature = "chaos")]
+    crate::chaos::direct().await?;
+    let connection = endpoint.connect(to, ACK_ALPN).await?;
+    let mut send = connection.open_uni().await?;
+    send.write_all(&serde_json::to_vec(ids)?).await?;
+    send.finish()?;
+    // Closing before the author has them all would throw the ids away.
+    send.stopped().await?;
+    connection.close(0u32.into(), b"acks sent");
+    Ok(())
+}
+
+/// Reads the ids a peer sent with [`send_acks`].
+pub async fn read_acks(connection: &Connection) -> Result<Vec<u64>> {
+    let mut recv = connection.accept_uni().await?;
+    let bytes = recv.read_to_end(MAX_ACK_BYTES).await?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
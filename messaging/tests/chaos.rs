@@ -0,0 +1,75 @@
+use anyhow::Result;
+use futures_lite::{StreamExt, stream};
+use messaging::chaos::{Chaos, ChaosState, Delayed, Fate};
+use std::time::{Duration, Instant};
+
+#[test]
+fn chaos_settings_parse() -> Result<()> {
+    let chaos: Chaos = "latency=200ms, jitter=50, loss=0.1,duplicate=0.05,seed=7".parse()?;
+    assert_eq!(chaos.latency, Duration::from_millis(200));
+    assert_eq!(chaos.jitter, Duration::from_millis(50));
+    assert_eq!(
+        (chaos.loss, chaos.duplicate, chaos.disconnect),
+        (0.1, 0.05, 0.0)
+    );
+    assert_eq!(chaos.seed, 7);
+    assert!("loss".parse::<Chaos>().is_err());
+    assert!("drizzle=1".parse::<Chaos>().is_err());
+    Ok(())
+}
+
+// The same seed gives the same run, so a failure can be replayed.
+#[test]
+fn same_seed_rolls_the_same() {
+    let chaos = Chaos {
+        jitter: Duration::from_millis(100),
+        loss: 0.3,
+        duplicate: 0.3,
+        seed: 42,
+        ..Default::default()
+    };
+    let fates = |mut state: ChaosState| {
+        (0..50)
+            .map(|_| match state.roll() {
+                Fate::Deliver(delay) => format!("deliver {:?}", delay),
+                Fate::Duplicate(delay) => format!("twice {:?}", delay),
+                Fate::Drop => "drop".to_string(),
+                Fate::Disconnect => "disconnect".to_string(),
+            })
+            .collect::<Vec<_>>()
+    };
+    let first = fates(ChaosState::new(chaos.clone()));
+    assert_eq!(first, fates(ChaosState::new(chaos)));
+    assert!(first.iter().any(|fate| fate == "drop"));
+    assert!(first.iter().any(|fate| fate.starts_with("twice")));
+    assert!(!first.iter().any(|fate| fate == "disconnect"));
+}
+
+// Every message waits out its own delay, so a burst isn't held up one latency per message.
+#[tokio::test]
+async fn burst_arrives_one_latency_later() {
+    const BURST: usize = 20;
+    let latency = Duration::from_millis(200);
+    let mut delayed = Delayed::off(stream::iter((0..BURST).map(Ok::<_, ()>)));
+    delayed.set_chaos(Chaos {
+        latency,
+        ..Default::default()
+    });
+
+    let start = Instant::now();
+    let received: Vec<_> = delayed.collect().await;
+    assert_eq!(received.len(), BURST);
+    assert!(start.elapsed() >= latency);
+    assert!(start.elapsed() < latency * 2, "took {:?}", start.elapsed());
+}
+
+#[tokio::test]
+async fn disconnect_ends_the_stream() {
+    let mut delayed = Delayed::off(stream::iter((0..5).map(Ok::<_, ()>)));
+    delayed.set_chaos(Chaos {
+        disconnect: 1.0,
+        ..Default::default()
+    });
+    assert_eq!(delayed.next().await, None);
+    assert!(delayed.disconnected());
+}
@@ -0,0 +1,83 @@
+use anyhow::Result;
+use futures_lite::StreamExt;
+use iroh::{Endpoint, RelayMode, SecretKey, Watcher, protocol::Router};
+use iroh_gossip::{api::Event, net::Gossip, proto::TopicId};
+use messaging::{Codec, Message, MessageBody, privacy};
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    time::Duration,
+};
+
+const TOPIC: [u8; 32] = [1; 32];
+
+#[test]
+fn padded_message_fills_a_bucket_and_still_verifies() -> Result<()> {
+    let secret_key = SecretKey::from_bytes(&rand::random());
+    let from = secret_key.public();
+    let signed = Message::new(MessageBody::Cover { from }).to_signed_vec(&secret_key, &TOPIC);
+
+    let padded = privacy::pad(&signed)?;
+    assert!(privacy::BUCKETS.contains(&padded.len()));
+
+    let message = Message::from_signed_bytes(&padded, &TOPIC)?;
+    assert!(matches!(message.body, MessageBody::Cover { from: f } if f == from));
+    Ok(())
+}
+
+async fn local_node() -> Result<(Endpoint, Gossip, Router)> {
+    let endpoint = Endpoint::builder()
+        .relay_mode(RelayMode::Disabled)
+        .bind_addr_v4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+        .bind()
+        .await?;
+    let gossip = Gossip::builder().spawn(endpoint.clone());
+    let router = Router::builder(endpoint.clone())
+        .accept(iroh_gossip::ALPN, gossip.clone())
+        .spawn();
+    Ok((endpoint, gossip, router))
+}
+
+// A padded message in the largest bucket still has to fit in one gossip frame, or it
+// never arrives.
+#[tokio::test]
+async fn largest_bucket_goes_through_gossip() -> Result<()> {
+    let largest = *privacy::BUCKETS.last().unwrap();
+    let secret_key = SecretKey::from_bytes(&rand::random());
+    let text = "a".repeat(largest - 1024);
+    for codec in [Codec::Json, Codec::Postcard] {
+        let message = Message::new(MessageBody::Message {
+            from: secret_key.public(),
+            text: text.clone(),
+            id: 1,
+            seq: 1,
+        });
+        let padded = privacy::pad(&message.to_signed_vec_with(&secret_key, &TOPIC, codec))?;
+        assert_eq!(padded.len(), largest);
+
+        let (alice_endpoint, alice, _alice_router) = local_node().await?;
+        let (bob_endpoint, bob, _bob_router) = local_node().await?;
+        let topic = TopicId::from_bytes(rand::random());
+        let bob_addr = bob_endpoint.node_addr().initialized().await;
+        alice_endpoint.add_node_addr(bob_addr.clone())?;
+
+        let mut bob_topic = bob.subscribe(topic, vec![]).await?;
+        let (alice_sender, _alice_receiver) = alice
+            .subscribe_and_join(topic, vec![bob_addr.node_id])
+            .await?
+            .split();
+        tokio::time::timeout(Duration::from_secs(10), bob_topic.joined()).await??;
+
+        alice_sender.broadcast(padded.clone().into()).await?;
+        let received = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Some(Event::Received(message)) = bob_topic.try_next().await? {
+                    return anyhow::Ok(message.content);
+                }
+            }
+        })
+        .await??;
+        assert_eq!(&received[..], &padded[..]);
+        assert!(Message::from_signed_bytes(&received, &TOPIC).is_ok());
+    }
+    Ok(())
+}
@@ -0,0 +1,184 @@
+//! Spins up an in-process room and measures how gossip delivers a steady stream of
+//! messages: `cargo run --release -p p2p-chat-bot --example bench -- --nodes 8 --messages 500`.
+//! Everything runs over loopback on one machine's CPUs, so the numbers show protocol
+//! overhead, not the network; debug builds spend most of the time on crypto.
+
+use anyhow::{Context, Result, bail};
+use p2p_chat_bot::{BotEvent, testkit::TestRoom};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// How long receivers keep listening after the last message went out.
+const DRAIN: Duration = Duration::from_secs(5);
+// Gives the gossip mesh time to form before anything is sent.
+const SETTLE: Duration = Duration::from_secs(2);
+
+struct Options {
+    nodes: usize,
+    messages: u64,
+    size: usize,
+    rate: u64,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut options = Self {
+            nodes: 5,
+            messages: 200,
+            size: 64,
+            rate: 50,
+        };
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .with_context(|| format!("{} needs a value", flag))?;
+            let number = || {
+                value
+                    .parse::<u64>()
+                    .with_context(|| format!("{} takes a number, not {}", flag, value))
+            };
+            match flag.as_str() {
+                "--nodes" => options.nodes = number()? as usize,
+                "--messages" => options.messages = number()?,
+                "--size" => options.size = number()? as usize,
+                "--rate" => options.rate = number()?,
+                _ => {
+                    bail!("usage: bench [--nodes N] [--messages N] [--size BYTES] [--rate PER_SEC]")
+                }
+            }
+        }
+        if options.nodes < 2 || options.rate == 0 {
+            bail!("bench needs at least 2 nodes and a rate above 0");
+        }
+        Ok(options)
+    }
+}
+
+// What one receiving node saw.
+#[derive(Default)]
+struct Received {
+    latencies: Vec<Duration>,
+    seqs: HashSet<u64>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let options = Options::parse(std::env::args().skip(1))?;
+    println!(
+        "> {} nodes, {} messages of {} bytes at {}/s",
+        options.nodes, options.messages, options.size, options.rate
+    );
+
+    let mut room = TestRoom::new();
+    let mut bots = Vec::with_capacity(options.nodes);
+    for n in 0..options.nodes {
+        bots.push(room.join(&format!("node-{}", n)).await?);
+    }
+    let mut bots = bots.into_iter();
+    let mut source = bots.next().expect("at least two nodes");
+    let sender = source.sender();
+    tokio::time::sleep(SETTLE).await;
+
+    // Send times by sequence number. Every node runs in this process, so one clock
+    // covers both ends.
+    let sent: Arc<Mutex<HashMap<u64, Instant>>> = Arc::default();
+    let done = Arc::new(tokio::sync::Notify::new());
+
+    // The source's own events are only drained, so its gossip receiver never backs up.
+    let drain = tokio::spawn(async move { while let Ok(Some(_)) = source.next_event().await {} });
+
+    let receivers: Vec<_> = bots
+        .map(|mut bot| {
+            let sent = sent.clone();
+            let done = done.clone();
+            tokio::spawn(async move {
+                let mut received = Received::default();
+                let finished = done.notified();
+                tokio::pin!(finished);
+                loop {
+                    let event = tokio::select! {
+                        event = bot.next_event() => event,
+                        _ = &mut finished => break,
+                    };
+                    let text = match event {
+                        Ok(Some(BotEvent::Message { text, .. })) => text,
+                        Ok(Some(_)) => continue,
+                        Ok(None) | Err(_) => break,
+                    };
+                    let at = Instant::now();
+                    let Some(seq) = text
+                        .split_once(' ')
+                        .and_then(|(seq, _)| seq.parse::<u64>().ok())
+                    else {
+                        continue;
+                    };
+                    if !received.seqs.insert(seq) {
+                        continue;
+                    }
+                    if let Some(sent_at) = sent.lock().unwrap().get(&seq) {
+                        received.latencies.push(at - *sent_at);
+                    }
+                }
+                received
+            })
+        })
+        .collect();
+
+    let padding = "x".repeat(options.size);
+    let mut ticker = tokio::time::interval(Duration::from_secs(1) / options.rate as u32);
+    let started = Instant::now();
+    for seq in 0..options.messages {
+        ticker.tick().await;
+        sent.lock().unwrap().insert(seq, Instant::now());
+        sender.send(format!("{} {}", seq, padding)).await?;
+    }
+    let sending = started.elapsed();
+    tokio::time::sleep(DRAIN).await;
+    done.notify_waiters();
+
+    let mut latencies = Vec::new();
+    let mut delivered = 0;
+    for (n, receiver) in receivers.into_iter().enumerate() {
+        let received = receiver.await?;
+        println!(
+            "> node-{}: {}/{} delivered",
+            n + 1,
+            received.seqs.len(),
+            options.messages
+        );
+        delivered += received.seqs.len() as u64;
+        latencies.extend(received.latencies);
+    }
+    drain.abort();
+
+    let expected = options.messages * (options.nodes as u64 - 1);
+    println!(
+        "> sent {} messages in {:.2?} ({:.0}/s)",
+        options.messages,
+        sending,
+        options.messages as f64 / sending.as_secs_f64()
+    );
+    println!(
+        "> loss: {:.2}% ({} of {} deliveries missing)",
+        100.0 * (expected - delivered) as f64 / expected as f64,
+        expected - delivered,
+        expected
+    );
+    latencies.sort();
+    if latencies.is_empty() {
+        println!("> latency: nothing was delivered");
+    } else {
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+        println!(
+            "> latency: p50 {:.2?}, p90 {:.2?}, p99 {:.2?}, max {:.2?}",
+            percentile(50),
+            percentile(90),
+            percentile(99),
+            latencies[latencies.len() - 1]
+        );
+    }
+    Ok(())
+}
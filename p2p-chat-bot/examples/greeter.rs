@@ -0,0 +1,29 @@
+use anyhow::Result;
+use p2p_chat_bot::{Bot, BotEvent};
+use std::str::FromStr;
+use ticket::Ticket;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let ticket = std::env::args()
+        .nth(1)
+        .ok_or_else(|| anyhow::anyhow!("usage: greeter <ticket>"))?;
+
+    let mut bot = Bot::join(Ticket::from_str(&ticket)?, "Greeter").await?;
+    println!("> greeter joined as {}", bot.node_id().fmt_short());
+
+    while let Some(event) = bot.next_event().await? {
+        match event {
+            BotEvent::Joined { name, .. } => {
+                bot.send(format!("👋 Welcome to the room, {}!", name))
+                    .await?;
+            }
+            BotEvent::Message { name, text, .. } if text.trim() == "!ping" => {
+                bot.send(format!("pong, {}", name)).await?;
+            }
+            _ => {}
+        }
+    }
+
+    bot.leave().await
+}
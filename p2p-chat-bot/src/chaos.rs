@@ -0,0 +1,5 @@
+//! Bad network on demand for a single bot. The knobs live in `messaging::chaos`, which
+//! the client and the server use too; a bot takes its own [`Chaos`] so tests can give
+//! each bot a different seed.
+
+pub use messaging::chaos::Chaos;
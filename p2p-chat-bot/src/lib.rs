@@ -0,0 +1,461 @@
+//! Small automation API for Chast rooms.
+//!
+//! ```no_run
+//! # async fn run(ticket: ticket::Ticket) -> anyhow::Result<()> {
+//! use p2p_chat_bot::{Bot, BotEvent};
+//!
+//! let mut bot = Bot::join(ticket, "greeter").await?;
+//! while let Some(event) = bot.next_event().await? {
+//!     if let BotEvent::Joined { name, .. } = event {
+//!         bot.send(format!("Welcome, {}!", name)).await?;
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::{Result, bail};
+use futures_lite::{Stream, StreamExt, stream};
+use iroh::{Endpoint, NodeId, SecretKey, protocol::Router};
+use iroh_gossip::{
+    api::{Event, GossipReceiver, GossipSender},
+    net::Gossip,
+    proto::TopicId,
+};
+use messaging::{
+    Capabilities, ClientInfo, Invite, Message, MessageBody, PROTOCOL_VERSION, SeenWindow,
+    StoredMessage, history::fetch_history, receipts::send_acks,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+use ticket::Ticket;
+
+#[cfg(feature = "chaos")]
+pub mod chaos;
+#[cfg(feature = "testkit")]
+pub mod testkit;
+
+const HISTORY_TIMEOUT: Duration = Duration::from_secs(10);
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub enum BotEvent {
+    Joined {
+        from: NodeId,
+        name: String,
+    },
+    Left {
+        from: NodeId,
+        name: String,
+    },
+    Message {
+        from: NodeId,
+        name: String,
+        text: String,
+    },
+    /// A `/me` action, e.g. `text` is "waves" for "* carol waves".
+    Action {
+        from: NodeId,
+        name: String,
+        text: String,
+    },
+    History {
+        messages: Vec<StoredMessage>,
+    },
+}
+
+/// Cloneable handle for posting into the room from other tasks.
+#[derive(Debug, Clone)]
+pub struct BotSender {
+    sender: GossipSender,
+    topic: TopicId,
+    node_id: NodeId,
+    secret_key: SecretKey,
+    name: String,
+    invite: Option<Invite>,
+}
+
+impl BotSender {
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub async fn send(&self, text: impl Into<String>) -> Result<()> {
+        self.broadcast(MessageBody::Message {
+            from: self.node_id,
+            text: text.into(),
+            id: rand::random::<u64>().max(1),
+            seq: 0,
+        })
+        .await
+    }
+
+    /// Posts a `/me` action, shown as "* name text" in the room.
+    pub async fn send_action(&self, text: impl Into<String>) -> Result<()> {
+        self.broadcast(MessageBody::Action {
+            from: self.node_id,
+            text: text.into(),
+            id: rand::random::<u64>().max(1),
+        })
+        .await
+    }
+
+    /// Tells the room we are leaving without tearing down the node.
+    pub async fn disconnect(&self) -> Result<()> {
+        self.broadcast(MessageBody::Disconnect {
+            from: self.node_id,
+            name: self.name.clone(),
+        })
+        .await
+    }
+
+    /// Gossips stored messages to the whole room, the way older history servers greeted
+    /// newcomers. Bots only take such an archive from the nodes named in their ticket.
+    pub async fn send_history(&self, messages: Vec<StoredMessage>) -> Result<()> {
+        self.broadcast(MessageBody::MessageHistory {
+            from: Some(self.node_id),
+            messages,
+            chunk: 0,
+            chunks: 0,
+        })
+        .await
+    }
+
+    async fn ack(&self, to: NodeId, id: u64) -> Result<()> {
+        self.broadcast(MessageBody::Ack {
+            from: self.node_id,
+            to,
+            id,
+        })
+        .await
+    }
+
+    async fn decline_call(&self, to: NodeId, call_id: u64) -> Result<()> {
+        self.broadcast(MessageBody::CallHangUp {
+            from: self.node_id,
+            to,
+            call_id,
+        })
+        .await
+    }
+
+    async fn announce(&self) -> Result<()> {
+        self.broadcast(MessageBody::AboutMe {
+            from: self.node_id,
+            name: self.name.clone(),
+            // Bots only read and post text.
+            client: ClientInfo {
+                version: concat!("chast-bot/", env!("CARGO_PKG_VERSION")).to_string(),
+                capabilities: Capabilities::COMPACT,
+                protocol: PROTOCOL_VERSION,
+            },
+            invite: self.invite,
+        })
+        .await
+    }
+
+    async fn broadcast(&self, body: MessageBody) -> Result<()> {
+        let message = Message::new(body);
+        self.sender
+            .broadcast(
+                message
+                    .to_signed_vec(&self.secret_key, self.topic.as_bytes())
+                    .into(),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+pub struct Bot {
+    sender: BotSender,
+    receiver: Receiver,
+    names: HashMap<NodeId, String>,
+    moderators: Vec<NodeId>,
+    // The nodes named in the ticket, the only ones whose gossiped archive is taken.
+    room_nodes: Vec<NodeId>,
+    router: Router,
+    // Set once a history node has given us the archive, so only the first one is asked.
+    history_loaded: bool,
+    // An archive fetched while handling a join, handed out on the next call.
+    pending_history: Option<Vec<StoredMessage>>,
+    // Drops gossip that comes round twice and moderation replayed from another time.
+    seen: SeenWindow,
+    // Members whose clients take acks over a direct connection rather than from the gossip.
+    direct_acks: HashSet<NodeId>,
+}
+
+// The gossip stream, with room for chaos to be switched on later when built with it.
+#[cfg(feature = "chaos")]
+type Receiver = messaging::chaos::Delayed<GossipReceiver, Event, iroh_gossip::api::ApiError>;
+#[cfg(not(feature = "chaos"))]
+type Receiver = GossipReceiver;
+
+impl Bot {
+    pub async fn join(ticket: Ticket, name: impl Into<String>) -> Result<Self> {
+        let endpoint = Endpoint::builder().discovery_n0().bind().await?;
+        Self::join_with_endpoint(endpoint, ticket, name).await
+    }
+
+    /// Joins using an already bound endpoint, e.g. one with a fixed secret key.
+    pub async fn join_with_endpoint(
+        endpoint: Endpoint,
+        ticket: Ticket,
+        name: impl Into<String>,
+    ) -> Result<Self> {
+        if ticket.is_locked() {
+            bail!("Ticket is password protected, unlock it first");
+        }
+        let gossip = Gossip::builder().spawn(endpoint.clone());
+        let router = Router::builder(endpoint.clone())
+            .accept(iroh_gossip::ALPN, gossip.clone())
+            .spawn();
+
+        let Ticket {
+            topic,
+            nodes,
+            moderators,
+            invite,
+            ..
+        } = ticket;
+        let node_ids: Vec<NodeId> = nodes.iter().map(|node| node.node_id).collect();
+        for node in nodes {
+            endpoint.add_node_addr(node)?;
+        }
+
+        // With nobody to bootstrap from we are the first member and must not wait for
+        // neighbors, otherwise joining would never complete.
+        let (sender, receiver) = if node_ids.is_empty() {
+            gossip.subscribe(topic, vec![]).await?.split()
+        } else {
+            gossip
+                .subscribe_and_join(topic, node_ids.clone())
+                .await?
+                .split()
+        };
+
+        let sender = BotSender {
+            sender,
+            topic,
+            node_id: endpoint.node_id(),
+            secret_key: endpoint.secret_key().clone(),
+            name: name.into(),
+            invite,
+        };
+        sender.announce().await?;
+
+        #[cfg(feature = "chaos")]
+        let receiver = messaging::chaos::Delayed::off(receiver);
+        Ok(Self {
+            sender,
+            receiver,
+            names: HashMap::new(),
+            moderators,
+            room_nodes: node_ids,
+            router,
+            history_loaded: false,
+            pending_history: None,
+            seen: SeenWindow::default(),
+            direct_acks: HashSet::new(),
+        })
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.sender.node_id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.sender.name
+    }
+
+    pub fn sender(&self) -> BotSender {
+        self.sender.clone()
+    }
+
+    pub fn name_of(&self, node_id: &NodeId) -> Option<&str> {
+        self.names.get(node_id).map(String::as_str)
+    }
+
+    pub fn online(&self) -> impl Iterator<Item = (&NodeId, &str)> {
+        self.names.iter().map(|(id, name)| (id, name.as_str()))
+    }
+
+    pub async fn send(&self, text: impl Into<String>) -> Result<()> {
+        self.sender.send(text).await
+    }
+
+    pub async fn send_action(&self, text: impl Into<String>) -> Result<()> {
+        self.sender.send_action(text).await
+    }
+
+    /// Runs everything this bot receives from now on through `chaos`.
+    #[cfg(feature = "chaos")]
+    pub fn set_chaos(&mut self, chaos: chaos::Chaos) {
+        self.receiver.set_chaos(chaos);
+    }
+
+    /// Waits for the next room event. Returns `None` once the gossip stream has closed.
+    pub async fn next_event(&mut self) -> Result<Option<BotEvent>> {
+        if let Some(messages) = self.pending_history.take() {
+            return Ok(Some(BotEvent::History { messages }));
+        }
+        loop {
+            let Some(event) = self.receiver.try_next().await? else {
+                // Shutting the router down closes the endpoint, so the rest of the room
+                // sees us vanish without a goodbye, like a pulled cable.
+                #[cfg(feature = "chaos")]
+                if self.receiver.disconnected() {
+                    self.router.shutdown().await?;
+                    bail!("simulated disconnect");
+                }
+                return Ok(None);
+            };
+            let Event::Received(msg) = event else {
+                continue;
+            };
+            let Ok(message) =
+                Message::from_signed_bytes(&msg.content, self.sender.topic.as_bytes())
+            else {
+                continue;
+            };
+            if !self.seen.admit(&message) {
+                continue;
+            }
+
+            match message.body {
+                MessageBody::AboutMe {
+                    from, name, client, ..
+                } => {
+                    let is_new_user = self.names.insert(from, name.clone()).is_none();
+                    if client.capabilities.contains(Capabilities::ACKS) {
+                        self.direct_acks.insert(from);
+                    } else {
+                        self.direct_acks.remove(&from);
+                    }
+                    if is_new_user {
+                        self.sender.announce().await?;
+                        if client.capabilities.contains(Capabilities::HISTORY)
+                            && self.room_nodes.contains(&from)
+                            && !self.history_loaded
+                        {
+                            self.load_history(from).await;
+                        }
+                        return Ok(Some(BotEvent::Joined { from, name }));
+                    }
+                }
+                MessageBody::Disconnect { from, name } => {
+                    self.names.remove(&from);
+                    self.direct_acks.remove(&from);
+                    return Ok(Some(BotEvent::Left { from, name }));
+                }
+                MessageBody::Message { from, .. } | MessageBody::Action { from, .. }
+                    if !self.moderators.is_empty() && !self.moderators.contains(&from) => {}
+                MessageBody::Message { from, text, id, .. } => {
+                    if id != 0 && from != self.sender.node_id {
+                        self.ack(from, id).await?;
+                    }
+                    let name = self
+                        .names
+                        .get(&from)
+                        .cloned()
+                        .unwrap_or_else(|| from.fmt_short());
+                    return Ok(Some(BotEvent::Message { from, name, text }));
+                }
+                MessageBody::Action { from, text, id } => {
+                    if id != 0 && from != self.sender.node_id {
+                        self.ack(from, id).await?;
+                    }
+                    let name = self
+                        .names
+                        .get(&from)
+                        .cloned()
+                        .unwrap_or_else(|| from.fmt_short());
+                    return Ok(Some(BotEvent::Action { from, name, text }));
+                }
+                MessageBody::MessageHistory {
+                    from: Some(from),
+                    messages,
+                    ..
+                } if self.room_nodes.contains(&from) => {
+                    self.history_loaded = true;
+                    return Ok(Some(BotEvent::History { messages }));
+                }
+                MessageBody::CallRequest { from, to, call_id } if to == self.sender.node_id => {
+                    self.sender.decline_call(from, call_id).await?;
+                }
+                MessageBody::CallRequest { .. }
+                | MessageBody::CallRinging { .. }
+                | MessageBody::CallAccept { .. }
+                | MessageBody::CallHangUp { .. }
+                | MessageBody::ScreenShareStarted { .. }
+                | MessageBody::ScreenShareStopped { .. }
+                | MessageBody::RoomInvite { .. }
+                | MessageBody::Ack { .. }
+                | MessageBody::RoomInfo { .. }
+                | MessageBody::JoinRequest { .. }
+                | MessageBody::KnockRequest { .. }
+                | MessageBody::ResendRequest { .. }
+                | MessageBody::Resend { .. }
+                | MessageBody::Presence { .. }
+                | MessageBody::JoinReply { .. }
+                | MessageBody::Kick { .. }
+                | MessageBody::Ban { .. }
+                | MessageBody::Announcement { .. }
+                | MessageBody::RevokeInvite { .. }
+                | MessageBody::Image { .. }
+                | MessageBody::File { .. }
+                | MessageBody::MessageHistory { .. }
+                | MessageBody::HistoryRequest { .. }
+                | MessageBody::Cover { .. }
+                | MessageBody::Unknown => {}
+            }
+        }
+    }
+
+    // Acks straight to the author when its client takes that, in the background so an
+    // unreachable author doesn't hold up the room. Older clients get a gossiped Ack.
+    async fn ack(&self, to: NodeId, id: u64) -> Result<()> {
+        if !self.direct_acks.contains(&to) {
+            return self.sender.ack(to, id).await;
+        }
+        let endpoint = self.router.endpoint().clone();
+        tokio::spawn(async move {
+            let _ = tokio::time::timeout(ACK_TIMEOUT, send_acks(&endpoint, to, &[id])).await;
+        });
+        Ok(())
+    }
+
+    // A history node that can't answer isn't fatal; the next one to show up is asked.
+    async fn load_history(&mut self, from: NodeId) {
+        let endpoint = self.router.endpoint();
+        if let Ok(Ok(messages)) =
+            tokio::time::timeout(HISTORY_TIMEOUT, fetch_history(endpoint, from, |_, _| {})).await
+        {
+            self.history_loaded = true;
+            self.pending_history = Some(messages);
+        }
+    }
+
+    /// Turns the bot into a stream of events. Grab a [`BotSender`] first to keep posting.
+    pub fn into_stream(self) -> impl Stream<Item = Result<BotEvent>> {
+        stream::unfold(self, |mut bot| async move {
+            match bot.next_event().await {
+                Ok(Some(event)) => Some((Ok(event), bot)),
+                Ok(None) => None,
+                Err(e) => Some((Err(e), bot)),
+            }
+        })
+    }
+
+    pub async fn leave(self) -> Result<()> {
+        self.sender.disconnect().await?;
+        self.router.shutdown().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+//! In-process rooms for tests: every member gets its own loopback-only endpoint with relays
+//! and discovery disabled, so nothing leaves the machine.
+
+use crate::{Bot, BotEvent};
+use anyhow::{Result, anyhow};
+use iroh::{Endpoint, NodeAddr, RelayMode, Watcher};
+use iroh_gossip::proto::TopicId;
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    time::Duration,
+};
+use ticket::Ticket;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn local_endpoint() -> Result<Endpoint> {
+    let endpoint = Endpoint::builder()
+        .relay_mode(RelayMode::Disabled)
+        .bind_addr_v4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+        .bind()
+        .await?;
+    Ok(endpoint)
+}
+
+pub struct TestRoom {
+    topic: TopicId,
+    members: Vec<NodeAddr>,
+}
+
+impl Default for TestRoom {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TestRoom {
+    pub fn new() -> Self {
+        Self {
+            topic: TopicId::from_bytes(rand::random()),
+            members: Vec::new(),
+        }
+    }
+
+    pub fn topic(&self) -> TopicId {
+        self.topic
+    }
+
+    /// A ticket pointing at everyone who has joined so far.
+    pub fn ticket(&self) -> Ticket {
+        Ticket {
+            topic: self.topic,
+            nodes: self.members.clone(),
+            moderators: Vec::new(),
+            owner: None,
+            info: Default::default(),
+            capacity: None,
+            knock: false,
+            incognito: false,
+            locked: None,
+            invite: None,
+        }
+    }
+
+    pub async fn join(&mut self, name: &str) -> Result<Bot> {
+        let endpoint = local_endpoint().await?;
+        let addr = endpoint.node_addr().initialized().await;
+
+        let bot = Bot::join_with_endpoint(endpoint, self.ticket(), name).await?;
+        self.members.push(addr);
+        Ok(bot)
+    }
+}
+
+/// Waits for the first event matching `predicate`, skipping everything else.
+pub async fn expect_event<F>(bot: &mut Bot, mut predicate: F) -> Result<BotEvent>
+where
+    F: FnMut(&BotEvent) -> bool,
+{
+    tokio::time::timeout(DEFAULT_TIMEOUT, async {
+        loop {
+            match bot.next_event().await? {
+                Some(event) if predicate(&event) => return Ok(event),
+                Some(_) => continue,
+                None => return Err(anyhow!("room closed before the expected event")),
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("timed out waiting for event on {}", bot.name()))?
+}
+
+pub async fn expect_joined(bot: &mut Bot, name: &str) -> Result<BotEvent> {
+    expect_event(
+        bot,
+        |event| matches!(event, BotEvent::Joined { name: n, .. } if n == name),
+    )
+    .await
+}
+
+pub async fn expect_message(bot: &mut Bot, text: &str) -> Result<BotEvent> {
+    expect_event(
+        bot,
+        |event| matches!(event, BotEvent::Message { text: t, .. } if t == text),
+    )
+    .await
+}
@@ -0,0 +1,117 @@
+use anyhow::Result;
+use chrono::Utc;
+use iroh::{
+    NodeId, Watcher,
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler, Router},
+};
+use messaging::{
+    StoredMessage,
+    history::{self, CHUNK_BYTES, HISTORY_ALPN, fetch_history},
+};
+use p2p_chat_bot::testkit::{DEFAULT_TIMEOUT, local_endpoint};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+// Serves `archive` over HISTORY_ALPN to `members` only, the way the history server does.
+#[derive(Debug, Clone)]
+struct Archive {
+    node_id: NodeId,
+    members: Arc<Mutex<HashSet<NodeId>>>,
+    archive: Vec<StoredMessage>,
+}
+
+impl ProtocolHandler for Archive {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let remote = connection.remote_node_id()?;
+        let (mut send, mut recv) = connection.accept_bi().await?;
+        let from = history::read_request(&mut recv)
+            .await
+            .map_err(|_| AcceptError::NotAllowed {})?;
+        if from != remote || !self.members.lock().unwrap().contains(&from) {
+            return Err(AcceptError::NotAllowed {});
+        }
+        history::send_history(&mut send, self.node_id, self.archive.clone())
+            .await
+            .map_err(|_| AcceptError::NotAllowed {})?;
+        connection.closed().await;
+        Ok(())
+    }
+}
+
+fn stored(text: String) -> StoredMessage {
+    StoredMessage {
+        from: iroh::SecretKey::from_bytes(&rand::random()).public(),
+        sender_name: "archive".to_string(),
+        text,
+        timestamp: Utc::now(),
+        id: None,
+        action: false,
+    }
+}
+
+// Starts a node serving `archive`, and returns it with its member list.
+async fn serve(archive: Vec<StoredMessage>) -> Result<(Router, Arc<Mutex<HashSet<NodeId>>>)> {
+    let endpoint = local_endpoint().await?;
+    let members = Arc::new(Mutex::new(HashSet::new()));
+    let handler = Archive {
+        node_id: endpoint.node_id(),
+        members: members.clone(),
+        archive,
+    };
+    let router = Router::builder(endpoint)
+        .accept(HISTORY_ALPN, handler)
+        .spawn();
+    Ok((router, members))
+}
+
+#[tokio::test]
+async fn history_is_fetched_from_a_serving_node() -> Result<()> {
+    // Big enough to need more than one chunk.
+    let archive: Vec<_> = (0..3)
+        .map(|i| stored(format!("{} {}", i, "x".repeat(CHUNK_BYTES / 2))))
+        .collect();
+    let (server, members) = serve(archive.clone()).await?;
+    let client = local_endpoint().await?;
+    client.add_node_addr(server.endpoint().node_addr().initialized().await)?;
+    members.lock().unwrap().insert(client.node_id());
+
+    let mut progress = Vec::new();
+    let fetched = tokio::time::timeout(
+        DEFAULT_TIMEOUT,
+        fetch_history(&client, server.endpoint().node_id(), |got, of| {
+            progress.push((got, of))
+        }),
+    )
+    .await??;
+    let texts = |messages: &[StoredMessage]| {
+        messages
+            .iter()
+            .map(|message| message.text.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(texts(&fetched), texts(&archive));
+    assert_eq!(
+        progress.last(),
+        Some(&(progress.len() as u32, progress.len() as u32))
+    );
+    assert!(progress.len() > 1, "{:?}", progress);
+    Ok(())
+}
+
+#[tokio::test]
+async fn history_is_refused_to_non_members() -> Result<()> {
+    let (server, _members) = serve(vec![stored("secret".to_string())]).await?;
+    let client = local_endpoint().await?;
+    client.add_node_addr(server.endpoint().node_addr().initialized().await)?;
+
+    let fetched = tokio::time::timeout(
+        DEFAULT_TIMEOUT,
+        fetch_history(&client, server.endpoint().node_id(), |_, _| {}),
+    )
+    .await?;
+    assert!(fetched.is_err());
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use anyhow::Result;
+use iroh::{
+    Watcher,
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler, Router},
+};
+use messaging::receipts::{self, ACK_ALPN, send_acks};
+use p2p_chat_bot::testkit::{DEFAULT_TIMEOUT, local_endpoint};
+use tokio::sync::mpsc;
+
+// Hands every batch of acked ids to the test, the way a client marks them delivered.
+#[derive(Debug, Clone)]
+struct Author {
+    acked: mpsc::UnboundedSender<Vec<u64>>,
+}
+
+impl ProtocolHandler for Author {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let ids = receipts::read_acks(&connection)
+            .await
+            .map_err(|_| AcceptError::NotAllowed {})?;
+        let _ = self.acked.send(ids);
+        connection.closed().await;
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn acks_reach_the_author_directly() -> Result<()> {
+    let (acked, mut batches) = mpsc::unbounded_channel();
+    let author = Router::builder(local_endpoint().await?)
+        .accept(ACK_ALPN, Author { acked })
+        .spawn();
+    let reader = local_endpoint().await?;
+    reader.add_node_addr(author.endpoint().node_addr().initialized().await)?;
+
+    tokio::time::timeout(
+        DEFAULT_TIMEOUT,
+        send_acks(&reader, author.endpoint().node_id(), &[1, 2, 3]),
+    )
+    .await??;
+    assert_eq!(batches.recv().await, Some(vec![1, 2, 3]));
+    Ok(())
+}
+
+#[tokio::test]
+async fn acks_fail_when_the_author_does_not_take_them() -> Result<()> {
+    let author = Router::builder(local_endpoint().await?).spawn();
+    let reader = local_endpoint().await?;
+    reader.add_node_addr(author.endpoint().node_addr().initialized().await)?;
+
+    let sent = tokio::time::timeout(
+        DEFAULT_TIMEOUT,
+        send_acks(&reader, author.endpoint().node_id(), &[1]),
+    )
+    .await?;
+    assert!(sent.is_err());
+    Ok(())
+}
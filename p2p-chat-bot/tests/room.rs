@@ -0,0 +1,178 @@
+use anyhow::Result;
+use chrono::{TimeZone, Utc};
+use messaging::StoredMessage;
+use p2p_chat_bot::{
+    BotEvent,
+    chaos::Chaos,
+    testkit::{DEFAULT_TIMEOUT, TestRoom, expect_event, expect_joined, expect_message},
+};
+use std::time::{Duration, Instant};
+
+#[tokio::test]
+async fn message_is_delivered_to_other_members() -> Result<()> {
+    let mut room = TestRoom::new();
+    let mut alice = room.join("alice").await?;
+    let mut bob = room.join("bob").await?;
+
+    expect_joined(&mut alice, "bob").await?;
+    expect_joined(&mut bob, "alice").await?;
+
+    bob.send("hello alice").await?;
+    match expect_message(&mut alice, "hello alice").await? {
+        BotEvent::Message { from, name, .. } => {
+            assert_eq!(from, bob.node_id());
+            assert_eq!(name, "bob");
+        }
+        other => panic!("unexpected event {:?}", other),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn action_is_delivered_as_action() -> Result<()> {
+    let mut room = TestRoom::new();
+    let mut alice = room.join("alice").await?;
+    let mut bob = room.join("bob").await?;
+
+    expect_joined(&mut alice, "bob").await?;
+    expect_joined(&mut bob, "alice").await?;
+
+    bob.send_action("waves").await?;
+    match expect_event(&mut alice, |event| matches!(event, BotEvent::Action { .. })).await? {
+        BotEvent::Action { from, name, text } => {
+            assert_eq!(from, bob.node_id());
+            assert_eq!(name, "bob");
+            assert_eq!(text, "waves");
+        }
+        other => panic!("unexpected event {:?}", other),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn chaos_latency_delays_delivery() -> Result<()> {
+    let mut room = TestRoom::new();
+    let mut alice = room.join("alice").await?;
+    let mut bob = room.join("bob").await?;
+
+    expect_joined(&mut alice, "bob").await?;
+    expect_joined(&mut bob, "alice").await?;
+
+    alice.set_chaos(Chaos {
+        latency: Duration::from_millis(300),
+        ..Default::default()
+    });
+    let sent = Instant::now();
+    bob.send("slow hello").await?;
+    expect_message(&mut alice, "slow hello").await?;
+    assert!(sent.elapsed() >= Duration::from_millis(300));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn chaos_duplicates_are_seen_once() -> Result<()> {
+    let mut room = TestRoom::new();
+    let mut alice = room.join("alice").await?;
+    let mut bob = room.join("bob").await?;
+
+    expect_joined(&mut alice, "bob").await?;
+    expect_joined(&mut bob, "alice").await?;
+
+    alice.set_chaos(Chaos {
+        duplicate: 1.0,
+        ..Default::default()
+    });
+    bob.send("echo").await?;
+    expect_message(&mut alice, "echo").await?;
+    // The second copy of "echo" is dropped by the seen window, so "after" comes next.
+    bob.send("after").await?;
+    match expect_event(&mut alice, |event| {
+        matches!(event, BotEvent::Message { .. })
+    })
+    .await?
+    {
+        BotEvent::Message { text, .. } => assert_eq!(text, "after"),
+        other => panic!("unexpected event {:?}", other),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn chaos_disconnect_drops_the_node() -> Result<()> {
+    let mut room = TestRoom::new();
+    let mut alice = room.join("alice").await?;
+    let mut bob = room.join("bob").await?;
+
+    expect_joined(&mut alice, "bob").await?;
+    expect_joined(&mut bob, "alice").await?;
+
+    alice.set_chaos(Chaos {
+        disconnect: 1.0,
+        ..Default::default()
+    });
+    bob.send("anyone there?").await?;
+    let event = tokio::time::timeout(DEFAULT_TIMEOUT, alice.next_event()).await?;
+    assert!(event.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn history_is_synced_to_newcomers() -> Result<()> {
+    let mut room = TestRoom::new();
+    let mut archive = room.join("archive").await?;
+    let mut carol = room.join("carol").await?;
+
+    expect_joined(&mut archive, "carol").await?;
+    let stored_at = Utc.with_ymd_and_hms(2025, 1, 1, 10, 0, 0).unwrap();
+    let stored = StoredMessage {
+        from: archive.node_id(),
+        sender_name: "archive".to_string(),
+        text: "earlier message".to_string(),
+        timestamp: stored_at,
+        id: None,
+        action: false,
+    };
+    archive.sender().send_history(vec![stored]).await?;
+
+    match expect_event(&mut carol, |event| {
+        matches!(event, BotEvent::History { .. })
+    })
+    .await?
+    {
+        BotEvent::History { messages } => {
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].text, "earlier message");
+            assert_eq!(messages[0].timestamp, stored_at);
+        }
+        other => panic!("unexpected event {:?}", other),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn leaving_member_is_reported() -> Result<()> {
+    let mut room = TestRoom::new();
+    let mut alice = room.join("alice").await?;
+    let bob = room.join("bob").await?;
+
+    expect_joined(&mut alice, "bob").await?;
+    let bob_id = bob.node_id();
+    bob.leave().await?;
+
+    match expect_event(&mut alice, |event| matches!(event, BotEvent::Left { .. })).await? {
+        BotEvent::Left { from, name } => {
+            assert_eq!(from, bob_id);
+            assert_eq!(name, "bob");
+        }
+        other => panic!("unexpected event {:?}", other),
+    }
+    assert!(alice.name_of(&bob_id).is_none());
+
+    Ok(())
+}
@@ -0,0 +1,52 @@
+#ifndef CHAST_H
+#define CHAST_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct ChastNode ChastNode;
+
+typedef enum ChastEventKind {
+    CHAST_EVENT_JOINED = 0,
+    CHAST_EVENT_LEFT = 1,
+    CHAST_EVENT_MESSAGE = 2,
+    CHAST_EVENT_HISTORY_MESSAGE = 3,
+    CHAST_EVENT_ACTION = 4, /* "/me" action, text is e.g. "waves" */
+} ChastEventKind;
+
+typedef struct ChastEvent {
+    ChastEventKind kind;
+    char *from;      /* sender node id */
+    char *name;      /* sender display name */
+    char *text;      /* message text, empty for join/leave */
+    char *timestamp; /* RFC 3339 in UTC, only set for history messages */
+} ChastEvent;
+
+/* Returns NULL on failure, see chast_last_error(). */
+ChastNode *chast_node_new(void);
+void chast_node_free(ChastNode *node);
+
+/* Caller frees with chast_string_free(). */
+char *chast_node_id(const ChastNode *node);
+
+/* Return 0 on success, -1 on failure. */
+int32_t chast_node_join(ChastNode *node, const char *ticket, const char *name);
+int32_t chast_node_send(const ChastNode *node, const char *text);
+/* Sends a "/me" action; pass the action without the "/me " prefix. */
+int32_t chast_node_send_action(const ChastNode *node, const char *text);
+
+/* Non-blocking. Returns NULL when no event is pending; free with chast_event_free(). */
+ChastEvent *chast_node_poll_event(ChastNode *node);
+void chast_event_free(ChastEvent *event);
+
+void chast_string_free(char *value);
+const char *chast_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CHAST_H */
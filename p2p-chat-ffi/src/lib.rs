@@ -0,0 +1,338 @@
+//! C API over the chat engine. See `include/chast.h` for the matching declarations.
+//!
+//! Every function that can fail returns `0` on success and `-1` on error; the error
+//! text is then available from `chast_last_error` on the same thread.
+
+use anyhow::{Result, anyhow};
+use iroh::Endpoint;
+use messaging::StoredMessage;
+use p2p_chat_bot::{Bot, BotEvent, BotSender};
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    ffi::{CStr, CString, c_char},
+    ptr,
+    str::FromStr,
+};
+use ticket::Ticket;
+use tokio::{runtime::Runtime, sync::mpsc};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+pub struct ChastNode {
+    runtime: Runtime,
+    endpoint: Endpoint,
+    sender: Option<BotSender>,
+    events: Option<mpsc::UnboundedReceiver<BotEvent>>,
+    pending_history: VecDeque<StoredMessage>,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChastEventKind {
+    Joined = 0,
+    Left = 1,
+    Message = 2,
+    HistoryMessage = 3,
+    Action = 4,
+}
+
+#[repr(C)]
+pub struct ChastEvent {
+    pub kind: ChastEventKind,
+    pub from: *mut c_char,
+    pub name: *mut c_char,
+    pub text: *mut c_char,
+    pub timestamp: *mut c_char,
+}
+
+fn set_last_error(error: anyhow::Error) {
+    let message = CString::new(error.to_string().replace('\0', ""))
+        .unwrap_or_else(|_| c"unknown error".to_owned());
+    LAST_ERROR.with(|slot| *slot.borrow_mut() = Some(message));
+}
+
+fn to_c_string(value: &str) -> *mut c_char {
+    CString::new(value.replace('\0', ""))
+        .map(CString::into_raw)
+        .unwrap_or(ptr::null_mut())
+}
+
+unsafe fn read_str<'a>(value: *const c_char, what: &str) -> Result<&'a str> {
+    if value.is_null() {
+        return Err(anyhow!("{} must not be null", what));
+    }
+    unsafe { CStr::from_ptr(value) }
+        .to_str()
+        .map_err(|_| anyhow!("{} is not valid UTF-8", what))
+}
+
+fn status(result: Result<()>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            set_last_error(e);
+            -1
+        }
+    }
+}
+
+fn event_to_c(kind: ChastEventKind, from: String, name: &str, text: &str, ts: &str) -> ChastEvent {
+    ChastEvent {
+        kind,
+        from: to_c_string(&from),
+        name: to_c_string(name),
+        text: to_c_string(text),
+        timestamp: to_c_string(ts),
+    }
+}
+
+fn new_node() -> Result<ChastNode> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let endpoint = runtime.block_on(Endpoint::builder().discovery_n0().bind())?;
+
+    Ok(ChastNode {
+        runtime,
+        endpoint,
+        sender: None,
+        events: None,
+        pending_history: VecDeque::new(),
+    })
+}
+
+fn join(node: &mut ChastNode, ticket: &str, name: &str) -> Result<()> {
+    if node.sender.is_some() {
+        return Err(anyhow!("node has already joined a room"));
+    }
+
+    let ticket = Ticket::from_str(ticket)?;
+    let mut bot =
+        node.runtime
+            .block_on(Bot::join_with_endpoint(node.endpoint.clone(), ticket, name))?;
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    node.sender = Some(bot.sender());
+    node.events = Some(rx);
+
+    node.runtime.spawn(async move {
+        loop {
+            match bot.next_event().await {
+                Ok(Some(event)) => {
+                    if tx.send(event).is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("Error receiving message: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn send(node: &ChastNode, text: &str) -> Result<()> {
+    let sender = node
+        .sender
+        .as_ref()
+        .ok_or_else(|| anyhow!("node has not joined a room"))?;
+    node.runtime.block_on(sender.send(text))
+}
+
+fn send_action(node: &ChastNode, text: &str) -> Result<()> {
+    let sender = node
+        .sender
+        .as_ref()
+        .ok_or_else(|| anyhow!("node has not joined a room"))?;
+    node.runtime.block_on(sender.send_action(text))
+}
+
+fn poll(node: &mut ChastNode) -> Option<ChastEvent> {
+    if let Some(stored) = node.pending_history.pop_front() {
+        return Some(event_to_c(
+            ChastEventKind::HistoryMessage,
+            stored.from.to_string(),
+            &stored.sender_name,
+            &stored.text,
+            &stored.timestamp.to_rfc3339(),
+        ));
+    }
+
+    let event = node.events.as_mut()?.try_recv().ok()?;
+    match event {
+        BotEvent::Joined { from, name } => Some(event_to_c(
+            ChastEventKind::Joined,
+            from.to_string(),
+            &name,
+            "",
+            "",
+        )),
+        BotEvent::Left { from, name } => Some(event_to_c(
+            ChastEventKind::Left,
+            from.to_string(),
+            &name,
+            "",
+            "",
+        )),
+        BotEvent::Message { from, name, text } => Some(event_to_c(
+            ChastEventKind::Message,
+            from.to_string(),
+            &name,
+            &text,
+            "",
+        )),
+        BotEvent::Action { from, name, text } => Some(event_to_c(
+            ChastEventKind::Action,
+            from.to_string(),
+            &name,
+            &text,
+            "",
+        )),
+        BotEvent::History { messages } => {
+            node.pending_history.extend(messages);
+            poll(node)
+        }
+    }
+}
+
+/// Creates a node with its own runtime and endpoint. Returns null on failure.
+#[unsafe(no_mangle)]
+pub extern "C" fn chast_node_new() -> *mut ChastNode {
+    match new_node() {
+        Ok(node) => Box::into_raw(Box::new(node)),
+        Err(e) => {
+            set_last_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// # Safety
+/// `node` must come from `chast_node_new`; the returned string is owned by the caller
+/// and must be released with `chast_string_free`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn chast_node_id(node: *const ChastNode) -> *mut c_char {
+    match unsafe { node.as_ref() } {
+        Some(node) => to_c_string(&node.endpoint.node_id().to_string()),
+        None => ptr::null_mut(),
+    }
+}
+
+/// # Safety
+/// `node` must come from `chast_node_new`; `ticket` and `name` must be NUL-terminated.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn chast_node_join(
+    node: *mut ChastNode,
+    ticket: *const c_char,
+    name: *const c_char,
+) -> i32 {
+    let Some(node) = (unsafe { node.as_mut() }) else {
+        return status(Err(anyhow!("node must not be null")));
+    };
+    status((|| {
+        let ticket = unsafe { read_str(ticket, "ticket") }?;
+        let name = unsafe { read_str(name, "name") }?;
+        join(node, ticket, name)
+    })())
+}
+
+/// # Safety
+/// `node` must come from `chast_node_new`; `text` must be NUL-terminated.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn chast_node_send(node: *const ChastNode, text: *const c_char) -> i32 {
+    let Some(node) = (unsafe { node.as_ref() }) else {
+        return status(Err(anyhow!("node must not be null")));
+    };
+    status(unsafe { read_str(text, "text") }.and_then(|text| send(node, text)))
+}
+
+/// Sends a `/me` action; `text` is just the action, without the "/me " prefix.
+///
+/// # Safety
+/// `node` must come from `chast_node_new`; `text` must be NUL-terminated.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn chast_node_send_action(
+    node: *const ChastNode,
+    text: *const c_char,
+) -> i32 {
+    let Some(node) = (unsafe { node.as_ref() }) else {
+        return status(Err(anyhow!("node must not be null")));
+    };
+    status(unsafe { read_str(text, "text") }.and_then(|text| send_action(node, text)))
+}
+
+/// Returns the next pending event without blocking, or null when there is none.
+///
+/// # Safety
+/// `node` must come from `chast_node_new`; the event must be released with
+/// `chast_event_free`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn chast_node_poll_event(node: *mut ChastNode) -> *mut ChastEvent {
+    let Some(node) = (unsafe { node.as_mut() }) else {
+        return ptr::null_mut();
+    };
+    match poll(node) {
+        Some(event) => Box::into_raw(Box::new(event)),
+        None => ptr::null_mut(),
+    }
+}
+
+/// # Safety
+/// `event` must come from `chast_node_poll_event` and not be freed twice.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn chast_event_free(event: *mut ChastEvent) {
+    if event.is_null() {
+        return;
+    }
+    let event = unsafe { Box::from_raw(event) };
+    for field in [event.from, event.name, event.text, event.timestamp] {
+        unsafe { chast_string_free(field) };
+    }
+}
+
+/// Leaves the room (if joined) and releases the node.
+///
+/// # Safety
+/// `node` must come from `chast_node_new` and not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn chast_node_free(node: *mut ChastNode) {
+    if node.is_null() {
+        return;
+    }
+    let node = unsafe { Box::from_raw(node) };
+    if let Some(sender) = &node.sender
+        && let Err(e) = node.runtime.block_on(sender.disconnect())
+    {
+        eprintln!("Error sending disconnect message: {}", e);
+    }
+    node.runtime.block_on(node.endpoint.close());
+    node.runtime
+        .shutdown_timeout(std::time::Duration::from_secs(5));
+}
+
+/// # Safety
+/// `value` must be a string returned by this library, or null.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn chast_string_free(value: *mut c_char) {
+    if !value.is_null() {
+        drop(unsafe { CString::from_raw(value) });
+    }
+}
+
+/// Returns the last error raised on this thread, or null. The pointer stays valid until
+/// the next failing call on the same thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn chast_last_error() -> *const c_char {
+    LAST_ERROR.with(|slot| {
+        slot.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
@@ -1,3 +1,6 @@
 fn main() {
-    slint_build::compile("slint/app-window.slint").expect("Slint build failed");
+    // Bundles translations/<lang>/LC_MESSAGES/p2p-chat.po into the binary for @tr strings.
+    let config =
+        slint_build::CompilerConfiguration::new().with_bundled_translations("translations");
+    slint_build::compile_with_config("slint/app-window.slint", config).expect("Slint build failed");
 }
@@ -0,0 +1,130 @@
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use slint::{ModelRc, SharedString, VecModel};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use crate::app::types;
+
+// Every file the app keeps (identity.key, contacts, settings, saved chats and so on)
+// is relative to the working directory, so an account is just a directory of its own.
+// The default account is the directory Chast was started in, which keeps setups from
+// before accounts existed working as they were.
+const ACCOUNTS_FILE: &str = "accounts.json";
+const ACCOUNTS_DIR: &str = "accounts";
+pub const DEFAULT_ACCOUNT: &str = "Default";
+
+static BASE_DIR: OnceLock<PathBuf> = OnceLock::new();
+// Set by the account switcher; App::run picks it up once the windows have closed.
+static SWITCH_TO: Mutex<Option<String>> = Mutex::new(None);
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Accounts {
+    // Not counting the default one.
+    names: Vec<String>,
+    last: String,
+}
+
+fn base_dir() -> &'static Path {
+    BASE_DIR.get_or_init(|| std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")))
+}
+
+fn load() -> Accounts {
+    let path = base_dir().join(ACCOUNTS_FILE);
+    match fs::read_to_string(&path) {
+        Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+            eprintln!("Failed to parse {}: {}", path.display(), e);
+            Accounts::default()
+        }),
+        Err(_) => Accounts::default(),
+    }
+}
+
+fn save(accounts: &Accounts) -> Result<()> {
+    let path = base_dir().join(ACCOUNTS_FILE);
+    fs::write(&path, serde_json::to_string_pretty(accounts)?)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn account_dir(name: &str) -> PathBuf {
+    if name == DEFAULT_ACCOUNT {
+        base_dir().to_path_buf()
+    } else {
+        base_dir().join(ACCOUNTS_DIR).join(name)
+    }
+}
+
+// The default account first, then the others in the order they were added.
+pub fn list() -> Vec<String> {
+    std::iter::once(DEFAULT_ACCOUNT.to_string())
+        .chain(load().names)
+        .collect()
+}
+
+pub fn current() -> String {
+    let accounts = load();
+    if accounts.names.contains(&accounts.last) {
+        accounts.last
+    } else {
+        DEFAULT_ACCOUNT.to_string()
+    }
+}
+
+// Moves into an account's directory. Only call this while no windows are open, since
+// everything already loaded still belongs to the previous account.
+pub fn enter(name: &str) -> Result<()> {
+    let dir = account_dir(name);
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    std::env::set_current_dir(&dir)
+        .with_context(|| format!("Failed to switch to {}", dir.display()))?;
+    let mut accounts = load();
+    if accounts.last != name {
+        accounts.last = name.to_string();
+        save(&accounts)?;
+    }
+    println!("DEBUG: Using account {} in {}", name, dir.display());
+    Ok(())
+}
+
+// Adds an account with nothing in it yet: a new identity, no contacts and default
+// settings.
+pub fn add(name: &str) -> Result<()> {
+    let name = name.trim();
+    if name.is_empty() {
+        bail!("Give the account a name");
+    }
+    // The name is a directory name, so nothing that could point out of the accounts folder.
+    if Path::new(name).file_name().and_then(|file| file.to_str()) != Some(name) {
+        bail!("An account name can't contain slashes");
+    }
+    let mut accounts = load();
+    if name == DEFAULT_ACCOUNT || accounts.names.iter().any(|known| known == name) {
+        bail!("There is already an account called {}", name);
+    }
+    fs::create_dir_all(account_dir(name)).context("Failed to create the account folder")?;
+    accounts.names.push(name.to_string());
+    save(&accounts)
+}
+
+// Asks App::run to reopen the windows in another account.
+pub fn switch(name: &str) {
+    *SWITCH_TO.lock().unwrap() = Some(name.to_string());
+    if let Err(e) = slint::quit_event_loop() {
+        eprintln!("Failed to close the windows: {}", e);
+    }
+}
+
+pub fn take_switch() -> Option<String> {
+    SWITCH_TO.lock().unwrap().take()
+}
+
+pub fn show(main: &types::StartWindow) {
+    let names: Vec<SharedString> = list().iter().map(SharedString::from).collect();
+    main.set_accounts_available(true);
+    main.set_accounts(ModelRc::new(VecModel::from(names)));
+    main.set_current_account(current().into());
+}
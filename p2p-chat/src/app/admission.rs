@@ -0,0 +1,317 @@
+use anyhow::{Context, Result, anyhow};
+use iroh::{
+    Endpoint, NodeId,
+    endpoint::{Connection, SendStream},
+    protocol::{AcceptError, ProtocolHandler},
+};
+use messaging::{JoinVerdict, Message, MessageBody};
+use n0_future::time::{Duration, timeout};
+use slint::{ModelRc, SharedString, VecModel, Weak};
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use crate::app::{
+    app_state::AppState,
+    i18n::trf,
+    types,
+    ui_handlers::{push_system_message, update_messages},
+};
+
+pub const ADMISSION_ALPN: &[u8] = b"chast/admission/0";
+const MAX_ADMISSION_BYTES: usize = 4 * 1024;
+// A room with a limit can't be joined while its owner is unreachable.
+const ADMISSION_TIMEOUT: Duration = Duration::from_secs(10);
+// A knock waits on a person, so give them a while to notice it.
+const KNOCK_TIMEOUT: Duration = Duration::from_secs(120);
+
+// A knock the owner hasn't answered yet; the reply goes out on `send` once they do.
+pub struct PendingKnock {
+    pub node_id: NodeId,
+    pub name: String,
+    send: SendStream,
+}
+
+impl fmt::Debug for PendingKnock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PendingKnock")
+            .field("node_id", &self.node_id)
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+// Asks the room owner for a seat before we subscribe to the topic, so a full room
+// rejects us up front instead of after we've shown up in everyone's user list.
+pub async fn request_admission(
+    endpoint: &Endpoint,
+    owner: NodeId,
+    name: String,
+    knock: bool,
+) -> Result<()> {
+    let from = endpoint.node_id();
+    let request = if knock {
+        MessageBody::KnockRequest { from, name }
+    } else {
+        MessageBody::JoinRequest { from, name }
+    };
+    let exchange = async {
+        #[cfg(feature = "chaos")]
+        messaging::chaos::direct().await?;
+        let connection = endpoint.connect(owner, ADMISSION_ALPN).await?;
+        let (mut send, mut recv) = connection.open_bi().await?;
+        send.write_all(&Message::new(request).to_vec()).await?;
+        send.finish()?;
+        let bytes = recv.read_to_end(MAX_ADMISSION_BYTES).await?;
+        connection.close(0u32.into(), b"admission done");
+        anyhow::Ok(Message::from_bytes(&bytes)?.body)
+    };
+
+    let wait = if knock {
+        KNOCK_TIMEOUT
+    } else {
+        ADMISSION_TIMEOUT
+    };
+    let verdict = match timeout(wait, exchange).await {
+        Ok(Ok(MessageBody::JoinReply {
+            from: replier,
+            to,
+            verdict,
+        })) if replier == owner && to == from => verdict,
+        Ok(Ok(_)) => return Err(anyhow!("Unexpected reply from the room owner")),
+        // Without the owner's yes there's no way in; joining anyway would let anyone past
+        // the limit just by making the owner hard to reach.
+        Ok(Err(e)) => return Err(e.context("Could not reach the room owner")),
+        Err(_) if knock => return Err(anyhow!("The room owner didn't answer your knock")),
+        Err(_) => return Err(anyhow!("The room owner didn't answer in time")),
+    };
+
+    match verdict {
+        JoinVerdict::Admitted => Ok(()),
+        JoinVerdict::RoomFull { capacity } => {
+            Err(anyhow!("This room is full ({} members max)", capacity))
+        }
+        JoinVerdict::Denied => Err(anyhow!("The room owner didn't let you in")),
+    }
+}
+
+// Returns our node id alongside the capacity verdict, or None if we don't own the room.
+fn capacity_verdict(
+    app_state: &Arc<Mutex<AppState>>,
+    from: NodeId,
+) -> Option<(NodeId, JoinVerdict)> {
+    let state = app_state.lock().unwrap();
+    let me = state.current_node_id?;
+    if state.room_owner != Some(me) {
+        return None;
+    }
+    match state.over_capacity(&from) {
+        Some(capacity) => Some((me, JoinVerdict::RoomFull { capacity })),
+        None => Some((me, JoinVerdict::Admitted)),
+    }
+}
+
+// Tells the room the owner let `to` in, so members and servers stop ignoring them.
+pub async fn publish_admitted(app_state: &Arc<Mutex<AppState>>, to: NodeId) -> Result<()> {
+    let (sender, from, secret_key) = {
+        let state = app_state.lock().unwrap();
+        (
+            state.sender.clone(),
+            state.current_node_id,
+            state.secret_key(),
+        )
+    };
+    let (Some(sender), Some(from), Some(secret_key)) = (sender, from, secret_key) else {
+        return Err(anyhow!("Not connected to a room"));
+    };
+    let message = Message::new(MessageBody::JoinReply {
+        from,
+        to,
+        verdict: JoinVerdict::Admitted,
+    });
+    sender
+        .broadcast(message.to_signed_vec(&secret_key, sender.topic()))
+        .await?;
+    Ok(())
+}
+
+async fn send_verdict(
+    mut send: SendStream,
+    from: NodeId,
+    to: NodeId,
+    verdict: JoinVerdict,
+) -> Result<()> {
+    let reply = Message::new(MessageBody::JoinReply { from, to, verdict });
+    send.write_all(&reply.to_vec()).await?;
+    send.finish()?;
+    Ok(())
+}
+
+pub fn update_knocks(chat_handle: &Weak<types::ChatWindow>, app_state: &Arc<Mutex<AppState>>) {
+    let items: Vec<types::KnockItem> = {
+        let state = app_state.lock().unwrap();
+        let knocks = state.knocks.lock().unwrap();
+        knocks
+            .iter()
+            .map(|knock| types::KnockItem {
+                name: SharedString::from(knock.name.clone()),
+                node_id: SharedString::from(knock.node_id.to_string()),
+            })
+            .collect()
+    };
+    let chat_handle = chat_handle.clone();
+
+    if let Err(e) = slint::invoke_from_event_loop(move || {
+        if let Some(chat) = chat_handle.upgrade() {
+            chat.set_knock_requests(ModelRc::new(VecModel::from(items)));
+        }
+    }) {
+        eprintln!("Failed to update join requests: {:?}", e);
+    }
+}
+
+pub async fn answer_knock(
+    node_id: String,
+    approve: bool,
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) -> Result<()> {
+    let node_id = NodeId::from_str(&node_id)?;
+    let knock = {
+        let state = app_state.lock().unwrap();
+        let mut knocks = state.knocks.lock().unwrap();
+        let index = knocks.iter().position(|knock| knock.node_id == node_id);
+        index.map(|index| knocks.remove(index))
+    };
+    update_knocks(&chat_handle, &app_state);
+    let knock = knock.ok_or_else(|| anyhow!("{} is no longer waiting", node_id.fmt_short()))?;
+
+    // The room may have filled up while they were waiting.
+    let (me, verdict) = capacity_verdict(&app_state, node_id)
+        .ok_or_else(|| anyhow!("Not the owner of this room"))?;
+    let verdict = match verdict {
+        JoinVerdict::Admitted if !approve => JoinVerdict::Denied,
+        verdict => verdict,
+    };
+
+    let notice = match verdict {
+        JoinVerdict::Admitted => trf("🚪 You let {} in", &[&knock.name]),
+        JoinVerdict::RoomFull { .. } => {
+            trf("🚪 The room is full, {} was turned away", &[&knock.name])
+        }
+        JoinVerdict::Denied => trf("🚪 You turned {} away", &[&knock.name]),
+    };
+    push_system_message(&app_state, notice);
+    update_messages(&chat_handle, &app_state);
+
+    // The room hears our yes before they can show up in it.
+    if verdict == JoinVerdict::Admitted {
+        app_state.lock().unwrap().admitted.insert(node_id);
+        if let Err(e) = publish_admitted(&app_state, node_id).await {
+            eprintln!("Failed to tell the room {} was let in: {}", knock.name, e);
+        }
+    }
+
+    send_verdict(knock.send, me, node_id, verdict)
+        .await
+        .with_context(|| format!("Failed to answer {}", knock.name))
+}
+
+// Answers join requests and knocks while we own the room; anyone else dialing the
+// admission ALPN is turned away.
+#[derive(Clone)]
+pub struct AdmissionProtocol {
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+}
+
+impl AdmissionProtocol {
+    pub fn new(app_state: Arc<Mutex<AppState>>, chat_handle: Weak<types::ChatWindow>) -> Self {
+        Self {
+            app_state,
+            chat_handle,
+        }
+    }
+
+    fn forget_knock(&self, node_id: NodeId) {
+        let removed = {
+            let state = self.app_state.lock().unwrap();
+            let mut knocks = state.knocks.lock().unwrap();
+            let before = knocks.len();
+            knocks.retain(|knock| knock.node_id != node_id);
+            knocks.len() != before
+        };
+        if removed {
+            update_knocks(&self.chat_handle, &self.app_state);
+        }
+    }
+}
+
+impl fmt::Debug for AdmissionProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdmissionProtocol").finish_non_exhaustive()
+    }
+}
+
+impl ProtocolHandler for AdmissionProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let remote = connection.remote_node_id()?;
+        let (send, mut recv) = connection.accept_bi().await?;
+        let bytes = recv
+            .read_to_end(MAX_ADMISSION_BYTES)
+            .await
+            .map_err(AcceptError::from_err)?;
+
+        let message = Message::from_bytes(&bytes).map_err(|_| AcceptError::NotAllowed {})?;
+        let (from, name) = match message.body {
+            MessageBody::JoinRequest { from, name } | MessageBody::KnockRequest { from, name } => {
+                (from, name)
+            }
+            _ => return Err(AcceptError::NotAllowed {}),
+        };
+        let refused = {
+            let state = self.app_state.lock().unwrap();
+            state.banned.contains(&from) || state.blocklist.lock().unwrap().is_blocked(&from)
+        };
+        if from != remote || refused {
+            return Err(AcceptError::NotAllowed {});
+        }
+        let (me, verdict) =
+            capacity_verdict(&self.app_state, from).ok_or(AcceptError::NotAllowed {})?;
+
+        println!(
+            "DEBUG: Join request from {} ({}): {:?}",
+            name,
+            from.fmt_short(),
+            verdict
+        );
+        let room_knock = self.app_state.lock().unwrap().room_knock;
+        if room_knock && verdict == JoinVerdict::Admitted {
+            {
+                let state = self.app_state.lock().unwrap();
+                let mut knocks = state.knocks.lock().unwrap();
+                knocks.retain(|knock| knock.node_id != from);
+                knocks.push(PendingKnock {
+                    node_id: from,
+                    name: name.clone(),
+                    send,
+                });
+            }
+            push_system_message(
+                &self.app_state,
+                trf("🚪 {} is knocking and wants to join", &[&name]),
+            );
+            update_messages(&self.chat_handle, &self.app_state);
+            update_knocks(&self.chat_handle, &self.app_state);
+        } else if let Err(e) = send_verdict(send, me, from, verdict).await {
+            eprintln!("Failed to answer join request from {}: {}", name, e);
+        }
+
+        // The joiner closes the connection once it has read the whole reply, or gives up.
+        connection.closed().await;
+        self.forget_knock(from);
+        Ok(())
+    }
+}
@@ -1,22 +1,79 @@
 use anyhow::Result;
-use slint::{ComponentHandle, Weak};
-use std::sync::{Arc, Mutex};
+use messaging::{PresenceStatus, RoomInfo};
+use slint::{ComponentHandle, Model, ModelRc, SharedString, VecModel, Weak};
+use std::{
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use ticket::Ticket;
 
+#[cfg(not(target_arch = "wasm32"))]
+use crate::app::accounts;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::app::device_sync;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::app::files;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::app::identity;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::app::images;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::app::screen_share::{self, ShareQuality, ShareSettings};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::app::stickers;
 use crate::app::{
+    admission,
     app_state::AppState,
-    networking::{cleanup_network_resources, send_disconnect, send_message},
-    room_handlers::{create_room, join_room},
-    types,
-    ui_handlers::update_messages,
+    appearance::{self, Appearance, TextSize},
+    blocklist, calls, connection, contacts, history,
+    i18n::{self, Language, trf},
+    networking::{cleanup_network_resources, leave_room, send_message},
+    notifications,
+    persistence::AutoSave,
+    presence::{self, AutoAway},
+    recent_rooms,
+    room_handlers::{RoomOptions, create_room, join_room, renew_invite},
+    runtime::Spawner,
+    save::{ChatSaveManager, Config},
+    saved_chats, scheduled,
+    speech::Speech,
+    spellcheck::{self, SpellCheck},
+    translate, types,
+    ui_handlers::{push_system_message, update_messages},
+    window_state,
+    word_filter::FilterMode,
 };
 
 pub struct App {}
 
 impl App {
     pub fn run() -> Result<()> {
+        #[cfg(not(target_arch = "wasm32"))]
+        accounts::enter(&accounts::current())?;
+
+        // Switching accounts closes the windows and opens them again from scratch, in
+        // the other account's directory.
+        loop {
+            Self::run_windows()?;
+            #[cfg(not(target_arch = "wasm32"))]
+            if let Some(account) = accounts::take_switch() {
+                accounts::enter(&account)?;
+                continue;
+            }
+            return Ok(());
+        }
+    }
+
+    fn run_windows() -> Result<()> {
+        #[cfg(not(target_arch = "wasm32"))]
         let rt = tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()?;
+        #[cfg(not(target_arch = "wasm32"))]
+        let spawner = Spawner::new(rt.handle().clone());
+        #[cfg(target_arch = "wasm32")]
+        let spawner = Spawner::new();
 
         let main = types::StartWindow::new()?;
         let main_handle = main.as_weak();
@@ -32,26 +89,105 @@ impl App {
 
         let app_state = Arc::new(Mutex::new(AppState::new()));
 
+        let language = {
+            let state = app_state.lock().unwrap();
+            let settings = state.settings.lock().unwrap();
+            settings.language
+        };
+        i18n::select(language);
+
+        let username = {
+            let state = app_state.lock().unwrap();
+            let settings = state.settings.lock().unwrap();
+            settings.profile.username.clone()
+        };
+        join.set_username(username.as_str().into());
+        create.set_username(username.into());
+        recent_rooms::refresh_start_recent_rooms(&main, &app_state);
+
+        #[cfg(not(target_arch = "wasm32"))]
+        Self::setup_accounts(&main_handle);
+
         Self::setup_navigation(&main_handle, &join_handle, &create_handle, &chat_handle);
 
+        let windows = window_state::Windows {
+            main: main_handle.clone(),
+            join: join_handle.clone(),
+            create: create_handle.clone(),
+            chat: chat_handle.clone(),
+        };
+
+        Self::setup_view_callbacks(&chat_handle, app_state.clone(), spawner.clone());
+
+        Self::setup_appearance(&windows, app_state.clone());
+
+        Self::setup_spelling(&chat_handle, app_state.clone());
+
+        #[cfg(not(target_arch = "wasm32"))]
+        Self::setup_device_sync(&chat_handle, app_state.clone(), spawner.clone());
+
+        #[cfg(not(target_arch = "wasm32"))]
+        Self::setup_identity(&chat_handle, app_state.clone());
+
+        Self::setup_peer_callbacks(
+            &main_handle,
+            &join_handle,
+            &chat_handle,
+            app_state.clone(),
+            spawner.clone(),
+        );
+
         Self::setup_networking_callbacks(
             &main_handle,
             &join_handle,
             &create_handle,
             &chat_handle,
             app_state.clone(),
-            rt.handle().clone(),
+            spawner,
         );
 
+        let window_state = window_state::restore(&windows, &app_state);
+        window_state::watch(&windows, window_state);
+
         let _ = main.show();
 
         let result = main.run();
 
+        #[cfg(not(target_arch = "wasm32"))]
         rt.shutdown_timeout(std::time::Duration::from_secs(5));
 
         result.map_err(|e| anyhow::anyhow!("Slint error: {}", e))
     }
 
+    #[cfg(not(target_arch = "wasm32"))]
+    fn setup_accounts(main_handle: &Weak<types::StartWindow>) {
+        let Some(main) = main_handle.upgrade() else {
+            return;
+        };
+        accounts::show(&main);
+
+        main.on_switch_account(move |account| {
+            if account != accounts::current() {
+                accounts::switch(&account);
+            }
+        });
+
+        let main_handle_clone = main_handle.clone();
+        main.on_add_account(move |name| {
+            let Some(main) = main_handle_clone.upgrade() else {
+                return;
+            };
+            match accounts::add(&name) {
+                Ok(()) => {
+                    main.set_new_account_name("".into());
+                    main.set_account_error("".into());
+                    accounts::switch(name.trim());
+                }
+                Err(e) => main.set_account_error(e.to_string().into()),
+            }
+        });
+    }
+
     fn setup_navigation(
         main_handle: &Weak<types::StartWindow>,
         join_handle: &Weak<types::JoinWindow>,
@@ -119,183 +255,1606 @@ impl App {
         }
     }
 
-    fn setup_networking_callbacks(
-        main_handle: &Weak<types::StartWindow>,
-        join_handle: &Weak<types::JoinWindow>,
-        create_handle: &Weak<types::CreateWindow>,
+    fn setup_view_callbacks(
         chat_handle: &Weak<types::ChatWindow>,
         app_state: Arc<Mutex<AppState>>,
-        rt_handle: tokio::runtime::Handle,
+        spawner: Spawner,
     ) {
+        let Some(chat) = chat_handle.upgrade() else {
+            return;
+        };
+
         {
             let app_state_clone = app_state.clone();
             let chat_handle_clone = chat_handle.clone();
-            let join_handle_clone = join_handle.clone();
-            let rt_handle_clone = rt_handle.clone();
+            chat.on_open_settings(move || {
+                let Some(chat) = chat_handle_clone.upgrade() else {
+                    return;
+                };
+                let (word_filter, auto_save, auto_away, show_joins, language, look, translation) = {
+                    let state = app_state_clone.lock().unwrap();
+                    let settings = state.settings.lock().unwrap();
+                    (
+                        settings.word_filter.clone(),
+                        settings.auto_save.clone(),
+                        settings.auto_away.clone(),
+                        settings.chat_events.show_joins,
+                        settings.language,
+                        settings.appearance.clone(),
+                        settings.translation.clone(),
+                    )
+                };
+                let (speech, files) = {
+                    let state = app_state_clone.lock().unwrap();
+                    let settings = state.settings.lock().unwrap();
+                    (settings.speech.clone(), settings.files.clone())
+                };
+                chat.set_language(language.index());
+                chat.set_text_size(look.text_size.index());
+                chat.set_high_contrast(look.high_contrast);
+                chat.set_filter_enabled(word_filter.enabled);
+                chat.set_filter_mode(word_filter.mode.index());
+                chat.set_filter_words(word_filter.words_text().into());
+                chat.set_auto_save_enabled(auto_save.enabled);
+                chat.set_auto_save_interval(auto_save.interval_secs.clamp(1, 3600) as i32);
+                chat.set_auto_away_enabled(auto_away.enabled);
+                chat.set_auto_away_minutes(auto_away.minutes.clamp(1, 240) as i32);
+                chat.set_show_joins(show_joins);
+                chat.set_speak_messages(speech.messages);
+                chat.set_speak_joins(speech.joins);
+                chat.set_translation_command(translation.command.into());
+                chat.set_translation_language(translation.language.into());
+                chat.set_files_auto_accept_mb(files.auto_accept_mb.min(4096) as i32);
+                chat.set_files_allowed_types(files.types_text().into());
+                chat.set_files_download_dir(files.download_dir.into());
+                chat.set_save_status("".into());
+                #[cfg(not(target_arch = "wasm32"))]
+                {
+                    chat.set_sync_status("".into());
+                    device_sync::show_settings(&chat, &app_state_clone);
+                    chat.set_identity_status("".into());
+                    chat.set_identity_password("".into());
+                    identity::show_settings(&chat);
+                }
+                chat.set_settings_open(true);
+            });
+        }
 
-            if let Some(join) = join_handle.upgrade() {
-                join.on_switch_to_chat_window(move |username, ticket_str| {
+        {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            chat.on_expand_burst(move |burst_id| {
+                {
+                    let state = app_state_clone.lock().unwrap();
+                    state.flood.lock().unwrap().expand(burst_id);
+                }
+                update_messages(&chat_handle_clone, &app_state_clone);
+            });
+        }
+
+        {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            chat.on_save_word_filter(move |enabled, mode, words| {
+                {
+                    let state = app_state_clone.lock().unwrap();
+                    let mut settings = state.settings.lock().unwrap();
+                    settings.word_filter.enabled = enabled;
+                    settings.word_filter.mode = FilterMode::from_index(mode);
+                    settings.word_filter.set_words(&words);
+                    if let Err(e) = settings.save() {
+                        eprintln!("Error saving settings: {}", e);
+                    }
+                }
+                update_messages(&chat_handle_clone, &app_state_clone);
+            });
+        }
+
+        {
+            let app_state_clone = app_state.clone();
+            chat.on_save_auto_save(move |enabled, interval_secs| {
+                let state = app_state_clone.lock().unwrap();
+                let auto_save = {
+                    let mut settings = state.settings.lock().unwrap();
+                    settings.auto_save = AutoSave {
+                        enabled,
+                        interval_secs: interval_secs.max(1) as u64,
+                    };
+                    if let Err(e) = settings.save() {
+                        eprintln!("Error saving settings: {}", e);
+                    }
+                    settings.auto_save.clone()
+                };
+                state.persister.configure(auto_save);
+            });
+        }
+
+        {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            let spawner = spawner.clone();
+            chat.on_save_auto_away(move |enabled, minutes| {
+                {
+                    let state = app_state_clone.lock().unwrap();
+                    let mut settings = state.settings.lock().unwrap();
+                    settings.auto_away = AutoAway {
+                        enabled,
+                        minutes: minutes.max(1) as u64,
+                    };
+                    if let Err(e) = settings.save() {
+                        eprintln!("Error saving settings: {}", e);
+                    }
+                }
+                // Re-checks straight away, so turning it off brings us back from away.
+                if app_state_clone.lock().unwrap().sender.is_some() {
                     let app_state = app_state_clone.clone();
                     let chat_handle = chat_handle_clone.clone();
-                    let join_handle = join_handle_clone.clone();
-                    let username = username.to_string();
-                    let ticket_str = ticket_str.to_string();
-
-                    rt_handle_clone.spawn(async move {
-                        if let Err(e) =
-                            join_room(username, ticket_str, app_state, chat_handle, join_handle)
-                                .await
-                        {
-                            eprintln!("Error joining room: {}", e);
+                    spawner.spawn(async move {
+                        if let Err(e) = presence::check_idle(&app_state, &chat_handle).await {
+                            eprintln!("Failed to send away status: {}", e);
                         }
                     });
-                });
-            }
+                }
+            });
         }
 
         {
             let app_state_clone = app_state.clone();
             let chat_handle_clone = chat_handle.clone();
-            let create_handle_clone = create_handle.clone();
-            let rt_handle_clone = rt_handle.clone();
+            chat.on_save_show_joins(move |show_joins| {
+                {
+                    let state = app_state_clone.lock().unwrap();
+                    let mut settings = state.settings.lock().unwrap();
+                    settings.chat_events.show_joins = show_joins;
+                    if let Err(e) = settings.save() {
+                        eprintln!("Error saving settings: {}", e);
+                    }
+                }
+                update_messages(&chat_handle_clone, &app_state_clone);
+            });
+        }
 
-            if let Some(create) = create_handle.upgrade() {
-                create.on_switch_to_chat_window(move |username| {
-                    let app_state = app_state_clone.clone();
-                    let chat_handle = chat_handle_clone.clone();
-                    let create_handle = create_handle_clone.clone();
-                    let username = username.to_string();
+        {
+            let app_state_clone = app_state.clone();
+            chat.on_save_speech(move |messages, joins| {
+                let state = app_state_clone.lock().unwrap();
+                let mut settings = state.settings.lock().unwrap();
+                settings.speech = Speech { messages, joins };
+                if let Err(e) = settings.save() {
+                    eprintln!("Error saving settings: {}", e);
+                }
+            });
+        }
 
-                    rt_handle_clone.spawn(async move {
-                        if let Err(e) =
-                            create_room(username, app_state, chat_handle, create_handle).await
-                        {
-                            eprintln!("Error creating room: {}", e);
-                        }
-                    });
-                });
-            }
+        {
+            let app_state_clone = app_state.clone();
+            chat.on_save_files(move |auto_accept_mb, allowed_types, download_dir| {
+                let state = app_state_clone.lock().unwrap();
+                let mut settings = state.settings.lock().unwrap();
+                settings.files.auto_accept_mb = auto_accept_mb.max(0) as u32;
+                settings.files.set_types(&allowed_types);
+                let download_dir = download_dir.trim();
+                if !download_dir.is_empty() {
+                    settings.files.download_dir = download_dir.to_string();
+                }
+                if let Err(e) = settings.save() {
+                    eprintln!("Error saving settings: {}", e);
+                }
+            });
         }
 
         {
             let app_state_clone = app_state.clone();
             let chat_handle_clone = chat_handle.clone();
-            let rt_handle_clone = rt_handle.clone();
+            chat.on_save_translation(move |command, language| {
+                {
+                    let state = app_state_clone.lock().unwrap();
+                    let mut settings = state.settings.lock().unwrap();
+                    settings.translation.command = command.trim().to_string();
+                    settings.translation.language = language.trim().to_string();
+                    if let Err(e) = settings.save() {
+                        eprintln!("Error saving settings: {}", e);
+                    }
+                }
+                translate::show(&chat_handle_clone, &app_state_clone);
+            });
+        }
 
-            if let Some(chat) = chat_handle.upgrade() {
-                chat.on_send_message(move |message| {
-                    println!("DEBUG: Sending message: '{}'", message);
-                    let app_state = app_state_clone.clone();
-                    let chat_handle = chat_handle_clone.clone();
-                    let message = message.to_string();
+        {
+            let app_state_clone = app_state.clone();
+            chat.on_save_language(move |index| {
+                let language = Language::from_index(index);
+                {
+                    let state = app_state_clone.lock().unwrap();
+                    let mut settings = state.settings.lock().unwrap();
+                    settings.language = language;
+                    if let Err(e) = settings.save() {
+                        eprintln!("Error saving settings: {}", e);
+                    }
+                }
+                i18n::select(language);
+            });
+        }
 
-                    rt_handle_clone.spawn(async move {
-                        match send_message(message.clone(), app_state.clone()).await {
-                            Ok(_) => {
-                                println!("DEBUG: Message sent successfully, updating UI");
-                                update_messages(&chat_handle, &app_state);
-                            }
-                            Err(e) => {
-                                eprintln!("ERROR: Failed to send message: {}", e);
-                            }
-                        }
-                    });
-                });
+        {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            chat.on_save_chat_now(move || {
+                let Some(chat) = chat_handle_clone.upgrade() else {
+                    return;
+                };
+                let status = match save_chat_now(&app_state_clone) {
+                    Ok(Some(path)) => format!("Saving to {}", path.display()),
+                    Ok(None) => String::new(),
+                    Err(e) => {
+                        eprintln!("Error saving chat: {}", e);
+                        e.to_string()
+                    }
+                };
+                chat.set_save_status(status.into());
+            });
+        }
+    }
+
+    fn setup_appearance(windows: &window_state::Windows, app_state: Arc<Mutex<AppState>>) {
+        let look = {
+            let state = app_state.lock().unwrap();
+            let settings = state.settings.lock().unwrap();
+            settings.appearance.clone()
+        };
+        appearance::apply(windows, &look);
+
+        let Some(chat) = windows.chat.upgrade() else {
+            return;
+        };
+        let windows = windows.clone();
+        chat.on_save_appearance(move |text_size, high_contrast| {
+            let look = Appearance {
+                text_size: TextSize::from_index(text_size),
+                high_contrast,
+            };
+            {
+                let state = app_state.lock().unwrap();
+                let mut settings = state.settings.lock().unwrap();
+                settings.appearance = look.clone();
+                if let Err(e) = settings.save() {
+                    eprintln!("Error saving settings: {}", e);
+                }
             }
+            appearance::apply(&windows, &look);
+        });
+    }
+
+    fn setup_spelling(chat_handle: &Weak<types::ChatWindow>, app_state: Arc<Mutex<AppState>>) {
+        let Some(chat) = chat_handle.upgrade() else {
+            return;
+        };
+        {
+            let state = app_state.lock().unwrap();
+            let available = state.spelling.lock().unwrap().is_available();
+            let enabled = state.settings.lock().unwrap().spell_check.enabled;
+            chat.set_spell_check_available(available);
+            chat.set_spell_check_enabled(enabled);
         }
 
         {
             let app_state_clone = app_state.clone();
-            let main_handle_clone = main_handle.clone();
             let chat_handle_clone = chat_handle.clone();
-            let rt_handle_clone = rt_handle.clone();
+            chat.on_check_spelling(move |text| {
+                let Some(chat) = chat_handle_clone.upgrade() else {
+                    return;
+                };
+                let issues = {
+                    let state = app_state_clone.lock().unwrap();
+                    if state.settings.lock().unwrap().spell_check.enabled {
+                        state.spelling.lock().unwrap().check(&text)
+                    } else {
+                        Vec::new()
+                    }
+                };
+                let issues: Vec<types::SpellingIssue> = issues
+                    .into_iter()
+                    .map(|issue| types::SpellingIssue {
+                        word: issue.word.into(),
+                        start: issue.start as i32,
+                        end: issue.end as i32,
+                        suggestions: ModelRc::new(VecModel::from(
+                            issue
+                                .suggestions
+                                .into_iter()
+                                .map(SharedString::from)
+                                .collect::<Vec<_>>(),
+                        )),
+                    })
+                    .collect();
+                chat.set_spelling_issues(ModelRc::new(VecModel::from(issues)));
+            });
+        }
 
-            if let Some(chat) = chat_handle.upgrade() {
-                chat.on_disconnect(move || {
-                    let app_state = app_state_clone.clone();
-                    let main_handle = main_handle_clone.clone();
-                    let chat_handle = chat_handle_clone.clone();
+        chat.on_fix_spelling(move |text, start, end, suggestion| {
+            match spellcheck::replace(&text, start as usize, end as usize, &suggestion) {
+                Ok(fixed) => fixed.into(),
+                Err(e) => {
+                    eprintln!("Error fixing spelling: {}", e);
+                    text
+                }
+            }
+        });
 
-                    rt_handle_clone.spawn(async move {
-                        if let Err(e) = send_disconnect(app_state.clone()).await {
-                            eprintln!("Error sending disconnect message: {}", e);
-                        }
+        {
+            let app_state_clone = app_state.clone();
+            chat.on_add_to_dictionary(move |word| {
+                let state = app_state_clone.lock().unwrap();
+                if let Err(e) = state.spelling.lock().unwrap().add_word(&word) {
+                    eprintln!("Error adding {} to the dictionary: {:#}", word, e);
+                }
+            });
+        }
 
-                        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+        {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            chat.on_save_spell_check(move |enabled| {
+                {
+                    let state = app_state_clone.lock().unwrap();
+                    let mut settings = state.settings.lock().unwrap();
+                    settings.spell_check = SpellCheck { enabled };
+                    if let Err(e) = settings.save() {
+                        eprintln!("Error saving settings: {}", e);
+                    }
+                }
+                if let Some(chat) = chat_handle_clone.upgrade() {
+                    chat.invoke_check_spelling(chat.get_current_message());
+                }
+            });
+        }
+    }
 
-                        if let Err(e) = cleanup_network_resources(app_state.clone()).await {
-                            eprintln!("Error cleaning up network resources: {}", e);
-                        }
+    #[cfg(not(target_arch = "wasm32"))]
+    fn setup_device_sync(
+        chat_handle: &Weak<types::ChatWindow>,
+        app_state: Arc<Mutex<AppState>>,
+        spawner: Spawner,
+    ) {
+        let Some(chat) = chat_handle.upgrade() else {
+            return;
+        };
 
-                        match slint::invoke_from_event_loop(move || {
-                            if let Some(chat) = chat_handle.upgrade() {
-                                if let Some(main) = main_handle.upgrade() {
-                                    match main.show() {
-                                        Ok(_) => println!("Main window shown successfully"),
-                                        Err(e) => println!("Error showing main window: {:?}", e),
-                                    }
-                                    match chat.hide() {
-                                        Ok(_) => println!("Chat window hidden successfully"),
-                                        Err(e) => println!("Error hiding chat window: {:?}", e),
-                                    }
-                                } else {
-                                    println!("ERROR: Main window handle is invalid");
-                                }
-                            } else {
-                                println!("ERROR: Chat window handle is invalid");
-                            }
-                        }) {
-                            Ok(_) => println!("Disconnect navigation successful"),
-                            Err(e) => {
-                                println!("ERROR: Failed to invoke disconnect navigation: {:?}", e)
-                            }
-                        }
-                    });
-                });
-            }
+        spawner.spawn(device_sync::apply_settings(app_state.clone()));
+
+        {
+            let app_state_clone = app_state.clone();
+            let spawner = spawner.clone();
+            chat.on_save_device_sync(move |enabled| {
+                {
+                    let state = app_state_clone.lock().unwrap();
+                    let mut settings = state.settings.lock().unwrap();
+                    settings.device_sync.enabled = enabled;
+                    if let Err(e) = settings.save() {
+                        eprintln!("Error saving settings: {}", e);
+                    }
+                }
+                spawner.spawn(device_sync::apply_settings(app_state_clone.clone()));
+            });
         }
 
         {
             let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            chat.on_link_device(move |device| {
+                let Some(chat) = chat_handle_clone.upgrade() else {
+                    return;
+                };
+                if let Err(e) = device_sync::link(&app_state_clone, &device) {
+                    chat.set_sync_status(e.to_string().into());
+                    return;
+                }
+                chat.set_link_device_id("".into());
+                let running = app_state_clone.lock().unwrap().device_sync.clone();
+                match running {
+                    Some(sync) => {
+                        chat.set_sync_status("Linked, syncing now".into());
+                        let app_state = app_state_clone.clone();
+                        spawner.spawn(async move { sync.sync_all(&app_state).await });
+                    }
+                    None => chat.set_sync_status("Linked. Turn on sync to start".into()),
+                }
+                device_sync::show_settings(&chat, &app_state_clone);
+            });
+        }
 
-            if let Some(chat) = chat_handle.upgrade() {
-                chat.on_copy_session_token(move || {
-                    let app_state = app_state_clone.clone();
-                    let token = {
-                        let state = app_state.lock().unwrap();
-                        state.current_session_token.clone()
-                    };
+        {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            chat.on_forget_devices(move || {
+                {
+                    let state = app_state_clone.lock().unwrap();
+                    let mut settings = state.settings.lock().unwrap();
+                    settings.device_sync.devices.clear();
+                    if let Err(e) = settings.save() {
+                        eprintln!("Error saving settings: {}", e);
+                    }
+                }
+                if let Some(chat) = chat_handle_clone.upgrade() {
+                    chat.set_sync_status("".into());
+                    device_sync::show_settings(&chat, &app_state_clone);
+                }
+            });
+        }
+    }
 
-                    if let Some(token) = token {
-                        match std::process::Command::new("pbcopy")
-                            .stdin(std::process::Stdio::piped())
-                            .spawn()
-                        {
-                            Ok(mut child) => {
-                                if let Some(mut stdin) = child.stdin.take() {
-                                    use std::io::Write;
-                                    if let Err(e) = stdin.write_all(token.as_bytes()) {
-                                        eprintln!("Failed to write to pbcopy: {}", e);
-                                    }
-                                    drop(stdin);
-                                    if let Err(e) = child.wait() {
-                                        eprintln!("pbcopy process failed: {}", e);
-                                    } else {
-                                        println!("Session token copied to clipboard successfully");
-                                    }
-                                } else {
-                                    eprintln!("Failed to access pbcopy stdin");
-                                }
-                            }
-                            Err(e) => {
-                                eprintln!("Failed to start pbcopy: {}. Token: {}", e, token);
-                            }
+    #[cfg(not(target_arch = "wasm32"))]
+    fn setup_identity(chat_handle: &Weak<types::ChatWindow>, app_state: Arc<Mutex<AppState>>) {
+        let Some(chat) = chat_handle.upgrade() else {
+            return;
+        };
+
+        {
+            let chat_handle_clone = chat_handle.clone();
+            chat.on_export_identity(move |password| {
+                let Some(chat) = chat_handle_clone.upgrade() else {
+                    return;
+                };
+                let status = match identity::export(&password) {
+                    Ok(Some(path)) => {
+                        chat.set_identity_password("".into());
+                        format!("Exported to {}", path.display())
+                    }
+                    Ok(None) => return,
+                    Err(e) => {
+                        eprintln!("Error exporting identity: {}", e);
+                        e.to_string()
+                    }
+                };
+                chat.set_identity_status(status.into());
+            });
+        }
+
+        {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            chat.on_import_identity(move |password| {
+                let Some(chat) = chat_handle_clone.upgrade() else {
+                    return;
+                };
+                let status = match identity::import(&password, &app_state_clone) {
+                    Ok(Some(id)) => {
+                        chat.set_identity_password("".into());
+                        format!("Now using {}", id.fmt_short())
+                    }
+                    Ok(None) => return,
+                    Err(e) => {
+                        eprintln!("Error importing identity: {}", e);
+                        e.to_string()
+                    }
+                };
+                chat.set_identity_status(status.into());
+                identity::show_settings(&chat);
+            });
+        }
+    }
+
+    fn setup_peer_callbacks(
+        main_handle: &Weak<types::StartWindow>,
+        join_handle: &Weak<types::JoinWindow>,
+        chat_handle: &Weak<types::ChatWindow>,
+        app_state: Arc<Mutex<AppState>>,
+        spawner: Spawner,
+    ) {
+        if let Some(main) = main_handle.upgrade() {
+            {
+                let app_state_clone = app_state.clone();
+                let main_handle_clone = main_handle.clone();
+                main.on_open_contacts(move || {
+                    if let Some(main) = main_handle_clone.upgrade() {
+                        contacts::refresh_start_contacts(&main, &app_state_clone);
+                        main.set_contacts_error("".into());
+                        main.set_show_contacts(true);
+                    }
+                });
+            }
+
+            {
+                let app_state_clone = app_state.clone();
+                let main_handle_clone = main_handle.clone();
+                main.on_add_contact(move |name, node_id, notes| {
+                    let Some(main) = main_handle_clone.upgrade() else {
+                        return;
+                    };
+                    match contacts::add_contact(&app_state_clone, &name, &node_id, &notes) {
+                        Ok(()) => {
+                            main.set_contacts_error("".into());
+                            main.set_new_contact_name("".into());
+                            main.set_new_contact_id("".into());
+                            main.set_new_contact_notes("".into());
+                        }
+                        Err(e) => {
+                            eprintln!("Error adding contact: {}", e);
+                            main.set_contacts_error(format!("{:#}", e).into());
                         }
-                    } else {
-                        eprintln!("No session token available to copy");
                     }
+                    contacts::refresh_start_contacts(&main, &app_state_clone);
                 });
             }
-        }
-    }
+
+            {
+                let app_state_clone = app_state.clone();
+                let main_handle_clone = main_handle.clone();
+                main.on_remove_contact(move |node_id| {
+                    let result = node_id
+                        .parse()
+                        .map_err(anyhow::Error::from)
+                        .and_then(|node_id| {
+                            let state = app_state_clone.lock().unwrap();
+                            let mut contacts = state.contacts.lock().unwrap();
+                            contacts.remove(&node_id)
+                        });
+                    if let Err(e) = result {
+                        eprintln!("Error removing contact: {}", e);
+                    }
+                    if let Some(main) = main_handle_clone.upgrade() {
+                        contacts::refresh_start_contacts(&main, &app_state_clone);
+                    }
+                });
+            }
+
+            {
+                let app_state_clone = app_state.clone();
+                main.on_update_contact_notes(move |node_id, notes| {
+                    let result = node_id
+                        .parse()
+                        .map_err(anyhow::Error::from)
+                        .and_then(|node_id| {
+                            let state = app_state_clone.lock().unwrap();
+                            let mut contacts = state.contacts.lock().unwrap();
+                            contacts.set_notes(&node_id, notes.to_string())
+                        });
+                    if let Err(e) = result {
+                        eprintln!("Error updating contact notes: {}", e);
+                    }
+                });
+            }
+
+            {
+                let main_handle_clone = main_handle.clone();
+                main.on_open_saved_chats(move || {
+                    if let Some(main) = main_handle_clone.upgrade() {
+                        main.set_saved_chats_error("".into());
+                        saved_chats::refresh_start_saved_chats(&main);
+                        main.set_show_saved_chats(true);
+                    }
+                });
+            }
+
+            {
+                let main_handle_clone = main_handle.clone();
+                main.on_filter_saved_chats(move |filter| {
+                    if let Some(main) = main_handle_clone.upgrade() {
+                        main.set_saved_chat_filter(filter);
+                        saved_chats::refresh_start_saved_chats(&main);
+                    }
+                });
+            }
+
+            {
+                let main_handle_clone = main_handle.clone();
+                main.on_toggle_chat_favorite(move |path| {
+                    if let Err(e) = saved_chats::toggle_favorite(&path) {
+                        eprintln!("Error starring saved chat: {}", e);
+                    }
+                    if let Some(main) = main_handle_clone.upgrade() {
+                        saved_chats::refresh_start_saved_chats(&main);
+                    }
+                });
+            }
+
+            {
+                let main_handle_clone = main_handle.clone();
+                main.on_set_chat_tags(move |path, tags| {
+                    if let Err(e) = saved_chats::set_tags(&path, &tags) {
+                        eprintln!("Error tagging saved chat: {}", e);
+                    }
+                    if let Some(main) = main_handle_clone.upgrade() {
+                        saved_chats::refresh_start_saved_chats(&main);
+                    }
+                });
+            }
+
+            {
+                let main_handle_clone = main_handle.clone();
+                main.on_rename_saved_chat(move |path, name| {
+                    if let Err(e) = saved_chats::rename(&path, &name) {
+                        eprintln!("Error renaming saved chat: {}", e);
+                    }
+                    if let Some(main) = main_handle_clone.upgrade() {
+                        saved_chats::refresh_start_saved_chats(&main);
+                    }
+                });
+            }
+
+            {
+                let main_handle_clone = main_handle.clone();
+                main.on_delete_saved_chats(move |path| {
+                    let Some(main) = main_handle_clone.upgrade() else {
+                        return;
+                    };
+                    let paths: Vec<SharedString> = if path.is_empty() {
+                        main.get_saved_chats()
+                            .iter()
+                            .filter(|chat| chat.selected)
+                            .map(|chat| chat.file_path)
+                            .collect()
+                    } else {
+                        vec![path]
+                    };
+                    let error = match saved_chats::delete(&paths) {
+                        Ok(()) => String::new(),
+                        Err(e) => format!("{:#}", e),
+                    };
+                    main.set_saved_chats_error(error.into());
+                    saved_chats::refresh_start_saved_chats(&main);
+                });
+            }
+
+            {
+                let app_state_clone = app_state.clone();
+                let main_handle_clone = main_handle.clone();
+                let chat_handle_clone = chat_handle.clone();
+                main.on_open_saved_chat(move |path| {
+                    let (Some(main), Some(chat)) =
+                        (main_handle_clone.upgrade(), chat_handle_clone.upgrade())
+                    else {
+                        return;
+                    };
+                    match saved_chats::open(&path, &app_state_clone, &chat_handle_clone) {
+                        Ok(opened) => {
+                            chat.set_viewing_saved(true);
+                            chat.set_saved_ticket(opened.ticket.unwrap_or_default().into());
+                            chat.set_saved_ticket_locked(opened.locked);
+                            let username = opened.username.unwrap_or_else(|| {
+                                let state = app_state_clone.lock().unwrap();
+                                let settings = state.settings.lock().unwrap();
+                                settings.profile.username.clone()
+                            });
+                            chat.set_saved_username(username.into());
+                            chat.set_saved_password("".into());
+                            chat.set_reconnecting(false);
+                            chat.set_connection_status("Offline".into());
+                            chat.set_room_name(opened.name.into());
+                            chat.set_room_topic("".into());
+                            chat.set_room_description("".into());
+                            chat.set_session_token("".into());
+                            chat.set_is_room_owner(false);
+                            main.set_show_saved_chats(false);
+                            let _ = chat.show();
+                            let _ = main.hide();
+                        }
+                        Err(e) => {
+                            eprintln!("Error opening saved chat: {}", e);
+                            main.set_saved_chats_error(format!("{:#}", e).into());
+                        }
+                    }
+                });
+            }
+
+            {
+                let main_handle_clone = main_handle.clone();
+                let join_handle_clone = join_handle.clone();
+                main.on_join_last_room(move |ticket| {
+                    if let (Some(main), Some(join)) =
+                        (main_handle_clone.upgrade(), join_handle_clone.upgrade())
+                    {
+                        join.set_peer_id(ticket);
+                        main.set_show_contacts(false);
+                        let _ = join.show();
+                        let _ = main.hide();
+                    }
+                });
+            }
+
+            {
+                let main_handle_clone = main_handle.clone();
+                let join_handle_clone = join_handle.clone();
+                main.on_rejoin_recent_room(move |ticket, locked| {
+                    let (Some(main), Some(join)) =
+                        (main_handle_clone.upgrade(), join_handle_clone.upgrade())
+                    else {
+                        return;
+                    };
+                    join.set_peer_id(ticket.clone());
+                    join.set_password("".into());
+                    join.set_peer_address("".into());
+                    join.set_join_error("".into());
+                    let _ = join.show();
+                    let _ = main.hide();
+                    // Without a name or the password there's nothing to join with yet, so
+                    // the join window is left to fill in.
+                    let username = join.get_username();
+                    if !locked && !username.is_empty() {
+                        join.invoke_switch_to_chat_window(username, ticket, "".into(), "".into());
+                    }
+                });
+            }
+
+            {
+                let app_state_clone = app_state.clone();
+                let main_handle_clone = main_handle.clone();
+                main.on_forget_recent_room(move |ticket| {
+                    {
+                        let state = app_state_clone.lock().unwrap();
+                        let mut rooms = state.recent_rooms.lock().unwrap();
+                        if let Err(e) = rooms.forget(&ticket) {
+                            eprintln!("Error saving recent rooms: {}", e);
+                        }
+                    }
+                    if let Some(main) = main_handle_clone.upgrade() {
+                        recent_rooms::refresh_start_recent_rooms(&main, &app_state_clone);
+                    }
+                });
+            }
+        }
+
+        if let Some(chat) = chat_handle.upgrade() {
+            {
+                let app_state_clone = app_state.clone();
+                let chat_handle_clone = chat_handle.clone();
+                chat.on_save_contact(move |username| {
+                    if let Err(e) =
+                        contacts::save_contact(&username, &chat_handle_clone, &app_state_clone)
+                    {
+                        eprintln!("Error saving contact: {}", e);
+                    }
+                });
+            }
+
+            {
+                let app_state_clone = app_state.clone();
+                let chat_handle_clone = chat_handle.clone();
+                chat.on_toggle_mute(move |username| {
+                    if let Err(e) =
+                        blocklist::toggle_mute(&username, &chat_handle_clone, &app_state_clone)
+                    {
+                        eprintln!("Error muting user: {}", e);
+                    }
+                });
+            }
+
+            {
+                let app_state_clone = app_state.clone();
+                let chat_handle_clone = chat_handle.clone();
+                chat.on_toggle_block(move |username| {
+                    if let Err(e) =
+                        blocklist::toggle_block(&username, &chat_handle_clone, &app_state_clone)
+                    {
+                        eprintln!("Error blocking user: {}", e);
+                    }
+                });
+            }
+
+            {
+                let app_state_clone = app_state.clone();
+                let chat_handle_clone = chat_handle.clone();
+                let spawner = spawner.clone();
+                chat.on_answer_knock(move |node_id, approve| {
+                    let app_state = app_state_clone.clone();
+                    let chat_handle = chat_handle_clone.clone();
+                    let node_id = node_id.to_string();
+
+                    spawner.spawn(async move {
+                        if let Err(e) =
+                            admission::answer_knock(node_id, approve, app_state, chat_handle).await
+                        {
+                            eprintln!("Error answering knock: {}", e);
+                        }
+                    });
+                });
+            }
+
+            {
+                let app_state_clone = app_state.clone();
+                let chat_handle_clone = chat_handle.clone();
+                chat.on_load_older_history(move || {
+                    history::load_older_page(&app_state_clone, &chat_handle_clone);
+                });
+            }
+
+            {
+                let app_state_clone = app_state.clone();
+                let chat_handle_clone = chat_handle.clone();
+                let spawner = spawner.clone();
+                chat.on_set_status(move |index| {
+                    let status = match index {
+                        1 => PresenceStatus::Away,
+                        2 => PresenceStatus::Busy,
+                        _ => PresenceStatus::Online,
+                    };
+                    let app_state = app_state_clone.clone();
+                    let chat_handle = chat_handle_clone.clone();
+
+                    spawner.spawn(async move {
+                        if let Err(e) = presence::set_status(status, app_state, chat_handle).await {
+                            eprintln!("Error changing status: {}", e);
+                        }
+                    });
+                });
+            }
+
+            {
+                let app_state_clone = app_state.clone();
+                let chat_handle_clone = chat_handle.clone();
+                let spawner = spawner.clone();
+                chat.on_user_active(move || {
+                    notifications::mark_read(&app_state_clone, &chat_handle_clone);
+                    let app_state = app_state_clone.clone();
+                    let chat_handle = chat_handle_clone.clone();
+
+                    spawner.spawn(async move {
+                        if let Err(e) = presence::mark_active(app_state, chat_handle).await {
+                            eprintln!("Error updating status: {}", e);
+                        }
+                    });
+                });
+            }
+
+            {
+                let app_state_clone = app_state.clone();
+                let chat_handle_clone = chat_handle.clone();
+                chat.on_mark_read(move || {
+                    notifications::mark_read(&app_state_clone, &chat_handle_clone);
+                });
+            }
+
+            {
+                let app_state_clone = app_state.clone();
+                let chat_handle_clone = chat_handle.clone();
+                chat.on_toggle_room_notifications(move || {
+                    if let Err(e) = notifications::toggle_mute(&chat_handle_clone, &app_state_clone)
+                    {
+                        eprintln!("Error muting room notifications: {}", e);
+                    }
+                });
+            }
+
+            {
+                let app_state_clone = app_state.clone();
+                let chat_handle_clone = chat_handle.clone();
+                chat.on_toggle_auto_translate(move || {
+                    if let Err(e) = translate::toggle_auto(&chat_handle_clone, &app_state_clone) {
+                        eprintln!("Error turning auto-translate on or off: {}", e);
+                    }
+                });
+            }
+
+            {
+                let app_state_clone = app_state.clone();
+                let chat_handle_clone = chat_handle.clone();
+                chat.on_translate_message(move |message_id| {
+                    translate::translate(&message_id, &app_state_clone, &chat_handle_clone);
+                });
+            }
+
+            {
+                let app_state_clone = app_state.clone();
+                let chat_handle_clone = chat_handle.clone();
+                chat.on_invite_contact(move |node_id| {
+                    let app_state = app_state_clone.clone();
+                    let chat_handle = chat_handle_clone.clone();
+                    let node_id = node_id.to_string();
+
+                    spawner.spawn(async move {
+                        if let Err(e) =
+                            contacts::invite_contact(node_id, app_state, chat_handle).await
+                        {
+                            eprintln!("Error inviting contact: {}", e);
+                        }
+                    });
+                });
+            }
+        }
+    }
+
+    fn setup_networking_callbacks(
+        main_handle: &Weak<types::StartWindow>,
+        join_handle: &Weak<types::JoinWindow>,
+        create_handle: &Weak<types::CreateWindow>,
+        chat_handle: &Weak<types::ChatWindow>,
+        app_state: Arc<Mutex<AppState>>,
+        spawner: Spawner,
+    ) {
+        {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            let join_handle_clone = join_handle.clone();
+            let spawner_clone = spawner.clone();
+
+            if let Some(join) = join_handle.upgrade() {
+                join.on_switch_to_chat_window(move |username, ticket_str, password, address| {
+                    let app_state = app_state_clone.clone();
+                    let chat_handle = chat_handle_clone.clone();
+                    let join_handle = join_handle_clone.clone();
+                    let username = username.to_string();
+                    let ticket_str = ticket_str.to_string();
+                    let password = password.to_string();
+                    let peer_address = address.to_string();
+
+                    spawner_clone.spawn(async move {
+                        if let Err(e) = join_room(
+                            username,
+                            ticket_str,
+                            password,
+                            peer_address,
+                            app_state,
+                            chat_handle,
+                            join_handle.clone(),
+                        )
+                        .await
+                        {
+                            eprintln!("Error joining room: {}", e);
+                            let error = format!("{:#}", e);
+                            if let Err(e) = slint::invoke_from_event_loop(move || {
+                                if let Some(join) = join_handle.upgrade() {
+                                    join.set_join_status("".into());
+                                    join.set_join_error(error.into());
+                                }
+                            }) {
+                                eprintln!("Failed to show join error: {:?}", e);
+                            }
+                        }
+                    });
+                });
+            }
+        }
+
+        if let Some(chat) = chat_handle.upgrade() {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            let join_handle_clone = join_handle.clone();
+            let spawner_clone = spawner.clone();
+
+            chat.on_retry_connection(move || {
+                if let Some(chat) = chat_handle_clone.upgrade() {
+                    chat.set_reconnecting(true);
+                }
+                let app_state = app_state_clone.clone();
+                let chat_handle = chat_handle_clone.clone();
+                let join_handle = join_handle_clone.clone();
+
+                spawner_clone.spawn(async move {
+                    if let Err(e) =
+                        connection::retry(app_state, chat_handle.clone(), join_handle).await
+                    {
+                        eprintln!("Error retrying the connection: {}", e);
+                    }
+                    if let Err(e) = slint::invoke_from_event_loop(move || {
+                        if let Some(chat) = chat_handle.upgrade() {
+                            chat.set_reconnecting(false);
+                        }
+                    }) {
+                        eprintln!("Failed to update reconnect status: {:?}", e);
+                    }
+                });
+            });
+        }
+
+        if let Some(chat) = chat_handle.upgrade() {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            let join_handle_clone = join_handle.clone();
+            let spawner_clone = spawner.clone();
+
+            chat.on_reconnect_saved(move |ticket_str, username, password| {
+                if let Some(chat) = chat_handle_clone.upgrade() {
+                    chat.set_reconnecting(true);
+                }
+                let app_state = app_state_clone.clone();
+                let chat_handle = chat_handle_clone.clone();
+                let join_handle = join_handle_clone.clone();
+                let (ticket_str, username, password) = (
+                    ticket_str.to_string(),
+                    username.to_string(),
+                    password.to_string(),
+                );
+
+                spawner_clone.spawn(async move {
+                    // The restored messages stay in the window; join_room only adds to them.
+                    let result = join_room(
+                        username,
+                        ticket_str,
+                        password,
+                        String::new(),
+                        app_state.clone(),
+                        chat_handle.clone(),
+                        join_handle,
+                    )
+                    .await;
+                    if let Err(e) = &result {
+                        eprintln!("Error reconnecting to saved room: {}", e);
+                        push_system_message(
+                            &app_state,
+                            trf(
+                                "Could not reconnect to this room: {}",
+                                &[&format!("{:#}", e)],
+                            ),
+                        );
+                        update_messages(&chat_handle, &app_state);
+                    }
+                    if let Err(e) = slint::invoke_from_event_loop(move || {
+                        if let Some(chat) = chat_handle.upgrade() {
+                            chat.set_reconnecting(false);
+                            if result.is_ok() {
+                                chat.set_viewing_saved(false);
+                            }
+                        }
+                    }) {
+                        eprintln!("Failed to update reconnect status: {:?}", e);
+                    }
+                });
+            });
+        }
+
+        {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            let create_handle_clone = create_handle.clone();
+            let spawner_clone = spawner.clone();
+
+            if let Some(create) = create_handle.upgrade() {
+                create.on_switch_to_chat_window(
+                    move |username,
+                          announcement,
+                          room_name,
+                          room_description,
+                          room_topic,
+                          capacity,
+                          knock,
+                          incognito,
+                          password| {
+                        let app_state = app_state_clone.clone();
+                        let chat_handle = chat_handle_clone.clone();
+                        let create_handle = create_handle_clone.clone();
+                        let username = username.to_string();
+                        let options = RoomOptions {
+                            announcement,
+                            info: RoomInfo {
+                                name: room_name.trim().to_string(),
+                                description: room_description.trim().to_string(),
+                                topic: room_topic.trim().to_string(),
+                            },
+                            capacity: u32::try_from(capacity).ok().filter(|c| *c > 0),
+                            knock,
+                            incognito,
+                            password: Some(password.to_string()).filter(|p| !p.is_empty()),
+                        };
+
+                        spawner_clone.spawn(async move {
+                            if let Err(e) = create_room(
+                                username,
+                                options,
+                                app_state,
+                                chat_handle,
+                                create_handle,
+                            )
+                            .await
+                            {
+                                eprintln!("Error creating room: {}", e);
+                            }
+                        });
+                    },
+                );
+            }
+        }
+
+        {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            let spawner_clone = spawner.clone();
+
+            if let Some(chat) = chat_handle.upgrade() {
+                chat.on_send_message(move |message| {
+                    // Shift+Enter on an empty composer leaves nothing but line breaks.
+                    if message.trim().is_empty() {
+                        return;
+                    }
+                    println!("DEBUG: Sending message: '{}'", message);
+                    let app_state = app_state_clone.clone();
+                    let chat_handle = chat_handle_clone.clone();
+                    let message = message.to_string();
+
+                    spawner_clone.spawn(async move {
+                        match send_message(message.clone(), app_state.clone(), chat_handle.clone())
+                            .await
+                        {
+                            Ok(_) => {
+                                println!("DEBUG: Message sent successfully, updating UI");
+                                update_messages(&chat_handle, &app_state);
+                            }
+                            Err(e) => {
+                                eprintln!("ERROR: Failed to send message: {}", e);
+                            }
+                        }
+                    });
+                });
+            }
+        }
+
+        if let Some(chat) = chat_handle.upgrade() {
+            chat.on_copy_code(|code| copy_to_clipboard(&code));
+        }
+
+        if let Some(chat) = chat_handle.upgrade() {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            chat.on_schedule_message(move |message, minutes| {
+                if message.trim().is_empty() {
+                    return;
+                }
+                println!("DEBUG: Scheduling a message in {} minutes", minutes);
+                app_state_clone
+                    .lock()
+                    .unwrap()
+                    .scheduled
+                    .add(message.to_string(), minutes.max(1) as u32);
+                scheduled::show(&chat_handle_clone, &app_state_clone);
+            });
+        }
+
+        if let Some(chat) = chat_handle.upgrade() {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            chat.on_cancel_scheduled(move |id| {
+                if let Ok(id) = id.parse() {
+                    app_state_clone.lock().unwrap().scheduled.cancel(id);
+                }
+                scheduled::show(&chat_handle_clone, &app_state_clone);
+            });
+        }
+
+        {
+            let app_state_clone = app_state.clone();
+            let main_handle_clone = main_handle.clone();
+            let chat_handle_clone = chat_handle.clone();
+            let spawner_clone = spawner.clone();
+
+            if let Some(chat) = chat_handle.upgrade() {
+                chat.on_disconnect(move || {
+                    let app_state = app_state_clone.clone();
+                    let main_handle = main_handle_clone.clone();
+                    let chat_handle = chat_handle_clone.clone();
+
+                    spawner_clone.spawn(async move {
+                        leave_room(app_state.clone(), chat_handle.clone()).await;
+
+                        n0_future::time::sleep(n0_future::time::Duration::from_millis(200)).await;
+
+                        if let Err(e) = cleanup_network_resources(app_state.clone()).await {
+                            eprintln!("Error cleaning up network resources: {}", e);
+                        }
+
+                        match slint::invoke_from_event_loop(move || {
+                            if let Some(chat) = chat_handle.upgrade() {
+                                chat.set_viewing_saved(false);
+                                if let Some(main) = main_handle.upgrade() {
+                                    recent_rooms::refresh_start_recent_rooms(&main, &app_state);
+                                    match main.show() {
+                                        Ok(_) => println!("Main window shown successfully"),
+                                        Err(e) => println!("Error showing main window: {:?}", e),
+                                    }
+                                    match chat.hide() {
+                                        Ok(_) => println!("Chat window hidden successfully"),
+                                        Err(e) => println!("Error hiding chat window: {:?}", e),
+                                    }
+                                } else {
+                                    println!("ERROR: Main window handle is invalid");
+                                }
+                            } else {
+                                println!("ERROR: Chat window handle is invalid");
+                            }
+                        }) {
+                            Ok(_) => println!("Disconnect navigation successful"),
+                            Err(e) => {
+                                println!("ERROR: Failed to invoke disconnect navigation: {:?}", e)
+                            }
+                        }
+                    });
+                });
+            }
+        }
+
+        {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            let spawner_clone = spawner.clone();
+
+            if let Some(chat) = chat_handle.upgrade() {
+                chat.on_start_call(move |username| {
+                    let app_state = app_state_clone.clone();
+                    let chat_handle = chat_handle_clone.clone();
+                    let username = username.to_string();
+
+                    spawner_clone.spawn(async move {
+                        if let Err(e) = calls::start_call(username, app_state, chat_handle).await {
+                            eprintln!("Error starting call: {}", e);
+                        }
+                    });
+                });
+            }
+        }
+
+        {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            let spawner_clone = spawner.clone();
+
+            if let Some(chat) = chat_handle.upgrade() {
+                chat.on_accept_call(move || {
+                    let app_state = app_state_clone.clone();
+                    let chat_handle = chat_handle_clone.clone();
+
+                    spawner_clone.spawn(async move {
+                        if let Err(e) = calls::accept_call(app_state, chat_handle).await {
+                            eprintln!("Error accepting call: {}", e);
+                        }
+                    });
+                });
+            }
+        }
+
+        {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            let spawner_clone = spawner.clone();
+
+            if let Some(chat) = chat_handle.upgrade() {
+                chat.on_hang_up_call(move || {
+                    let app_state = app_state_clone.clone();
+                    let chat_handle = chat_handle_clone.clone();
+
+                    spawner_clone.spawn(async move {
+                        if let Err(e) = calls::hang_up(app_state, chat_handle).await {
+                            eprintln!("Error hanging up call: {}", e);
+                        }
+                    });
+                });
+            }
+        }
+
+        {
+            let app_state_clone = app_state.clone();
+
+            if let Some(chat) = chat_handle.upgrade() {
+                chat.on_copy_session_token(move || {
+                    let app_state = app_state_clone.clone();
+                    let token = {
+                        let state = app_state.lock().unwrap();
+                        state.current_session_token.clone()
+                    };
+
+                    if let Some(token) = token {
+                        copy_to_clipboard(&token);
+                    } else {
+                        eprintln!("No session token available to copy");
+                    }
+                });
+            }
+        }
+
+        {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            let spawner_clone = spawner.clone();
+
+            if let Some(chat) = chat_handle.upgrade() {
+                chat.on_new_invite(move || {
+                    let app_state = app_state_clone.clone();
+                    let chat_handle = chat_handle_clone.clone();
+
+                    spawner_clone.spawn(async move {
+                        if let Err(e) = renew_invite(app_state, chat_handle).await {
+                            eprintln!("Error renewing invite: {}", e);
+                        }
+                    });
+                });
+            }
+        }
+
+        {
+            let app_state_clone = app_state.clone();
+
+            if let Some(chat) = chat_handle.upgrade() {
+                chat.on_copy_session_words(move || {
+                    let token = app_state_clone
+                        .lock()
+                        .unwrap()
+                        .current_session_token
+                        .clone();
+
+                    match token.as_deref().map(Ticket::from_str) {
+                        Some(Ok(ticket)) => copy_to_clipboard(&ticket.to_words()),
+                        Some(Err(e)) => eprintln!("Failed to read session token: {}", e),
+                        None => eprintln!("No session token available to copy"),
+                    }
+                });
+            }
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            let spawner_clone = spawner.clone();
+
+            if let Some(chat) = chat_handle.upgrade() {
+                chat.on_start_screen_share(move |fps, quality| {
+                    let app_state = app_state_clone.clone();
+                    let chat_handle = chat_handle_clone.clone();
+                    let settings = ShareSettings {
+                        fps: fps.max(1) as u32,
+                        quality: ShareQuality::from_index(quality),
+                    };
+
+                    spawner_clone.spawn(async move {
+                        if let Err(e) =
+                            screen_share::start_sharing(settings, app_state, chat_handle).await
+                        {
+                            eprintln!("Error starting screen share: {}", e);
+                        }
+                    });
+                });
+            }
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            let spawner_clone = spawner.clone();
+
+            if let Some(chat) = chat_handle.upgrade() {
+                chat.on_stop_screen_share(move || {
+                    let app_state = app_state_clone.clone();
+                    let chat_handle = chat_handle_clone.clone();
+
+                    spawner_clone.spawn(async move {
+                        if let Err(e) = screen_share::stop_sharing(app_state, chat_handle).await {
+                            eprintln!("Error stopping screen share: {}", e);
+                        }
+                    });
+                });
+            }
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            let spawner_clone = spawner.clone();
+
+            if let Some(chat) = chat_handle.upgrade() {
+                chat.on_watch_screen_share(move || {
+                    let app_state = app_state_clone.clone();
+                    let chat_handle = chat_handle_clone.clone();
+
+                    spawner_clone.spawn(async move {
+                        if let Err(e) = screen_share::watch_share(app_state, chat_handle).await {
+                            eprintln!("Screen share viewer stopped: {}", e);
+                        }
+                    });
+                });
+            }
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+
+            if let Some(chat) = chat_handle.upgrade() {
+                chat.on_stop_watching_screen(move || {
+                    let sharer = app_state_clone
+                        .lock()
+                        .unwrap()
+                        .screen_viewer
+                        .as_ref()
+                        .map(|(id, _)| *id);
+                    if let Some(sharer) = sharer {
+                        screen_share::stop_watching(&app_state_clone, &chat_handle_clone, sharer);
+                    }
+                });
+            }
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+
+            if let Some(chat) = chat_handle.upgrade() {
+                chat.on_paste_image(move || {
+                    let Some(chat) = chat_handle_clone.upgrade() else {
+                        return false;
+                    };
+                    images::paste(&chat, &app_state_clone)
+                });
+            }
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            let spawner_clone = spawner.clone();
+
+            if let Some(chat) = chat_handle.upgrade() {
+                chat.on_send_pasted_image(move || {
+                    let app_state = app_state_clone.clone();
+                    let chat_handle = chat_handle_clone.clone();
+
+                    spawner_clone.spawn(async move {
+                        if let Err(e) = images::send_pasted(app_state, chat_handle).await {
+                            eprintln!("Error sending image: {}", e);
+                        }
+                    });
+                });
+            }
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let app_state_clone = app_state.clone();
+
+            if let Some(chat) = chat_handle.upgrade() {
+                chat.on_cancel_pasted_image(move || {
+                    images::cancel_pasted(&app_state_clone);
+                });
+            }
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(chat) = chat_handle.upgrade() {
+            chat.set_stickers_available(true);
+
+            let chat_handle_clone = chat_handle.clone();
+            chat.on_refresh_stickers(move || {
+                if let Some(chat) = chat_handle_clone.upgrade() {
+                    stickers::show(&chat);
+                }
+            });
+
+            let chat_handle_clone = chat_handle.clone();
+            chat.on_add_sticker(move || match stickers::add() {
+                Ok(true) => {
+                    if let Some(chat) = chat_handle_clone.upgrade() {
+                        stickers::show(&chat);
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => eprintln!("Error adding sticker: {}", e),
+            });
+
+            let chat_handle_clone = chat_handle.clone();
+            chat.on_remove_sticker(move |name| {
+                if let Err(e) = stickers::remove(&name) {
+                    eprintln!("Error removing sticker: {}", e);
+                }
+                if let Some(chat) = chat_handle_clone.upgrade() {
+                    stickers::show(&chat);
+                }
+            });
+
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            let spawner_clone = spawner.clone();
+            chat.on_send_sticker(move |name| {
+                let app_state = app_state_clone.clone();
+                let chat_handle = chat_handle_clone.clone();
+
+                spawner_clone.spawn(async move {
+                    if let Err(e) = stickers::send(name.to_string(), app_state, chat_handle).await {
+                        eprintln!("Error sending sticker: {}", e);
+                    }
+                });
+            });
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(chat) = chat_handle.upgrade() {
+            chat.set_files_available(true);
+
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            let spawner_clone = spawner.clone();
+            chat.on_offer_file(move || {
+                let Some(path) = rfd::FileDialog::new().set_title("Send a File").pick_file() else {
+                    return;
+                };
+                let app_state = app_state_clone.clone();
+                let chat_handle = chat_handle_clone.clone();
+
+                spawner_clone.spawn(async move {
+                    if let Err(e) = files::offer(path, app_state, chat_handle).await {
+                        eprintln!("Error offering file: {}", e);
+                    }
+                });
+            });
+
+            let app_state_clone = app_state.clone();
+            let chat_handle_clone = chat_handle.clone();
+            let spawner_clone = spawner.clone();
+            chat.on_answer_file_offer(move |id, accept| {
+                let app_state = app_state_clone.clone();
+                let chat_handle = chat_handle_clone.clone();
+
+                // Downloads are spawned on the runtime, so answer from there.
+                spawner_clone.spawn(async move {
+                    files::answer(&id, accept, &app_state, &chat_handle);
+                });
+            });
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        if let Some(chat) = chat_handle.upgrade() {
+            chat.on_start_screen_share(|_, _| {
+                eprintln!("Screen sharing is not available in the browser build");
+            });
+            chat.on_watch_screen_share(|| {
+                eprintln!("Screen sharing is not available in the browser build");
+            });
+        }
+    }
+}
+
+// Asks where to write the open room's chat and hands the path to the auto-save task,
+// which has the session. None when the dialog is cancelled.
+fn save_chat_now(app_state: &Arc<Mutex<AppState>>) -> Result<Option<PathBuf>> {
+    let (persister, topic) = {
+        let state = app_state.lock().unwrap();
+        (state.persister.clone(), state.room_topic)
+    };
+    let Some(topic) = topic else {
+        anyhow::bail!("Open or join a room first");
+    };
+    let manager = ChatSaveManager::new(Config::default())?;
+    let Some(path) =
+        manager.open_file_explorer_for_save(&format!("chat_{}.json", topic.fmt_short()))?
+    else {
+        return Ok(None);
+    };
+    persister.save_to(path.clone())?;
+    Ok(Some(path))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn copy_to_clipboard(text: &str) {
+    match std::process::Command::new("pbcopy")
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+    {
+        Ok(mut child) => {
+            if let Some(mut stdin) = child.stdin.take() {
+                use std::io::Write;
+                if let Err(e) = stdin.write_all(text.as_bytes()) {
+                    eprintln!("Failed to write to pbcopy: {}", e);
+                }
+                drop(stdin);
+                if let Err(e) = child.wait() {
+                    eprintln!("pbcopy process failed: {}", e);
+                } else {
+                    println!("Copied to clipboard successfully");
+                }
+            } else {
+                eprintln!("Failed to access pbcopy stdin");
+            }
+        }
+        Err(e) => {
+            eprintln!("Failed to start pbcopy: {}. Text: {}", e, text);
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn copy_to_clipboard(text: &str) {
+    eprintln!(
+        "Clipboard is not available in the browser build. Text: {}",
+        text
+    );
 }
@@ -1,21 +1,125 @@
-use iroh::{Endpoint, NodeId, protocol::Router};
+use chrono::{DateTime, Utc};
+use iroh::{Endpoint, NodeId, SecretKey, protocol::Router};
+use iroh_gossip::proto::TopicId;
+use messaging::{ClientInfo, Invite, RoomInfo, SeenWindow, StoredMessage};
 use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, atomic::AtomicBool},
 };
 
-use crate::app::types;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::app::device_sync::DeviceSync;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::app::files::Files;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::app::images::Pictures;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::app::screen_share::ScreenShare;
+use crate::app::{
+    admission::PendingKnock,
+    blocklist::BlockList,
+    calls::CallState,
+    contacts::ContactStore,
+    flood::FloodGuard,
+    last_seen::LastSeen,
+    paths::PathKind,
+    persistence::Persister,
+    presence::Presence,
+    privacy::RoomSender,
+    recent_rooms::RecentRooms,
+    recovery::{Outbox, SeqTracker},
+    save::MessageSpill,
+    scheduled::Schedule,
+    settings::Settings,
+    spellcheck::SpellChecker,
+    types,
+};
 
 #[derive(Clone)]
 pub struct AppState {
-    pub sender: Option<iroh_gossip::api::GossipSender>,
+    pub sender: Option<RoomSender>,
     pub endpoint: Option<Endpoint>,
     pub router: Option<Router>,
     pub current_username: String,
     pub current_node_id: Option<NodeId>,
     pub current_session_token: Option<String>,
+    pub room_topic: Option<TopicId>,
+    // Empty unless we're in an announcement room.
+    pub moderators: Vec<NodeId>,
+    pub room_owner: Option<NodeId>,
+    // The nodes in the ticket we joined with, usually the room's server.
+    pub room_nodes: Vec<NodeId>,
+    // Banned by the room owner while we were here; their messages are dropped.
+    pub banned: HashSet<NodeId>,
+    // The invite from the ticket we joined with, passed on in our AboutMe.
+    pub room_invite: Option<Invite>,
+    // Kept so a lost connection can be retried without asking for it again.
+    pub room_password: Option<String>,
+    // The address typed under "Advanced" in the join window, kept for retries like the password.
+    pub room_peer_address: Option<String>,
+    // How far we'd read the room in an earlier session, from its saved chat.
+    pub last_read: Option<DateTime<Utc>>,
+    pub scheduled: Schedule,
+    // Set when the room's tickets carry invites; newcomers need a valid one to join.
+    pub invites_required: bool,
+    pub revoked_invites: HashSet<u64>,
+    pub room_info: RoomInfo,
+    pub room_capacity: Option<u32>,
+    pub room_knock: bool,
+    // Who the owner let in after knocking, from the JoinReplies it sends to the room.
+    pub admitted: HashSet<NodeId>,
+    // Set from the ticket: nothing from this room is written to disk.
+    pub room_incognito: bool,
+    pub knocks: Arc<Mutex<Vec<PendingKnock>>>,
     pub names: Arc<Mutex<HashMap<NodeId, String>>>,
+    pub clients: Arc<Mutex<HashMap<NodeId, ClientInfo>>>,
+    pub presence: Arc<Mutex<Presence>>,
+    // Gossip neighbours we're watching, and whether we reach them directly.
+    pub paths: Arc<Mutex<HashMap<NodeId, Option<PathKind>>>>,
+    // Our gossip neighbours right now; see connection.rs.
+    pub neighbors: HashSet<NodeId>,
+    pub connection_lost: bool,
     pub messages: Arc<Mutex<Vec<types::ChatMessage>>>,
+    // History pages not shown yet, oldest first.
+    pub older_history: Arc<Mutex<Vec<StoredMessage>>>,
+    // Every history node in the room sends its archive; the first one is enough.
+    pub history_loaded: bool,
+    // Messages from others since we last typed; see notifications.rs.
+    pub unread: usize,
+    // Messages moved out of `messages` to keep memory flat, opened on first use.
+    pub spill: Arc<Mutex<Option<MessageSpill>>>,
+    // Queues messages for the background auto-save task.
+    pub persister: Persister,
+    // Bumped whenever we leave a room, so the tasks started for it know to stop.
+    pub room_generation: u64,
+    // Acks waiting to go straight back to each author, sent together once the batch is up.
+    pub pending_acks: HashMap<NodeId, Vec<u64>>,
+    pub call: CallState,
+    pub contacts: Arc<Mutex<ContactStore>>,
+    pub recent_rooms: Arc<Mutex<RecentRooms>>,
+    pub last_seen: Arc<Mutex<LastSeen>>,
+    pub blocklist: Arc<Mutex<BlockList>>,
+    pub settings: Arc<Mutex<Settings>>,
+    pub spelling: Arc<Mutex<SpellChecker>>,
+    pub flood: Arc<Mutex<FloodGuard>>,
+    pub seqs: Arc<Mutex<SeqTracker>>,
+    pub outbox: Arc<Mutex<Outbox>>,
+    // Ids of recent chat messages, so replays and duplicates never reach the UI.
+    pub seen: Arc<Mutex<SeenWindow>>,
+    pub render_pending: Arc<AtomicBool>,
+    #[cfg(not(target_arch = "wasm32"))]
+    pub screen_share: Option<ScreenShare>,
+    #[cfg(not(target_arch = "wasm32"))]
+    pub screen_viewer: Option<(NodeId, iroh::endpoint::Connection)>,
+    #[cfg(not(target_arch = "wasm32"))]
+    pub screen_sharers: Vec<NodeId>,
+    #[cfg(not(target_arch = "wasm32"))]
+    pub pictures: Arc<Mutex<Pictures>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    pub files: Arc<Mutex<Files>>,
+    // Runs outside of rooms while syncing saved chats with my other devices is on.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub device_sync: Option<DeviceSync>,
 }
 
 impl Default for AppState {
@@ -33,8 +137,154 @@ impl AppState {
             current_username: String::new(),
             current_node_id: None,
             current_session_token: None,
+            room_topic: None,
+            moderators: Vec::new(),
+            room_owner: None,
+            room_nodes: Vec::new(),
+            banned: HashSet::new(),
+            room_invite: None,
+            room_password: None,
+            room_peer_address: None,
+            last_read: None,
+            scheduled: Schedule::default(),
+            invites_required: false,
+            revoked_invites: HashSet::new(),
+            room_info: RoomInfo::default(),
+            room_capacity: None,
+            room_knock: false,
+            admitted: HashSet::new(),
+            room_incognito: false,
+            knocks: Arc::new(Mutex::new(Vec::new())),
             names: Arc::new(Mutex::new(HashMap::new())),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            presence: Arc::new(Mutex::new(Presence::default())),
+            paths: Arc::new(Mutex::new(HashMap::new())),
+            neighbors: HashSet::new(),
+            connection_lost: false,
             messages: Arc::new(Mutex::new(Vec::<types::ChatMessage>::new())),
+            older_history: Arc::new(Mutex::new(Vec::new())),
+            history_loaded: false,
+            unread: 0,
+            spill: Arc::new(Mutex::new(None)),
+            persister: Persister::default(),
+            room_generation: 0,
+            pending_acks: HashMap::new(),
+            call: CallState::Idle,
+            contacts: Arc::new(Mutex::new(ContactStore::load())),
+            recent_rooms: Arc::new(Mutex::new(RecentRooms::load())),
+            last_seen: Arc::new(Mutex::new(LastSeen::load())),
+            blocklist: Arc::new(Mutex::new(BlockList::load())),
+            settings: Arc::new(Mutex::new(Settings::load())),
+            spelling: Arc::new(Mutex::new(SpellChecker::load())),
+            flood: Arc::new(Mutex::new(FloodGuard::default())),
+            seqs: Arc::new(Mutex::new(SeqTracker::default())),
+            outbox: Arc::new(Mutex::new(Outbox::default())),
+            seen: Arc::new(Mutex::new(SeenWindow::default())),
+            render_pending: Arc::new(AtomicBool::new(false)),
+            #[cfg(not(target_arch = "wasm32"))]
+            screen_share: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            screen_viewer: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            screen_sharers: Vec::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            pictures: Arc::new(Mutex::new(Pictures::default())),
+            #[cfg(not(target_arch = "wasm32"))]
+            files: Arc::new(Mutex::new(Files::default())),
+            #[cfg(not(target_arch = "wasm32"))]
+            device_sync: None,
+        }
+    }
+
+    pub fn can_post(&self, node_id: &NodeId) -> bool {
+        self.moderators.is_empty() || self.moderators.contains(node_id)
+    }
+
+    // Kicks and bans are only honoured from the room's owner or its moderators.
+    pub fn can_moderate(&self, node_id: &NodeId) -> bool {
+        self.room_owner == Some(*node_id) || self.moderators.contains(node_id)
+    }
+
+    // Why a newcomer can't join with `invite`, or None if they can. The owner never
+    // needs one.
+    pub fn invite_problem(&self, from: &NodeId, invite: Option<&Invite>) -> Option<&'static str> {
+        let (Some(owner), Some(topic)) = (self.room_owner, self.room_topic) else {
+            return None;
+        };
+        if !self.invites_required || *from == owner {
+            return None;
+        }
+        match invite {
+            None => Some("no invite"),
+            Some(invite) if !invite.verify(&owner, topic.as_bytes()) => Some("a forged invite"),
+            Some(invite) if self.revoked_invites.contains(&invite.id) => Some("a revoked invite"),
+            Some(_) => None,
+        }
+    }
+
+    // In a knock room only those the owner let in take part; the owner, its moderators
+    // and the ticket's nodes never had to knock.
+    pub fn is_admitted(&self, node_id: &NodeId) -> bool {
+        !self.room_knock
+            || self.current_node_id == Some(*node_id)
+            || self.can_moderate(node_id)
+            || self.room_nodes.contains(node_id)
+            || self.admitted.contains(node_id)
+    }
+
+    // The room's capacity when letting `from` in would go over it. Only the owner keeps
+    // count, so this is None for everyone else.
+    pub fn over_capacity(&self, from: &NodeId) -> Option<u32> {
+        let me = self.current_node_id?;
+        let capacity = self.room_capacity?;
+        if self.room_owner != Some(me) {
+            return None;
+        }
+        let names = self.names.lock().unwrap();
+        let members = 1 + names.keys().filter(|id| **id != me).count();
+        (!names.contains_key(from) && members >= capacity as usize).then_some(capacity)
+    }
+
+    // Records the name `from` announced and returns how we'll show it. Two people using
+    // the same name, us included, are both tagged with a short NodeId to tell them apart.
+    pub fn assign_name(&self, from: NodeId, name: &str) -> String {
+        let tagged = |id: &NodeId| format!("{}#{}", name, id.fmt_short());
+        let mut names = self.names.lock().unwrap();
+        let clashing: Vec<NodeId> = names
+            .iter()
+            .filter(|(id, shown)| {
+                **id != from
+                    && Some(**id) != self.current_node_id
+                    && (shown.as_str() == name || **shown == tagged(id))
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &clashing {
+            names.insert(*id, tagged(id));
         }
+
+        let shown = if clashing.is_empty() && name != self.current_username {
+            name.to_string()
+        } else {
+            tagged(&from)
+        };
+        names.insert(from, shown.clone());
+        shown
+    }
+
+    pub fn node_id_by_name(&self, name: &str) -> Option<NodeId> {
+        self.names
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(id, known)| *known == name && Some(**id) != self.current_node_id)
+            .map(|(id, _)| *id)
+    }
+
+    // Gossip messages are signed with our node key; None until we're in a room.
+    pub fn secret_key(&self) -> Option<SecretKey> {
+        self.endpoint
+            .as_ref()
+            .map(|endpoint| endpoint.secret_key().clone())
     }
 }
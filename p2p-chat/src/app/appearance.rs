@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+use slint::{ComponentHandle, Global};
+
+use crate::app::{types, window_state::Windows};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextSize {
+    Small,
+    #[default]
+    Normal,
+    Large,
+    Larger,
+}
+
+impl TextSize {
+    pub fn from_index(index: i32) -> Self {
+        match index {
+            0 => TextSize::Small,
+            2 => TextSize::Large,
+            3 => TextSize::Larger,
+            _ => TextSize::Normal,
+        }
+    }
+
+    pub fn index(self) -> i32 {
+        match self {
+            TextSize::Small => 0,
+            TextSize::Normal => 1,
+            TextSize::Large => 2,
+            TextSize::Larger => 3,
+        }
+    }
+
+    // Scales the text and the boxes it sits in, so larger text doesn't get cut off.
+    fn scale(self) -> f32 {
+        match self {
+            TextSize::Small => 0.85,
+            TextSize::Normal => 1.0,
+            TextSize::Large => 1.25,
+            TextSize::Larger => 1.5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Appearance {
+    pub text_size: TextSize,
+    pub high_contrast: bool,
+}
+
+fn set_theme<'a, C: ComponentHandle>(component: &'a C, appearance: &Appearance)
+where
+    types::Theme<'a>: Global<'a, C>,
+{
+    let theme = component.global::<types::Theme>();
+    theme.set_scale(appearance.text_size.scale());
+    theme.set_high_contrast(appearance.high_contrast);
+}
+
+// Every window has its own copy of the Theme global, so each one is set.
+pub fn apply(windows: &Windows, appearance: &Appearance) {
+    if let Some(main) = windows.main.upgrade() {
+        set_theme(&main, appearance);
+    }
+    if let Some(join) = windows.join.upgrade() {
+        set_theme(&join, appearance);
+    }
+    if let Some(create) = windows.create.upgrade() {
+        set_theme(&create, appearance);
+    }
+    if let Some(chat) = windows.chat.upgrade() {
+        set_theme(&chat, appearance);
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::{Result, anyhow};
+use cpal::{
+    FromSample, Sample, SampleFormat, SizedSample, Stream, StreamConfig,
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+};
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+// Calls carry 48 kHz mono PCM in 10 ms frames, which keeps one frame inside a single
+// QUIC datagram.
+pub const SAMPLE_RATE: u32 = 48_000;
+pub const FRAME_SAMPLES: usize = 480;
+
+#[derive(Debug, Clone)]
+pub struct AudioSession {
+    stop: Arc<AtomicBool>,
+    playback: Arc<Mutex<VecDeque<f32>>>,
+    output_rate: u32,
+}
+
+impl AudioSession {
+    pub fn play(&self, frame: &[i16]) {
+        let samples: Vec<f32> = frame.iter().map(|s| s.to_sample::<f32>()).collect();
+        let mut queue = self.playback.lock().unwrap();
+        queue.extend(resample(&samples, SAMPLE_RATE, self.output_rate));
+
+        // More than 200 ms queued means we fell behind; drop the stale audio.
+        let excess = queue.len().saturating_sub(self.output_rate as usize / 5);
+        queue.drain(..excess);
+    }
+
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+// cpal streams are not Send, so they live on their own thread until the session stops.
+pub fn start(captured: mpsc::UnboundedSender<Vec<i16>>) -> Result<AudioSession> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let playback = Arc::new(Mutex::new(VecDeque::new()));
+    let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+
+    {
+        let stop = stop.clone();
+        let playback = playback.clone();
+        std::thread::spawn(move || {
+            let streams = match open_streams(captured, playback) {
+                Ok((input, output, output_rate)) => {
+                    let _ = ready_tx.send(Ok(output_rate));
+                    (input, output)
+                }
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    return;
+                }
+            };
+
+            while !stop.load(Ordering::Relaxed) {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            drop(streams);
+            println!("DEBUG: Audio streams closed");
+        });
+    }
+
+    let output_rate = ready_rx
+        .recv()
+        .map_err(|_| anyhow!("Audio thread exited unexpectedly"))??;
+
+    Ok(AudioSession {
+        stop,
+        playback,
+        output_rate,
+    })
+}
+
+fn open_streams(
+    captured: mpsc::UnboundedSender<Vec<i16>>,
+    playback: Arc<Mutex<VecDeque<f32>>>,
+) -> Result<(Stream, Stream, u32)> {
+    let host = cpal::default_host();
+    let input = host
+        .default_input_device()
+        .ok_or_else(|| anyhow!("No microphone found"))?;
+    let output = host
+        .default_output_device()
+        .ok_or_else(|| anyhow!("No speaker found"))?;
+
+    let input_config = input.default_input_config()?;
+    let output_config = output.default_output_config()?;
+    let output_rate = output_config.sample_rate().0;
+
+    let input_format = input_config.sample_format();
+    let input_config: StreamConfig = input_config.into();
+    let input_stream = match input_format {
+        SampleFormat::F32 => build_input::<f32>(&input, &input_config, captured)?,
+        SampleFormat::I16 => build_input::<i16>(&input, &input_config, captured)?,
+        SampleFormat::U16 => build_input::<u16>(&input, &input_config, captured)?,
+        other => return Err(anyhow!("Unsupported microphone format {:?}", other)),
+    };
+
+    let output_format = output_config.sample_format();
+    let output_config: StreamConfig = output_config.into();
+    let output_stream = match output_format {
+        SampleFormat::F32 => build_output::<f32>(&output, &output_config, playback)?,
+        SampleFormat::I16 => build_output::<i16>(&output, &output_config, playback)?,
+        SampleFormat::U16 => build_output::<u16>(&output, &output_config, playback)?,
+        other => return Err(anyhow!("Unsupported speaker format {:?}", other)),
+    };
+
+    input_stream.play()?;
+    output_stream.play()?;
+
+    Ok((input_stream, output_stream, output_rate))
+}
+
+fn build_input<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    captured: mpsc::UnboundedSender<Vec<i16>>,
+) -> Result<Stream>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let channels = config.channels as usize;
+    let rate = config.sample_rate.0;
+    let mut pending: Vec<f32> = Vec::new();
+
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[T], _| {
+            let mono: Vec<f32> = data
+                .chunks(channels)
+                .map(|frame| {
+                    frame.iter().map(|s| s.to_sample::<f32>()).sum::<f32>() / channels as f32
+                })
+                .collect();
+            pending.extend(resample(&mono, rate, SAMPLE_RATE));
+
+            while pending.len() >= FRAME_SAMPLES {
+                let frame = pending
+                    .drain(..FRAME_SAMPLES)
+                    .map(|s| s.to_sample::<i16>())
+                    .collect();
+                if captured.send(frame).is_err() {
+                    return;
+                }
+            }
+        },
+        |e| eprintln!("Microphone stream error: {}", e),
+        None,
+    )?;
+    Ok(stream)
+}
+
+fn build_output<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    playback: Arc<Mutex<VecDeque<f32>>>,
+) -> Result<Stream>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels as usize;
+
+    let stream = device.build_output_stream(
+        config,
+        move |data: &mut [T], _| {
+            let mut queue = playback.lock().unwrap();
+            for frame in data.chunks_mut(channels) {
+                let sample = queue.pop_front().unwrap_or(0.0);
+                for out in frame {
+                    *out = T::from_sample(sample);
+                }
+            }
+        },
+        |e| eprintln!("Speaker stream error: {}", e),
+        None,
+    )?;
+    Ok(stream)
+}
+
+fn resample(input: &[f32], from: u32, to: u32) -> Vec<f32> {
+    if from == to || input.is_empty() {
+        return input.to_vec();
+    }
+
+    let len = (input.len() as u64 * to as u64 / from as u64) as usize;
+    let last = input.len() - 1;
+    (0..len)
+        .map(|i| {
+            let pos = i as f64 * from as f64 / to as f64;
+            let index = pos as usize;
+            let frac = (pos - index as f64) as f32;
+            let a = input[index.min(last)];
+            let b = input[(index + 1).min(last)];
+            a + (b - a) * frac
+        })
+        .collect()
+}
+
+pub fn encode_frame(seq: u32, frame: &[i16]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(4 + frame.len() * 2);
+    bytes.extend_from_slice(&seq.to_be_bytes());
+    for sample in frame {
+        bytes.extend_from_slice(&sample.to_le_bytes());
+    }
+    bytes
+}
+
+pub fn decode_frame(bytes: &[u8]) -> Option<(u32, Vec<i16>)> {
+    let (seq, samples) = bytes.split_first_chunk::<4>()?;
+    let frame = samples
+        .chunks_exact(2)
+        .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+        .collect();
+    Some((u32::from_be_bytes(*seq), frame))
+}
@@ -0,0 +1,169 @@
+use anyhow::{Context, Result, anyhow};
+use iroh::NodeId;
+use messaging::MessageBody;
+use serde::{Deserialize, Serialize};
+use slint::Weak;
+use std::{
+    collections::HashSet,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use crate::app::{
+    app_state::AppState,
+    i18n::trf,
+    types,
+    ui_handlers::{push_system_message, update_messages, update_online_users},
+};
+
+const BLOCKLIST_FILE: &str = "blocklist.json";
+
+#[derive(Default, Serialize, Deserialize)]
+struct BlockListFile {
+    blocked: Vec<NodeId>,
+    muted: Vec<NodeId>,
+}
+
+// Blocked peers never reach the chat at all; muted peers are kept in history but hidden.
+#[derive(Debug)]
+pub struct BlockList {
+    path: PathBuf,
+    blocked: HashSet<NodeId>,
+    muted: HashSet<NodeId>,
+}
+
+impl BlockList {
+    pub fn load() -> Self {
+        Self::load_from(BLOCKLIST_FILE)
+    }
+
+    pub fn load_from(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let file: BlockListFile = match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+                eprintln!("Failed to parse {}: {}", path.display(), e);
+                BlockListFile::default()
+            }),
+            Err(_) => BlockListFile::default(),
+        };
+        Self {
+            path,
+            blocked: file.blocked.into_iter().collect(),
+            muted: file.muted.into_iter().collect(),
+        }
+    }
+
+    pub fn is_blocked(&self, node_id: &NodeId) -> bool {
+        self.blocked.contains(node_id)
+    }
+
+    pub fn is_muted(&self, node_id: &NodeId) -> bool {
+        self.muted.contains(node_id)
+    }
+
+    // Presence messages still get through so blocked peers stay in the online list and
+    // can be unblocked from there.
+    pub fn drops(&self, body: &MessageBody) -> bool {
+        match body {
+            MessageBody::AboutMe { .. } | MessageBody::Disconnect { .. } => false,
+            body => body.sender().is_some_and(|from| self.is_blocked(&from)),
+        }
+    }
+
+    pub fn set_blocked(&mut self, node_id: NodeId, blocked: bool) -> Result<()> {
+        if blocked {
+            self.blocked.insert(node_id);
+        } else {
+            self.blocked.remove(&node_id);
+        }
+        self.save()
+    }
+
+    pub fn set_muted(&mut self, node_id: NodeId, muted: bool) -> Result<()> {
+        if muted {
+            self.muted.insert(node_id);
+        } else {
+            self.muted.remove(&node_id);
+        }
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let file = BlockListFile {
+            blocked: self.blocked.iter().copied().collect(),
+            muted: self.muted.iter().copied().collect(),
+        };
+        fs::write(&self.path, serde_json::to_string_pretty(&file)?)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+pub fn toggle_mute(
+    username: &str,
+    chat_handle: &Weak<types::ChatWindow>,
+    app_state: &Arc<Mutex<AppState>>,
+) -> Result<()> {
+    let muted = {
+        let state = app_state.lock().unwrap();
+        let node_id = state
+            .node_id_by_name(username)
+            .ok_or_else(|| anyhow!("Unknown user {}", username))?;
+        let mut blocklist = state.blocklist.lock().unwrap();
+        let muted = !blocklist.is_muted(&node_id);
+        blocklist.set_muted(node_id, muted)?;
+        muted
+    };
+
+    let notice = if muted {
+        trf(
+            "🔇 Muted {}. Their messages are hidden until you unmute them.",
+            &[&username],
+        )
+    } else {
+        trf("🔈 Unmuted {}", &[&username])
+    };
+    push_system_message(app_state, notice);
+    update_messages(chat_handle, app_state);
+    update_online_users(chat_handle, app_state);
+    Ok(())
+}
+
+pub fn toggle_block(
+    username: &str,
+    chat_handle: &Weak<types::ChatWindow>,
+    app_state: &Arc<Mutex<AppState>>,
+) -> Result<()> {
+    let blocked = {
+        let state = app_state.lock().unwrap();
+        let node_id = state
+            .node_id_by_name(username)
+            .ok_or_else(|| anyhow!("Unknown user {}", username))?;
+        let blocked = {
+            let mut blocklist = state.blocklist.lock().unwrap();
+            let blocked = !blocklist.is_blocked(&node_id);
+            blocklist.set_blocked(node_id, blocked)?;
+            blocked
+        };
+
+        if blocked {
+            let sender_id = node_id.to_string();
+            state
+                .messages
+                .lock()
+                .unwrap()
+                .retain(|message| message.sender_id != sender_id);
+        }
+        blocked
+    };
+
+    let notice = if blocked {
+        trf("🚫 Blocked {}", &[&username])
+    } else {
+        trf("✅ Unblocked {}", &[&username])
+    };
+    push_system_message(app_state, notice);
+    update_messages(chat_handle, app_state);
+    update_online_users(chat_handle, app_state);
+    Ok(())
+}
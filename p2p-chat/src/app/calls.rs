@@ -0,0 +1,487 @@
+use anyhow::{Result, anyhow};
+use iroh::{
+    NodeId,
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler},
+};
+use messaging::{Capabilities, Message, MessageBody};
+use slint::{SharedString, Weak};
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::app::audio;
+use crate::app::{app_state::AppState, types};
+
+pub const VOICE_ALPN: &[u8] = b"chast/voice/0";
+
+#[derive(Debug, Clone, Default)]
+pub enum CallState {
+    #[default]
+    Idle,
+    Outgoing {
+        peer: NodeId,
+        call_id: u64,
+        ringing: bool,
+    },
+    Incoming {
+        peer: NodeId,
+        call_id: u64,
+    },
+    Connecting {
+        peer: NodeId,
+        call_id: u64,
+    },
+    Active {
+        peer: NodeId,
+        call_id: u64,
+        connection: Connection,
+    },
+}
+
+impl CallState {
+    fn current(&self) -> Option<(NodeId, u64)> {
+        match self {
+            CallState::Idle => None,
+            CallState::Outgoing { peer, call_id, .. }
+            | CallState::Incoming { peer, call_id }
+            | CallState::Connecting { peer, call_id }
+            | CallState::Active { peer, call_id, .. } => Some((*peer, *call_id)),
+        }
+    }
+
+    fn status(&self) -> &'static str {
+        match self {
+            CallState::Idle => "",
+            CallState::Outgoing { ringing: false, .. } => "Calling…",
+            CallState::Outgoing { ringing: true, .. } => "Ringing…",
+            CallState::Incoming { .. } => "Incoming call",
+            CallState::Connecting { .. } => "Connecting…",
+            CallState::Active { .. } => "In call",
+        }
+    }
+}
+
+// Accepts the audio connection from the peer whose call we just accepted; anyone else
+// dialing the voice ALPN is turned away.
+#[derive(Clone)]
+pub struct VoiceProtocol {
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+}
+
+impl VoiceProtocol {
+    pub fn new(app_state: Arc<Mutex<AppState>>, chat_handle: Weak<types::ChatWindow>) -> Self {
+        Self {
+            app_state,
+            chat_handle,
+        }
+    }
+}
+
+impl fmt::Debug for VoiceProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VoiceProtocol").finish_non_exhaustive()
+    }
+}
+
+impl ProtocolHandler for VoiceProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let remote = connection.remote_node_id()?;
+        let call_id = {
+            let mut state = self.app_state.lock().unwrap();
+            match state.call {
+                CallState::Connecting { peer, call_id } if peer == remote => {
+                    state.call = CallState::Active {
+                        peer,
+                        call_id,
+                        connection: connection.clone(),
+                    };
+                    call_id
+                }
+                _ => {
+                    println!(
+                        "DEBUG: Rejected voice connection from {}",
+                        remote.fmt_short()
+                    );
+                    return Err(AcceptError::NotAllowed {});
+                }
+            }
+        };
+
+        update_call_ui(&self.chat_handle, &self.app_state);
+        run_call_audio(
+            connection,
+            call_id,
+            self.app_state.clone(),
+            self.chat_handle.clone(),
+        )
+        .await;
+        Ok(())
+    }
+}
+
+async fn send_signal(app_state: &Arc<Mutex<AppState>>, body: MessageBody) -> Result<()> {
+    let (sender, secret_key) = {
+        let state = app_state.lock().unwrap();
+        (state.sender.clone(), state.secret_key())
+    };
+    let (Some(sender), Some(secret_key)) = (sender, secret_key) else {
+        return Err(anyhow!("Not connected to a room"));
+    };
+    sender
+        .broadcast(Message::new(body).to_signed_vec(&secret_key, sender.topic()))
+        .await?;
+    Ok(())
+}
+
+fn local_node_id(app_state: &Arc<Mutex<AppState>>) -> Result<NodeId> {
+    app_state
+        .lock()
+        .unwrap()
+        .current_node_id
+        .ok_or_else(|| anyhow!("Not connected to a room"))
+}
+
+pub async fn start_call(
+    peer_name: String,
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) -> Result<()> {
+    let from = local_node_id(&app_state)?;
+    let (peer, call_id) = {
+        let mut state = app_state.lock().unwrap();
+        if !matches!(state.call, CallState::Idle) {
+            return Err(anyhow!("Already in a call"));
+        }
+
+        let peer = state
+            .names
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(id, name)| **name == peer_name && **id != from)
+            .map(|(id, _)| *id)
+            .ok_or_else(|| anyhow!("Unknown user {}", peer_name))?;
+        let can_call = state
+            .clients
+            .lock()
+            .unwrap()
+            .get(&peer)
+            .is_none_or(|client| client.supports(Capabilities::VOICE));
+        if !can_call {
+            return Err(anyhow!("{} can't take voice calls", peer_name));
+        }
+
+        let call_id = rand::random();
+        state.call = CallState::Outgoing {
+            peer,
+            call_id,
+            ringing: false,
+        };
+        (peer, call_id)
+    };
+
+    update_call_ui(&chat_handle, &app_state);
+    send_signal(
+        &app_state,
+        MessageBody::CallRequest {
+            from,
+            to: peer,
+            call_id,
+        },
+    )
+    .await?;
+    println!("DEBUG: Calling {} ({})", peer_name, peer.fmt_short());
+    Ok(())
+}
+
+pub async fn accept_call(
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) -> Result<()> {
+    let from = local_node_id(&app_state)?;
+    let (peer, call_id) = {
+        let mut state = app_state.lock().unwrap();
+        let CallState::Incoming { peer, call_id } = state.call else {
+            return Err(anyhow!("No incoming call to accept"));
+        };
+        state.call = CallState::Connecting { peer, call_id };
+        (peer, call_id)
+    };
+
+    update_call_ui(&chat_handle, &app_state);
+    send_signal(
+        &app_state,
+        MessageBody::CallAccept {
+            from,
+            to: peer,
+            call_id,
+        },
+    )
+    .await
+}
+
+pub async fn hang_up(
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) -> Result<()> {
+    let call = std::mem::take(&mut app_state.lock().unwrap().call);
+    let Some((peer, call_id)) = call.current() else {
+        return Ok(());
+    };
+    if let CallState::Active { connection, .. } = &call {
+        connection.close(0u32.into(), b"hang up");
+    }
+
+    update_call_ui(&chat_handle, &app_state);
+    let from = local_node_id(&app_state)?;
+    send_signal(
+        &app_state,
+        MessageBody::CallHangUp {
+            from,
+            to: peer,
+            call_id,
+        },
+    )
+    .await
+}
+
+pub async fn handle_call_signal(
+    body: MessageBody,
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) -> Result<()> {
+    let me = local_node_id(&app_state)?;
+
+    match body {
+        MessageBody::CallRequest { from, to, call_id } if to == me => {
+            let busy = {
+                let mut state = app_state.lock().unwrap();
+                if matches!(state.call, CallState::Idle) {
+                    state.call = CallState::Incoming {
+                        peer: from,
+                        call_id,
+                    };
+                    false
+                } else {
+                    true
+                }
+            };
+
+            let reply = if busy {
+                MessageBody::CallHangUp {
+                    from: me,
+                    to: from,
+                    call_id,
+                }
+            } else {
+                update_call_ui(&chat_handle, &app_state);
+                MessageBody::CallRinging {
+                    from: me,
+                    to: from,
+                    call_id,
+                }
+            };
+            send_signal(&app_state, reply).await?;
+        }
+        MessageBody::CallRinging { from, to, call_id } if to == me => {
+            {
+                let mut state = app_state.lock().unwrap();
+                if let CallState::Outgoing {
+                    peer,
+                    call_id: id,
+                    ringing,
+                } = &mut state.call
+                    && *peer == from
+                    && *id == call_id
+                {
+                    *ringing = true;
+                }
+            }
+            update_call_ui(&chat_handle, &app_state);
+        }
+        MessageBody::CallAccept { from, to, call_id } if to == me => {
+            let endpoint = {
+                let mut state = app_state.lock().unwrap();
+                match state.call {
+                    CallState::Outgoing {
+                        peer, call_id: id, ..
+                    } if peer == from && id == call_id => {
+                        state.call = CallState::Connecting { peer, call_id };
+                        state.endpoint.clone()
+                    }
+                    _ => None,
+                }
+            };
+            let Some(endpoint) = endpoint else {
+                return Ok(());
+            };
+            update_call_ui(&chat_handle, &app_state);
+
+            let connection = match endpoint.connect(from, VOICE_ALPN).await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    hang_up(app_state, chat_handle).await?;
+                    return Err(anyhow!("Failed to open voice connection: {}", e));
+                }
+            };
+
+            {
+                let mut state = app_state.lock().unwrap();
+                if !matches!(state.call, CallState::Connecting { call_id: id, .. } if id == call_id)
+                {
+                    connection.close(0u32.into(), b"call ended");
+                    return Ok(());
+                }
+                state.call = CallState::Active {
+                    peer: from,
+                    call_id,
+                    connection: connection.clone(),
+                };
+            }
+            update_call_ui(&chat_handle, &app_state);
+
+            n0_future::task::spawn(run_call_audio(connection, call_id, app_state, chat_handle));
+        }
+        MessageBody::CallHangUp { from, to, call_id } if to == me => {
+            let ended = {
+                let mut state = app_state.lock().unwrap();
+                if state.call.current() == Some((from, call_id)) {
+                    Some(std::mem::take(&mut state.call))
+                } else {
+                    None
+                }
+            };
+            if let Some(CallState::Active { connection, .. }) = &ended {
+                connection.close(0u32.into(), b"hang up");
+            }
+            if ended.is_some() {
+                update_call_ui(&chat_handle, &app_state);
+                println!("DEBUG: Call ended by {}", from.fmt_short());
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn run_call_audio(
+    connection: Connection,
+    call_id: u64,
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) {
+    let (frames_tx, mut frames_rx) = tokio::sync::mpsc::unbounded_channel();
+    let audio = match tokio::task::spawn_blocking(move || audio::start(frames_tx)).await {
+        Ok(Ok(audio)) => audio,
+        Ok(Err(e)) => {
+            eprintln!("Failed to start audio: {}", e);
+            if let Err(e) = hang_up(app_state, chat_handle).await {
+                eprintln!("Error hanging up: {}", e);
+            }
+            return;
+        }
+        Err(e) => {
+            eprintln!("Audio task panicked: {}", e);
+            return;
+        }
+    };
+
+    let outgoing = connection.clone();
+    let send_task = tokio::spawn(async move {
+        let mut seq: u32 = 0;
+        while let Some(frame) = frames_rx.recv().await {
+            seq = seq.wrapping_add(1);
+            if let Err(e) = outgoing.send_datagram(audio::encode_frame(seq, &frame).into()) {
+                println!("DEBUG: Stopped sending audio: {}", e);
+                break;
+            }
+        }
+    });
+
+    let mut last_seq: u32 = 0;
+    while let Ok(datagram) = connection.read_datagram().await {
+        if let Some((seq, frame)) = audio::decode_frame(&datagram) {
+            // Datagrams can arrive out of order; late frames are useless for playback.
+            if (seq.wrapping_sub(last_seq) as i32) > 0 {
+                last_seq = seq;
+                audio.play(&frame);
+            }
+        }
+    }
+
+    audio.stop();
+    send_task.abort();
+    end_call(call_id, &app_state, &chat_handle);
+}
+
+#[cfg(target_arch = "wasm32")]
+async fn run_call_audio(
+    connection: Connection,
+    call_id: u64,
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) {
+    eprintln!("Voice calls are not supported in the browser build");
+    connection.close(0u32.into(), b"unsupported");
+    end_call(call_id, &app_state, &chat_handle);
+}
+
+fn end_call(call_id: u64, app_state: &Arc<Mutex<AppState>>, chat_handle: &Weak<types::ChatWindow>) {
+    {
+        let mut state = app_state.lock().unwrap();
+        if state.call.current().map(|(_, id)| id) == Some(call_id) {
+            state.call = CallState::Idle;
+        }
+    }
+    update_call_ui(chat_handle, app_state);
+    println!("DEBUG: Call {} finished", call_id);
+}
+
+pub fn update_call_ui(chat_handle: &Weak<types::ChatWindow>, app_state: &Arc<Mutex<AppState>>) {
+    let (status, peer_name, incoming, active) = {
+        let state = app_state.lock().unwrap();
+        let peer_name = state
+            .call
+            .current()
+            .map(|(peer, _)| {
+                state
+                    .names
+                    .lock()
+                    .unwrap()
+                    .get(&peer)
+                    .cloned()
+                    .unwrap_or_else(|| peer.fmt_short())
+            })
+            .unwrap_or_default();
+        (
+            state.call.status(),
+            peer_name,
+            matches!(state.call, CallState::Incoming { .. }),
+            matches!(state.call, CallState::Active { .. }),
+        )
+    };
+
+    let chat_handle_clone = chat_handle.clone();
+    match slint::invoke_from_event_loop(move || {
+        if let Some(chat) = chat_handle_clone.upgrade() {
+            chat.set_call_status(SharedString::from(status));
+            chat.set_call_peer(SharedString::from(peer_name));
+            chat.set_call_incoming(incoming);
+            chat.set_call_active(active);
+        } else {
+            println!("DEBUG: Chat window handle is invalid, cannot update call overlay");
+        }
+    }) {
+        Ok(_) => {}
+        Err(e) => println!(
+            "ERROR: Failed to update call overlay from event loop: {:?}",
+            e
+        ),
+    }
+}
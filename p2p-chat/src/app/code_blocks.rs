@@ -0,0 +1,187 @@
+use slint::{Color, ModelRc, SharedString, VecModel};
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Style, Theme, ThemeSet},
+    parsing::SyntaxSet,
+    util::LinesWithEndings,
+};
+
+use crate::app::types;
+
+// Code written between ``` fences, with whatever followed the opening fence as its
+// language, e.g. ```rust.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Part {
+    Prose(String),
+    Code { language: String, code: String },
+}
+
+// Splits a message into prose and code blocks, or None when it has no fence. A block
+// that's never closed runs to the end of the message, as people often leave it off.
+pub fn split(text: &str) -> Option<Vec<Part>> {
+    if !text.contains("```") {
+        return None;
+    }
+    let mut parts = Vec::new();
+    let mut prose: Vec<&str> = Vec::new();
+    let mut code: Option<(String, Vec<&str>)> = None;
+    for line in text.lines() {
+        let fence = line.trim();
+        match &mut code {
+            Some((language, lines)) if fence.starts_with("```") => {
+                parts.push(Part::Code {
+                    language: std::mem::take(language),
+                    code: lines.join("\n"),
+                });
+                code = None;
+            }
+            Some((_, lines)) => lines.push(line),
+            // ```let x = 1;``` on a line of its own.
+            None if fence.len() > 6 && fence.starts_with("```") && fence.ends_with("```") => {
+                flush_prose(&mut prose, &mut parts);
+                parts.push(Part::Code {
+                    language: String::new(),
+                    code: fence[3..fence.len() - 3].to_string(),
+                });
+            }
+            None if fence.starts_with("```") => {
+                flush_prose(&mut prose, &mut parts);
+                code = Some((fence[3..].trim().to_lowercase(), Vec::new()));
+            }
+            None => prose.push(line),
+        }
+    }
+    if let Some((language, lines)) = code {
+        parts.push(Part::Code {
+            language,
+            code: lines.join("\n"),
+        });
+    }
+    flush_prose(&mut prose, &mut parts);
+    parts
+        .iter()
+        .any(|part| matches!(part, Part::Code { .. }))
+        .then_some(parts)
+}
+
+fn flush_prose(prose: &mut Vec<&str>, parts: &mut Vec<Part>) {
+    let text = prose.join("\n");
+    prose.clear();
+    if !text.trim().is_empty() {
+        parts.push(Part::Prose(text.trim_matches('\n').to_string()));
+    }
+}
+
+pub fn to_ui(parts: Vec<Part>) -> types::MessageParts {
+    let parts: Vec<types::MessagePart> = parts
+        .into_iter()
+        .map(|part| match part {
+            Part::Prose(text) => types::MessagePart {
+                code: false,
+                text: SharedString::from(text),
+                language: SharedString::new(),
+                lines: ModelRc::default(),
+            },
+            Part::Code { language, code } => {
+                let lines: Vec<types::CodeLine> = highlight(&language, &code)
+                    .into_iter()
+                    .map(|spans| {
+                        let spans: Vec<types::CodeSpan> = spans
+                            .into_iter()
+                            .map(|(text, color)| types::CodeSpan {
+                                text: SharedString::from(text),
+                                color,
+                            })
+                            .collect();
+                        types::CodeLine {
+                            spans: ModelRc::new(VecModel::from(spans)),
+                        }
+                    })
+                    .collect();
+                types::MessagePart {
+                    code: true,
+                    text: SharedString::from(code),
+                    language: SharedString::from(language),
+                    lines: ModelRc::new(VecModel::from(lines)),
+                }
+            }
+        })
+        .collect();
+    types::MessageParts {
+        parts: ModelRc::new(VecModel::from(parts)),
+    }
+}
+
+// Each line of a block as runs of text in one colour.
+type Highlighted = Vec<Vec<(String, Color)>>;
+
+const PLAIN: Color = Color::from_rgb_u8(0xd4, 0xd4, 0xd4);
+// Blocks highlighted so far. The chat is redrawn on every message, and syntect is too
+// slow to run over every block each time.
+const CACHE_LIMIT: usize = 256;
+
+static SYNTAXES: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+static THEME: LazyLock<Theme> = LazyLock::new(|| {
+    ThemeSet::load_defaults()
+        .themes
+        .remove("base16-ocean.dark")
+        .unwrap_or_default()
+});
+static CACHE: LazyLock<Mutex<HashMap<(String, String), Highlighted>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Highlights a block with the syntax named after its fence, e.g. "rust", "py" or "diff".
+// An unknown or missing language is shown plain. Every line gets at least one span, so
+// blank lines keep their height.
+fn highlight(language: &str, code: &str) -> Highlighted {
+    let key = (language.to_string(), code.to_string());
+    if let Some(lines) = CACHE.lock().unwrap().get(&key) {
+        return lines.clone();
+    }
+
+    let syntax = SYNTAXES
+        .find_syntax_by_token(language)
+        .unwrap_or_else(|| SYNTAXES.find_syntax_plain_text());
+    let mut highlighter = HighlightLines::new(syntax, &THEME);
+    let lines: Highlighted = LinesWithEndings::from(code)
+        .map(|line| {
+            let ranges = match highlighter.highlight_line(line, &SYNTAXES) {
+                Ok(ranges) => ranges
+                    .into_iter()
+                    .map(|(style, text)| (text, color(style)))
+                    .collect(),
+                Err(_) => vec![(line, PLAIN)],
+            };
+            // Neighbouring tokens of one colour share a span, to keep the Text count down.
+            let mut spans: Vec<(String, Color)> = Vec::new();
+            for (text, color) in ranges {
+                let text = text.trim_end_matches(['\n', '\r']);
+                match spans.last_mut() {
+                    _ if text.is_empty() => {}
+                    Some((last, last_color)) if *last_color == color => last.push_str(text),
+                    _ => spans.push((text.to_string(), color)),
+                }
+            }
+            if spans.is_empty() {
+                spans.push((String::new(), PLAIN));
+            }
+            spans
+        })
+        .collect();
+
+    let mut cache = CACHE.lock().unwrap();
+    if cache.len() >= CACHE_LIMIT {
+        cache.clear();
+    }
+    cache.insert(key, lines.clone());
+    lines
+}
+
+fn color(style: Style) -> Color {
+    let foreground = style.foreground;
+    Color::from_argb_u8(foreground.a, foreground.r, foreground.g, foreground.b)
+}
@@ -0,0 +1,145 @@
+use anyhow::{Result, anyhow};
+use iroh::NodeId;
+use slint::{SharedString, Weak};
+use std::sync::{Arc, Mutex};
+
+use crate::app::{
+    app_state::AppState,
+    i18n::{tr, trf},
+    networking::{cleanup_network_resources, leave_room},
+    room_handlers::join_room,
+    types,
+    ui_handlers::{push_system_message, update_messages},
+};
+
+pub fn neighbor_up(
+    peer: NodeId,
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+) {
+    let recovered = {
+        let mut state = app_state.lock().unwrap();
+        state.neighbors.insert(peer);
+        std::mem::take(&mut state.connection_lost)
+    };
+    // Gossip found its way back on its own.
+    if recovered {
+        println!("> Connection to the room is back");
+        show(false, chat_handle);
+    }
+}
+
+// Running out of neighbours is only a problem while the room still has people in it who
+// never said goodbye; the last one leaving properly isn't a lost connection.
+pub fn neighbor_down(
+    peer: NodeId,
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+) {
+    let stranded = {
+        let mut state = app_state.lock().unwrap();
+        state.neighbors.remove(&peer);
+        state.neighbors.is_empty()
+            && state
+                .names
+                .lock()
+                .unwrap()
+                .keys()
+                .any(|id| state.current_node_id != Some(*id))
+    };
+    if stranded {
+        lost("all neighbours dropped", app_state, chat_handle);
+    }
+}
+
+// Shows the banner until a neighbour comes back or the user retries.
+pub fn lost(reason: &str, app_state: &Arc<Mutex<AppState>>, chat_handle: &Weak<types::ChatWindow>) {
+    {
+        let mut state = app_state.lock().unwrap();
+        if state.connection_lost || state.room_topic.is_none() {
+            return;
+        }
+        state.connection_lost = true;
+    }
+    println!("> Connection to the room lost: {}", reason);
+    show(true, chat_handle);
+}
+
+fn show(lost: bool, chat_handle: &Weak<types::ChatWindow>) {
+    let chat_handle = chat_handle.clone();
+    if let Err(e) = slint::invoke_from_event_loop(move || {
+        if let Some(chat) = chat_handle.upgrade() {
+            chat.set_connection_lost(lost);
+            chat.set_connection_status(SharedString::from(if lost {
+                "Connection lost"
+            } else {
+                "Connected"
+            }));
+        }
+    }) {
+        eprintln!("Failed to update connection status: {:?}", e);
+    }
+}
+
+// Tears the room down and joins it again with the same ticket, name and password. The
+// messages already in the window are kept.
+pub async fn retry(
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+    join_handle: Weak<types::JoinWindow>,
+) -> Result<()> {
+    let (ticket, username, password, peer_address) = {
+        let state = app_state.lock().unwrap();
+        let ticket = state
+            .current_session_token
+            .clone()
+            .ok_or_else(|| anyhow!("Not in a room"))?;
+        (
+            ticket,
+            state.current_username.clone(),
+            state.room_password.clone().unwrap_or_default(),
+            state.room_peer_address.clone().unwrap_or_default(),
+        )
+    };
+    println!("> Retrying the connection to the room");
+
+    leave_room(app_state.clone(), chat_handle.clone()).await;
+    let messages = std::mem::take(&mut *app_state.lock().unwrap().messages.lock().unwrap());
+    let scheduled = std::mem::take(&mut app_state.lock().unwrap().scheduled);
+    cleanup_network_resources(app_state.clone()).await?;
+    *app_state.lock().unwrap().messages.lock().unwrap() = messages;
+    app_state.lock().unwrap().scheduled = scheduled;
+
+    if let Err(e) = join_room(
+        username.clone(),
+        ticket.clone(),
+        password.clone(),
+        peer_address.clone(),
+        app_state.clone(),
+        chat_handle.clone(),
+        join_handle,
+    )
+    .await
+    {
+        // Keep what's needed to try again.
+        {
+            let mut state = app_state.lock().unwrap();
+            state.current_session_token = Some(ticket);
+            state.current_username = username;
+            state.room_password = (!password.is_empty()).then_some(password);
+            state.room_peer_address = (!peer_address.is_empty()).then_some(peer_address);
+        }
+        push_system_message(
+            &app_state,
+            trf(
+                "Could not reconnect to this room: {}",
+                &[&format!("{:#}", e)],
+            ),
+        );
+        update_messages(&chat_handle, &app_state);
+        return Err(e);
+    }
+    push_system_message(&app_state, tr("🔌 Reconnected to the room").to_string());
+    update_messages(&chat_handle, &app_state);
+    Ok(())
+}
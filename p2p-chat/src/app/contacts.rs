@@ -0,0 +1,328 @@
+use anyhow::{Context, Result, anyhow};
+use iroh::{
+    NodeId,
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler},
+};
+use messaging::{Message, MessageBody};
+use serde::{Deserialize, Serialize};
+use slint::{ModelRc, SharedString, VecModel, Weak};
+use std::{
+    fmt, fs,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use crate::app::{
+    app_state::AppState,
+    i18n::trf,
+    types,
+    ui_handlers::{push_system_message, update_messages},
+};
+
+pub const INVITE_ALPN: &[u8] = b"chast/invite/0";
+const CONTACTS_FILE: &str = "contacts.json";
+const MAX_INVITE_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Contact {
+    pub name: String,
+    pub node_id: NodeId,
+    pub last_room: Option<String>,
+    #[serde(default)]
+    pub notes: String,
+}
+
+#[derive(Debug)]
+pub struct ContactStore {
+    path: PathBuf,
+    contacts: Vec<Contact>,
+}
+
+impl ContactStore {
+    pub fn load() -> Self {
+        Self::load_from(CONTACTS_FILE)
+    }
+
+    pub fn load_from(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let contacts = match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+                eprintln!("Failed to parse {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        println!("DEBUG: Loaded {} contacts", contacts.len());
+        Self { path, contacts }
+    }
+
+    pub fn contacts(&self) -> &[Contact] {
+        &self.contacts
+    }
+
+    pub fn get(&self, node_id: &NodeId) -> Option<&Contact> {
+        self.contacts.iter().find(|c| c.node_id == *node_id)
+    }
+
+    pub fn upsert(
+        &mut self,
+        name: String,
+        node_id: NodeId,
+        last_room: Option<String>,
+    ) -> Result<()> {
+        match self.contacts.iter_mut().find(|c| c.node_id == node_id) {
+            Some(contact) => {
+                contact.name = name;
+                if last_room.is_some() {
+                    contact.last_room = last_room;
+                }
+            }
+            None => self.contacts.push(Contact {
+                name,
+                node_id,
+                last_room,
+                notes: String::new(),
+            }),
+        }
+        self.contacts.sort_by_key(|c| c.name.to_lowercase());
+        self.save()
+    }
+
+    pub fn set_notes(&mut self, node_id: &NodeId, notes: String) -> Result<()> {
+        let contact = self
+            .contacts
+            .iter_mut()
+            .find(|c| c.node_id == *node_id)
+            .ok_or_else(|| anyhow!("Unknown contact {}", node_id.fmt_short()))?;
+        contact.notes = notes;
+        self.save()
+    }
+
+    pub fn remove(&mut self, node_id: &NodeId) -> Result<()> {
+        self.contacts.retain(|c| c.node_id != *node_id);
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let data = serde_json::to_string_pretty(&self.contacts)?;
+        fs::write(&self.path, data)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+pub fn contact_items(store: &ContactStore) -> Vec<types::ContactItem> {
+    store
+        .contacts()
+        .iter()
+        .map(|contact| types::ContactItem {
+            name: SharedString::from(contact.name.clone()),
+            node_id: SharedString::from(contact.node_id.to_string()),
+            short_id: SharedString::from(contact.node_id.fmt_short()),
+            last_room: SharedString::from(contact.last_room.clone().unwrap_or_default()),
+            notes: SharedString::from(contact.notes.clone()),
+        })
+        .collect()
+}
+
+// Runs on the UI thread from StartWindow callbacks, so no event loop hop is needed.
+pub fn refresh_start_contacts(main: &types::StartWindow, app_state: &Arc<Mutex<AppState>>) {
+    let state = app_state.lock().unwrap();
+    let items = contact_items(&state.contacts.lock().unwrap());
+    main.set_contacts(ModelRc::new(VecModel::from(items)));
+}
+
+pub fn update_contacts(chat_handle: &Weak<types::ChatWindow>, app_state: &Arc<Mutex<AppState>>) {
+    let chat_handle_clone = chat_handle.clone();
+    let app_state_clone = app_state.clone();
+
+    match slint::invoke_from_event_loop(move || {
+        if let Some(chat) = chat_handle_clone.upgrade() {
+            let state = app_state_clone.lock().unwrap();
+            let items = contact_items(&state.contacts.lock().unwrap());
+            chat.set_contacts(ModelRc::new(VecModel::from(items)));
+        } else {
+            println!("DEBUG: Chat window handle is invalid, cannot update contacts");
+        }
+    }) {
+        Ok(_) => {}
+        Err(e) => println!(
+            "ERROR: Failed to invoke contacts update from event loop: {:?}",
+            e
+        ),
+    }
+}
+
+pub fn save_contact(
+    peer_name: &str,
+    chat_handle: &Weak<types::ChatWindow>,
+    app_state: &Arc<Mutex<AppState>>,
+) -> Result<()> {
+    {
+        let state = app_state.lock().unwrap();
+        let node_id = state
+            .node_id_by_name(peer_name)
+            .ok_or_else(|| anyhow!("Unknown user {}", peer_name))?;
+        let last_room = state.current_session_token.clone();
+        state
+            .contacts
+            .lock()
+            .unwrap()
+            .upsert(peer_name.to_string(), node_id, last_room)?;
+    }
+
+    push_system_message(
+        app_state,
+        trf("⭐ Saved {} to your contacts", &[&peer_name]),
+    );
+    update_messages(chat_handle, app_state);
+    update_contacts(chat_handle, app_state);
+    Ok(())
+}
+
+// Keeps a known contact's name and last room current whenever we meet them in a room.
+pub fn refresh_contact(app_state: &Arc<Mutex<AppState>>, node_id: NodeId, name: &str) {
+    let state = app_state.lock().unwrap();
+    let mut contacts = state.contacts.lock().unwrap();
+    if contacts.get(&node_id).is_some()
+        && let Err(e) = contacts.upsert(
+            name.to_string(),
+            node_id,
+            state.current_session_token.clone(),
+        )
+    {
+        eprintln!("Failed to update contact {}: {}", name, e);
+    }
+}
+
+pub fn add_contact(
+    app_state: &Arc<Mutex<AppState>>,
+    name: &str,
+    node_id: &str,
+    notes: &str,
+) -> Result<()> {
+    let node_id = NodeId::from_str(node_id.trim()).context("Invalid node id")?;
+    let state = app_state.lock().unwrap();
+    let mut contacts = state.contacts.lock().unwrap();
+    contacts.upsert(name.trim().to_string(), node_id, None)?;
+    if !notes.is_empty() {
+        contacts.set_notes(&node_id, notes.to_string())?;
+    }
+    Ok(())
+}
+
+pub async fn invite_contact(
+    node_id: String,
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) -> Result<()> {
+    let node_id = NodeId::from_str(&node_id)?;
+    let (endpoint, from, name, ticket, contact_name) = {
+        let state = app_state.lock().unwrap();
+        let contact_name = state
+            .contacts
+            .lock()
+            .unwrap()
+            .get(&node_id)
+            .map_or_else(|| node_id.fmt_short(), |c| c.name.clone());
+        (
+            state.endpoint.clone(),
+            state.current_node_id,
+            state.current_username.clone(),
+            state.current_session_token.clone(),
+            contact_name,
+        )
+    };
+    let (Some(endpoint), Some(from), Some(ticket)) = (endpoint, from, ticket) else {
+        return Err(anyhow!("Not connected to a room"));
+    };
+
+    let result = async {
+        let connection = endpoint.connect(node_id, INVITE_ALPN).await?;
+        let mut send = connection.open_uni().await?;
+        let invite = Message::new(MessageBody::RoomInvite { from, name, ticket });
+        send.write_all(&invite.to_vec()).await?;
+        send.finish()?;
+        // The invitee closes the connection once it has read the whole invite.
+        connection.closed().await;
+        anyhow::Ok(())
+    }
+    .await;
+
+    let notice = match &result {
+        Ok(()) => trf("📨 Invite sent to {}", &[&contact_name]),
+        Err(_) => trf(
+            "📨 Could not reach {}. They need to have Chast open in a room to get invites.",
+            &[&contact_name],
+        ),
+    };
+    push_system_message(&app_state, notice);
+    update_messages(&chat_handle, &app_state);
+    result
+}
+
+#[derive(Clone)]
+pub struct InviteProtocol {
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+}
+
+impl InviteProtocol {
+    pub fn new(app_state: Arc<Mutex<AppState>>, chat_handle: Weak<types::ChatWindow>) -> Self {
+        Self {
+            app_state,
+            chat_handle,
+        }
+    }
+}
+
+impl fmt::Debug for InviteProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InviteProtocol").finish_non_exhaustive()
+    }
+}
+
+impl ProtocolHandler for InviteProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let remote = connection.remote_node_id()?;
+        let mut recv = connection.accept_uni().await?;
+        let bytes = recv
+            .read_to_end(MAX_INVITE_BYTES)
+            .await
+            .map_err(AcceptError::from_err)?;
+        connection.close(0u32.into(), b"invite received");
+
+        let message: Message = serde_json::from_slice(&bytes).map_err(AcceptError::from_err)?;
+        let MessageBody::RoomInvite { from, name, ticket } = message.body else {
+            return Err(AcceptError::NotAllowed {});
+        };
+        if from != remote
+            || self
+                .app_state
+                .lock()
+                .unwrap()
+                .blocklist
+                .lock()
+                .unwrap()
+                .is_blocked(&from)
+        {
+            return Err(AcceptError::NotAllowed {});
+        }
+
+        println!(
+            "DEBUG: Received room invite from {} ({})",
+            name,
+            from.fmt_short()
+        );
+        push_system_message(
+            &self.app_state,
+            trf(
+                "📨 {} invited you to another room. Disconnect and join with this token:\n\n{}",
+                &[&name, &ticket],
+            ),
+        );
+        update_messages(&self.chat_handle, &self.app_state);
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+use anyhow::{Result, anyhow};
+use iroh::{
+    NodeId,
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler},
+};
+use messaging::{Capabilities, Message, MessageBody, receipts};
+use n0_future::time::{Duration, sleep};
+use slint::{SharedString, Weak};
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use crate::app::{app_state::AppState, types, ui_handlers::update_messages};
+
+// How long a sent message waits for the first ack before it is shown as failed.
+const ACK_TIMEOUT: Duration = Duration::from_secs(15);
+// Acks for one author queued within this long of the first go out on one connection.
+const ACK_BATCH: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    Pending,
+    Sent,
+    Delivered,
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Sent => "sent",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+}
+
+// Returns false if the message is gone or already delivered; a late ack still wins over
+// a timeout, but nothing downgrades a delivered message.
+pub fn set_status(app_state: &Arc<Mutex<AppState>>, id: u64, status: DeliveryStatus) -> bool {
+    let message_id = id.to_string();
+    let state = app_state.lock().unwrap();
+    let mut messages = state.messages.lock().unwrap();
+    let Some(message) = messages
+        .iter_mut()
+        .rev()
+        .find(|message| message.is_own && message.message_id == message_id)
+    else {
+        return false;
+    };
+    if message.delivery == DeliveryStatus::Delivered.as_str() || message.delivery == status.as_str()
+    {
+        return false;
+    }
+    message.delivery = SharedString::from(status.as_str());
+    true
+}
+
+// Acks straight to the author when its client takes that, batched so a burst of
+// messages costs one connection. Older clients still get an Ack gossiped to the room.
+pub async fn send_ack(app_state: &Arc<Mutex<AppState>>, to: NodeId, id: u64) -> Result<()> {
+    let direct = app_state
+        .lock()
+        .unwrap()
+        .clients
+        .lock()
+        .unwrap()
+        .get(&to)
+        .is_some_and(|client| client.capabilities.contains(Capabilities::ACKS));
+    if !direct {
+        return gossip_ack(app_state, to, id).await;
+    }
+
+    let first = {
+        let mut state = app_state.lock().unwrap();
+        let queued = state.pending_acks.entry(to).or_default();
+        queued.push(id);
+        queued.len() == 1
+    };
+    if !first {
+        return Ok(());
+    }
+
+    sleep(ACK_BATCH).await;
+    let (endpoint, ids) = {
+        let mut state = app_state.lock().unwrap();
+        (
+            state.endpoint.clone(),
+            state.pending_acks.remove(&to).unwrap_or_default(),
+        )
+    };
+    let Some(endpoint) = endpoint else {
+        return Err(anyhow!("Not connected to a room"));
+    };
+    if ids.is_empty() {
+        // We left the room while the batch was waiting.
+        return Ok(());
+    }
+    receipts::send_acks(&endpoint, to, &ids).await
+}
+
+async fn gossip_ack(app_state: &Arc<Mutex<AppState>>, to: NodeId, id: u64) -> Result<()> {
+    let (sender, from, secret_key) = {
+        let state = app_state.lock().unwrap();
+        (
+            state.sender.clone(),
+            state.current_node_id,
+            state.secret_key(),
+        )
+    };
+    let (Some(sender), Some(from), Some(secret_key)) = (sender, from, secret_key) else {
+        return Err(anyhow!("Not connected to a room"));
+    };
+    let ack = Message::new(MessageBody::Ack { from, to, id });
+    sender
+        .broadcast(ack.to_signed_vec(&secret_key, sender.topic()))
+        .await?;
+    Ok(())
+}
+
+pub fn handle_ack(
+    to: NodeId,
+    id: u64,
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+) {
+    if app_state.lock().unwrap().current_node_id != Some(to) {
+        return;
+    }
+    if set_status(app_state, id, DeliveryStatus::Delivered) {
+        update_messages(chat_handle, app_state);
+    }
+}
+
+pub async fn expire_unacked(
+    id: u64,
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) {
+    sleep(ACK_TIMEOUT).await;
+
+    let still_sent = {
+        let message_id = id.to_string();
+        let state = app_state.lock().unwrap();
+        let messages = state.messages.lock().unwrap();
+        messages.iter().any(|message| {
+            message.message_id == message_id && message.delivery == DeliveryStatus::Sent.as_str()
+        })
+    };
+    if still_sent && set_status(&app_state, id, DeliveryStatus::Failed) {
+        update_messages(&chat_handle, &app_state);
+    }
+}
+
+// Takes acks for our messages straight from the members who got them.
+#[derive(Clone)]
+pub struct AckProtocol {
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+}
+
+impl AckProtocol {
+    pub fn new(app_state: Arc<Mutex<AppState>>, chat_handle: Weak<types::ChatWindow>) -> Self {
+        Self {
+            app_state,
+            chat_handle,
+        }
+    }
+}
+
+impl fmt::Debug for AckProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AckProtocol").finish_non_exhaustive()
+    }
+}
+
+impl ProtocolHandler for AckProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let remote = connection.remote_node_id()?;
+        let me = {
+            let state = self.app_state.lock().unwrap();
+            // An ack only counts from someone in the room, the same as a gossiped one.
+            let member = state.names.lock().unwrap().contains_key(&remote)
+                && state.is_admitted(&remote)
+                && !state.banned.contains(&remote);
+            if !member {
+                return Err(AcceptError::NotAllowed {});
+            }
+            state.current_node_id
+        };
+        let Some(me) = me else {
+            return Err(AcceptError::NotAllowed {});
+        };
+
+        let ids = receipts::read_acks(&connection)
+            .await
+            .map_err(|_| AcceptError::NotAllowed {})?;
+        for id in ids {
+            handle_ack(me, id, &self.app_state, &self.chat_handle);
+        }
+        // The member closes once it knows we have them all.
+        connection.closed().await;
+        Ok(())
+    }
+}
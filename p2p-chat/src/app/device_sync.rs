@@ -0,0 +1,363 @@
+use anyhow::{Result, anyhow, bail};
+use chrono::{DateTime, Utc};
+use iroh::{
+    Endpoint, NodeId,
+    endpoint::{Connection, RecvStream, SendStream},
+    protocol::{AcceptError, ProtocolHandler, Router},
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use tokio::time::{Duration, sleep};
+
+use crate::app::{
+    app_state::AppState,
+    networking::{IDENTITY_FILE, load_or_create_secret_key},
+    save::{ChatSaveManager, ChatSession, Config},
+    types,
+};
+
+pub const SYNC_ALPN: &[u8] = b"chast/sync/0";
+// Devices sharing identity.key share its NodeId too, and a node can't dial itself, so
+// each device's sync endpoint has a key of its own.
+const DEVICE_KEY_FILE: &str = "device.key";
+const SYNC_EVERY: Duration = Duration::from_secs(300);
+const MAX_FRAME_BYTES: usize = 64 * 1024 * 1024;
+
+// What a device has saved of one room, enough for the other side to tell whether
+// their copies differ.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    session_id: String,
+    last_updated: DateTime<Utc>,
+    messages: usize,
+}
+
+// The id other devices link to, shown in the settings dialog.
+pub fn device_id() -> Result<NodeId> {
+    Ok(load_or_create_secret_key(DEVICE_KEY_FILE)?.public())
+}
+
+// Only devices set up with the same identity.key can work this out, which is what
+// makes them "my own devices".
+fn sync_key() -> Result<[u8; 32]> {
+    let identity = load_or_create_secret_key(IDENTITY_FILE)?;
+    Ok(blake3::derive_key(
+        "chast device sync v1",
+        &identity.to_bytes(),
+    ))
+}
+
+// Ties the shared key to both ends of the connection, so a proof can't be replayed
+// by some other node.
+fn proof(key: &[u8; 32], from: NodeId, to: NodeId) -> blake3::Hash {
+    let mut data = [0u8; 64];
+    data[..32].copy_from_slice(from.as_bytes());
+    data[32..].copy_from_slice(to.as_bytes());
+    blake3::keyed_hash(key, &data)
+}
+
+async fn check_proof(
+    recv: &mut RecvStream,
+    key: &[u8; 32],
+    from: NodeId,
+    to: NodeId,
+) -> Result<()> {
+    let mut bytes = [0u8; 32];
+    recv.read_exact(&mut bytes).await?;
+    // Hash comparisons are constant time.
+    if blake3::Hash::from_bytes(bytes) != proof(key, from, to) {
+        bail!("{} is not set up with this identity", from.fmt_short());
+    }
+    Ok(())
+}
+
+// A sync endpoint running outside of any room while sync is turned on.
+#[derive(Debug, Clone)]
+pub struct DeviceSync {
+    router: Router,
+    key: [u8; 32],
+}
+
+impl DeviceSync {
+    pub async fn start(app_state: Arc<Mutex<AppState>>) -> Result<Self> {
+        let key = sync_key()?;
+        let endpoint = Endpoint::builder()
+            .secret_key(load_or_create_secret_key(DEVICE_KEY_FILE)?)
+            .discovery_n0()
+            .bind()
+            .await?;
+        let me = endpoint.node_id();
+        let router = Router::builder(endpoint)
+            .accept(
+                SYNC_ALPN,
+                SyncProtocol {
+                    me,
+                    key,
+                    app_state: app_state.clone(),
+                },
+            )
+            .spawn();
+        println!("> device sync: this device is {}", me);
+
+        let sync = Self { router, key };
+        let periodic = sync.clone();
+        tokio::spawn(async move {
+            while !periodic.router.is_shutdown() {
+                periodic.sync_all(&app_state).await;
+                sleep(SYNC_EVERY).await;
+            }
+        });
+        Ok(sync)
+    }
+
+    pub async fn shutdown(&self) {
+        if let Err(e) = self.router.shutdown().await {
+            eprintln!("Error shutting down device sync: {}", e);
+        }
+    }
+
+    // Trades saved chats with every linked device that can be reached right now.
+    pub async fn sync_all(&self, app_state: &Arc<Mutex<AppState>>) {
+        let devices = {
+            let state = app_state.lock().unwrap();
+            let settings = state.settings.lock().unwrap();
+            settings.device_sync.devices.clone()
+        };
+        for device in devices {
+            match self.sync_with(device, app_state).await {
+                Ok(updated) => println!(
+                    "> device sync: {} chats updated from {}",
+                    updated,
+                    device.fmt_short()
+                ),
+                Err(e) => eprintln!("Device sync with {} failed: {:#}", device.fmt_short(), e),
+            }
+        }
+    }
+
+    async fn sync_with(&self, device: NodeId, app_state: &Arc<Mutex<AppState>>) -> Result<usize> {
+        let me = self.router.endpoint().node_id();
+        let connection = self.router.endpoint().connect(device, SYNC_ALPN).await?;
+        let (mut send, mut recv) = connection.open_bi().await?;
+        send.write_all(proof(&self.key, me, device).as_bytes())
+            .await?;
+        check_proof(&mut recv, &self.key, device, me).await?;
+        let updated = exchange(send, recv, app_state).await?;
+        // The other side waits for this, so it knows we've read everything.
+        connection.close(0u32.into(), b"synced");
+        Ok(updated)
+    }
+}
+
+#[derive(Clone)]
+struct SyncProtocol {
+    me: NodeId,
+    key: [u8; 32],
+    app_state: Arc<Mutex<AppState>>,
+}
+
+impl fmt::Debug for SyncProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyncProtocol").finish_non_exhaustive()
+    }
+}
+
+impl ProtocolHandler for SyncProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let remote = connection.remote_node_id()?;
+        let (mut send, mut recv) = connection.accept_bi().await?;
+        if let Err(e) = check_proof(&mut recv, &self.key, remote, self.me).await {
+            eprintln!("Turned away a sync request: {:#}", e);
+            return Err(AcceptError::NotAllowed {});
+        }
+        send.write_all(proof(&self.key, self.me, remote).as_bytes())
+            .await
+            .map_err(AcceptError::from_err)?;
+        match exchange(send, recv, &self.app_state).await {
+            Ok(updated) => println!(
+                "> device sync: {} chats updated from {}",
+                updated,
+                remote.fmt_short()
+            ),
+            Err(e) => eprintln!("Device sync with {} failed: {:#}", remote.fmt_short(), e),
+        }
+        connection.closed().await;
+        Ok(())
+    }
+}
+
+// Both sides send their index, then every chat the other's copy of differs from, and
+// merge what they get. One round leaves both devices with the same files.
+async fn exchange(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    app_state: &Arc<Mutex<AppState>>,
+) -> Result<usize> {
+    let ours =
+        tokio::task::spawn_blocking(|| ChatSaveManager::new(Config::default())?.load_sessions())
+            .await??;
+    let index: Vec<Entry> = ours
+        .iter()
+        .map(|session| Entry {
+            session_id: session.session_id.clone(),
+            last_updated: session.last_updated,
+            messages: session.messages.len(),
+        })
+        .collect();
+    write_frame(&mut send, &index).await?;
+    let theirs: HashMap<String, Entry> = read_frame::<Vec<Entry>>(&mut recv)
+        .await?
+        .into_iter()
+        .map(|entry| (entry.session_id.clone(), entry))
+        .collect();
+
+    let outgoing: Vec<ChatSession> = ours
+        .into_iter()
+        .filter(|session| {
+            theirs.get(&session.session_id).is_none_or(|entry| {
+                entry.last_updated != session.last_updated
+                    || entry.messages != session.messages.len()
+            })
+        })
+        .collect();
+    // Sent and read at the same time, so neither side stalls on a full stream.
+    let sending = async {
+        write_frame(&mut send, &outgoing.len()).await?;
+        for session in &outgoing {
+            write_frame(&mut send, session).await?;
+        }
+        send.finish()?;
+        anyhow::Ok(())
+    };
+    let receiving = async {
+        let count: usize = read_frame(&mut recv).await?;
+        let mut incoming = Vec::new();
+        for _ in 0..count {
+            incoming.push(read_frame::<ChatSession>(&mut recv).await?);
+        }
+        anyhow::Ok(incoming)
+    };
+    let ((), incoming) = tokio::try_join!(sending, receiving)?;
+
+    let open_room = app_state
+        .lock()
+        .unwrap()
+        .room_topic
+        .map(|topic| topic.to_string());
+    tokio::task::spawn_blocking(move || store(incoming, open_room)).await?
+}
+
+fn store(incoming: Vec<ChatSession>, open_room: Option<String>) -> Result<usize> {
+    let manager = ChatSaveManager::new(Config::default())?;
+    let mut updated = 0;
+    for session in incoming {
+        // The id ends up in a file name.
+        if session.session_id.is_empty()
+            || !session
+                .session_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric())
+        {
+            eprintln!("Skipping a synced chat with a bad id");
+            continue;
+        }
+        // The open room's file belongs to its auto-save task, which would write over
+        // the merge. It's brought up to date on the first sync after leaving.
+        if open_room.as_deref() == Some(session.session_id.as_str()) {
+            continue;
+        }
+        let merged = match manager.load_session(&session.session_id)? {
+            Some(mut local) => {
+                local.merge(session);
+                local
+            }
+            None => session,
+        };
+        manager.save_chat_to_file(&merged, &manager.session_path(&merged.session_id))?;
+        updated += 1;
+    }
+    Ok(updated)
+}
+
+async fn write_frame<T: Serialize>(send: &mut SendStream, value: &T) -> Result<()> {
+    let bytes = serde_json::to_vec(value)?;
+    send.write_all(&(bytes.len() as u32).to_be_bytes()).await?;
+    send.write_all(&bytes).await?;
+    Ok(())
+}
+
+async fn read_frame<T: DeserializeOwned>(recv: &mut RecvStream) -> Result<T> {
+    let mut len = [0u8; 4];
+    recv.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_BYTES {
+        bail!("Sync frame of {} bytes is too large", len);
+    }
+    let mut bytes = vec![0u8; len];
+    recv.read_exact(&mut bytes).await?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+// Starts or stops the sync endpoint to match the settings.
+pub async fn apply_settings(app_state: Arc<Mutex<AppState>>) {
+    let (enabled, running) = {
+        let state = app_state.lock().unwrap();
+        let enabled = state.settings.lock().unwrap().device_sync.enabled;
+        (enabled, state.device_sync.clone())
+    };
+    match (enabled, running) {
+        (true, None) => match DeviceSync::start(app_state.clone()).await {
+            Ok(sync) => app_state.lock().unwrap().device_sync = Some(sync),
+            Err(e) => eprintln!("Failed to start device sync: {:#}", e),
+        },
+        (false, Some(sync)) => {
+            app_state.lock().unwrap().device_sync = None;
+            sync.shutdown().await;
+            println!("> device sync: stopped");
+        }
+        _ => {}
+    }
+}
+
+// Runs on the UI thread when the settings dialog opens or the linked devices change.
+pub fn show_settings(chat: &types::ChatWindow, app_state: &Arc<Mutex<AppState>>) {
+    let settings = {
+        let state = app_state.lock().unwrap();
+        let settings = state.settings.lock().unwrap();
+        settings.device_sync.clone()
+    };
+    chat.set_sync_available(true);
+    chat.set_sync_enabled(settings.enabled);
+    match device_id() {
+        Ok(id) => chat.set_sync_device_id(id.to_string().into()),
+        Err(e) => eprintln!("Failed to load the device key: {:#}", e),
+    }
+    chat.set_sync_devices(
+        settings
+            .devices
+            .iter()
+            .map(|device| device.fmt_short())
+            .collect::<Vec<_>>()
+            .join(", ")
+            .into(),
+    );
+}
+
+pub fn link(app_state: &Arc<Mutex<AppState>>, device: &str) -> Result<()> {
+    let device = NodeId::from_str(device.trim()).map_err(|_| anyhow!("Not a device id"))?;
+    if device == device_id()? {
+        bail!("That's this device's id");
+    }
+    let state = app_state.lock().unwrap();
+    let mut settings = state.settings.lock().unwrap();
+    if !settings.device_sync.devices.contains(&device) {
+        settings.device_sync.devices.push(device);
+        settings.save()?;
+    }
+    Ok(())
+}
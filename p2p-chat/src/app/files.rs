@@ -0,0 +1,608 @@
+use anyhow::{Context, Result, anyhow, bail};
+use iroh::{
+    NodeId,
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler},
+};
+use messaging::{Message, MessageBody};
+use slint::{ModelRc, SharedString, VecModel, Weak};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use crate::app::{
+    app_state::AppState,
+    delivery::{self, DeliveryStatus},
+    history,
+    i18n::trf,
+    networking::display_chat_message,
+    types,
+    ui_handlers::{local_time, push_system_message, update_messages},
+};
+
+// Version 1 answers with the file's hash and sends from wherever the asker left off.
+pub const FILE_ALPN: &[u8] = b"chast/file/1";
+const MIB: u64 = 1024 * 1024;
+const CHUNK: usize = 64 * 1024;
+// Tries in a row that get nothing through before a download is given up on. Any try
+// that gets part of the file through starts the count again.
+const ATTEMPTS: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_secs(3);
+
+// Someone's file waiting for a yes or no.
+#[derive(Debug, Clone)]
+pub struct Offer {
+    pub from: NodeId,
+    pub sender: String,
+    pub id: u64,
+    pub name: String,
+    pub size: u64,
+}
+
+// A file we offered, and its hash as it was then.
+#[derive(Debug, Clone)]
+pub struct Offered {
+    pub path: PathBuf,
+    pub hash: blake3::Hash,
+}
+
+#[derive(Debug, Default)]
+pub struct Files {
+    // What we offered in this room, by id, so it can be handed out when asked for.
+    pub offered: HashMap<u64, Offered>,
+    // Offers waiting for an answer, oldest first.
+    pub incoming: Vec<Offer>,
+}
+
+impl Files {
+    pub fn clear(&mut self) {
+        self.offered.clear();
+        self.incoming.clear();
+    }
+}
+
+pub fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{} B", bytes),
+        1024..MIB => format!("{:.1} KB", bytes as f64 / 1024.0),
+        MIB..1_073_741_824 => format!("{:.1} MB", bytes as f64 / MIB as f64),
+        _ => format!("{:.1} GB", bytes as f64 / 1_073_741_824.0),
+    }
+}
+
+// What an offer says in the chat.
+pub fn caption(name: &str, size: u64) -> String {
+    format!("📎 {} ({})", name, format_size(size))
+}
+
+// A download that broke off part way through. What arrived is kept, so trying again
+// carries on from there.
+#[derive(Debug)]
+struct Interrupted {
+    received: u64,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.error)
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+fn interrupted(error: impl Into<anyhow::Error>, received: u64) -> anyhow::Error {
+    anyhow::Error::new(Interrupted {
+        received,
+        error: error.into(),
+    })
+}
+
+async fn hash_file(path: &Path) -> Result<blake3::Hash> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::open(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(blake3::Hasher::new().update_reader(file)?.finalize())
+    })
+    .await?
+}
+
+// Offered names come from someone else, so only the bare file name is ever used.
+fn safe_name(name: &str) -> Option<String> {
+    let name = Path::new(name).file_name()?.to_str()?.trim();
+    (!name.is_empty() && !name.starts_with('.')).then(|| name.to_string())
+}
+
+pub fn show(chat_handle: &Weak<types::ChatWindow>, app_state: &Arc<Mutex<AppState>>) {
+    let offers: Vec<types::FileOffer> = {
+        let state = app_state.lock().unwrap();
+        let files = state.files.lock().unwrap();
+        files
+            .incoming
+            .iter()
+            .map(|offer| types::FileOffer {
+                id: SharedString::from(offer.id.to_string()),
+                sender: SharedString::from(offer.sender.as_str()),
+                name: SharedString::from(offer.name.as_str()),
+                size: SharedString::from(format_size(offer.size)),
+            })
+            .collect()
+    };
+    let chat_handle = chat_handle.clone();
+    if let Err(e) = slint::invoke_from_event_loop(move || {
+        if let Some(chat) = chat_handle.upgrade() {
+            chat.set_file_offers(ModelRc::new(VecModel::from(offers)));
+        }
+    }) {
+        eprintln!("Failed to update file offers: {:?}", e);
+    }
+}
+
+// Offers a file from disk to the room. It stays where it is and is read again for
+// everyone who accepts it, until we leave.
+pub async fn offer(
+    path: PathBuf,
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) -> Result<()> {
+    let size = fs::metadata(&path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?
+        .len();
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(safe_name)
+        .context("This file can't be sent under its name")?;
+    // Lets whoever downloads it check it arrived whole, and pick up a broken download
+    // again even if it's offered anew.
+    let hash = hash_file(&path).await?;
+
+    let (sender, node_id, username, has_peers, secret_key) = {
+        let state = app_state.lock().unwrap();
+        let has_peers = state
+            .names
+            .lock()
+            .unwrap()
+            .keys()
+            .any(|id| state.current_node_id != Some(*id));
+        (
+            state.sender.clone(),
+            state.current_node_id,
+            state.current_username.clone(),
+            has_peers,
+            state.secret_key(),
+        )
+    };
+    let (Some(sender), Some(node_id), Some(secret_key)) = (sender, node_id, secret_key) else {
+        return Err(anyhow!("Not connected to a room"));
+    };
+    if !app_state.lock().unwrap().can_post(&node_id) {
+        return Err(anyhow!(
+            "Only moderators can post in this announcement room"
+        ));
+    }
+
+    let id = rand::random::<u64>().max(1);
+    let text = caption(&name, size);
+    let new_message = types::ChatMessage {
+        username: SharedString::from(username.clone()),
+        content: SharedString::from(text.as_str()),
+        timestamp: SharedString::from(local_time(chrono::Utc::now())),
+        sender_id: SharedString::from(node_id.to_string()),
+        burst_id: 0,
+        message_id: SharedString::from(id.to_string()),
+        delivery: SharedString::from(DeliveryStatus::Pending.as_str()),
+        is_own: true,
+        is_system: false,
+        event: types::SystemEvent::None,
+        is_announcement: false,
+        is_action: false,
+        image_index: -1,
+        code_index: -1,
+        translation: Default::default(),
+        name_color: Default::default(),
+    };
+    {
+        let state = app_state.lock().unwrap();
+        state
+            .files
+            .lock()
+            .unwrap()
+            .offered
+            .insert(id, Offered { path, hash });
+        state.messages.lock().unwrap().push(new_message);
+        state.persister.record(&username, &text);
+    }
+    history::spill_overflow(&app_state);
+    update_messages(&chat_handle, &app_state);
+
+    let msg = Message::new(MessageBody::File {
+        from: node_id,
+        id,
+        name: name.clone(),
+        size,
+    });
+    if let Err(e) = sender
+        .broadcast(msg.to_signed_vec(&secret_key, sender.topic()))
+        .await
+    {
+        delivery::set_status(&app_state, id, DeliveryStatus::Failed);
+        return Err(e);
+    }
+    println!("DEBUG: Offered {} ({} bytes)", name, size);
+
+    if has_peers {
+        delivery::set_status(&app_state, id, DeliveryStatus::Sent);
+        n0_future::task::spawn(delivery::expire_unacked(id, app_state, chat_handle));
+    } else {
+        delivery::set_status(&app_state, id, DeliveryStatus::Failed);
+    }
+    Ok(())
+}
+
+// Someone offered a file: show it in the chat, then save it straight away if the
+// settings allow, or ask.
+pub fn handle_offer(
+    from: NodeId,
+    id: u64,
+    name: String,
+    size: u64,
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+) {
+    if app_state.lock().unwrap().current_node_id == Some(from) {
+        return;
+    }
+    display_chat_message(
+        from,
+        caption(&name, size),
+        id,
+        false,
+        app_state,
+        chat_handle,
+    );
+
+    let (sender, settings) = {
+        let state = app_state.lock().unwrap();
+        let sender = state
+            .names
+            .lock()
+            .unwrap()
+            .get(&from)
+            .cloned()
+            .unwrap_or_else(|| from.fmt_short());
+        let settings = state.settings.lock().unwrap().files.clone();
+        (sender, settings)
+    };
+    let Some(name) = safe_name(&name).filter(|name| settings.allows(name)) else {
+        println!("DEBUG: Refused file {:?} from {}", name, from.fmt_short());
+        push_system_message(
+            app_state,
+            trf(
+                "Refused {} from {}: that type of file isn't allowed",
+                &[&name, &sender],
+            ),
+        );
+        update_messages(chat_handle, app_state);
+        return;
+    };
+
+    let offer = Offer {
+        from,
+        sender,
+        id,
+        name,
+        size,
+    };
+    if settings.auto_accepts(size) {
+        tokio::spawn(download(offer, app_state.clone(), chat_handle.clone()));
+        return;
+    }
+    app_state
+        .lock()
+        .unwrap()
+        .files
+        .lock()
+        .unwrap()
+        .incoming
+        .push(offer);
+    show(chat_handle, app_state);
+}
+
+// The answer to an offer, by id.
+pub fn answer(
+    id: &str,
+    accept: bool,
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+) {
+    let offer = {
+        let state = app_state.lock().unwrap();
+        let mut files = state.files.lock().unwrap();
+        files
+            .incoming
+            .iter()
+            .position(|offer| offer.id.to_string() == id)
+            .map(|index| files.incoming.remove(index))
+    };
+    show(chat_handle, app_state);
+    if let Some(offer) = offer.filter(|_| accept) {
+        tokio::spawn(download(offer, app_state.clone(), chat_handle.clone()));
+    }
+}
+
+async fn download(
+    offer: Offer,
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) {
+    let notice = match fetch(&offer, &app_state).await {
+        Ok(path) => {
+            println!("DEBUG: Saved {} to {}", offer.name, path.display());
+            trf("Saved {} to {}", &[&offer.name, &path.display()])
+        }
+        Err(e) => {
+            eprintln!(
+                "Failed to download {} from {}: {:#}",
+                offer.name,
+                offer.from.fmt_short(),
+                e
+            );
+            let notice = trf("Couldn't download {}: {}", &[&offer.name, &e]);
+            // The connection gave out rather than the file, so the offer goes back on
+            // the list and saving it again resumes where this left off.
+            if e.is::<Interrupted>() {
+                app_state
+                    .lock()
+                    .unwrap()
+                    .files
+                    .lock()
+                    .unwrap()
+                    .incoming
+                    .push(offer);
+                show(&chat_handle, &app_state);
+            }
+            notice
+        }
+    };
+    push_system_message(&app_state, notice);
+    update_messages(&chat_handle, &app_state);
+}
+
+// A name in the download folder that isn't taken yet.
+async fn free_path(dir: &Path, name: &str) -> PathBuf {
+    let mut path = dir.join(name);
+    let mut copy = 1;
+    while fs::try_exists(&path).await.unwrap_or(false) {
+        copy += 1;
+        path = dir.join(format!("{}-{}", copy, name));
+    }
+    path
+}
+
+async fn fetch(offer: &Offer, app_state: &Arc<Mutex<AppState>>) -> Result<PathBuf> {
+    let (endpoint, dir) = {
+        let state = app_state.lock().unwrap();
+        let dir = state.settings.lock().unwrap().files.download_dir.clone();
+        (state.endpoint.clone(), dir)
+    };
+    let endpoint = endpoint.ok_or_else(|| anyhow!("Not connected to a room"))?;
+    let dir = PathBuf::from(dir);
+    fs::create_dir_all(&dir)
+        .await
+        .with_context(|| format!("Failed to create {}", dir.display()))?;
+
+    let mut attempt = 1;
+    loop {
+        match fetch_range(&endpoint, offer, &dir).await {
+            Err(e) if attempt < ATTEMPTS && e.is::<Interrupted>() => {
+                if e.downcast_ref::<Interrupted>().unwrap().received > 0 {
+                    attempt = 1;
+                }
+                println!(
+                    "DEBUG: Download of {} broke off ({:#}), trying again",
+                    offer.name, e
+                );
+                tokio::time::sleep(RETRY_DELAY * attempt).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Asks for the file from however much of it is already in its .part file, and
+// finishes the download once it's all there and matches the hash.
+async fn fetch_range(endpoint: &iroh::Endpoint, offer: &Offer, dir: &Path) -> Result<PathBuf> {
+    let connection = endpoint
+        .connect(offer.from, FILE_ALPN)
+        .await
+        .map_err(|e| interrupted(e, 0))?;
+    let (mut send, mut recv) = connection.open_bi().await.map_err(|e| interrupted(e, 0))?;
+    send.write_all(&offer.id.to_be_bytes())
+        .await
+        .map_err(|e| interrupted(e, 0))?;
+
+    let mut len = [0u8; 8];
+    recv.read_exact(&mut len)
+        .await
+        .map_err(|e| interrupted(e, 0))?;
+    let len = u64::from_be_bytes(len);
+    if len == 0 {
+        bail!("they no longer have it");
+    }
+    // Only what was agreed to is taken, not whatever turns up.
+    if len != offer.size {
+        bail!("the file is {} bytes, not the {} offered", len, offer.size);
+    }
+    let mut hash = [0u8; 32];
+    recv.read_exact(&mut hash)
+        .await
+        .map_err(|e| interrupted(e, 0))?;
+    let hash = blake3::Hash::from_bytes(hash);
+
+    // Written under another name until it's complete, so a broken transfer never
+    // looks like a finished file. The name goes by the content, so a broken download
+    // is picked up again whichever offer of the same file it's saved from.
+    let part = dir.join(format!("{}.{}.part", offer.name, &hash.to_hex()[..16]));
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&part)
+        .await
+        .with_context(|| format!("Failed to create {}", part.display()))?;
+    let mut have = file.metadata().await?.len();
+    if have > len {
+        file.set_len(0).await?;
+        have = 0;
+    }
+    file.seek(SeekFrom::Start(have)).await?;
+    if have > 0 {
+        println!(
+            "DEBUG: Resuming {} at {} of {} bytes",
+            offer.name, have, len
+        );
+    }
+    send.write_all(&have.to_be_bytes())
+        .await
+        .map_err(|e| interrupted(e, 0))?;
+    send.finish().map_err(|e| interrupted(e, 0))?;
+
+    let mut received = 0;
+    let mut buf = vec![0u8; CHUNK];
+    while have + received < len {
+        let want = buf.len().min((len - have - received) as usize);
+        let read = match recv.read(&mut buf[..want]).await {
+            Ok(Some(read)) => read,
+            Ok(None) => {
+                file.flush().await?;
+                return Err(interrupted(
+                    anyhow!(
+                        "they stopped sending with {} bytes to go",
+                        len - have - received
+                    ),
+                    received,
+                ));
+            }
+            Err(e) => {
+                file.flush().await?;
+                return Err(interrupted(e, received));
+            }
+        };
+        file.write_all(&buf[..read]).await?;
+        received += read as u64;
+    }
+    file.flush().await?;
+    drop(file);
+    connection.close(0u32.into(), b"done");
+
+    if hash_file(&part).await? != hash {
+        let _ = fs::remove_file(&part).await;
+        bail!("it didn't arrive intact");
+    }
+    let path = free_path(dir, &offer.name).await;
+    fs::rename(&part, &path).await?;
+    Ok(path)
+}
+
+// Hands out the files we offered to anyone who asks for one by id.
+#[derive(Clone)]
+pub struct FileProtocol {
+    app_state: Arc<Mutex<AppState>>,
+}
+
+impl FileProtocol {
+    pub fn new(app_state: Arc<Mutex<AppState>>) -> Self {
+        Self { app_state }
+    }
+}
+
+impl fmt::Debug for FileProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileProtocol").finish_non_exhaustive()
+    }
+}
+
+impl ProtocolHandler for FileProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let peer = connection.remote_node_id()?;
+        if self
+            .app_state
+            .lock()
+            .unwrap()
+            .blocklist
+            .lock()
+            .unwrap()
+            .is_blocked(&peer)
+        {
+            return Err(AcceptError::NotAllowed {});
+        }
+
+        if let Err(e) = serve_file(&connection, &self.app_state).await {
+            println!(
+                "DEBUG: Sending a file to {} failed: {}",
+                peer.fmt_short(),
+                e
+            );
+        }
+        connection.closed().await;
+        Ok(())
+    }
+}
+
+async fn serve_file(connection: &Connection, app_state: &Arc<Mutex<AppState>>) -> Result<()> {
+    let (mut send, mut recv) = connection.accept_bi().await?;
+    let mut id = [0u8; 8];
+    recv.read_exact(&mut id).await?;
+    let id = u64::from_be_bytes(id);
+    let offered = {
+        let state = app_state.lock().unwrap();
+        let files = state.files.lock().unwrap();
+        files.offered.get(&id).cloned()
+    };
+    let file = match &offered {
+        Some(offered) => fs::File::open(&offered.path).await.ok(),
+        None => None,
+    };
+
+    // A length of zero tells them we don't have it (any more).
+    let (Some(mut file), Some(offered)) = (file, offered) else {
+        send.write_all(&0u64.to_be_bytes()).await?;
+        send.finish()?;
+        return Ok(());
+    };
+    let len = file.metadata().await?.len();
+    send.write_all(&len.to_be_bytes()).await?;
+    send.write_all(offered.hash.as_bytes()).await?;
+
+    // Then they say how much they already have, and get the rest.
+    let mut offset = [0u8; 8];
+    recv.read_exact(&mut offset).await?;
+    let offset = u64::from_be_bytes(offset);
+    if offset > len {
+        bail!("asked to start at {} of a {} byte file", offset, len);
+    }
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut buf = vec![0u8; CHUNK];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        send.write_all(&buf[..read]).await?;
+    }
+    send.finish()?;
+    Ok(())
+}
@@ -0,0 +1,64 @@
+use iroh::NodeId;
+use n0_future::time::{Duration, Instant};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+const BURST_WINDOW: Duration = Duration::from_secs(5);
+pub const BURST_THRESHOLD: usize = 20;
+
+// Tracks how fast each peer is posting. Once someone sends more than BURST_THRESHOLD
+// messages inside BURST_WINDOW, their messages share a burst id until they slow down,
+// and the chat view collapses each burst into a single expandable row.
+#[derive(Debug, Default)]
+pub struct FloodGuard {
+    recent: HashMap<NodeId, VecDeque<Instant>>,
+    active: HashMap<NodeId, i32>,
+    expanded: HashSet<i32>,
+    next_burst_id: i32,
+}
+
+pub enum Burst {
+    None,
+    Started(i32),
+    Ongoing(i32),
+}
+
+impl FloodGuard {
+    pub fn record(&mut self, from: NodeId) -> Burst {
+        let now = Instant::now();
+        let recent = self.recent.entry(from).or_default();
+        recent.push_back(now);
+        while recent
+            .front()
+            .is_some_and(|sent| now.duration_since(*sent) > BURST_WINDOW)
+        {
+            recent.pop_front();
+        }
+
+        if recent.len() <= BURST_THRESHOLD {
+            self.active.remove(&from);
+            return Burst::None;
+        }
+
+        match self.active.get(&from) {
+            Some(burst_id) => Burst::Ongoing(*burst_id),
+            None => {
+                self.next_burst_id += 1;
+                self.active.insert(from, self.next_burst_id);
+                Burst::Started(self.next_burst_id)
+            }
+        }
+    }
+
+    pub fn expand(&mut self, burst_id: i32) {
+        self.expanded.insert(burst_id);
+    }
+
+    pub fn is_collapsed(&self, burst_id: i32) -> bool {
+        burst_id != 0 && !self.expanded.contains(&burst_id)
+    }
+
+    pub fn forget(&mut self, from: &NodeId) {
+        self.recent.remove(from);
+        self.active.remove(from);
+    }
+}
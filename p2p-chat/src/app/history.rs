@@ -0,0 +1,206 @@
+use iroh::NodeId;
+use messaging::StoredMessage;
+use slint::{SharedString, Weak};
+use std::sync::{Arc, Mutex};
+
+use crate::app::{
+    app_state::AppState,
+    notifications,
+    save::{ChatSaveManager, Config, SpilledMessage, SystemEvent},
+    types,
+    ui_handlers::{local_time, update_messages},
+};
+
+// Rooms can have thousands of stored messages; only this many go into the chat view at
+// a time, older ones are added as the user scrolls up.
+const HISTORY_PAGE: usize = 100;
+// Past this many messages in memory the oldest go to disk, a chunk at a time so a busy
+// room doesn't write on every message.
+const MAX_MESSAGES: usize = 2000;
+const SPILL_CHUNK: usize = 200;
+
+fn to_spilled(message: types::ChatMessage) -> SpilledMessage {
+    SpilledMessage {
+        username: message.username.to_string(),
+        content: message.content.to_string(),
+        timestamp: message.timestamp.to_string(),
+        sender_id: message.sender_id.to_string(),
+        message_id: message.message_id.to_string(),
+        delivery: message.delivery.to_string(),
+        is_own: message.is_own,
+        is_system: message.is_system,
+        event: SystemEvent::from_ui(message.event),
+        is_announcement: message.is_announcement,
+        is_action: message.is_action,
+        translation: message.translation.to_string(),
+    }
+}
+
+fn from_spilled(message: SpilledMessage) -> types::ChatMessage {
+    types::ChatMessage {
+        username: SharedString::from(message.username),
+        content: SharedString::from(message.content),
+        timestamp: SharedString::from(message.timestamp),
+        sender_id: SharedString::from(message.sender_id),
+        burst_id: 0,
+        message_id: SharedString::from(message.message_id),
+        delivery: SharedString::from(message.delivery),
+        is_own: message.is_own,
+        is_system: message.is_system,
+        event: SystemEvent::to_ui(message.event),
+        is_announcement: message.is_announcement,
+        is_action: message.is_action,
+        image_index: -1,
+        code_index: -1,
+        translation: SharedString::from(message.translation),
+        name_color: Default::default(),
+    }
+}
+
+// Moves the oldest messages out to the save directory once memory holds more than
+// MAX_MESSAGES. Call after adding a new message.
+pub fn spill_overflow(app_state: &Arc<Mutex<AppState>>) {
+    let state = app_state.lock().unwrap();
+    let evicted: Vec<SpilledMessage> = {
+        let mut messages = state.messages.lock().unwrap();
+        if messages.len() <= MAX_MESSAGES {
+            return;
+        }
+        let count = messages.len() - (MAX_MESSAGES - SPILL_CHUNK);
+        // Incognito rooms never touch the disk, so their oldest messages are just gone.
+        if state.room_incognito {
+            messages.drain(..count);
+            return;
+        }
+        messages.drain(..count).map(to_spilled).collect()
+    };
+
+    let mut spill = state.spill.lock().unwrap();
+    if spill.is_none() {
+        let session_id = state
+            .room_topic
+            .map_or_else(|| "room".to_string(), |topic| topic.fmt_short());
+        match ChatSaveManager::new(Config::default())
+            .and_then(|manager| manager.open_spill(&session_id))
+        {
+            Ok(opened) => *spill = Some(opened),
+            Err(e) => {
+                eprintln!("Dropping {} old messages: {}", evicted.len(), e);
+                return;
+            }
+        }
+    }
+    if let Some(spill) = spill.as_mut() {
+        match spill.push(&evicted) {
+            Ok(()) => println!(
+                "DEBUG: Spilled {} messages to disk, {} there now",
+                evicted.len(),
+                spill.len()
+            ),
+            Err(e) => eprintln!("Dropping {} old messages: {}", evicted.len(), e),
+        }
+    }
+}
+
+pub fn has_older(state: &AppState) -> bool {
+    !state.older_history.lock().unwrap().is_empty()
+        || state
+            .spill
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|spill| !spill.is_empty())
+}
+
+fn to_chat_message(stored: StoredMessage, me: Option<NodeId>) -> types::ChatMessage {
+    types::ChatMessage {
+        username: SharedString::from(stored.sender_name),
+        content: SharedString::from(stored.text),
+        timestamp: SharedString::from(local_time(stored.timestamp)),
+        sender_id: SharedString::from(stored.from.to_string()),
+        burst_id: 0,
+        message_id: SharedString::new(),
+        delivery: SharedString::new(),
+        is_own: me == Some(stored.from),
+        is_system: false,
+        event: types::SystemEvent::None,
+        is_announcement: false,
+        is_action: stored.action,
+        image_index: -1,
+        code_index: -1,
+        translation: Default::default(),
+        name_color: Default::default(),
+    }
+}
+
+// Puts the newest page of a received history in front of the chat and keeps the rest
+// back. Returns how many messages the history had in total.
+pub fn load_history(messages: Vec<StoredMessage>, app_state: &Arc<Mutex<AppState>>) -> usize {
+    let state = app_state.lock().unwrap();
+    let mut older: Vec<StoredMessage> = {
+        let blocklist = state.blocklist.lock().unwrap();
+        messages
+            .into_iter()
+            // A history node may have kept posts the room's moderators never allowed.
+            .filter(|stored| !blocklist.is_blocked(&stored.from) && state.can_post(&stored.from))
+            .collect()
+    };
+    let total = older.len();
+    let latest = older.split_off(total.saturating_sub(HISTORY_PAGE));
+    let timestamps: Vec<_> = latest.iter().map(|stored| stored.timestamp).collect();
+
+    let mut chat_messages = state.messages.lock().unwrap();
+    chat_messages.splice(
+        0..0,
+        latest
+            .into_iter()
+            .map(|stored| to_chat_message(stored, state.current_node_id)),
+    );
+    notifications::place_divider(&mut chat_messages, timestamps, state.last_read);
+    *state.older_history.lock().unwrap() = older;
+    total
+}
+
+// Spilled messages are newer than any history we haven't shown yet, so they come back first.
+pub fn load_older_page(app_state: &Arc<Mutex<AppState>>, chat_handle: &Weak<types::ChatWindow>) {
+    {
+        let state = app_state.lock().unwrap();
+        let restored = match state.spill.lock().unwrap().as_mut() {
+            Some(spill) if !spill.is_empty() => spill.pop(HISTORY_PAGE),
+            _ => Ok(Vec::new()),
+        };
+        match restored {
+            Ok(page) if !page.is_empty() => {
+                println!("DEBUG: Restored {} spilled messages", page.len());
+                state
+                    .messages
+                    .lock()
+                    .unwrap()
+                    .splice(0..0, page.into_iter().map(from_spilled));
+                drop(state);
+                update_messages(chat_handle, app_state);
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Failed to restore spilled messages: {}", e),
+        }
+
+        let mut older = state.older_history.lock().unwrap();
+        if older.is_empty() {
+            return;
+        }
+        let start = older.len().saturating_sub(HISTORY_PAGE);
+        let page = older.split_off(start);
+        println!(
+            "DEBUG: Loading {} older messages, {} left",
+            page.len(),
+            older.len()
+        );
+        state.messages.lock().unwrap().splice(
+            0..0,
+            page.into_iter()
+                .map(|stored| to_chat_message(stored, state.current_node_id)),
+        );
+    }
+    update_messages(chat_handle, app_state);
+}
@@ -0,0 +1,165 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+// The .slint strings are translated by slint from translations/<lang>/LC_MESSAGES/p2p-chat.po,
+// bundled at build time. Messages made in Rust are looked up here instead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Language {
+    #[default]
+    System,
+    English,
+    Spanish,
+}
+
+impl Language {
+    pub fn from_index(index: i32) -> Self {
+        match index {
+            1 => Language::English,
+            2 => Language::Spanish,
+            _ => Language::System,
+        }
+    }
+
+    pub fn index(self) -> i32 {
+        match self {
+            Language::System => 0,
+            Language::English => 1,
+            Language::Spanish => 2,
+        }
+    }
+
+    // What System means on this machine. Anything we have no translation for is English.
+    fn resolve(self) -> Self {
+        match self {
+            Language::System => match sys_locale::get_locale() {
+                Some(locale) if locale.to_lowercase().starts_with("es") => Language::Spanish,
+                _ => Language::English,
+            },
+            language => language,
+        }
+    }
+
+    // The folder name under translations/.
+    fn code(self) -> &'static str {
+        match self {
+            Language::Spanish => "es",
+            _ => "",
+        }
+    }
+}
+
+static CURRENT: AtomicU8 = AtomicU8::new(0);
+
+fn current() -> Language {
+    match CURRENT.load(Ordering::Relaxed) {
+        2 => Language::Spanish,
+        _ => Language::English,
+    }
+}
+
+// Slint only has the bundled translations once a component exists, so this runs after the
+// windows are created. Messages already in the chat keep the language they were shown in.
+pub fn select(language: Language) {
+    let language = language.resolve();
+    CURRENT.store(language.index() as u8, Ordering::Relaxed);
+    if let Err(e) = slint::select_bundled_translation(language.code()) {
+        eprintln!("Failed to switch the UI to {:?}: {}", language, e);
+    }
+    println!("DEBUG: UI language is {:?}", language);
+}
+
+pub fn tr(text: &'static str) -> &'static str {
+    match current() {
+        Language::Spanish => spanish(text).unwrap_or(text),
+        _ => text,
+    }
+}
+
+// Like tr, then fills each {} with the next argument.
+pub fn trf(text: &'static str, args: &[&dyn Display]) -> String {
+    let mut parts = tr(text).split("{}");
+    let mut out = parts.next().unwrap_or_default().to_string();
+    let mut args = args.iter();
+    for part in parts {
+        if let Some(arg) = args.next() {
+            out.push_str(&arg.to_string());
+        }
+        out.push_str(part);
+    }
+    out
+}
+
+fn spanish(text: &str) -> Option<&'static str> {
+    Some(match text {
+        "✅ Successfully joined room!\n\nYou can share this room token with others:\n\nCOPY TOKEN FROM THE BUTTON AT THE TOPBAR\n\nInstructions: Select and copy the text between the brackets to share with others." => {
+            "✅ ¡Te has unido a la sala!\n\nPuedes compartir el token de esta sala con otras personas:\n\nCOPIA EL TOKEN CON EL BOTÓN DE LA BARRA SUPERIOR\n\nInstrucciones: selecciona y copia el texto entre corchetes para compartirlo."
+        }
+        "🎫 Room created successfully!\n\nShare this invitation token with others to join:\n\nCOPY TOKEN FROM THE BUTTON AT THE TOPBAR\n\nInstructions: Select and copy the text between the brackets to share with others." => {
+            "🎫 ¡Sala creada!\n\nComparte este token de invitación para que otros se unan:\n\nCOPIA EL TOKEN CON EL BOTÓN DE LA BARRA SUPERIOR\n\nInstrucciones: selecciona y copia el texto entre corchetes para compartirlo."
+        }
+        "🎉 {} joined the room" => "🎉 {} se ha unido a la sala",
+        "👋 {} disconnected" => "👋 {} se ha desconectado",
+        "Could not reconnect to this room: {}" => "No se pudo volver a conectar a esta sala: {}",
+        "🔗 The old invite link no longer works. Copy Token now gives you the new one." => {
+            "🔗 El enlace de invitación anterior ya no funciona. Copiar token te da ahora el nuevo."
+        }
+        "🔗 The room owner revoked an invite link. Nobody new can join with it." => {
+            "🔗 El propietario de la sala revocó un enlace de invitación. Ya nadie puede unirse con él."
+        }
+        "⭐ Saved {} to your contacts" => "⭐ {} guardado en tus contactos",
+        "📨 Invite sent to {}" => "📨 Invitación enviada a {}",
+        "📨 Could not reach {}. They need to have Chast open in a room to get invites." => {
+            "📨 No se pudo contactar con {}. Necesita tener Chast abierto en una sala para recibir invitaciones."
+        }
+        "📨 {} invited you to another room. Disconnect and join with this token:\n\n{}" => {
+            "📨 {} te ha invitado a otra sala. Desconéctate y únete con este token:\n\n{}"
+        }
+        "⚠ Someone else here is also called {}. They show up as {}, and others see you with a node id tag too." => {
+            "⚠ Aquí hay otra persona que también se llama {}. Aparece como {}, y los demás también te ven con una etiqueta de id de nodo."
+        }
+        "🚫 {} was banned from the room" => "🚫 {} fue expulsado de la sala de forma permanente",
+        "🚫 {} was kicked from the room" => "🚫 {} fue expulsado de la sala",
+        "🔇 Muted {}. Their messages are hidden until you unmute them." => {
+            "🔇 {} silenciado. Sus mensajes quedan ocultos hasta que le quites el silencio."
+        }
+        "🔈 Unmuted {}" => "🔈 {} ya no está silenciado",
+        "🚫 Blocked {}" => "🚫 {} bloqueado",
+        "✅ Unblocked {}" => "✅ {} desbloqueado",
+        "🚪 You let {} in" => "🚪 Dejaste entrar a {}",
+        "🚪 The room is full, {} was turned away" => "🚪 La sala está llena, {} no pudo entrar",
+        "🚪 You turned {} away" => "🚪 Rechazaste a {}",
+        "🚪 {} is knocking and wants to join" => "🚪 {} está llamando y quiere unirse",
+        "✓ Reconnected to the relay." => "✓ Conectado de nuevo al relay.",
+        "⚠ Lost the connection to the relay. Anyone without a direct path can't reach you until it's back." => {
+            "⚠ Se perdió la conexión con el relay. Quien no tenga una ruta directa no podrá contactarte hasta que vuelva."
+        }
+        "⚠ Lost the connection to the relay. 1 person connected through it can't reach you until it's back." => {
+            "⚠ Se perdió la conexión con el relay. 1 persona conectada a través de él no podrá contactarte hasta que vuelva."
+        }
+        "⚠ Lost the connection to the relay. {} people connected through it can't reach you until it's back." => {
+            "⚠ Se perdió la conexión con el relay. {} personas conectadas a través de él no podrán contactarte hasta que vuelva."
+        }
+        "🖼 Image" => "🖼 Imagen",
+        "🖼 Loading image..." => "🖼 Cargando imagen...",
+        "🖼 The image couldn't be fetched" => "🖼 No se pudo obtener la imagen",
+        "🔌 Reconnected to the room" => "🔌 Se ha vuelto a conectar a la sala",
+        "New messages" => "Mensajes nuevos",
+        "{} says: {}" => "{} dice: {}",
+        "{} joined" => "{} se ha unido",
+        "{} left" => "{} se ha ido",
+        "Refused {} from {}: that type of file isn't allowed" => {
+            "Rechazado {} de {}: ese tipo de archivo no está permitido"
+        }
+        "Saved {} to {}" => "{} guardado en {}",
+        "Couldn't download {}: {}" => "No se pudo descargar {}: {}",
+        "Untitled room" => "Sala sin nombre",
+        "🚪 Knocking… waiting for the room owner to let you in" => {
+            "🚪 Llamando… esperando a que el dueño de la sala te deje entrar"
+        }
+        _ => return None,
+    })
+}
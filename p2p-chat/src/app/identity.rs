@@ -0,0 +1,122 @@
+use anyhow::{Context, Result, anyhow, bail};
+use crypto_secretbox::{KeyInit, XSalsa20Poly1305, aead::Aead};
+use iroh::{NodeId, SecretKey};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use ticket::derive_key;
+
+use crate::app::{
+    app_state::AppState,
+    networking::{IDENTITY_FILE, load_or_create_secret_key, write_private},
+    types,
+};
+
+// An exported identity is this header, then the salt, nonce and the encrypted key.
+const MAGIC: &[u8] = b"chast identity v2\n";
+const EXTENSION: &str = "chastid";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const MIN_PASSWORD_CHARS: usize = 8;
+// The key being replaced by an import is kept here, in case it was the wrong file.
+const BACKUP_FILE: &str = "identity.key.bak";
+
+fn seal(secret_key: &SecretKey, password: &str) -> Result<Vec<u8>> {
+    let salt: [u8; SALT_LEN] = rand::random();
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let cipher = XSalsa20Poly1305::new(&derive_key(password, &salt).into());
+    let ciphertext = cipher
+        .encrypt(&nonce.into(), secret_key.to_bytes().as_slice())
+        .map_err(|_| anyhow!("Failed to encrypt identity"))?;
+    Ok([MAGIC, &salt[..], &nonce[..], &ciphertext[..]].concat())
+}
+
+fn open(bytes: &[u8], password: &str) -> Result<SecretKey> {
+    let rest = bytes
+        .strip_prefix(MAGIC)
+        .context("Not an exported identity")?;
+    if rest.len() < SALT_LEN + NONCE_LEN {
+        bail!("Identity file is damaged");
+    }
+    let (salt, rest) = rest.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let cipher = XSalsa20Poly1305::new(&derive_key(password, salt).into());
+    let plaintext = cipher
+        .decrypt(nonce.into(), ciphertext)
+        .map_err(|_| anyhow!("Wrong password for this identity"))?;
+    let bytes = <[u8; 32]>::try_from(plaintext.as_slice()).context("Identity file is damaged")?;
+    Ok(SecretKey::from_bytes(&bytes))
+}
+
+pub fn node_id() -> Result<NodeId> {
+    Ok(load_or_create_secret_key(IDENTITY_FILE)?.public())
+}
+
+// Writes identity.key, password protected, to a file of the user's choosing. Returns
+// None when the picker was cancelled.
+pub fn export(password: &str) -> Result<Option<PathBuf>> {
+    if password.chars().count() < MIN_PASSWORD_CHARS {
+        bail!(
+            "Use a password of at least {} characters",
+            MIN_PASSWORD_CHARS
+        );
+    }
+    let secret_key = load_or_create_secret_key(IDENTITY_FILE)?;
+    let Some(path) = rfd::FileDialog::new()
+        .add_filter("Chat identity", &[EXTENSION])
+        .set_file_name(&format!(
+            "{}.{}",
+            secret_key.public().fmt_short(),
+            EXTENSION
+        ))
+        .set_title("Export Identity")
+        .save_file()
+    else {
+        return Ok(None);
+    };
+    write_private(&path, seal(&secret_key, password)?)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    println!("DEBUG: Exported identity to {}", path.display());
+    Ok(Some(path))
+}
+
+// Replaces identity.key with one exported on another device. The endpoint holds on to
+// the key it was bound with, so this only works outside of a room.
+pub fn import(password: &str, app_state: &Arc<Mutex<AppState>>) -> Result<Option<NodeId>> {
+    if app_state.lock().unwrap().endpoint.is_some() {
+        bail!("Leave the room before importing an identity");
+    }
+    let Some(path) = rfd::FileDialog::new()
+        .add_filter("Chat identity", &[EXTENSION])
+        .set_title("Import Identity")
+        .pick_file()
+    else {
+        return Ok(None);
+    };
+    let bytes = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let secret_key = open(&bytes, password)?;
+
+    if Path::new(IDENTITY_FILE).exists() {
+        fs::read(IDENTITY_FILE)
+            .and_then(|current| write_private(BACKUP_FILE, current))
+            .context("Failed to back up the current identity")?;
+    }
+    write_private(IDENTITY_FILE, secret_key.to_bytes()).context("Failed to save the identity")?;
+    println!(
+        "DEBUG: Imported identity {} from {}",
+        secret_key.public(),
+        path.display()
+    );
+    Ok(Some(secret_key.public()))
+}
+
+// Runs on the UI thread when the settings dialog opens or the identity changes.
+pub fn show_settings(chat: &types::ChatWindow) {
+    chat.set_identity_available(true);
+    match node_id() {
+        Ok(id) => chat.set_identity_id(id.to_string().into()),
+        Err(e) => eprintln!("Failed to load identity: {:#}", e),
+    }
+}
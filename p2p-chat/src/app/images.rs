@@ -0,0 +1,592 @@
+use anyhow::{Result, anyhow, bail};
+use image::{
+    AnimationDecoder, DynamicImage, ImageDecoder, ImageFormat,
+    codecs::{gif::GifDecoder, jpeg::JpegEncoder},
+    imageops::FilterType,
+};
+use iroh::{
+    NodeId,
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler},
+};
+use messaging::{Message, MessageBody};
+use n0_future::time::{Duration, Instant, sleep};
+use slint::{Model, Rgba8Pixel, SharedPixelBuffer, SharedString, VecModel, Weak};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::Cursor,
+    sync::{Arc, Mutex},
+};
+
+use crate::app::{
+    app_state::AppState,
+    delivery::{self, DeliveryStatus},
+    history,
+    i18n::tr,
+    networking::display_chat_message,
+    types,
+    ui_handlers::{local_time, update_messages},
+};
+
+pub const IMAGE_ALPN: &[u8] = b"chast/image/0";
+const MAX_IMAGE_BYTES: usize = 10 * 1024 * 1024;
+// Pasted images are scaled down to fit this, which keeps screenshots well under the limit.
+const MAX_SIDE: u32 = 2048;
+// Every frame of a GIF is kept decoded, so long or large ones are cut short at this much memory.
+const MAX_ANIMATION_BYTES: usize = 128 * 1024 * 1024;
+const FRAME_TICK: Duration = Duration::from_millis(40);
+// What browsers use for GIFs that ask for no delay at all.
+const DEFAULT_FRAME_DELAY: Duration = Duration::from_millis(100);
+
+pub enum Picture {
+    Loading,
+    Ready(SharedPixelBuffer<Rgba8Pixel>),
+    Animated(Animation),
+    Failed,
+}
+
+impl Picture {
+    // The still picture, or the first frame of an animation.
+    pub fn first_frame(&self) -> Option<&SharedPixelBuffer<Rgba8Pixel>> {
+        match self {
+            Picture::Ready(pixels) => Some(pixels),
+            Picture::Animated(animation) => animation.frames.first().map(|(pixels, _)| pixels),
+            Picture::Loading | Picture::Failed => None,
+        }
+    }
+}
+
+// A GIF's frames with how long each stays up. Every copy plays from when it was decoded.
+pub struct Animation {
+    frames: Vec<(SharedPixelBuffer<Rgba8Pixel>, Duration)>,
+    length: Duration,
+    started: Instant,
+}
+
+impl Animation {
+    pub fn current(&self) -> usize {
+        let mut at = self.started.elapsed().as_millis() % self.length.as_millis().max(1);
+        for (index, (_, delay)) in self.frames.iter().enumerate() {
+            if at < delay.as_millis() {
+                return index;
+            }
+            at -= delay.as_millis();
+        }
+        0
+    }
+}
+
+// A pasted image waiting in the preview for Send or Cancel.
+pub struct Pasted {
+    bytes: Arc<Vec<u8>>,
+    pixels: SharedPixelBuffer<Rgba8Pixel>,
+}
+
+// Pictures in the chat by message id, and the encoded ones we posted, which anyone in the
+// room can fetch from us for as long as we stay.
+#[derive(Default)]
+pub struct Pictures {
+    shown: HashMap<u64, Picture>,
+    posted: HashMap<u64, Arc<Vec<u8>>>,
+    pasted: Option<Pasted>,
+    // Animations in the chat's images model: their index there, message id and the frame
+    // showing. Set each time the messages are rendered.
+    playing: Vec<(usize, u64, usize)>,
+    player_running: bool,
+}
+
+impl Pictures {
+    pub fn get(&self, message_id: &str) -> Option<&Picture> {
+        self.shown.get(&message_id.parse().ok()?)
+    }
+
+    pub fn set_playing(&mut self, playing: Vec<(usize, u64, usize)>) {
+        self.playing = playing;
+    }
+
+    // The frames that changed since the last call, by index in the images model.
+    fn advance(&mut self) -> Vec<(usize, SharedPixelBuffer<Rgba8Pixel>)> {
+        let mut changed = Vec::new();
+        for (index, id, shown) in &mut self.playing {
+            let Some(Picture::Animated(animation)) = self.shown.get(id) else {
+                continue;
+            };
+            let current = animation.current();
+            if current != *shown {
+                *shown = current;
+                changed.push((*index, animation.frames[current].0.clone()));
+            }
+        }
+        changed
+    }
+
+    // Leaving the room ends it for everyone fetching from us, so nothing is kept.
+    // The player stops by itself once no animation is left.
+    pub fn clear(&mut self) {
+        *self = Pictures {
+            player_running: self.player_running,
+            ..Pictures::default()
+        };
+    }
+}
+
+// What an image message says in place of the picture while there isn't one to show.
+pub fn caption(picture: &Picture) -> Option<&'static str> {
+    match picture {
+        Picture::Loading => Some(tr("🖼 Loading image...")),
+        Picture::Failed => Some(tr("🖼 The image couldn't be fetched")),
+        Picture::Ready(_) | Picture::Animated(_) => None,
+    }
+}
+
+// Decodes a posted PNG, JPEG or GIF. GIFs with more than one frame come out animated.
+pub fn decode(bytes: &[u8]) -> Result<Picture> {
+    // Keeps a small file from decoding into gigabytes of pixels.
+    let mut limits = image::Limits::default();
+    limits.max_image_width = Some(MAX_SIDE);
+    limits.max_image_height = Some(MAX_SIDE);
+
+    let mut reader = image::ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    match reader.format() {
+        Some(ImageFormat::Png | ImageFormat::Jpeg) => {
+            reader.limits(limits);
+            Ok(Picture::Ready(to_pixels(&reader.decode()?)))
+        }
+        Some(ImageFormat::Gif) => {
+            let mut decoder = GifDecoder::new(Cursor::new(bytes))?;
+            decoder.set_limits(limits)?;
+            let (width, height) = decoder.dimensions();
+            let frame_bytes = (width as usize * height as usize * 4).max(1);
+            let mut frames = Vec::new();
+            for frame in decoder
+                .into_frames()
+                .take((MAX_ANIMATION_BYTES / frame_bytes).max(1))
+            {
+                let frame = frame?;
+                let (numer, denom) = frame.delay().numer_denom_ms();
+                let mut delay = Duration::from_millis((numer / denom.max(1)) as u64);
+                if delay < Duration::from_millis(20) {
+                    delay = DEFAULT_FRAME_DELAY;
+                }
+                let rgba = frame.into_buffer();
+                let pixels =
+                    SharedPixelBuffer::clone_from_slice(rgba.as_raw(), rgba.width(), rgba.height());
+                frames.push((pixels, delay));
+            }
+            match frames.len() {
+                0 => bail!("the GIF has no frames"),
+                1 => Ok(Picture::Ready(frames.remove(0).0)),
+                _ => Ok(Picture::Animated(Animation {
+                    length: frames.iter().map(|(_, delay)| *delay).sum(),
+                    frames,
+                    started: Instant::now(),
+                })),
+            }
+        }
+        _ => bail!("not a PNG, JPEG or GIF image"),
+    }
+}
+
+// Swaps in the next frame of every animation on screen. Runs while any picture in the
+// room is animated.
+async fn play(app_state: Arc<Mutex<AppState>>, chat_handle: Weak<types::ChatWindow>) {
+    loop {
+        sleep(FRAME_TICK).await;
+        let changed = {
+            let state = app_state.lock().unwrap();
+            let mut pictures = state.pictures.lock().unwrap();
+            if !pictures
+                .shown
+                .values()
+                .any(|picture| matches!(picture, Picture::Animated(_)))
+            {
+                pictures.player_running = false;
+                break;
+            }
+            pictures.advance()
+        };
+        if changed.is_empty() {
+            continue;
+        }
+
+        let chat_handle = chat_handle.clone();
+        if let Err(e) = slint::invoke_from_event_loop(move || {
+            let Some(chat) = chat_handle.upgrade() else {
+                return;
+            };
+            let model = chat.get_images();
+            let Some(images) = model.as_any().downcast_ref::<VecModel<slint::Image>>() else {
+                return;
+            };
+            for (index, pixels) in changed {
+                if index < images.row_count() {
+                    images.set_row_data(index, slint::Image::from_rgba8(pixels));
+                }
+            }
+        }) {
+            eprintln!("Failed to show GIF frame: {:?}", e);
+            break;
+        }
+    }
+}
+
+// Shows a picture for a message, starting the player if it's the first animation.
+fn show_picture(
+    id: u64,
+    picture: Picture,
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+) {
+    let start_player = {
+        let state = app_state.lock().unwrap();
+        let mut pictures = state.pictures.lock().unwrap();
+        let animated = matches!(picture, Picture::Animated(_));
+        pictures.shown.insert(id, picture);
+        let start_player = animated && !pictures.player_running;
+        if start_player {
+            pictures.player_running = true;
+        }
+        start_player
+    };
+    if start_player {
+        tokio::spawn(play(app_state.clone(), chat_handle.clone()));
+    }
+}
+
+// The clipboard is read with the platform's own tools, the same way screenshots are taken.
+fn clipboard_png() -> Option<Vec<u8>> {
+    let tools: &[(&str, &[&str])] = &[
+        ("wl-paste", &["--no-newline", "--type", "image/png"]),
+        (
+            "xclip",
+            &["-selection", "clipboard", "-t", "image/png", "-o"],
+        ),
+        ("pngpaste", &["-"]),
+    ];
+    tools.iter().find_map(|(tool, args)| {
+        let output = std::process::Command::new(tool).args(*args).output().ok()?;
+        (output.status.success() && output.stdout.starts_with(b"\x89PNG")).then_some(output.stdout)
+    })
+}
+
+fn to_pixels(image: &DynamicImage) -> SharedPixelBuffer<Rgba8Pixel> {
+    let rgba = image.to_rgba8();
+    SharedPixelBuffer::clone_from_slice(rgba.as_raw(), rgba.width(), rgba.height())
+}
+
+// Screenshots stay PNG so text in them stays sharp; photos too big for that go as JPEG.
+fn prepare(png: &[u8]) -> Result<Pasted> {
+    let mut image = image::load_from_memory_with_format(png, ImageFormat::Png)?;
+    if image.width() > MAX_SIDE || image.height() > MAX_SIDE {
+        image = image.resize(MAX_SIDE, MAX_SIDE, FilterType::Triangle);
+    }
+    let mut bytes = Vec::new();
+    image.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)?;
+    if bytes.len() > MAX_IMAGE_BYTES {
+        bytes.clear();
+        JpegEncoder::new_with_quality(&mut bytes, 85).encode_image(&image.to_rgb8())?;
+    }
+    if bytes.len() > MAX_IMAGE_BYTES {
+        bail!("The image is too large to send");
+    }
+    Ok(Pasted {
+        bytes: Arc::new(bytes),
+        pixels: to_pixels(&image),
+    })
+}
+
+// Runs on the UI thread when Ctrl+V is pressed in the message box. Returns false when the
+// clipboard holds no image, so text gets pasted as usual.
+pub fn paste(chat: &types::ChatWindow, app_state: &Arc<Mutex<AppState>>) -> bool {
+    let Some(png) = clipboard_png() else {
+        return false;
+    };
+    match prepare(&png) {
+        Ok(pasted) => {
+            chat.set_paste_preview(slint::Image::from_rgba8(pasted.pixels.clone()));
+            chat.set_paste_preview_open(true);
+            app_state.lock().unwrap().pictures.lock().unwrap().pasted = Some(pasted);
+        }
+        Err(e) => eprintln!("Error pasting image: {}", e),
+    }
+    true
+}
+
+pub fn cancel_pasted(app_state: &Arc<Mutex<AppState>>) {
+    app_state.lock().unwrap().pictures.lock().unwrap().pasted = None;
+}
+
+// Posts the image waiting in the preview.
+pub async fn send_pasted(
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) -> Result<()> {
+    let pasted = {
+        let state = app_state.lock().unwrap();
+        state.pictures.lock().unwrap().pasted.take()
+    };
+    let Some(pasted) = pasted else {
+        return Ok(());
+    };
+    post(
+        pasted.bytes,
+        Picture::Ready(pasted.pixels),
+        app_state,
+        chat_handle,
+    )
+    .await
+}
+
+// Posts an already encoded image, such as a sticker, as it is. GIFs keep their frames.
+pub async fn send_file(
+    bytes: Vec<u8>,
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) -> Result<()> {
+    if bytes.len() > MAX_IMAGE_BYTES {
+        bail!("The image is too large to send");
+    }
+    let bytes = Arc::new(bytes);
+    let picture = {
+        let bytes = bytes.clone();
+        tokio::task::spawn_blocking(move || decode(&bytes)).await??
+    };
+    post(bytes, picture, app_state, chat_handle).await
+}
+
+async fn post(
+    bytes: Arc<Vec<u8>>,
+    picture: Picture,
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) -> Result<()> {
+    let (sender, node_id, username, has_peers, secret_key) = {
+        let state = app_state.lock().unwrap();
+        let has_peers = state
+            .names
+            .lock()
+            .unwrap()
+            .keys()
+            .any(|id| state.current_node_id != Some(*id));
+        (
+            state.sender.clone(),
+            state.current_node_id,
+            state.current_username.clone(),
+            has_peers,
+            state.secret_key(),
+        )
+    };
+    let (Some(sender), Some(node_id), Some(secret_key)) = (sender, node_id, secret_key) else {
+        return Err(anyhow!("Not connected to a room"));
+    };
+    if !app_state.lock().unwrap().can_post(&node_id) {
+        return Err(anyhow!(
+            "Only moderators can post in this announcement room"
+        ));
+    }
+    let Some(first_frame) = picture.first_frame() else {
+        bail!("There is no picture to post");
+    };
+
+    let id = rand::random::<u64>().max(1);
+    let (width, height) = (first_frame.width(), first_frame.height());
+    let size = bytes.len() as u32;
+    let new_message = types::ChatMessage {
+        username: SharedString::from(username.clone()),
+        content: SharedString::from(tr("🖼 Image")),
+        timestamp: SharedString::from(local_time(chrono::Utc::now())),
+        sender_id: SharedString::from(node_id.to_string()),
+        burst_id: 0,
+        message_id: SharedString::from(id.to_string()),
+        delivery: SharedString::from(DeliveryStatus::Pending.as_str()),
+        is_own: true,
+        is_system: false,
+        event: types::SystemEvent::None,
+        is_announcement: false,
+        is_action: false,
+        image_index: -1,
+        code_index: -1,
+        translation: Default::default(),
+        name_color: Default::default(),
+    };
+
+    {
+        let state = app_state.lock().unwrap();
+        state.pictures.lock().unwrap().posted.insert(id, bytes);
+        state.messages.lock().unwrap().push(new_message);
+        state.persister.record(&username, tr("🖼 Image"));
+    }
+    show_picture(id, picture, &app_state, &chat_handle);
+    history::spill_overflow(&app_state);
+    update_messages(&chat_handle, &app_state);
+
+    let msg = Message::new(MessageBody::Image {
+        from: node_id,
+        id,
+        width,
+        height,
+        size,
+    });
+    if let Err(e) = sender
+        .broadcast(msg.to_signed_vec(&secret_key, sender.topic()))
+        .await
+    {
+        delivery::set_status(&app_state, id, DeliveryStatus::Failed);
+        return Err(e);
+    }
+    println!(
+        "DEBUG: Posted a {}x{} image ({} bytes)",
+        width, height, size
+    );
+
+    if has_peers {
+        delivery::set_status(&app_state, id, DeliveryStatus::Sent);
+        n0_future::task::spawn(delivery::expire_unacked(id, app_state, chat_handle));
+    } else {
+        delivery::set_status(&app_state, id, DeliveryStatus::Failed);
+    }
+    Ok(())
+}
+
+// Someone posted an image: show its place in the chat right away and fetch it from them.
+pub fn handle_image(
+    from: NodeId,
+    id: u64,
+    size: u32,
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+) {
+    if app_state.lock().unwrap().current_node_id == Some(from) {
+        return;
+    }
+    app_state
+        .lock()
+        .unwrap()
+        .pictures
+        .lock()
+        .unwrap()
+        .shown
+        .insert(id, Picture::Loading);
+    display_chat_message(
+        from,
+        tr("🖼 Image").to_string(),
+        id,
+        false,
+        app_state,
+        chat_handle,
+    );
+
+    let app_state = app_state.clone();
+    let chat_handle = chat_handle.clone();
+    tokio::spawn(async move {
+        let picture = fetch(from, id, size, &app_state).await.unwrap_or_else(|e| {
+            eprintln!("Failed to fetch image from {}: {}", from.fmt_short(), e);
+            Picture::Failed
+        });
+        show_picture(id, picture, &app_state, &chat_handle);
+        update_messages(&chat_handle, &app_state);
+    });
+}
+
+async fn fetch(
+    from: NodeId,
+    id: u64,
+    size: u32,
+    app_state: &Arc<Mutex<AppState>>,
+) -> Result<Picture> {
+    if size as usize > MAX_IMAGE_BYTES {
+        bail!("the image is {} bytes, over the limit", size);
+    }
+    let endpoint = app_state
+        .lock()
+        .unwrap()
+        .endpoint
+        .clone()
+        .ok_or_else(|| anyhow!("Not connected to a room"))?;
+    let connection = endpoint.connect(from, IMAGE_ALPN).await?;
+    let (mut send, mut recv) = connection.open_bi().await?;
+    send.write_all(&id.to_be_bytes()).await?;
+    send.finish()?;
+
+    let mut len = [0u8; 4];
+    recv.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 {
+        bail!("they no longer have it");
+    }
+    if len > MAX_IMAGE_BYTES {
+        bail!("the image is {} bytes, over the limit", len);
+    }
+    let mut bytes = vec![0u8; len];
+    recv.read_exact(&mut bytes).await?;
+    connection.close(0u32.into(), b"done");
+
+    tokio::task::spawn_blocking(move || decode(&bytes)).await?
+}
+
+// Hands out the images we posted to anyone who asks for one by id.
+#[derive(Clone)]
+pub struct ImageProtocol {
+    app_state: Arc<Mutex<AppState>>,
+}
+
+impl ImageProtocol {
+    pub fn new(app_state: Arc<Mutex<AppState>>) -> Self {
+        Self { app_state }
+    }
+}
+
+impl fmt::Debug for ImageProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ImageProtocol").finish_non_exhaustive()
+    }
+}
+
+impl ProtocolHandler for ImageProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let peer = connection.remote_node_id()?;
+        if self
+            .app_state
+            .lock()
+            .unwrap()
+            .blocklist
+            .lock()
+            .unwrap()
+            .is_blocked(&peer)
+        {
+            return Err(AcceptError::NotAllowed {});
+        }
+
+        if let Err(e) = serve_image(&connection, &self.app_state).await {
+            println!(
+                "DEBUG: Sending an image to {} failed: {}",
+                peer.fmt_short(),
+                e
+            );
+        }
+        connection.closed().await;
+        Ok(())
+    }
+}
+
+async fn serve_image(connection: &Connection, app_state: &Arc<Mutex<AppState>>) -> Result<()> {
+    let (mut send, mut recv) = connection.accept_bi().await?;
+    let mut id = [0u8; 8];
+    recv.read_exact(&mut id).await?;
+    let id = u64::from_be_bytes(id);
+    let bytes = {
+        let state = app_state.lock().unwrap();
+        let pictures = state.pictures.lock().unwrap();
+        pictures.posted.get(&id).cloned()
+    };
+
+    // A length of zero tells them we don't have it.
+    let len = bytes.as_ref().map_or(0, |bytes| bytes.len() as u32);
+    send.write_all(&len.to_be_bytes()).await?;
+    if let Some(bytes) = bytes {
+        send.write_all(&bytes).await?;
+    }
+    send.finish()?;
+    Ok(())
+}
@@ -0,0 +1,131 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use crate::app::app_state::AppState;
+
+// Kept next to contacts.json, but covers everyone we've met, not just saved contacts.
+const LAST_SEEN_FILE: &str = "last_seen.json";
+// Every message bumps the timestamp; the file only needs to be roughly current.
+const SAVE_EVERY: chrono::TimeDelta = chrono::TimeDelta::minutes(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sighting {
+    pub name: String,
+    // Topic of the room we last saw them in.
+    pub room: String,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub struct LastSeen {
+    path: PathBuf,
+    seen: HashMap<NodeId, Sighting>,
+    saved_at: DateTime<Utc>,
+}
+
+impl LastSeen {
+    pub fn load() -> Self {
+        Self::load_from(LAST_SEEN_FILE)
+    }
+
+    pub fn load_from(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let seen = match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+                eprintln!("Failed to parse {}: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Self {
+            path,
+            seen,
+            saved_at: Utc::now(),
+        }
+    }
+
+    pub fn record(&mut self, node_id: NodeId, name: &str, room: &str) {
+        let now = Utc::now();
+        let moved = self
+            .seen
+            .get(&node_id)
+            .is_none_or(|sighting| sighting.name != name || sighting.room != room);
+        self.seen.insert(
+            node_id,
+            Sighting {
+                name: name.to_string(),
+                room: room.to_string(),
+                at: now,
+            },
+        );
+        if moved || now - self.saved_at >= SAVE_EVERY {
+            self.flush();
+        }
+    }
+
+    // People last seen in `room` who aren't in `online`, most recent first.
+    pub fn offline_in(
+        &self,
+        room: &str,
+        online: &HashMap<NodeId, String>,
+    ) -> Vec<(NodeId, &Sighting)> {
+        let mut offline: Vec<_> = self
+            .seen
+            .iter()
+            .filter(|(id, sighting)| sighting.room == room && !online.contains_key(*id))
+            .map(|(id, sighting)| (*id, sighting))
+            .collect();
+        offline.sort_by_key(|(_, sighting)| std::cmp::Reverse(sighting.at));
+        offline
+    }
+
+    pub fn flush(&mut self) {
+        self.saved_at = Utc::now();
+        if let Err(e) = self.save() {
+            eprintln!("Failed to save last seen times: {}", e);
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        let data = serde_json::to_string_pretty(&self.seen)?;
+        fs::write(&self.path, data)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+pub fn format_ago(at: DateTime<Utc>) -> String {
+    let elapsed = Utc::now() - at;
+    if elapsed.num_minutes() < 1 {
+        "last seen just now".to_string()
+    } else if elapsed.num_hours() < 1 {
+        format!("last seen {}m ago", elapsed.num_minutes())
+    } else if elapsed.num_days() < 1 {
+        format!("last seen {}h ago", elapsed.num_hours())
+    } else {
+        format!("last seen {}d ago", elapsed.num_days())
+    }
+}
+
+// Notes that `from` just said something in the current room.
+pub fn saw(app_state: &Arc<Mutex<AppState>>, from: NodeId, name: Option<&str>) {
+    let state = app_state.lock().unwrap();
+    if state.current_node_id == Some(from) {
+        return;
+    }
+    let Some(room) = state.room_topic.map(|topic| topic.to_string()) else {
+        return;
+    };
+    let names = state.names.lock().unwrap();
+    let Some(name) = name.or_else(|| names.get(&from).map(String::as_str)) else {
+        return;
+    };
+    state.last_seen.lock().unwrap().record(from, name, &room);
+}
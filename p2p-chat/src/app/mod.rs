@@ -1,7 +1,53 @@
+#[cfg(not(target_arch = "wasm32"))]
+pub mod accounts;
+pub mod admission;
+#[allow(clippy::module_inception)]
 pub mod app;
 pub mod app_state;
+pub mod appearance;
+pub mod blocklist;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod audio;
+pub mod calls;
+pub mod code_blocks;
+pub mod connection;
+pub mod contacts;
+pub mod delivery;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod device_sync;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod files;
+pub mod flood;
+pub mod history;
+pub mod i18n;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod identity;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod images;
+pub mod last_seen;
+pub mod network_settings;
 pub mod networking;
+pub mod notifications;
+pub mod paths;
+pub mod persistence;
+pub mod presence;
+pub mod privacy;
+pub mod recent_rooms;
+pub mod recovery;
 pub mod room_handlers;
+pub mod runtime;
 pub mod save;
+pub mod saved_chats;
+pub mod scheduled;
+pub mod settings;
+pub mod speech;
+pub mod spellcheck;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod stickers;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod screen_share;
+pub mod translate;
 pub mod types;
 pub mod ui_handlers;
+pub mod window_state;
+pub mod word_filter;
@@ -0,0 +1,86 @@
+use iroh::endpoint::TransportConfig;
+use iroh_gossip::{
+    net::{Builder, Gossip},
+    proto::{HyparviewConfig, PlumtreeConfig},
+};
+use n0_future::time::Duration;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+// In small-room mode every peer keeps a direct link to up to this many others, so rooms
+// below that size are a full mesh and each message goes straight to everyone.
+const SMALL_ROOM_LINKS: usize = 16;
+
+// How this node reaches other peers. The endpoint is built when a room is opened or
+// joined, so changes apply from the next room on.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkSettings {
+    pub transport: Transport,
+    // Looks up relays and peers' published addresses through this server instead of the
+    // system resolver, e.g. "1.1.1.1:53" where local DNS is filtered.
+    pub dns_server: Option<SocketAddr>,
+    // Trades bandwidth for delivery in private rooms of a handful of people.
+    pub small_room: bool,
+    // Seconds between QUIC keep-alive pings, iroh's default of 1 when unset. Only worth
+    // raising to save traffic; NATs that drop idle mappings need it short.
+    pub keep_alive_secs: Option<u64>,
+    // Seconds without hearing from a peer before the connection is given up, 30 when
+    // unset. Raise it on networks with long outages to ride them out.
+    pub idle_timeout_secs: Option<u64>,
+    // Pads every message to a few fixed sizes and sends a dummy one now and then, so
+    // relays can't tell from sizes and timing what was sent or when anyone is talking.
+    pub cover_traffic: bool,
+}
+
+impl NetworkSettings {
+    // None leaves iroh's own transport settings alone.
+    pub fn transport_config(&self) -> Option<TransportConfig> {
+        if self.keep_alive_secs.is_none() && self.idle_timeout_secs.is_none() {
+            return None;
+        }
+        let mut config = TransportConfig::default();
+        config.keep_alive_interval(Some(Duration::from_secs(
+            self.keep_alive_secs.unwrap_or(1).max(1),
+        )));
+        if let Some(secs) = self.idle_timeout_secs {
+            match Duration::from_secs(secs).try_into() {
+                Ok(timeout) => {
+                    config.max_idle_timeout(Some(timeout));
+                }
+                Err(_) => eprintln!("idle_timeout_secs {} is too large, ignoring it", secs),
+            }
+        }
+        Some(config)
+    }
+
+    pub fn gossip(&self) -> Builder {
+        if !self.small_room {
+            return Gossip::builder();
+        }
+        Gossip::builder()
+            .membership_config(HyparviewConfig {
+                active_view_capacity: SMALL_ROOM_LINKS,
+                passive_view_capacity: SMALL_ROOM_LINKS * 2,
+                ..Default::default()
+            })
+            .broadcast_config(PlumtreeConfig {
+                // Kept longer so a peer that missed a message can still graft it in.
+                message_cache_retention: Duration::from_secs(120),
+                message_id_retention: Duration::from_secs(300),
+                ..Default::default()
+            })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    // Direct QUIC over UDP where hole punching works, the relay's HTTPS connection
+    // everywhere else.
+    #[default]
+    Auto,
+    // QUIC over UDP only, the relay is never used. Handy for testing hole punching,
+    // but peers behind strict NATs can't be reached at all.
+    Direct,
+}
@@ -1,62 +1,241 @@
 use anyhow::Result;
 use futures_lite::StreamExt;
-use iroh::{Endpoint, NodeId, protocol::Router};
-use iroh_gossip::{api::Event, api::GossipReceiver, net::Gossip, proto::TopicId};
+use iroh::{Endpoint, NodeId, Watcher, protocol::Router};
+use iroh_gossip::{api::Event, api::GossipReceiver, proto::TopicId};
 use messaging::*;
 use slint::{SharedString, Weak};
 use std::sync::{Arc, Mutex};
 use ticket::*;
 
 use crate::app::{
+    admission::{self, AdmissionProtocol},
     app_state::AppState,
-    types,
-    ui_handlers::{handle_user_connect, handle_user_disconnect, update_messages},
+    calls::{self, VoiceProtocol},
+    connection,
+    contacts::{self, InviteProtocol},
+    delivery::{self, AckProtocol, DeliveryStatus},
+    flood::{BURST_THRESHOLD, Burst, FloodGuard},
+    history,
+    i18n::{tr, trf},
+    last_seen,
+    network_settings::{NetworkSettings, Transport},
+    notifications, paths,
+    persistence::Persister,
+    presence,
+    privacy::RoomSender,
+    recent_rooms,
+    recovery::{self, Outbox, ResendProtocol, Seen, SeqTracker},
+    room_handlers::update_room_info,
+    scheduled::Schedule,
+    speech, translate, types,
+    ui_handlers::{
+        handle_user_connect, handle_user_disconnect, local_time, push_system_message,
+        update_messages,
+    },
 };
+#[cfg(not(target_arch = "wasm32"))]
+use crate::app::{files, images, screen_share};
 
 const DEFAULT_RELAY_URL: &str = "https://relay.iroh.link";
+const CLIENT_VERSION: &str = concat!("chast/", env!("CARGO_PKG_VERSION"));
+const HISTORY_TIMEOUT: n0_future::time::Duration = n0_future::time::Duration::from_secs(30);
+// How long to wait for our own direct addresses when the relay is turned off.
+const DIRECT_ADDR_WAIT: n0_future::time::Duration = n0_future::time::Duration::from_secs(5);
+#[cfg(not(target_arch = "wasm32"))]
+pub const IDENTITY_FILE: &str = "identity.key";
+
+// Contacts are keyed by NodeId, so the desktop app keeps its key between runs.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_or_create_secret_key(path: &str) -> Result<iroh::SecretKey> {
+    if let Ok(bytes) = std::fs::read(path)
+        && let Ok(bytes) = <[u8; 32]>::try_from(bytes.as_slice())
+    {
+        return Ok(iroh::SecretKey::from_bytes(&bytes));
+    }
+
+    let secret_key = iroh::SecretKey::from_bytes(&rand::random());
+    write_private(path, secret_key.to_bytes())?;
+    println!("DEBUG: Created new key in {}", path);
+    Ok(secret_key)
+}
+
+// Writes a file only we can read, for secret keys and whatever holds one.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn write_private(
+    path: impl AsRef<std::path::Path>,
+    contents: impl AsRef<[u8]>,
+) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    // The mode only applies to a new file, so one written before is tightened as well.
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    std::io::Write::write_all(&mut file, contents.as_ref())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn bind_endpoint(network: &NetworkSettings) -> Result<Endpoint> {
+    let builder = Endpoint::builder()
+        .secret_key(load_or_create_secret_key(IDENTITY_FILE)?)
+        .discovery_n0();
+    let builder = match network.transport {
+        Transport::Auto => builder,
+        Transport::Direct => builder.relay_mode(iroh::RelayMode::Disabled),
+    };
+    let builder = match network.transport_config() {
+        Some(config) => builder.transport_config(config),
+        None => builder,
+    };
+    let builder = match network.dns_server {
+        Some(server) => builder.dns_resolver(iroh::dns::DnsResolver::with_nameserver(server)),
+        None => builder,
+    };
+    Ok(builder.bind().await?)
+}
+
+// Browsers can't open UDP sockets, so all traffic rides the relay's WebSocket transport
+// and the ticket we hand out only carries our relay URL.
+#[cfg(target_arch = "wasm32")]
+async fn bind_endpoint(_network: &NetworkSettings) -> Result<Endpoint> {
+    Ok(Endpoint::builder()
+        .discovery_n0()
+        .relay_mode(iroh::RelayMode::Default)
+        .bind()
+        .await?)
+}
+
+// The browser build has no audio, so it can't take calls, and can't fetch files either.
+// Everyone reads both wire codecs.
+pub fn local_capabilities() -> Capabilities {
+    #[cfg(not(target_arch = "wasm32"))]
+    return Capabilities::VOICE
+        .union(Capabilities::FILES)
+        .union(Capabilities::COMPACT)
+        .union(Capabilities::ACKS);
+    #[cfg(target_arch = "wasm32")]
+    return Capabilities::COMPACT.union(Capabilities::ACKS);
+}
+
+fn about_me(from: NodeId, name: String, invite: Option<Invite>) -> MessageBody {
+    MessageBody::AboutMe {
+        from,
+        name,
+        client: ClientInfo {
+            version: CLIENT_VERSION.to_string(),
+            capabilities: local_capabilities(),
+            protocol: PROTOCOL_VERSION,
+        },
+        invite,
+    }
+}
 
 pub async fn setup_networking(
     ticket: Option<Ticket>,
     username: String,
-) -> Result<(
-    iroh_gossip::api::GossipSender,
-    GossipReceiver,
-    Endpoint,
-    Router,
-    Ticket,
-)> {
-    let (topic, nodes) = match ticket {
-        Some(Ticket { topic, nodes }) => {
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) -> Result<(RoomSender, GossipReceiver, Endpoint, Router, Ticket)> {
+    let (topic, nodes, gatekeeper, invite) = match ticket {
+        Some(Ticket {
+            topic,
+            nodes,
+            owner,
+            capacity,
+            knock,
+            invite,
+            ..
+        }) => {
             println!("> joining chat room for topic {topic}");
-            (topic, nodes)
+            let gatekeeper = owner.filter(|_| capacity.is_some() || knock);
+            (topic, nodes, gatekeeper.map(|owner| (owner, knock)), invite)
         }
         None => {
             let topic = TopicId::from_bytes(rand::random());
             println!("> opening chat room for topic {topic}");
-            (topic, vec![])
+            (topic, vec![], None, None)
         }
     };
 
-    let endpoint = Endpoint::builder().discovery_n0().bind().await?;
+    let network = app_state
+        .lock()
+        .unwrap()
+        .settings
+        .lock()
+        .unwrap()
+        .network
+        .clone();
+    let endpoint = bind_endpoint(&network).await?;
     println!("> our node id: {}", endpoint.node_id());
 
-    let gossip = Gossip::builder().spawn(endpoint.clone());
+    let gossip = network.gossip().spawn(endpoint.clone());
     let router = Router::builder(endpoint.clone())
         .accept(iroh_gossip::ALPN, gossip.clone())
-        .spawn();
+        .accept(
+            calls::VOICE_ALPN,
+            VoiceProtocol::new(app_state.clone(), chat_handle.clone()),
+        )
+        .accept(
+            contacts::INVITE_ALPN,
+            InviteProtocol::new(app_state.clone(), chat_handle.clone()),
+        )
+        .accept(
+            admission::ADMISSION_ALPN,
+            AdmissionProtocol::new(app_state.clone(), chat_handle.clone()),
+        )
+        .accept(
+            recovery::RESEND_ALPN,
+            ResendProtocol::new(app_state.clone()),
+        )
+        .accept(
+            receipts::ACK_ALPN,
+            AckProtocol::new(app_state.clone(), chat_handle.clone()),
+        );
+    #[cfg(not(target_arch = "wasm32"))]
+    let router = router
+        .accept(
+            screen_share::SCREEN_ALPN,
+            screen_share::ScreenShareProtocol::new(app_state.clone()),
+        )
+        .accept(
+            images::IMAGE_ALPN,
+            images::ImageProtocol::new(app_state.clone()),
+        )
+        .accept(files::FILE_ALPN, files::FileProtocol::new(app_state));
+    let router = router.spawn();
 
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    n0_future::time::sleep(n0_future::time::Duration::from_millis(500)).await;
 
     let ticket = {
         let mut node_addr = iroh::NodeAddr::new(endpoint.node_id());
 
-        if let Some(_discovery) = endpoint.discovery() {
+        if network.transport == Transport::Direct {
+            // Without a relay, the ticket has to carry addresses peers can dial directly.
+            let mut watcher = endpoint.node_addr();
+            match n0_future::time::timeout(DIRECT_ADDR_WAIT, watcher.initialized()).await {
+                Ok(addr) => node_addr = addr,
+                Err(_) => eprintln!("No direct addresses found, the ticket may not be reachable"),
+            }
+        } else if let Some(_discovery) = endpoint.discovery() {
             let relay_url = DEFAULT_RELAY_URL.parse().expect("Invalid relay URL");
             node_addr = node_addr.with_relay_url(relay_url);
         }
 
         let nodes = vec![node_addr];
-        Ticket { topic, nodes }
+        Ticket {
+            topic,
+            nodes,
+            moderators: Vec::new(),
+            owner: None,
+            info: RoomInfo::default(),
+            capacity: None,
+            knock: false,
+            incognito: false,
+            locked: None,
+            invite: None,
+        }
     };
     println!("> ticket to join us: {ticket}");
 
@@ -67,16 +246,26 @@ pub async fn setup_networking(
             endpoint.add_node_addr(node)?;
         }
 
+        if let Some((owner, knock)) = gatekeeper
+            && let Err(e) =
+                admission::request_admission(&endpoint, owner, username.clone(), knock).await
+        {
+            if let Err(e) = router.shutdown().await {
+                eprintln!("Error shutting down router: {}", e);
+            }
+            return Err(e);
+        }
+
         let (sender, receiver) = gossip.subscribe_and_join(topic, node_ids).await?.split();
+        let sender = RoomSender::new(sender, topic, network.cover_traffic);
         println!("> connected!");
         println!("DEBUG: About to send AboutMe message");
 
-        let message = Message::new(MessageBody::AboutMe {
-            from: endpoint.node_id(),
-            name: username,
-        });
+        let message = Message::new(about_me(endpoint.node_id(), username, invite));
         println!("DEBUG: Created AboutMe message, about to broadcast");
-        sender.broadcast(message.to_vec().into()).await?;
+        sender
+            .broadcast(message.to_signed_vec(endpoint.secret_key(), sender.topic()))
+            .await?;
         println!("DEBUG: AboutMe message broadcast complete");
 
         Ok((sender, receiver, endpoint, router, ticket))
@@ -84,264 +273,1049 @@ pub async fn setup_networking(
         println!("> creating new room, subscribing to topic...");
         let subscription = gossip.subscribe(topic, vec![]).await?;
         let (sender, receiver) = subscription.split();
+        let sender = RoomSender::new(sender, topic, network.cover_traffic);
         println!("> connected!");
         println!("DEBUG: About to send AboutMe message");
 
-        let message = Message::new(MessageBody::AboutMe {
-            from: endpoint.node_id(),
-            name: username,
-        });
+        let message = Message::new(about_me(endpoint.node_id(), username, invite));
         println!("DEBUG: Created AboutMe message, about to broadcast");
-        sender.broadcast(message.to_vec().into()).await?;
+        sender
+            .broadcast(message.to_signed_vec(endpoint.secret_key(), sender.topic()))
+            .await?;
         println!("DEBUG: AboutMe message broadcast complete");
 
         Ok((sender, receiver, endpoint, router, ticket))
     }
 }
 
-pub async fn handle_messages(
-    mut receiver: GossipReceiver,
-    chat_handle: Weak<types::ChatWindow>,
-    app_state: Arc<Mutex<AppState>>,
-) -> Result<()> {
-    loop {
+// Shows the room's archive, from whichever history node answered first.
+fn apply_history(
+    messages: Vec<StoredMessage>,
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+) {
+    if app_state.lock().unwrap().history_loaded {
+        println!("DEBUG: Ignoring another copy of the room history");
+        return;
+    }
+    app_state.lock().unwrap().history_loaded = true;
+    println!(
+        "DEBUG: Received message history with {} messages",
+        messages.len()
+    );
+
+    let message_count = history::load_history(messages, app_state);
+
+    if message_count > 0 {
+        let system_message = types::ChatMessage {
+            username: SharedString::from("System"),
+            content: SharedString::from(format!(
+                "--- Loaded {} messages from history ---",
+                message_count
+            )),
+            timestamp: SharedString::from(local_time(chrono::Utc::now())),
+            sender_id: SharedString::new(),
+            burst_id: 0,
+            message_id: SharedString::new(),
+            delivery: SharedString::new(),
+            is_own: false,
+            is_system: true,
+            event: types::SystemEvent::History,
+            is_announcement: false,
+            is_action: false,
+            image_index: -1,
+            code_index: -1,
+            translation: Default::default(),
+            name_color: Default::default(),
+        };
+
         {
             let state = app_state.lock().unwrap();
-            if state.sender.is_none() {
-                println!("DEBUG: Sender is None, stopping message handling");
-                break;
+            let mut chat_messages = state.messages.lock().unwrap();
+            chat_messages.push(system_message);
+        }
+    }
+
+    update_messages(chat_handle, app_state);
+    println!("DEBUG: Message history loaded and displayed");
+}
+
+// Asks a history node for the archive over a direct connection, so it isn't gossiped to
+// everyone each time someone joins.
+fn request_history(
+    from: NodeId,
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+) {
+    let Some(endpoint) = app_state.lock().unwrap().endpoint.clone() else {
+        return;
+    };
+    let app_state = app_state.clone();
+    let chat_handle = chat_handle.clone();
+    n0_future::task::spawn(async move {
+        println!("DEBUG: Asking {} for the room history", from.fmt_short());
+        let fetch = messaging::history::fetch_history(&endpoint, from, |received, total| {
+            show_history_progress(received, total, &chat_handle);
+        });
+        let result = n0_future::time::timeout(HISTORY_TIMEOUT, fetch).await;
+        show_history_progress(0, 0, &chat_handle);
+        match result {
+            Ok(Ok(messages)) => apply_history(messages, &app_state, &chat_handle),
+            Ok(Err(e)) => eprintln!("Failed to fetch history from {}: {}", from.fmt_short(), e),
+            Err(_) => eprintln!("Timed out fetching history from {}", from.fmt_short()),
+        }
+    });
+}
+
+// "Loading history 3/10" next to the connection status while a big archive comes in;
+// 0 of 0 hides it.
+fn show_history_progress(received: u32, total: u32, chat_handle: &Weak<types::ChatWindow>) {
+    // An archive that fits in one chunk arrives too quickly to be worth showing.
+    if total == 1 {
+        return;
+    }
+    let chat_handle = chat_handle.clone();
+    if let Err(e) = slint::invoke_from_event_loop(move || {
+        if let Some(chat) = chat_handle.upgrade() {
+            chat.set_history_chunk(received as i32);
+            chat.set_history_chunks(total as i32);
+        }
+    }) {
+        eprintln!("Failed to show history progress: {:?}", e);
+    }
+}
+
+// Reads the room's gossip until we leave the room or the stream dies.
+pub async fn handle_messages(
+    receiver: GossipReceiver,
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) {
+    let room = app_state.lock().unwrap().room_generation;
+    #[cfg(feature = "chaos")]
+    let receiver = messaging::chaos::stream(receiver);
+    let mut receiver = std::pin::pin!(receiver);
+    while let Some(event) = receiver.next().await {
+        if app_state.lock().unwrap().room_generation != room {
+            return;
+        }
+        match event {
+            Ok(event) => handle_event(event, &app_state, &chat_handle).await,
+            Err(e) => {
+                eprintln!("Error receiving message: {}", e);
+                connection::lost(&e.to_string(), &app_state, &chat_handle);
             }
         }
+    }
+    // The gossip stream ending while we're still in the room means we've been cut off.
+    if app_state.lock().unwrap().room_generation == room {
+        connection::lost("gossip stream closed", &app_state, &chat_handle);
+    }
+    println!("DEBUG: Stopped reading the room");
+}
 
-        match receiver.try_next().await {
-            Ok(Some(event)) => {
-                if let Event::Received(msg) = event {
-                    match Message::from_bytes(&msg.content)?.body {
-                        MessageBody::AboutMe { from, name } => {
-                            let is_new_user = {
-                                let state = app_state.lock().unwrap();
-                                let names = state.names.lock().unwrap();
-                                !names.contains_key(&from)
-                            };
-
-                            {
-                                let state = app_state.lock().unwrap();
-                                let mut names = state.names.lock().unwrap();
-                                names.insert(from, name.clone());
-                            }
-
-                            if is_new_user {
-                                handle_user_connect(&chat_handle, &app_state, &name);
-
-                                let (sender, current_node_id, current_username) = {
-                                    let state = app_state.lock().unwrap();
-                                    (
-                                        state.sender.clone(),
-                                        state.current_node_id,
-                                        state.current_username.clone(),
-                                    )
-                                };
-
-                                if let (Some(sender), Some(current_node_id)) =
-                                    (sender, current_node_id)
-                                {
-                                    let response_message = Message::new(MessageBody::AboutMe {
-                                        from: current_node_id,
-                                        name: current_username,
-                                    });
-
-                                    if let Err(e) =
-                                        sender.broadcast(response_message.to_vec().into()).await
-                                    {
-                                        eprintln!("Failed to send AboutMe response: {}", e);
-                                    } else {
-                                        println!(
-                                            "DEBUG: Sent AboutMe response to new user {}",
-                                            name
-                                        );
-                                    }
-                                }
-                            }
-
-                            crate::app::ui_handlers::update_online_users(&chat_handle, &app_state);
-
-                            println!("> {} is now known as {}", from.fmt_short(), name);
-                        }
-                        MessageBody::Disconnect { from, name } => {
-                            {
-                                let state = app_state.lock().unwrap();
-                                let mut names = state.names.lock().unwrap();
-                                names.remove(&from);
-                            }
+// Applies one gossip event to the room.
+pub async fn handle_event(
+    event: Event,
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+) {
+    let msg = match event {
+        Event::Received(msg) => msg,
+        Event::NeighborUp(peer) => {
+            connection::neighbor_up(peer, app_state, chat_handle);
+            paths::watch(peer, app_state, chat_handle);
+            return;
+        }
+        Event::NeighborDown(peer) => {
+            connection::neighbor_down(peer, app_state, chat_handle);
+            return;
+        }
+        _ => return,
+    };
+    let Some(topic) = app_state.lock().unwrap().room_topic else {
+        return;
+    };
+    let message = match Message::from_signed_bytes(&msg.content, topic.as_bytes()) {
+        Ok(message) => message,
+        Err(e) => {
+            println!("DEBUG: Dropped unverified message: {}", e);
+            return;
+        }
+    };
+    if app_state
+        .lock()
+        .unwrap()
+        .blocklist
+        .lock()
+        .unwrap()
+        .drops(&message.body)
+    {
+        return;
+    }
+    if let Some(from) = message.body.sender()
+        && app_state.lock().unwrap().banned.contains(&from)
+    {
+        println!("DEBUG: Dropped message from banned {}", from.fmt_short());
+        return;
+    }
+    if !app_state
+        .lock()
+        .unwrap()
+        .seen
+        .lock()
+        .unwrap()
+        .admit(&message)
+    {
+        println!("DEBUG: Dropped duplicate or replayed message");
+        return;
+    }
+    if let Some(from) = message.body.sender()
+        && !app_state.lock().unwrap().is_admitted(&from)
+    {
+        println!("DEBUG: Ignored {}, who wasn't let in", from.fmt_short());
+        if let MessageBody::AboutMe { name, invite, .. } = &message.body {
+            turn_away(from, name, *invite, "no answer to a knock", app_state).await;
+        }
+        return;
+    }
+    let body = message.body;
+    if let Some(from) = body.sender() {
+        let name = match &body {
+            MessageBody::AboutMe { name, .. } => Some(name.as_str()),
+            _ => None,
+        };
+        last_seen::saw(app_state, from, name);
+    }
 
-                            handle_user_disconnect(&chat_handle, &app_state, &name);
+    match body {
+        MessageBody::AboutMe {
+            from,
+            name,
+            client,
+            invite,
+        } => {
+            let (problem, full) = {
+                let state = app_state.lock().unwrap();
+                let is_new_user = !state.names.lock().unwrap().contains_key(&from);
+                let problem = is_new_user
+                    .then(|| state.invite_problem(&from, invite.as_ref()))
+                    .flatten();
+                (problem, is_new_user && state.over_capacity(&from).is_some())
+            };
+            // A bad invite keeps them out for good, a full room only until a seat frees up.
+            if let Some(problem) = problem {
+                app_state.lock().unwrap().banned.insert(from);
+                turn_away(from, &name, invite, problem, app_state).await;
+                return;
+            }
+            if full {
+                turn_away(from, &name, invite, "the room already full", app_state).await;
+                return;
+            }
+            // Anyone can claim to keep history; we only ask the nodes our ticket names.
+            let keeps_history = client.capabilities.contains(Capabilities::HISTORY)
+                && app_state.lock().unwrap().room_nodes.contains(&from);
+            app_state
+                .lock()
+                .unwrap()
+                .clients
+                .lock()
+                .unwrap()
+                .insert(from, client);
+            let (is_new_user, shown, clashes_with_me) = {
+                let state = app_state.lock().unwrap();
+                let is_new_user = !state.names.lock().unwrap().contains_key(&from);
+                let shown = state.assign_name(from, &name);
+                (is_new_user, shown, name == state.current_username)
+            };
 
-                            println!("> {} ({}) disconnected", name, from.fmt_short());
+            if is_new_user && keeps_history && !app_state.lock().unwrap().history_loaded {
+                request_history(from, app_state, chat_handle);
+            }
+            if is_new_user {
+                handle_user_connect(chat_handle, app_state, &shown);
+                if clashes_with_me {
+                    push_system_message(
+                        app_state,
+                        trf(
+                            "⚠ Someone else here is also called {}. They show up as {}, and others see you with a node id tag too.",
+                            &[&name, &shown],
+                        ),
+                    );
+                    update_messages(chat_handle, app_state);
+                }
+
+                let (sender, current_node_id, current_username, secret_key, invite) = {
+                    let state = app_state.lock().unwrap();
+                    (
+                        state.sender.clone(),
+                        state.current_node_id,
+                        state.current_username.clone(),
+                        state.secret_key(),
+                        state.room_invite,
+                    )
+                };
+
+                if let (Some(sender), Some(current_node_id), Some(secret_key)) =
+                    (sender, current_node_id, secret_key)
+                {
+                    let response_message =
+                        Message::new(about_me(current_node_id, current_username, invite));
+
+                    if let Err(e) = sender
+                        .broadcast(response_message.to_signed_vec(&secret_key, sender.topic()))
+                        .await
+                    {
+                        eprintln!("Failed to send AboutMe response: {}", e);
+                    } else {
+                        println!("DEBUG: Sent AboutMe response to new user {}", name);
+                    }
+
+                    let room_info = {
+                        let state = app_state.lock().unwrap();
+                        (state.room_owner == Some(current_node_id) && !state.room_info.is_empty())
+                            .then(|| state.room_info.clone())
+                    };
+                    if let Some(info) = room_info {
+                        let info_message = Message::new(MessageBody::RoomInfo {
+                            from: current_node_id,
+                            info,
+                        });
+                        if let Err(e) = sender
+                            .broadcast(info_message.to_signed_vec(&secret_key, sender.topic()))
+                            .await
+                        {
+                            eprintln!("Failed to send room info: {}", e);
                         }
-                        MessageBody::Message { from, text } => {
-                            let (sender_name, is_own) = {
-                                let state = app_state.lock().unwrap();
-                                let names = state.names.lock().unwrap();
-                                let sender_name = names
-                                    .get(&from)
-                                    .map_or_else(|| from.fmt_short(), String::to_string);
-                                let is_own = state.current_node_id == Some(from);
-                                (sender_name, is_own)
-                            };
-
-                            let new_message = types::ChatMessage {
-                                username: SharedString::from(sender_name.clone()),
-                                content: SharedString::from(text.clone()),
-                                timestamp: SharedString::from(
-                                    chrono::Local::now().format("%d/%m/%Y %H:%M:%S").to_string(),
-                                ),
-                                is_own,
-                                is_system: false,
-                            };
-
-                            {
-                                let state = app_state.lock().unwrap();
-                                let mut messages = state.messages.lock().unwrap();
-                                messages.push(new_message);
-                            }
-
-                            update_messages(&chat_handle, &app_state);
-                            println!(
-                                "DEBUG: Message added to GUI - from {}: {}",
-                                sender_name, text
-                            );
+                    }
+
+                    #[cfg(not(target_arch = "wasm32"))]
+                    if app_state.lock().unwrap().screen_share.is_some() {
+                        let share_message = Message::new(MessageBody::ScreenShareStarted {
+                            from: current_node_id,
+                        });
+                        if let Err(e) = sender
+                            .broadcast(share_message.to_signed_vec(&secret_key, sender.topic()))
+                            .await
+                        {
+                            eprintln!("Failed to announce screen share: {}", e);
                         }
-                        MessageBody::MessageHistory { messages } => {
-                            println!(
-                                "DEBUG: Received message history with {} messages",
-                                messages.len()
-                            );
-
-                            let message_count = messages.len();
-
-                            {
-                                let state = app_state.lock().unwrap();
-                                let mut chat_messages = state.messages.lock().unwrap();
-
-                                for (index, stored_msg) in messages.into_iter().enumerate() {
-                                    let is_own = state.current_node_id == Some(stored_msg.from);
-
-                                    let history_message = types::ChatMessage {
-                                        username: SharedString::from(stored_msg.sender_name),
-                                        content: SharedString::from(stored_msg.text),
-                                        timestamp: SharedString::from(stored_msg.timestamp),
-                                        is_own,
-                                        is_system: false,
-                                    };
-
-                                    chat_messages.insert(index, history_message);
-                                }
-                            }
-
-                            if message_count > 0 {
-                                let system_message = types::ChatMessage {
-                                    username: SharedString::from("System"),
-                                    content: SharedString::from(format!(
-                                        "--- Loaded {} messages from history ---",
-                                        message_count
-                                    )),
-                                    timestamp: SharedString::from(
-                                        chrono::Local::now()
-                                            .format("%d/%m/%Y %H:%M:%S")
-                                            .to_string(),
-                                    ),
-                                    is_own: false,
-                                    is_system: true,
-                                };
-
-                                {
-                                    let state = app_state.lock().unwrap();
-                                    let mut chat_messages = state.messages.lock().unwrap();
-                                    chat_messages.push(system_message);
-                                }
-                            }
-
-                            update_messages(&chat_handle, &app_state);
-                            println!("DEBUG: Message history loaded and displayed");
+                    }
+
+                    // Newcomers didn't see who we let in before them.
+                    let admitted: Vec<NodeId> = {
+                        let state = app_state.lock().unwrap();
+                        if state.room_owner == Some(current_node_id) {
+                            state.admitted.iter().copied().collect()
+                        } else {
+                            Vec::new()
                         }
+                    };
+                    for to in admitted {
+                        if let Err(e) = admission::publish_admitted(app_state, to).await {
+                            eprintln!("Failed to repeat who was let in: {}", e);
+                        }
+                    }
+
+                    // Online is what everyone assumes, so only other statuses need repeating.
+                    let status = app_state.lock().unwrap().presence.lock().unwrap().status();
+                    if status != PresenceStatus::Online
+                        && let Err(e) = presence::broadcast_status(app_state).await
+                    {
+                        eprintln!("Failed to send presence: {}", e);
                     }
                 }
             }
-            Ok(None) => {
-                println!("DEBUG: Message stream ended");
-                break;
+
+            contacts::refresh_contact(app_state, from, &name);
+            crate::app::ui_handlers::update_online_users(chat_handle, app_state);
+
+            println!("> {} is now known as {}", from.fmt_short(), name);
+        }
+        MessageBody::Disconnect { from, name } => {
+            let shown = {
+                let state = app_state.lock().unwrap();
+                let mut names = state.names.lock().unwrap();
+                state.last_seen.lock().unwrap().flush();
+                names.remove(&from).unwrap_or(name.clone())
+            };
+
+            handle_user_disconnect(chat_handle, app_state, &shown);
+            app_state
+                .lock()
+                .unwrap()
+                .flood
+                .lock()
+                .unwrap()
+                .forget(&from);
+            app_state.lock().unwrap().seqs.lock().unwrap().forget(&from);
+            app_state
+                .lock()
+                .unwrap()
+                .clients
+                .lock()
+                .unwrap()
+                .remove(&from);
+            app_state
+                .lock()
+                .unwrap()
+                .presence
+                .lock()
+                .unwrap()
+                .forget(&from);
+
+            #[cfg(not(target_arch = "wasm32"))]
+            screen_share::handle_share_signal(
+                MessageBody::ScreenShareStopped { from },
+                app_state,
+                chat_handle,
+            );
+
+            println!("> {} ({}) disconnected", name, from.fmt_short());
+        }
+        MessageBody::Message { from, .. } if !app_state.lock().unwrap().can_post(&from) => {
+            println!(
+                "DEBUG: Dropped message from non-moderator {}",
+                from.fmt_short()
+            );
+        }
+        MessageBody::Message {
+            from,
+            text,
+            id,
+            seq,
+        } => {
+            let seen = {
+                let state = app_state.lock().unwrap();
+                let mut seqs = state.seqs.lock().unwrap();
+                seqs.observe(from, seq)
+            };
+            match seen {
+                Seen::Duplicate => return,
+                Seen::Gap(missing) => {
+                    let app_state = app_state.clone();
+                    let chat_handle = chat_handle.clone();
+                    n0_future::task::spawn(async move {
+                        if let Err(e) =
+                            recovery::recover_missing(from, missing, app_state, chat_handle).await
+                        {
+                            eprintln!("Failed to recover missed messages: {}", e);
+                        }
+                    });
+                }
+                Seen::New => {}
             }
-            Err(e) => {
-                let should_continue = {
-                    let state = app_state.lock().unwrap();
-                    state.sender.is_some()
-                };
 
-                if should_continue {
-                    eprintln!("Error receiving message: {}", e);
+            display_chat_message(from, text, id, false, app_state, chat_handle);
+        }
+        MessageBody::Action { from, .. } if !app_state.lock().unwrap().can_post(&from) => {
+            println!(
+                "DEBUG: Dropped action from non-moderator {}",
+                from.fmt_short()
+            );
+        }
+        MessageBody::Action { from, text, id } => {
+            display_chat_message(from, text, id, true, app_state, chat_handle);
+        }
+        MessageBody::Image { from, .. } if !app_state.lock().unwrap().can_post(&from) => {
+            println!(
+                "DEBUG: Dropped image from non-moderator {}",
+                from.fmt_short()
+            );
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        MessageBody::Image { from, id, size, .. } => {
+            images::handle_image(from, id, size, app_state, chat_handle);
+        }
+        // The browser build can't fetch the picture, so it only shows that one was posted.
+        #[cfg(target_arch = "wasm32")]
+        MessageBody::Image { from, id, .. } => {
+            display_chat_message(
+                from,
+                tr("🖼 Image").to_string(),
+                id,
+                false,
+                app_state,
+                chat_handle,
+            );
+        }
+        MessageBody::File { from, .. } if !app_state.lock().unwrap().can_post(&from) => {
+            println!(
+                "DEBUG: Dropped file offer from non-moderator {}",
+                from.fmt_short()
+            );
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        MessageBody::File {
+            from,
+            id,
+            name,
+            size,
+        } => {
+            files::handle_offer(from, id, name, size, app_state, chat_handle);
+        }
+        // The browser build can't fetch files, so it only shows that one was offered.
+        #[cfg(target_arch = "wasm32")]
+        MessageBody::File { from, id, name, .. } => {
+            display_chat_message(
+                from,
+                format!("📎 {}", name),
+                id,
+                false,
+                app_state,
+                chat_handle,
+            );
+        }
+        // Sent by a newer client in a kind this build doesn't know yet.
+        MessageBody::Unknown => {
+            println!("DEBUG: Skipped a message from a newer protocol version");
+        }
+        body @ (MessageBody::CallRequest { .. }
+        | MessageBody::CallRinging { .. }
+        | MessageBody::CallAccept { .. }
+        | MessageBody::CallHangUp { .. }) => {
+            let app_state = app_state.clone();
+            let chat_handle = chat_handle.clone();
+            n0_future::task::spawn(async move {
+                if let Err(e) = calls::handle_call_signal(body, app_state, chat_handle).await {
+                    eprintln!("Error handling call signal: {}", e);
+                }
+            });
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        body
+        @ (MessageBody::ScreenShareStarted { .. } | MessageBody::ScreenShareStopped { .. }) => {
+            screen_share::handle_share_signal(body, app_state, chat_handle);
+        }
+        #[cfg(target_arch = "wasm32")]
+        MessageBody::ScreenShareStarted { .. } | MessageBody::ScreenShareStopped { .. } => {}
+        MessageBody::Ack { to, id, .. } => {
+            delivery::handle_ack(to, id, app_state, chat_handle);
+        }
+        MessageBody::RoomInfo { from, info } => {
+            let changed = {
+                let mut state = app_state.lock().unwrap();
+                if state.room_owner == Some(from) && state.room_info != info {
+                    state.room_info = info;
+                    true
                 } else {
-                    println!("DEBUG: Stopping message handling due to disconnection");
-                    break;
+                    false
                 }
+            };
+            if changed {
+                update_room_info(chat_handle, app_state);
+            }
+        }
+        MessageBody::Presence { from, status } => {
+            app_state
+                .lock()
+                .unwrap()
+                .presence
+                .lock()
+                .unwrap()
+                .set_peer(from, status);
+            crate::app::ui_handlers::update_online_users(chat_handle, app_state);
+        }
+        MessageBody::Announcement { from, text, .. } => {
+            let shown = {
+                let state = app_state.lock().unwrap();
+                // Servers are trusted for being in the ticket, not for what they say they are.
+                if !state.room_nodes.contains(&from) && !state.can_moderate(&from) {
+                    None
+                } else {
+                    Some(
+                        state
+                            .names
+                            .lock()
+                            .unwrap()
+                            .get(&from)
+                            .cloned()
+                            .unwrap_or_else(|| from.fmt_short()),
+                    )
+                }
+            };
+            let Some(shown) = shown else {
+                println!(
+                    "DEBUG: Dropped announcement from {}, who isn't in charge of the room",
+                    from.fmt_short()
+                );
+                return;
+            };
+
+            let announcement = types::ChatMessage {
+                username: SharedString::from(format!("📢 {}", shown)),
+                content: SharedString::from(text.clone()),
+                timestamp: SharedString::from(local_time(chrono::Utc::now())),
+                sender_id: SharedString::from(from.to_string()),
+                burst_id: 0,
+                message_id: SharedString::new(),
+                delivery: SharedString::new(),
+                is_own: false,
+                is_system: true,
+                event: types::SystemEvent::None,
+                is_announcement: true,
+                is_action: false,
+                image_index: -1,
+                code_index: -1,
+                translation: Default::default(),
+                name_color: Default::default(),
+            };
+            {
+                let state = app_state.lock().unwrap();
+                state.messages.lock().unwrap().push(announcement);
+                state.persister.record(&shown, &text);
             }
+            history::spill_overflow(app_state);
+            update_messages(chat_handle, app_state);
+            println!("> Announcement from {}: {}", shown, text);
+        }
+        MessageBody::Kick { from, target } => {
+            handle_removal(from, target, false, app_state, chat_handle);
+        }
+        MessageBody::Ban { from, target } => {
+            handle_removal(from, target, true, app_state, chat_handle);
+        }
+        MessageBody::RevokeInvite { from, invite } => {
+            let newly_revoked = {
+                let mut state = app_state.lock().unwrap();
+                state.room_owner == Some(from) && state.revoked_invites.insert(invite)
+            };
+            if newly_revoked {
+                println!("> Invite {:016x} was revoked", invite);
+                push_system_message(
+                    app_state,
+                    tr("🔗 The room owner revoked an invite link. Nobody new can join with it.")
+                        .to_string(),
+                );
+                update_messages(chat_handle, app_state);
+            }
+        }
+        MessageBody::JoinReply {
+            from,
+            to,
+            verdict: JoinVerdict::Admitted,
+        } => {
+            let (newly_admitted, is_me) = {
+                let mut state = app_state.lock().unwrap();
+                (
+                    state.room_owner == Some(from) && state.admitted.insert(to),
+                    state.room_owner == Some(from) && state.current_node_id == Some(to),
+                )
+            };
+            if newly_admitted {
+                println!("DEBUG: The owner let {} in", to.fmt_short());
+            }
+            // Our AboutMe may have beaten the owner's answer to some peers, so we say
+            // it again now that they've heard it.
+            if is_me && let Err(e) = send_about_me(app_state).await {
+                eprintln!("Failed to repeat AboutMe: {}", e);
+            }
+        }
+        MessageBody::RoomInvite { .. }
+        | MessageBody::JoinRequest { .. }
+        | MessageBody::KnockRequest { .. }
+        | MessageBody::ResendRequest { .. }
+        | MessageBody::Resend { .. }
+        | MessageBody::JoinReply { .. }
+        | MessageBody::MessageHistory { .. }
+        | MessageBody::HistoryRequest { .. } => {
+            // Invites, join requests, resends and history arrive over a direct connection, never
+            // through the room; only the owner's yes to a knock is passed on
+        }
+        MessageBody::Cover { .. } => {
+            // Dummy traffic from a peer with cover traffic on; there's nothing in it
         }
     }
+}
+
+// Tells the room who we are again, the same AboutMe we sent on joining.
+async fn send_about_me(app_state: &Arc<Mutex<AppState>>) -> Result<()> {
+    let (sender, from, name, secret_key, invite) = {
+        let state = app_state.lock().unwrap();
+        (
+            state.sender.clone(),
+            state.current_node_id,
+            state.current_username.clone(),
+            state.secret_key(),
+            state.room_invite,
+        )
+    };
+    let (Some(sender), Some(from), Some(secret_key)) = (sender, from, secret_key) else {
+        return Err(anyhow::anyhow!("Not connected to a room"));
+    };
+    let message = Message::new(about_me(from, name, invite));
+    sender
+        .broadcast(message.to_signed_vec(&secret_key, sender.topic()))
+        .await?;
     Ok(())
 }
 
-pub async fn send_message(message: String, app_state: Arc<Mutex<AppState>>) -> Result<()> {
-    let (sender, node_id, username) = {
+// Someone we don't know yet showed up without a valid invite, with the room already
+// full, or in a knock room without being let in. The owner asks them to leave and
+// repeats an invite's revocation for anyone who missed it.
+async fn turn_away(
+    from: NodeId,
+    name: &str,
+    invite: Option<Invite>,
+    problem: &str,
+    app_state: &Arc<Mutex<AppState>>,
+) {
+    println!(
+        "> Turned away {} ({}) who joined with {}",
+        name,
+        from.fmt_short(),
+        problem
+    );
+    let (me, sender, secret_key, revoked) = {
         let state = app_state.lock().unwrap();
+        let revoked = invite
+            .map(|invite| invite.id)
+            .filter(|id| state.revoked_invites.contains(id));
+        (
+            state
+                .current_node_id
+                .filter(|me| state.room_owner == Some(*me)),
+            state.sender.clone(),
+            state.secret_key(),
+            revoked,
+        )
+    };
+    let (Some(me), Some(sender), Some(secret_key)) = (me, sender, secret_key) else {
+        return;
+    };
+
+    let mut bodies = Vec::new();
+    if let Some(invite) = revoked {
+        bodies.push(MessageBody::RevokeInvite { from: me, invite });
+    }
+    bodies.push(MessageBody::Kick {
+        from: me,
+        target: from,
+    });
+    for body in bodies {
+        if let Err(e) = sender
+            .broadcast(Message::new(body).to_signed_vec(&secret_key, sender.topic()))
+            .await
+        {
+            eprintln!("Failed to turn away {}: {}", name, e);
+        }
+    }
+}
+
+// The room owner or a moderator removed `target` from the room. If that's us we leave,
+// otherwise they disappear from the user list like a disconnect.
+fn handle_removal(
+    from: NodeId,
+    target: NodeId,
+    ban: bool,
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+) {
+    let (allowed, is_me) = {
+        let state = app_state.lock().unwrap();
+        (
+            state.can_moderate(&from),
+            state.current_node_id == Some(target),
+        )
+    };
+    if !allowed {
+        println!(
+            "DEBUG: Ignored kick or ban from non-moderator {}",
+            from.fmt_short()
+        );
+        return;
+    }
+
+    if is_me {
+        println!(
+            "> You were {} from the room",
+            if ban { "banned" } else { "kicked" }
+        );
+        let chat_handle = chat_handle.clone();
+        if let Err(e) = slint::invoke_from_event_loop(move || {
+            if let Some(chat) = chat_handle.upgrade() {
+                chat.invoke_disconnect();
+            }
+        }) {
+            eprintln!("Failed to leave the room: {:?}", e);
+        }
+        return;
+    }
+
+    let shown = {
+        let mut state = app_state.lock().unwrap();
+        if ban {
+            state.banned.insert(target);
+        }
+        state.admitted.remove(&target);
+        state.clients.lock().unwrap().remove(&target);
+        state.presence.lock().unwrap().forget(&target);
+        state.names.lock().unwrap().remove(&target)
+    };
+    if let Some(shown) = shown {
+        push_system_message(
+            app_state,
+            if ban {
+                trf("🚫 {} was banned from the room", &[&shown])
+            } else {
+                trf("🚫 {} was kicked from the room", &[&shown])
+            },
+        );
+        update_messages(chat_handle, app_state);
+        crate::app::ui_handlers::update_online_users(chat_handle, app_state);
+    }
+}
+
+pub fn display_chat_message(
+    from: NodeId,
+    text: String,
+    id: u64,
+    is_action: bool,
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+) {
+    if id != 0 && app_state.lock().unwrap().current_node_id != Some(from) {
+        let app_state = app_state.clone();
+        n0_future::task::spawn(async move {
+            if let Err(e) = delivery::send_ack(&app_state, from, id).await {
+                eprintln!("Failed to send ack: {}", e);
+            }
+        });
+    }
+
+    let (sender_name, is_own) = {
+        let state = app_state.lock().unwrap();
+        let names = state.names.lock().unwrap();
+        let sender_name = names
+            .get(&from)
+            .map_or_else(|| from.fmt_short(), String::to_string);
+        let is_own = state.current_node_id == Some(from);
+        (sender_name, is_own)
+    };
+
+    let burst = app_state.lock().unwrap().flood.lock().unwrap().record(from);
+    let burst_id = match burst {
+        Burst::None => 0,
+        Burst::Ongoing(burst_id) => burst_id,
+        Burst::Started(burst_id) => {
+            // Pull the messages that made up the burst into the group too.
+            let sender_id = from.to_string();
+            let state = app_state.lock().unwrap();
+            let mut messages = state.messages.lock().unwrap();
+            messages
+                .iter_mut()
+                .rev()
+                .filter(|message| message.sender_id == sender_id)
+                .take(BURST_THRESHOLD)
+                .for_each(|message| message.burst_id = burst_id);
+            burst_id
+        }
+    };
+
+    let new_message = types::ChatMessage {
+        username: SharedString::from(sender_name.clone()),
+        content: SharedString::from(text.clone()),
+        timestamp: SharedString::from(local_time(chrono::Utc::now())),
+        sender_id: SharedString::from(from.to_string()),
+        burst_id,
+        message_id: SharedString::from(id.to_string()),
+        delivery: SharedString::new(),
+        is_own,
+        is_system: false,
+        event: types::SystemEvent::None,
+        is_announcement: false,
+        is_action,
+        image_index: -1,
+        code_index: -1,
+        translation: Default::default(),
+        name_color: Default::default(),
+    };
+
+    {
+        let state = app_state.lock().unwrap();
+        let mut messages = state.messages.lock().unwrap();
+        messages.push(new_message);
+        if is_action {
+            state.persister.record_action(&sender_name, &text);
+        } else {
+            state.persister.record(&sender_name, &text);
+        }
+    }
+    history::spill_overflow(app_state);
+
+    update_messages(chat_handle, app_state);
+    let muted_sender = app_state
+        .lock()
+        .unwrap()
+        .blocklist
+        .lock()
+        .unwrap()
+        .is_muted(&from);
+    if !is_own && !muted_sender {
+        notifications::on_message(&sender_name, &text, app_state, chat_handle);
+        speech::on_message(&sender_name, &text, is_action, app_state);
+    }
+    if !is_own && id != 0 && translate::is_auto(&app_state.lock().unwrap()) {
+        translate::translate(&id.to_string(), app_state, chat_handle);
+    }
+    println!(
+        "DEBUG: Message added to GUI - from {}: {}",
+        sender_name, text
+    );
+}
+
+pub async fn send_message(
+    message: String,
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) -> Result<()> {
+    let (sender, node_id, username, has_peers, secret_key) = {
+        let state = app_state.lock().unwrap();
+        let has_peers = state
+            .names
+            .lock()
+            .unwrap()
+            .keys()
+            .any(|id| state.current_node_id != Some(*id));
         (
             state.sender.clone(),
             state.current_node_id,
             state.current_username.clone(),
+            has_peers,
+            state.secret_key(),
         )
     };
 
-    if let (Some(sender), Some(node_id)) = (sender, node_id) {
+    if let (Some(sender), Some(node_id), Some(secret_key)) = (sender, node_id, secret_key) {
+        if !app_state.lock().unwrap().can_post(&node_id) {
+            return Err(anyhow::anyhow!(
+                "Only moderators can post in this announcement room"
+            ));
+        }
+
+        // "/me waves" is sent as an action rather than a message.
+        let action = message
+            .strip_prefix("/me ")
+            .map(str::trim)
+            .filter(|action| !action.is_empty())
+            .map(str::to_string);
+        let text = action.clone().unwrap_or_else(|| message.clone());
+
+        let id = rand::random::<u64>().max(1);
         let new_message = types::ChatMessage {
-            username: SharedString::from(username),
-            content: SharedString::from(message.clone()),
-            timestamp: SharedString::from(
-                chrono::Local::now().format("%d/%m/%Y %H:%M:%S").to_string(),
-            ),
+            username: SharedString::from(username.clone()),
+            content: SharedString::from(text.clone()),
+            timestamp: SharedString::from(local_time(chrono::Utc::now())),
+            sender_id: SharedString::from(node_id.to_string()),
+            burst_id: 0,
+            message_id: SharedString::from(id.to_string()),
+            delivery: SharedString::from(DeliveryStatus::Pending.as_str()),
             is_own: true,
             is_system: false,
+            event: types::SystemEvent::None,
+            is_announcement: false,
+            is_action: action.is_some(),
+            image_index: -1,
+            code_index: -1,
+            translation: Default::default(),
+            name_color: Default::default(),
         };
 
         {
             let state = app_state.lock().unwrap();
             let mut messages = state.messages.lock().unwrap();
             messages.push(new_message);
+            if action.is_some() {
+                state.persister.record_action(&username, &text);
+            } else {
+                state.persister.record(&username, &text);
+            }
         }
+        history::spill_overflow(&app_state);
+        update_messages(&chat_handle, &app_state);
 
-        let msg = Message::new(MessageBody::Message {
-            from: node_id,
-            text: message,
-        });
-        sender.broadcast(msg.to_vec().into()).await?;
+        // Actions aren't numbered, so they can't be recovered if gossip drops one.
+        let body = match action {
+            Some(text) => MessageBody::Action {
+                from: node_id,
+                text,
+                id,
+            },
+            None => {
+                let seq = {
+                    let state = app_state.lock().unwrap();
+                    let mut outbox = state.outbox.lock().unwrap();
+                    outbox.record(id, &message)
+                };
+                MessageBody::Message {
+                    from: node_id,
+                    text: message,
+                    id,
+                    seq,
+                }
+            }
+        };
+        // Anyone rebroadcasting our own message later gets it dropped like any other replay.
+        if let Some(content_id) = body.content_id() {
+            app_state
+                .lock()
+                .unwrap()
+                .seen
+                .lock()
+                .unwrap()
+                .insert(content_id);
+        }
+        let msg = Message::new(body);
+        if let Err(e) = sender
+            .broadcast(msg.to_signed_vec(&secret_key, sender.topic()))
+            .await
+        {
+            delivery::set_status(&app_state, id, DeliveryStatus::Failed);
+            return Err(e);
+        }
+
+        // Gossip accepts the message even when nobody else is in the room.
+        if has_peers {
+            delivery::set_status(&app_state, id, DeliveryStatus::Sent);
+            n0_future::task::spawn(delivery::expire_unacked(id, app_state, chat_handle));
+        } else {
+            delivery::set_status(&app_state, id, DeliveryStatus::Failed);
+        }
     }
 
     Ok(())
 }
 
+// Hangs up any call and says goodbye to the room, before the connection is torn down.
+pub async fn leave_room(app_state: Arc<Mutex<AppState>>, chat_handle: Weak<types::ChatWindow>) {
+    if let Err(e) = calls::hang_up(app_state.clone(), chat_handle).await {
+        eprintln!("Error hanging up call: {}", e);
+    }
+    if let Err(e) = send_disconnect(app_state).await {
+        eprintln!("Error sending disconnect message: {}", e);
+    }
+}
+
 pub async fn send_disconnect(app_state: Arc<Mutex<AppState>>) -> Result<()> {
-    let (sender, node_id, username) = {
+    let (sender, node_id, username, secret_key) = {
         let state = app_state.lock().unwrap();
         (
             state.sender.clone(),
             state.current_node_id,
             state.current_username.clone(),
+            state.secret_key(),
         )
     };
 
-    if let (Some(sender), Some(node_id)) = (sender, node_id) {
+    if let (Some(sender), Some(node_id), Some(secret_key)) = (sender, node_id, secret_key) {
         let msg = Message::new(MessageBody::Disconnect {
             from: node_id,
             name: username,
         });
-        sender.broadcast(msg.to_vec().into()).await?;
+        sender
+            .broadcast(msg.to_signed_vec(&secret_key, sender.topic()))
+            .await?;
         println!("DEBUG: Disconnect message sent");
     }
 
@@ -349,13 +1323,70 @@ pub async fn send_disconnect(app_state: Arc<Mutex<AppState>>) -> Result<()> {
 }
 
 pub async fn cleanup_network_resources(app_state: Arc<Mutex<AppState>>) -> Result<()> {
+    recent_rooms::record(&app_state);
+
     let (endpoint, router) = {
         let mut state = app_state.lock().unwrap();
+        if let calls::CallState::Active { connection, .. } = std::mem::take(&mut state.call) {
+            connection.close(0u32.into(), b"disconnected");
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            if let Some(share) = state.screen_share.take() {
+                share.stop();
+            }
+            if let Some((_, connection)) = state.screen_viewer.take() {
+                connection.close(0u32.into(), b"disconnected");
+            }
+            state.screen_sharers.clear();
+            state.pictures.lock().unwrap().clear();
+            state.files.lock().unwrap().clear();
+        }
         state.sender = None;
         state.current_node_id = None;
         state.current_session_token = None;
+        state.room_topic = None;
+        state.last_seen.lock().unwrap().flush();
+        state.moderators.clear();
+        state.room_owner = None;
+        state.room_nodes.clear();
+        state.admitted.clear();
+        state.banned.clear();
+        state.room_invite = None;
+        state.room_password = None;
+        state.room_peer_address = None;
+        state.invites_required = false;
+        state.revoked_invites.clear();
+        state.room_info = RoomInfo::default();
+        state.room_capacity = None;
+        state.room_knock = false;
+        state.room_incognito = false;
+        state.knocks.lock().unwrap().clear();
         state.names.lock().unwrap().clear();
+        state.clients.lock().unwrap().clear();
+        state.pending_acks.clear();
+        state.presence.lock().unwrap().leave_room();
+        state.paths.lock().unwrap().clear();
+        state.neighbors.clear();
+        state.connection_lost = false;
         state.messages.lock().unwrap().clear();
+        state.older_history.lock().unwrap().clear();
+        state.history_loaded = false;
+        // Leaving with nothing unread means we saw everything.
+        if state.unread == 0 {
+            state.persister.mark_read();
+        }
+        state.unread = 0;
+        state.last_read = None;
+        state.scheduled = Schedule::default();
+        *state.spill.lock().unwrap() = None;
+        // The auto-save task writes what it has left once this last handle is gone.
+        state.persister = Persister::default();
+        state.room_generation += 1;
+        *state.flood.lock().unwrap() = FloodGuard::default();
+        *state.seqs.lock().unwrap() = SeqTracker::default();
+        *state.outbox.lock().unwrap() = Outbox::default();
+        *state.seen.lock().unwrap() = SeenWindow::default();
 
         let endpoint = state.endpoint.take();
         let router = state.router.take();
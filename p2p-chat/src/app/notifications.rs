@@ -0,0 +1,179 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use slint::{SharedString, Weak};
+use std::sync::{Arc, Mutex};
+
+use crate::app::{app_state::AppState, i18n::tr, types};
+
+pub fn is_muted(state: &AppState) -> bool {
+    let Some(topic) = state.room_topic else {
+        return false;
+    };
+    let topic = topic.to_string();
+    state
+        .settings
+        .lock()
+        .unwrap()
+        .notifications
+        .muted_rooms
+        .contains(&topic)
+}
+
+// Flips the current room between muted and not. Muting also clears what's unread.
+pub fn toggle_mute(
+    chat_handle: &Weak<types::ChatWindow>,
+    app_state: &Arc<Mutex<AppState>>,
+) -> Result<()> {
+    let muted = {
+        let mut state = app_state.lock().unwrap();
+        let Some(topic) = state.room_topic else {
+            return Ok(());
+        };
+        let topic = topic.to_string();
+        let muted = {
+            let mut settings = state.settings.lock().unwrap();
+            let muted_rooms = &mut settings.notifications.muted_rooms;
+            let muted = !muted_rooms.contains(&topic);
+            if muted {
+                muted_rooms.push(topic);
+            } else {
+                muted_rooms.retain(|room| *room != topic);
+            }
+            settings.save()?;
+            muted
+        };
+        if muted && state.unread > 0 {
+            state.unread = 0;
+            state.persister.mark_read();
+        }
+        muted
+    };
+    println!("DEBUG: Room notifications muted: {}", muted);
+    show(chat_handle, app_state);
+    Ok(())
+}
+
+// Counts a message from someone else as unread and, if it's the first since we were last
+// active, pops up a notification. Muted rooms still get the message, just quietly.
+pub fn on_message(
+    sender_name: &str,
+    text: &str,
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+) {
+    let (first, room) = {
+        let mut state = app_state.lock().unwrap();
+        if is_muted(&state) {
+            return;
+        }
+        state.unread += 1;
+        (state.unread == 1, state.room_info.name.clone())
+    };
+    show(chat_handle, app_state);
+    if first {
+        desktop_notify(&room, sender_name, text);
+    }
+}
+
+// Typing in the chat counts as having read it.
+pub fn mark_read(app_state: &Arc<Mutex<AppState>>, chat_handle: &Weak<types::ChatWindow>) {
+    {
+        let mut state = app_state.lock().unwrap();
+        if state.unread == 0 {
+            return;
+        }
+        state.unread = 0;
+        state.persister.mark_read();
+    }
+    show(chat_handle, app_state);
+}
+
+// Puts the "New messages" divider in front of the first message newer than `last_read`,
+// given the timestamps of the first of `messages`. Nothing newer means no divider.
+pub fn place_divider(
+    messages: &mut Vec<types::ChatMessage>,
+    timestamps: impl IntoIterator<Item = DateTime<Utc>>,
+    last_read: Option<DateTime<Utc>>,
+) {
+    let Some(last_read) = last_read else {
+        return;
+    };
+    let Some(offset) = timestamps.into_iter().position(|at| at > last_read) else {
+        return;
+    };
+    messages.retain(|message| message.event != types::SystemEvent::Unread);
+    messages.insert(
+        offset.min(messages.len()),
+        types::ChatMessage {
+            username: SharedString::new(),
+            content: SharedString::from(tr("New messages")),
+            timestamp: SharedString::new(),
+            sender_id: SharedString::new(),
+            burst_id: 0,
+            message_id: SharedString::new(),
+            delivery: SharedString::new(),
+            is_own: false,
+            is_system: true,
+            event: types::SystemEvent::Unread,
+            is_announcement: false,
+            is_action: false,
+            image_index: -1,
+            code_index: -1,
+            translation: Default::default(),
+            name_color: Default::default(),
+        },
+    );
+}
+
+fn show(chat_handle: &Weak<types::ChatWindow>, app_state: &Arc<Mutex<AppState>>) {
+    let (muted, unread) = {
+        let state = app_state.lock().unwrap();
+        (is_muted(&state), state.unread)
+    };
+    let chat_handle = chat_handle.clone();
+    if let Err(e) = slint::invoke_from_event_loop(move || {
+        if let Some(chat) = chat_handle.upgrade() {
+            chat.set_notifications_muted(muted);
+            chat.set_unread_count(unread as i32);
+        }
+    }) {
+        eprintln!("Failed to update unread count: {:?}", e);
+    }
+}
+
+// Uses the platform's own notifier, which also plays its message sound. Windows has
+// neither tool, so it only gets the unread count.
+#[cfg(not(target_arch = "wasm32"))]
+fn desktop_notify(room: &str, sender_name: &str, text: &str) {
+    let title = if room.is_empty() {
+        sender_name.to_string()
+    } else {
+        format!("{} ({})", sender_name, room)
+    };
+    let mut command = if cfg!(target_os = "macos") {
+        let mut command = std::process::Command::new("osascript");
+        command.arg("-e").arg(format!(
+            "display notification {:?} with title {:?} sound name \"default\"",
+            text, title
+        ));
+        command
+    } else {
+        let mut command = std::process::Command::new("notify-send");
+        command
+            .args(["--app-name", "Chast"])
+            .arg("--hint=string:sound-name:message-new-instant")
+            .arg(title)
+            .arg(text);
+        command
+    };
+    // Waited on off the UI so the notifier doesn't linger as a zombie.
+    std::thread::spawn(move || {
+        if let Err(e) = command.status() {
+            println!("DEBUG: No desktop notification: {}", e);
+        }
+    });
+}
+
+// The browser build only shows the unread count.
+#[cfg(target_arch = "wasm32")]
+fn desktop_notify(_room: &str, _sender_name: &str, _text: &str) {}
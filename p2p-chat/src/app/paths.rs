@@ -0,0 +1,255 @@
+use futures_lite::StreamExt;
+use iroh::{
+    Endpoint, NodeId, RelayUrl, Watcher,
+    endpoint::{ConnectionType, DirectAddr, DirectAddrType},
+};
+use slint::{SharedString, Weak};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use crate::app::{
+    app_state::AppState,
+    i18n::{tr, trf},
+    types,
+    ui_handlers::{push_system_message, update_messages, update_online_users},
+};
+
+// How we currently reach a gossip neighbour. Only neighbours have a connection of
+// their own; everyone else's messages arrive through them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathKind {
+    Direct,
+    Relay,
+}
+
+impl PathKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PathKind::Direct => "direct",
+            PathKind::Relay => "relay",
+        }
+    }
+}
+
+// What happened to the path to a peer, in hole punching terms.
+#[derive(Debug, Clone)]
+pub enum PathEvent {
+    // Talking through the relay while a direct UDP address is being tried.
+    Punching { addr: SocketAddr, relay: RelayUrl },
+    // Hole punching worked, or the peer was reachable directly to begin with.
+    Direct { addr: SocketAddr },
+    // Only the relay works, either from the start or after direct attempts failed.
+    Relayed { relay: RelayUrl },
+    // No verified path at all.
+    Lost,
+}
+
+impl PathEvent {
+    fn from_conn_type(conn_type: ConnectionType) -> Self {
+        match conn_type {
+            ConnectionType::Mixed(addr, relay) => PathEvent::Punching { addr, relay },
+            ConnectionType::Direct(addr) => PathEvent::Direct { addr },
+            ConnectionType::Relay(relay) => PathEvent::Relayed { relay },
+            ConnectionType::None => PathEvent::Lost,
+        }
+    }
+
+    fn kind(&self) -> Option<PathKind> {
+        match self {
+            PathEvent::Direct { .. } => Some(PathKind::Direct),
+            PathEvent::Punching { .. } | PathEvent::Relayed { .. } => Some(PathKind::Relay),
+            PathEvent::Lost => None,
+        }
+    }
+}
+
+// Follows the connection to a new gossip neighbour until we leave the room.
+pub fn watch(
+    peer: NodeId,
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+) {
+    let (watcher, topic) = {
+        let state = app_state.lock().unwrap();
+        if state.paths.lock().unwrap().insert(peer, None).is_some() {
+            return;
+        }
+        let Some(watcher) = state
+            .endpoint
+            .as_ref()
+            .and_then(|endpoint| endpoint.conn_type(peer))
+        else {
+            return;
+        };
+        (watcher, state.room_topic)
+    };
+
+    let app_state = app_state.clone();
+    let chat_handle = chat_handle.clone();
+    n0_future::task::spawn(async move {
+        let mut updates = watcher.stream();
+        while let Some(conn_type) = updates.next().await {
+            let event = PathEvent::from_conn_type(conn_type);
+            {
+                let state = app_state.lock().unwrap();
+                if state.room_topic != topic {
+                    break;
+                }
+                state.paths.lock().unwrap().insert(peer, event.kind());
+            }
+            log_event(peer, &event);
+            update_online_users(&chat_handle, &app_state);
+        }
+    });
+}
+
+fn log_event(peer: NodeId, event: &PathEvent) {
+    let peer = peer.fmt_short();
+    match event {
+        PathEvent::Punching { addr, relay } => {
+            println!(
+                "> path to {}: trying direct {} via relay {}",
+                peer, addr, relay
+            )
+        }
+        PathEvent::Direct { addr } => println!("> path to {}: direct {}", peer, addr),
+        PathEvent::Relayed { relay } => println!("> path to {}: relayed via {}", peer, relay),
+        PathEvent::Lost => println!("> path to {}: none", peer),
+    }
+}
+
+// Our side of the relay: the relay we're registered with, if any, and the neighbours
+// whose only working path to us goes through it.
+#[derive(Debug, Clone, Default)]
+pub struct RelayStatus {
+    pub home_relay: Option<RelayUrl>,
+    pub relayed_peers: Vec<NodeId>,
+}
+
+pub fn relay_status(app_state: &Arc<Mutex<AppState>>) -> RelayStatus {
+    let state = app_state.lock().unwrap();
+    RelayStatus {
+        home_relay: state
+            .endpoint
+            .as_ref()
+            .and_then(|endpoint| endpoint.home_relay().get().into_iter().next()),
+        relayed_peers: state
+            .paths
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, path)| **path == Some(PathKind::Relay))
+            .map(|(peer, _)| *peer)
+            .collect(),
+    }
+}
+
+// Warns in the chat when we lose our relay, since anyone without a direct path to us
+// stops getting our messages until it comes back.
+pub fn watch_relay(
+    endpoint: Endpoint,
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+) {
+    let topic = app_state.lock().unwrap().room_topic;
+    let app_state = app_state.clone();
+    let chat_handle = chat_handle.clone();
+    n0_future::task::spawn(async move {
+        let mut updates = endpoint.home_relay().stream();
+        drop(endpoint);
+        let mut had_relay = false;
+        let mut lost = false;
+        while let Some(relays) = updates.next().await {
+            if app_state.lock().unwrap().room_topic != topic {
+                break;
+            }
+            let status = relay_status(&app_state);
+            let notice = match (had_relay, relays.first()) {
+                (false, Some(relay)) => {
+                    println!("> home relay: {}", relay);
+                    had_relay = true;
+                    std::mem::take(&mut lost).then(|| tr("✓ Reconnected to the relay.").to_string())
+                }
+                (true, None) => {
+                    had_relay = false;
+                    lost = true;
+                    Some(match status.relayed_peers.len() {
+                        0 => tr("⚠ Lost the connection to the relay. Anyone without a direct path can't reach you until it's back.").to_string(),
+                        1 => tr("⚠ Lost the connection to the relay. 1 person connected through it can't reach you until it's back.").to_string(),
+                        n => trf("⚠ Lost the connection to the relay. {} people connected through it can't reach you until it's back.", &[&n]),
+                    })
+                }
+                _ => None,
+            };
+            if let Some(notice) = notice {
+                println!("> {}", notice);
+                push_system_message(&app_state, notice);
+                update_messages(&chat_handle, &app_state);
+            }
+        }
+    });
+}
+
+// The addresses others on the internet can dial us at. Local interface addresses only
+// work on the same network, so they're left out.
+pub fn external_addresses<'a>(addrs: impl IntoIterator<Item = &'a DirectAddr>) -> Vec<SocketAddr> {
+    addrs
+        .into_iter()
+        .filter(|addr| {
+            matches!(
+                addr.typ,
+                DirectAddrType::Qad | DirectAddrType::Portmapped | DirectAddrType::Qad4LocalPort
+            )
+        })
+        .map(|addr| addr.addr)
+        .collect()
+}
+
+// Keeps the chat window's "Reachable at" line up to date for as long as we're in the room.
+pub fn watch_addresses(
+    endpoint: Endpoint,
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+) {
+    let topic = app_state.lock().unwrap().room_topic;
+    let app_state = app_state.clone();
+    let chat_handle = chat_handle.clone();
+    n0_future::task::spawn(async move {
+        let mut updates = endpoint.direct_addresses().stream();
+        drop(endpoint);
+        while let Some(addrs) = updates.next().await {
+            if app_state.lock().unwrap().room_topic != topic {
+                break;
+            }
+            let mut reachable = external_addresses(addrs.iter().flatten());
+            // Without relays nothing public is found, but peers on the same network can
+            // still dial the local addresses from the join window.
+            if reachable.is_empty() {
+                reachable = addrs.iter().flatten().map(|addr| addr.addr).collect();
+            }
+            let shown = reachable
+                .iter()
+                .map(SocketAddr::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            println!(
+                "> reachable at: {}",
+                if shown.is_empty() {
+                    "relay only"
+                } else {
+                    &shown
+                }
+            );
+            let chat_handle = chat_handle.clone();
+            if let Err(e) = slint::invoke_from_event_loop(move || {
+                if let Some(chat) = chat_handle.upgrade() {
+                    chat.set_reachable_at(SharedString::from(shown));
+                }
+            }) {
+                eprintln!("Failed to update reachable addresses: {:?}", e);
+            }
+        }
+    });
+}
@@ -0,0 +1,372 @@
+#[cfg(not(target_arch = "wasm32"))]
+use chrono::{DateTime, Utc};
+#[cfg(not(target_arch = "wasm32"))]
+use n0_future::time::Duration;
+use serde::{Deserialize, Serialize};
+#[cfg(not(target_arch = "wasm32"))]
+use std::{path::PathBuf, sync::Arc};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::{
+    sync::mpsc,
+    time::{Instant, timeout_at},
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::app::save::{ChatMessage, ChatSaveManager, ChatSession, Config};
+use crate::app::save::{MessageType, SystemEvent};
+
+// Whether the open room is written to saved_chats on its own, and how often. Kept in
+// settings.json and applied to the running auto-save task as soon as it's changed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutoSave {
+    pub enabled: bool,
+    // New messages are written out at most this long after the first unsaved one, so a
+    // busy room isn't rewritten on every message.
+    pub interval_secs: u64,
+}
+
+impl Default for AutoSave {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_secs: 5,
+        }
+    }
+}
+
+impl AutoSave {
+    #[cfg(not(target_arch = "wasm32"))]
+    fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.max(1))
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn manager(&self) -> anyhow::Result<ChatSaveManager> {
+        ChatSaveManager::new(Config {
+            auto_save_enabled: self.enabled,
+            ..Config::default()
+        })
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug)]
+enum Job {
+    Record(ChatMessage),
+    Configure(AutoSave),
+    // Writes the session so far to a file the user picked, whether auto-save is on or not.
+    SaveTo(PathBuf),
+    MarkRead(DateTime<Utc>),
+    // Adds a translation to the latest saved message from `sender` with this content.
+    Translate {
+        sender: String,
+        content: String,
+        translation: String,
+    },
+}
+
+// Auto-save runs in its own task so sending never waits on serialising or writing the
+// session. The chat paths only queue messages here; dropping the last handle makes the
+// task write whatever is left and stop.
+#[derive(Debug, Clone, Default)]
+pub struct Persister {
+    #[cfg(not(target_arch = "wasm32"))]
+    tx: Option<mpsc::UnboundedSender<Job>>,
+}
+
+impl Persister {
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn start(
+        session_id: String,
+        ticket: String,
+        username: String,
+        auto_save: AutoSave,
+    ) -> Self {
+        let manager = match auto_save.manager() {
+            Ok(manager) => manager,
+            Err(e) => {
+                eprintln!("Auto-save disabled: {}", e);
+                return Self::default();
+            }
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        n0_future::task::spawn(run(
+            rx,
+            (session_id, ticket, username),
+            Arc::new(manager),
+            auto_save,
+        ));
+        Self { tx: Some(tx) }
+    }
+
+    // The browser build has nowhere to save to.
+    #[cfg(target_arch = "wasm32")]
+    pub fn start(
+        _session_id: String,
+        _ticket: String,
+        _username: String,
+        _auto_save: AutoSave,
+    ) -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, sender: &str, content: &str) {
+        self.record_as(sender, content, MessageType::Text);
+    }
+
+    // A /me action; `content` is the action without the "/me " prefix.
+    pub fn record_action(&self, sender: &str, content: &str) {
+        self.record_as(sender, content, MessageType::Action);
+    }
+
+    // A join or leave, kept so saved chats can tell it apart from what people said.
+    pub fn record_event(&self, event: SystemEvent, content: &str) {
+        self.record_as("System", content, MessageType::Event(event));
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn record_as(&self, sender: &str, content: &str, message_type: MessageType) {
+        let message = ChatMessage {
+            sender: sender.to_string(),
+            content: content.to_string(),
+            timestamp: Utc::now(),
+            message_type,
+            translation: None,
+        };
+        // Without a task at all (auto-save failed to start, or an incognito room) there's
+        // nothing to report.
+        if self.tx.is_some() && self.send(Job::Record(message)).is_err() {
+            eprintln!("Auto-save task has stopped, message not saved");
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn record_as(&self, _sender: &str, _content: &str, _message_type: MessageType) {}
+
+    // Everything up to now has been read.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn mark_read(&self) {
+        let _ = self.send(Job::MarkRead(Utc::now()));
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn mark_read(&self) {}
+
+    // Saves a translation alongside the message it was made from.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn record_translation(&self, sender: &str, content: &str, translation: &str) {
+        let _ = self.send(Job::Translate {
+            sender: sender.to_string(),
+            content: content.to_string(),
+            translation: translation.to_string(),
+        });
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn record_translation(&self, _sender: &str, _content: &str, _translation: &str) {}
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn configure(&self, auto_save: AutoSave) {
+        if self.send(Job::Configure(auto_save)).is_err() {
+            println!("DEBUG: Not in a room, auto-save settings apply from the next one");
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn configure(&self, _auto_save: AutoSave) {}
+
+    // Fails when there's no room open to save.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save_to(&self, path: PathBuf) -> anyhow::Result<()> {
+        self.send(Job::SaveTo(path))
+            .map_err(|_| anyhow::anyhow!("There is no open chat to save"))
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn save_to(&self, _path: std::path::PathBuf) -> anyhow::Result<()> {
+        anyhow::bail!("Saving chats isn't available in the browser")
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn send(&self, job: Job) -> Result<(), ()> {
+        let Some(tx) = &self.tx else {
+            return Err(());
+        };
+        tx.send(job).map_err(|_| ())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn run(
+    mut rx: mpsc::UnboundedReceiver<Job>,
+    (session_id, ticket, username): (String, String, String),
+    mut manager: Arc<ChatSaveManager>,
+    mut auto_save: AutoSave,
+) {
+    let mut session = open_session(session_id, &manager).await;
+    session.ticket = Some(ticket);
+    session.username = Some(username);
+    let mut due: Option<Instant> = None;
+    loop {
+        let next = match due {
+            Some(at) => match timeout_at(at, rx.recv()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    session = save(session, &manager).await;
+                    due = None;
+                    continue;
+                }
+            },
+            None => rx.recv().await,
+        };
+        let Some(job) = next else {
+            break;
+        };
+        match job {
+            Job::Record(message) => {
+                if !session.participants.contains(&message.sender) {
+                    session.participants.push(message.sender.clone());
+                }
+                session.last_updated = message.timestamp;
+                session.messages.push(message);
+                if due.is_none() && auto_save.enabled {
+                    due = Some(Instant::now() + auto_save.interval());
+                }
+            }
+            Job::Translate {
+                sender,
+                content,
+                translation,
+            } => {
+                let Some(message) = session
+                    .messages
+                    .iter_mut()
+                    .rev()
+                    .find(|m| m.sender == sender && m.content == content)
+                else {
+                    continue;
+                };
+                message.translation = Some(translation);
+                if due.is_none() && auto_save.enabled {
+                    due = Some(Instant::now() + auto_save.interval());
+                }
+            }
+            Job::MarkRead(at) => {
+                session.last_read = session.last_read.max(Some(at));
+                if due.is_none() && auto_save.enabled {
+                    due = Some(Instant::now() + auto_save.interval());
+                }
+            }
+            Job::Configure(new) => {
+                if new == auto_save {
+                    continue;
+                }
+                match new.manager() {
+                    Ok(new_manager) => manager = Arc::new(new_manager),
+                    Err(e) => {
+                        eprintln!("Failed to apply auto-save settings: {}", e);
+                        continue;
+                    }
+                }
+                println!(
+                    "DEBUG: Auto-save {}, every {}s",
+                    if new.enabled { "on" } else { "off" },
+                    new.interval().as_secs()
+                );
+                // Anything still unsaved goes out on the new schedule, or not at all.
+                due = (new.enabled && !session.messages.is_empty())
+                    .then(|| Instant::now() + new.interval());
+                auto_save = new;
+            }
+            Job::SaveTo(path) => {
+                let manager = manager.clone();
+                let snapshot = session.clone();
+                match tokio::task::spawn_blocking(move || {
+                    manager.save_chat_to_file(&snapshot, &path)
+                })
+                .await
+                {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => eprintln!("Failed to save chat: {:#}", e),
+                    Err(e) => eprintln!("Save task panicked: {}", e),
+                }
+            }
+        }
+    }
+    if due.is_some() {
+        save(session, &manager).await;
+    }
+    println!("DEBUG: Auto-save task finished");
+}
+
+// Picks up the room's saved chat if there is one, so new messages are added to it rather
+// than starting another file.
+#[cfg(not(target_arch = "wasm32"))]
+async fn open_session(session_id: String, manager: &Arc<ChatSaveManager>) -> ChatSession {
+    let manager = manager.clone();
+    let id = session_id.clone();
+    match tokio::task::spawn_blocking(move || manager.load_session(&id)).await {
+        Ok(Ok(Some(session))) => {
+            println!(
+                "DEBUG: Continuing saved chat {} ({} messages)",
+                session.session_id,
+                session.messages.len()
+            );
+            return session;
+        }
+        Ok(Ok(None)) => {}
+        Ok(Err(e)) => eprintln!("Failed to load saved chat, starting a new one: {:#}", e),
+        Err(e) => eprintln!("Loading the saved chat panicked: {}", e),
+    }
+    let now = Utc::now();
+    ChatSession {
+        session_id,
+        participants: Vec::new(),
+        messages: Vec::new(),
+        created_at: now,
+        last_updated: now,
+        ticket: None,
+        username: None,
+        last_read: None,
+    }
+}
+
+// Hands the session to the blocking pool for the write and gets it back afterwards.
+#[cfg(not(target_arch = "wasm32"))]
+async fn save(session: ChatSession, manager: &Arc<ChatSaveManager>) -> ChatSession {
+    let manager = manager.clone();
+    let (session_id, created_at, ticket, username, last_read) = (
+        session.session_id.clone(),
+        session.created_at,
+        session.ticket.clone(),
+        session.username.clone(),
+        session.last_read,
+    );
+    match tokio::task::spawn_blocking(move || {
+        let result = manager.auto_save_chat(&session);
+        (session, result)
+    })
+    .await
+    {
+        Ok((session, result)) => {
+            if let Err(e) = result {
+                eprintln!("Failed to auto-save chat: {}", e);
+            }
+            session
+        }
+        Err(e) => {
+            eprintln!("Auto-save task panicked, starting over: {}", e);
+            ChatSession {
+                session_id,
+                participants: Vec::new(),
+                messages: Vec::new(),
+                created_at,
+                last_updated: Utc::now(),
+                ticket,
+                username,
+                last_read,
+            }
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use anyhow::{Result, anyhow};
+use iroh::NodeId;
+use messaging::{Message, MessageBody, PresenceStatus};
+use n0_future::time::{Duration, Instant, sleep};
+use serde::{Deserialize, Serialize};
+use slint::Weak;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::app::{app_state::AppState, types, ui_handlers::update_online_users};
+
+const IDLE_CHECK: Duration = Duration::from_secs(30);
+
+// Whether not typing or posting for a while shows us as away, and after how long. Kept
+// in settings.json and read on every idle check, so changes apply straight away.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutoAway {
+    pub enabled: bool,
+    pub minutes: u64,
+}
+
+impl Default for AutoAway {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            minutes: 5,
+        }
+    }
+}
+
+impl AutoAway {
+    fn away_after(&self) -> Option<Duration> {
+        self.enabled
+            .then(|| Duration::from_secs(self.minutes.max(1) * 60))
+    }
+}
+
+#[derive(Debug)]
+pub struct Presence {
+    // What the user picked; idleness only ever turns Online into Away.
+    chosen: PresenceStatus,
+    idle: bool,
+    last_active: Instant,
+    peers: HashMap<NodeId, PresenceStatus>,
+}
+
+impl Default for Presence {
+    fn default() -> Self {
+        Self {
+            chosen: PresenceStatus::Online,
+            idle: false,
+            last_active: Instant::now(),
+            peers: HashMap::new(),
+        }
+    }
+}
+
+impl Presence {
+    pub fn status(&self) -> PresenceStatus {
+        match self.chosen {
+            PresenceStatus::Online if self.idle => PresenceStatus::Away,
+            chosen => chosen,
+        }
+    }
+
+    pub fn peer_status(&self, node_id: &NodeId) -> PresenceStatus {
+        self.peers.get(node_id).copied().unwrap_or_default()
+    }
+
+    pub fn set_peer(&mut self, node_id: NodeId, status: PresenceStatus) {
+        self.peers.insert(node_id, status);
+    }
+
+    pub fn forget(&mut self, node_id: &NodeId) {
+        self.peers.remove(node_id);
+    }
+
+    // The chosen status carries over to the next room; everything else starts fresh.
+    pub fn leave_room(&mut self) {
+        self.peers.clear();
+        self.idle = false;
+        self.last_active = Instant::now();
+    }
+}
+
+pub async fn broadcast_status(app_state: &Arc<Mutex<AppState>>) -> Result<()> {
+    let (sender, from, secret_key, status) = {
+        let state = app_state.lock().unwrap();
+        let status = state.presence.lock().unwrap().status();
+        (
+            state.sender.clone(),
+            state.current_node_id,
+            state.secret_key(),
+            status,
+        )
+    };
+    let (Some(sender), Some(from), Some(secret_key)) = (sender, from, secret_key) else {
+        return Err(anyhow!("Not connected to a room"));
+    };
+    let message = Message::new(MessageBody::Presence { from, status });
+    sender
+        .broadcast(message.to_signed_vec(&secret_key, sender.topic()))
+        .await?;
+    Ok(())
+}
+
+// Applies a change to our own presence and tells the room if the visible status moved.
+async fn update_own(
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+    change: impl FnOnce(&mut Presence),
+) -> Result<()> {
+    let changed = {
+        let state = app_state.lock().unwrap();
+        let mut presence = state.presence.lock().unwrap();
+        let before = presence.status();
+        change(&mut presence);
+        presence.status() != before
+    };
+    if changed {
+        update_online_users(chat_handle, app_state);
+        broadcast_status(app_state).await?;
+    }
+    Ok(())
+}
+
+pub async fn set_status(
+    status: PresenceStatus,
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) -> Result<()> {
+    update_own(&app_state, &chat_handle, |presence| {
+        presence.chosen = status;
+        presence.idle = false;
+        presence.last_active = Instant::now();
+    })
+    .await
+}
+
+// Called whenever the user types or posts, bringing them back from auto-away.
+pub async fn mark_active(
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) -> Result<()> {
+    update_own(&app_state, &chat_handle, |presence| {
+        presence.idle = false;
+        presence.last_active = Instant::now();
+    })
+    .await
+}
+
+// Flips us to away once we've been idle for as long as the settings say.
+pub async fn check_idle(
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+) -> Result<()> {
+    let away_after = {
+        let state = app_state.lock().unwrap();
+        let settings = state.settings.lock().unwrap();
+        settings.auto_away.away_after()
+    };
+    update_own(app_state, chat_handle, |presence| match away_after {
+        Some(away_after) => {
+            if presence.last_active.elapsed() >= away_after {
+                presence.idle = true;
+            }
+        }
+        // Turning auto-away off brings us straight back.
+        None => presence.idle = false,
+    })
+    .await
+}
+
+// Checks for idleness every IDLE_CHECK. Runs until we leave the room.
+pub async fn watch_idle(app_state: Arc<Mutex<AppState>>, chat_handle: Weak<types::ChatWindow>) {
+    let room = app_state.lock().unwrap().room_generation;
+    loop {
+        sleep(IDLE_CHECK).await;
+        if app_state.lock().unwrap().room_generation != room {
+            break;
+        }
+        if let Err(e) = check_idle(&app_state, &chat_handle).await {
+            eprintln!("Failed to send away status: {}", e);
+        }
+    }
+}
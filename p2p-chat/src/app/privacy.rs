@@ -0,0 +1,87 @@
+use anyhow::Result;
+use iroh_gossip::proto::TopicId;
+use messaging::{Message, MessageBody};
+use n0_future::time::{Duration, sleep};
+use rand::Rng;
+use std::sync::{Arc, Mutex};
+
+use crate::app::app_state::AppState;
+
+// With cover traffic on, a dummy message goes out at a random point in this range after
+// the last one, so quiet stretches don't give away when nobody is talking.
+const COVER_MIN: Duration = Duration::from_secs(20);
+const COVER_MAX: Duration = Duration::from_secs(120);
+
+// The room's gossip sender. With cover traffic on, every message is padded to one of
+// messaging::privacy::BUCKETS so its size doesn't say what it is.
+#[derive(Debug, Clone)]
+pub struct RoomSender {
+    sender: iroh_gossip::api::GossipSender,
+    topic: TopicId,
+    pad: bool,
+}
+
+impl RoomSender {
+    pub fn new(sender: iroh_gossip::api::GossipSender, topic: TopicId, pad: bool) -> Self {
+        Self { sender, topic, pad }
+    }
+
+    // What every message we sign for this room has to be signed with.
+    pub fn topic(&self) -> &[u8; 32] {
+        self.topic.as_bytes()
+    }
+
+    pub fn covered(&self) -> bool {
+        self.pad
+    }
+
+    pub async fn broadcast(&self, message: Vec<u8>) -> Result<()> {
+        let message = if self.pad {
+            messaging::privacy::pad(&message)?
+        } else {
+            message
+        };
+        // Built with the chaos feature, CHAST_CHAOS drops, delays or repeats what we send.
+        #[cfg(feature = "chaos")]
+        let copies = messaging::chaos::copies().await;
+        #[cfg(not(feature = "chaos"))]
+        let copies = 1;
+        for _ in 0..copies {
+            self.sender.broadcast(message.clone().into()).await?;
+        }
+        Ok(())
+    }
+}
+
+// Sends a dummy message now and then. Runs until we leave the room.
+pub async fn cover_traffic(app_state: Arc<Mutex<AppState>>) {
+    let room = app_state.lock().unwrap().room_generation;
+    loop {
+        let wait = rand::rng().random_range(COVER_MIN..=COVER_MAX);
+        sleep(wait).await;
+        if app_state.lock().unwrap().room_generation != room {
+            break;
+        }
+        if let Err(e) = send_cover(&app_state).await {
+            eprintln!("Failed to send cover traffic: {}", e);
+        }
+    }
+}
+
+pub async fn send_cover(app_state: &Arc<Mutex<AppState>>) -> Result<()> {
+    let (sender, from, secret_key) = {
+        let state = app_state.lock().unwrap();
+        (
+            state.sender.clone(),
+            state.current_node_id,
+            state.secret_key(),
+        )
+    };
+    let (Some(sender), Some(from), Some(secret_key)) = (sender, from, secret_key) else {
+        return Ok(());
+    };
+    let message = Message::new(MessageBody::Cover { from });
+    sender
+        .broadcast(message.to_signed_vec(&secret_key, sender.topic()))
+        .await
+}
@@ -0,0 +1,128 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use slint::{ModelRc, SharedString, VecModel};
+use std::{
+    fs,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use ticket::Ticket;
+
+use crate::app::{app_state::AppState, types};
+
+const RECENT_ROOMS_FILE: &str = "recent_rooms.json";
+const MAX_RECENT_ROOMS: usize = 8;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecentRoom {
+    // One entry per room, even when its ticket changes.
+    pub topic: String,
+    pub name: String,
+    pub ticket: String,
+    pub last_active: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub struct RecentRooms {
+    path: PathBuf,
+    rooms: Vec<RecentRoom>,
+}
+
+impl RecentRooms {
+    pub fn load() -> Self {
+        Self::load_from(RECENT_ROOMS_FILE)
+    }
+
+    pub fn load_from(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let rooms = match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+                eprintln!("Failed to parse {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        Self { path, rooms }
+    }
+
+    // Newest first.
+    pub fn rooms(&self) -> &[RecentRoom] {
+        &self.rooms
+    }
+
+    pub fn visit(&mut self, topic: String, name: String, ticket: String) -> Result<()> {
+        self.rooms.retain(|room| room.topic != topic);
+        self.rooms.insert(
+            0,
+            RecentRoom {
+                topic,
+                name,
+                ticket,
+                last_active: Utc::now(),
+            },
+        );
+        self.rooms.truncate(MAX_RECENT_ROOMS);
+        self.save()
+    }
+
+    pub fn forget(&mut self, ticket: &str) -> Result<()> {
+        self.rooms.retain(|room| room.ticket != ticket);
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let data = serde_json::to_string_pretty(&self.rooms)?;
+        fs::write(&self.path, data)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+// Runs when a room is joined or created and again on leaving, which picks up a ticket
+// renewed while we were in it.
+pub fn record(app_state: &Arc<Mutex<AppState>>) {
+    let state = app_state.lock().unwrap();
+    let (Some(topic), Some(ticket)) = (state.room_topic, state.current_session_token.clone())
+    else {
+        return;
+    };
+    let name = if state.room_info.name.is_empty() {
+        format!(
+            "Room {}",
+            topic.to_string().chars().take(10).collect::<String>()
+        )
+    } else {
+        state.room_info.name.clone()
+    };
+    if let Err(e) = state
+        .recent_rooms
+        .lock()
+        .unwrap()
+        .visit(topic.to_string(), name, ticket)
+    {
+        eprintln!("Error saving recent rooms: {}", e);
+    }
+}
+
+// Runs on the UI thread whenever the start window is about to be shown.
+pub fn refresh_start_recent_rooms(main: &types::StartWindow, app_state: &Arc<Mutex<AppState>>) {
+    let state = app_state.lock().unwrap();
+    let recent_rooms = state.recent_rooms.lock().unwrap();
+    let items: Vec<types::RecentRoomItem> = recent_rooms
+        .rooms()
+        .iter()
+        .map(|room| types::RecentRoomItem {
+            name: SharedString::from(room.name.clone()),
+            ticket: SharedString::from(room.ticket.clone()),
+            last_active: SharedString::from(
+                room.last_active
+                    .with_timezone(&chrono::Local)
+                    .format("%d/%m/%Y %H:%M")
+                    .to_string(),
+            ),
+            locked: Ticket::from_str(&room.ticket).is_ok_and(|ticket| ticket.is_locked()),
+        })
+        .collect();
+    main.set_recent_rooms(ModelRc::new(VecModel::from(items)));
+}
@@ -0,0 +1,258 @@
+use anyhow::{Result, anyhow};
+use iroh::{
+    NodeId,
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler},
+};
+use messaging::{Message, MessageBody, SentMessage, message_id};
+use n0_future::time::{Duration, timeout};
+use slint::Weak;
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use crate::app::{app_state::AppState, networking::display_chat_message, types};
+
+pub const RESEND_ALPN: &[u8] = b"chast/resend/0";
+// How many of our own messages we keep around to fill other people's gaps.
+const OUTBOX_LIMIT: usize = 256;
+// A bigger hole than this is more likely a long outage than a few dropped packets.
+const MAX_RECOVERY: usize = 64;
+const MAX_RESEND_BYTES: usize = 1024 * 1024;
+const RESEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+pub struct Outbox {
+    next_seq: u64,
+    sent: VecDeque<SentMessage>,
+}
+
+impl Outbox {
+    // Numbers a message we're about to send and keeps it for resending.
+    pub fn record(&mut self, id: u64, text: &str) -> u64 {
+        self.next_seq += 1;
+        if self.sent.len() == OUTBOX_LIMIT {
+            self.sent.pop_front();
+        }
+        self.sent.push_back(SentMessage {
+            seq: self.next_seq,
+            id,
+            text: text.to_string(),
+        });
+        self.next_seq
+    }
+
+    pub fn lookup(&self, seqs: &[u64]) -> Vec<SentMessage> {
+        self.sent
+            .iter()
+            .filter(|message| seqs.contains(&message.seq))
+            .cloned()
+            .collect()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Seen {
+    New,
+    Duplicate,
+    // New, but the listed messages before it never arrived.
+    Gap(Vec<u64>),
+}
+
+#[derive(Debug, Default)]
+struct SenderSeqs {
+    last: u64,
+    missing: BTreeSet<u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct SeqTracker {
+    senders: HashMap<NodeId, SenderSeqs>,
+}
+
+impl SeqTracker {
+    pub fn observe(&mut self, from: NodeId, seq: u64) -> Seen {
+        if seq == 0 {
+            return Seen::New;
+        }
+        let sender = self.senders.entry(from).or_default();
+        // The first message we see from someone is our starting point, not a gap.
+        if sender.last == 0 {
+            sender.last = seq;
+            return Seen::New;
+        }
+        if seq <= sender.last {
+            return if sender.missing.remove(&seq) {
+                Seen::New
+            } else {
+                Seen::Duplicate
+            };
+        }
+
+        let first_missing = (sender.last + 1).max(seq.saturating_sub(MAX_RECOVERY as u64));
+        let gap: Vec<u64> = (first_missing..seq).collect();
+        sender.missing.extend(&gap);
+        sender.last = seq;
+        if gap.is_empty() {
+            Seen::New
+        } else {
+            Seen::Gap(gap)
+        }
+    }
+
+    // Stops waiting for messages the sender couldn't give us back.
+    pub fn give_up(&mut self, from: &NodeId, seqs: &[u64]) {
+        if let Some(sender) = self.senders.get_mut(from) {
+            for seq in seqs {
+                sender.missing.remove(seq);
+            }
+        }
+    }
+
+    pub fn forget(&mut self, from: &NodeId) {
+        self.senders.remove(from);
+    }
+}
+
+pub async fn recover_missing(
+    from: NodeId,
+    seqs: Vec<u64>,
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) -> Result<()> {
+    let (endpoint, me) = {
+        let state = app_state.lock().unwrap();
+        (state.endpoint.clone(), state.current_node_id)
+    };
+    let (Some(endpoint), Some(me)) = (endpoint, me) else {
+        return Err(anyhow!("Not connected to a room"));
+    };
+    println!(
+        "DEBUG: Missing {} messages from {}, asking for them again",
+        seqs.len(),
+        from.fmt_short()
+    );
+
+    let request = Message::new(MessageBody::ResendRequest {
+        from: me,
+        seqs: seqs.clone(),
+    });
+    let exchange = async {
+        #[cfg(feature = "chaos")]
+        messaging::chaos::direct().await?;
+        let connection = endpoint.connect(from, RESEND_ALPN).await?;
+        let (mut send, mut recv) = connection.open_bi().await?;
+        send.write_all(&request.to_vec()).await?;
+        send.finish()?;
+        let bytes = recv.read_to_end(MAX_RESEND_BYTES).await?;
+        connection.close(0u32.into(), b"resend done");
+        anyhow::Ok(Message::from_bytes(&bytes)?.body)
+    };
+    let result = match timeout(RESEND_TIMEOUT, exchange).await {
+        Ok(Ok(MessageBody::Resend {
+            from: replier,
+            mut messages,
+        })) if replier == from => {
+            messages.sort_by_key(|message| message.seq);
+            for message in messages {
+                let fresh = {
+                    let state = app_state.lock().unwrap();
+                    let seen = state.seqs.lock().unwrap().observe(from, message.seq);
+                    let id = message_id(&from, message.seq, message.id, &message.text);
+                    seen == Seen::New && state.seen.lock().unwrap().insert(id)
+                };
+                if fresh {
+                    display_chat_message(
+                        from,
+                        message.text,
+                        message.id,
+                        false,
+                        &app_state,
+                        &chat_handle,
+                    );
+                }
+            }
+            Ok(())
+        }
+        Ok(Ok(_)) => Err(anyhow!("Unexpected reply to a resend request")),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(anyhow!("Timed out waiting for resent messages")),
+    };
+
+    // Whatever didn't come back by now isn't coming.
+    let state = app_state.lock().unwrap();
+    state.seqs.lock().unwrap().give_up(&from, &seqs);
+    result
+}
+
+// Hands our recent messages back to peers that missed them.
+#[derive(Clone)]
+pub struct ResendProtocol {
+    app_state: Arc<Mutex<AppState>>,
+}
+
+impl ResendProtocol {
+    pub fn new(app_state: Arc<Mutex<AppState>>) -> Self {
+        Self { app_state }
+    }
+}
+
+impl fmt::Debug for ResendProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResendProtocol").finish_non_exhaustive()
+    }
+}
+
+impl ProtocolHandler for ResendProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let remote = connection.remote_node_id()?;
+        let (mut send, mut recv) = connection.accept_bi().await?;
+        let bytes = recv
+            .read_to_end(MAX_RESEND_BYTES)
+            .await
+            .map_err(AcceptError::from_err)?;
+
+        let message = Message::from_bytes(&bytes).map_err(|_| AcceptError::NotAllowed {})?;
+        let MessageBody::ResendRequest { from, seqs } = message.body else {
+            return Err(AcceptError::NotAllowed {});
+        };
+        let (me, messages) = {
+            let state = self.app_state.lock().unwrap();
+            // Only people in the room with us get our messages back, never someone who
+            // was banned, wasn't let in or whom we blocked.
+            let member = state.names.lock().unwrap().contains_key(&remote)
+                && state.is_admitted(&remote)
+                && !state.banned.contains(&remote)
+                && !state.blocklist.lock().unwrap().is_blocked(&remote);
+            if from != remote || !member {
+                println!(
+                    "DEBUG: Refused a resend request from {}, who isn't in the room",
+                    remote.fmt_short()
+                );
+                return Err(AcceptError::NotAllowed {});
+            }
+            let messages = state.outbox.lock().unwrap().lookup(&seqs);
+            (state.current_node_id, messages)
+        };
+        let Some(me) = me else {
+            return Err(AcceptError::NotAllowed {});
+        };
+
+        println!(
+            "DEBUG: Resending {} of {} messages to {}",
+            messages.len(),
+            seqs.len(),
+            from.fmt_short()
+        );
+        let reply = Message::new(MessageBody::Resend { from: me, messages });
+        send.write_all(&reply.to_vec())
+            .await
+            .map_err(AcceptError::from_err)?;
+        send.finish().map_err(AcceptError::from_err)?;
+        // The requester closes the connection once it has read the whole reply.
+        connection.closed().await;
+        Ok(())
+    }
+}
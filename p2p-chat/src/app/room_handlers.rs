@@ -1,6 +1,11 @@
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use iroh::{Endpoint, NodeAddr, NodeId, protocol::Router};
+use iroh_gossip::{api::GossipReceiver, proto::TopicId};
+use messaging::{Invite, Message, MessageBody, RoomInfo};
 use slint::{ComponentHandle, ModelRc, SharedString, VecModel, Weak};
 use std::{
+    net::SocketAddr,
     str::FromStr,
     sync::{Arc, Mutex},
 };
@@ -8,198 +13,463 @@ use ticket::*;
 
 use crate::app::{
     app_state::AppState,
+    contacts::update_contacts,
+    i18n::tr,
     networking::{handle_messages, setup_networking},
-    types,
-    ui_handlers::update_online_users,
+    notifications, paths,
+    persistence::Persister,
+    presence,
+    privacy::{self, RoomSender},
+    recent_rooms, saved_chats, scheduled, translate, types,
+    ui_handlers::{push_system_message, render_messages, update_messages, update_online_users},
 };
 
 fn create_room_joined_message() -> String {
-    format!(
-        "✅ Successfully joined room!\n\nYou can share this room token with others:\n\nCOPY TOKEN FROM THE BUTTON AT THE TOPBAR\n\nInstructions: Select and copy the text between the brackets to share with others.",
-    )
+    tr("✅ Successfully joined room!\n\nYou can share this room token with others:\n\nCOPY TOKEN FROM THE BUTTON AT THE TOPBAR\n\nInstructions: Select and copy the text between the brackets to share with others.")
+        .to_string()
 }
 
 fn create_room_created_message() -> String {
-    format!(
-        "🎫 Room created successfully!\n\nShare this invitation token with others to join:\n\nCOPY TOKEN FROM THE BUTTON AT THE TOPBAR\n\nInstructions: Select and copy the text between the brackets to share with others.",
-    )
+    tr("🎫 Room created successfully!\n\nShare this invitation token with others to join:\n\nCOPY TOKEN FROM THE BUTTON AT THE TOPBAR\n\nInstructions: Select and copy the text between the brackets to share with others.")
+        .to_string()
 }
 
-pub async fn join_room(
+fn apply_room_info(chat: &types::ChatWindow, info: &RoomInfo) {
+    chat.set_room_name(SharedString::from(if info.name.is_empty() {
+        tr("Untitled room")
+    } else {
+        info.name.as_str()
+    }));
+    chat.set_room_topic(SharedString::from(info.topic.as_str()));
+    chat.set_room_description(SharedString::from(info.description.as_str()));
+}
+
+pub fn update_room_info(chat_handle: &Weak<types::ChatWindow>, app_state: &Arc<Mutex<AppState>>) {
+    let info = app_state.lock().unwrap().room_info.clone();
+    let chat_handle = chat_handle.clone();
+    if let Err(e) = slint::invoke_from_event_loop(move || {
+        if let Some(chat) = chat_handle.upgrade() {
+            apply_room_info(&chat, &info);
+        }
+    }) {
+        eprintln!("Failed to update room info: {:?}", e);
+    }
+}
+
+pub fn set_join_status(join_handle: &Weak<types::JoinWindow>, status: &str) {
+    let join_handle = join_handle.clone();
+    let status = SharedString::from(status);
+    if let Err(e) = slint::invoke_from_event_loop(move || {
+        if let Some(join) = join_handle.upgrade() {
+            join.set_join_status(status);
+        }
+    }) {
+        eprintln!("Failed to update join status: {:?}", e);
+    }
+}
+
+// Dials a peer at an address typed into the join window, for networks where neither
+// discovery nor the relay can find it. Plain "ip:port" (several separated by commas) is
+// the node that shared the ticket; "<node id>@ip:port" is any other member.
+fn add_peer_address(ticket: &mut Ticket, input: &str) -> Result<()> {
+    let (node_id, addrs) = match input.trim().split_once('@') {
+        Some((node_id, addrs)) => (
+            NodeId::from_str(node_id.trim()).context("Invalid node id in the peer address")?,
+            addrs,
+        ),
+        None => match ticket.nodes.first() {
+            Some(node) => (node.node_id, input),
+            None => bail!("This ticket names no peer, write the address as <node id>@<ip:port>"),
+        },
+    };
+    let addrs = addrs
+        .split(',')
+        .map(|addr| {
+            addr.trim()
+                .parse::<SocketAddr>()
+                .with_context(|| format!("Invalid peer address {:?}", addr.trim()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    match ticket.nodes.iter_mut().find(|node| node.node_id == node_id) {
+        Some(node) => node.direct_addresses.extend(addrs),
+        None => ticket
+            .nodes
+            .push(NodeAddr::from_parts(node_id, None, addrs)),
+    }
+    Ok(())
+}
+
+// Everything about the room we're going into that the session keeps, whether we
+// joined it or created it.
+struct Entry {
     username: String,
-    ticket_str: String,
+    // The ticket as it's handed out, so still locked if the room has a password.
+    token: String,
+    topic: TopicId,
+    moderators: Vec<NodeId>,
+    owner: Option<NodeId>,
+    // The nodes the ticket names, before any address typed in by hand.
+    nodes: Vec<NodeId>,
+    info: RoomInfo,
+    capacity: Option<u32>,
+    knock: bool,
+    incognito: bool,
+    // What we show the owner to be let in; the owner needs none.
+    invite: Option<Invite>,
+    invites_required: bool,
+    password: Option<String>,
+    peer_address: Option<String>,
+    last_read: Option<DateTime<Utc>>,
+    // The system message with the ticket that the chat opens with.
+    greeting: String,
+}
+
+// Takes over once the room's networking is up: fills in the app state, starts the
+// session and its watchers, then shows the chat window. `switch` runs on the UI
+// thread to close whichever window we came from.
+async fn enter_room(
+    entry: Entry,
+    (sender, receiver, endpoint, router): (RoomSender, GossipReceiver, Endpoint, Router),
     app_state: Arc<Mutex<AppState>>,
     chat_handle: Weak<types::ChatWindow>,
-    join_handle: Weak<types::JoinWindow>,
+    switch: impl FnOnce(&types::ChatWindow) + Send + 'static,
 ) -> Result<()> {
-    let ticket = Ticket::from_str(&ticket_str)?;
-    let (sender, receiver, endpoint, router, _ticket) =
-        setup_networking(Some(ticket), username.clone()).await?;
-
     {
         let mut state = app_state.lock().unwrap();
         state.sender = Some(sender);
-        state.current_username = username.clone();
+        state.current_username = entry.username.clone();
+        if let Err(e) = state
+            .settings
+            .lock()
+            .unwrap()
+            .remember_username(&entry.username)
+        {
+            eprintln!("Error saving settings: {}", e);
+        }
         state.current_node_id = Some(endpoint.node_id());
-        state.current_session_token = Some(ticket_str.clone());
+        state.current_session_token = Some(entry.token.clone());
+        state.room_topic = Some(entry.topic);
+        state.last_read = entry.last_read;
+        // Incognito rooms get a persister that drops everything.
+        let auto_save = state.settings.lock().unwrap().auto_save.clone();
+        state.persister = if entry.incognito {
+            Persister::default()
+        } else {
+            Persister::start(
+                entry.topic.to_string(),
+                entry.token.clone(),
+                entry.username.clone(),
+                auto_save,
+            )
+        };
+        state.moderators = entry.moderators;
+        state.room_owner = entry.owner;
+        state.room_nodes = entry.nodes;
+        state.room_info = entry.info;
+        state.room_capacity = entry.capacity;
+        state.room_knock = entry.knock;
+        state.room_incognito = entry.incognito;
+        state.room_invite = entry.invite;
+        state.room_password = entry.password;
+        state.room_peer_address = entry.peer_address;
+        state.invites_required = entry.invites_required;
         state.endpoint = Some(endpoint.clone());
         state.router = Some(router);
     }
 
-    let chat_handle_clone = chat_handle.clone();
-    let app_state_clone = app_state.clone();
-    tokio::spawn(async move {
-        if let Err(e) = handle_messages(receiver, chat_handle_clone, app_state_clone).await {
-            eprintln!("Error handling messages: {}", e);
-        }
-    });
+    recent_rooms::record(&app_state);
+
+    n0_future::task::spawn(handle_messages(
+        receiver,
+        app_state.clone(),
+        chat_handle.clone(),
+    ));
+    if app_state
+        .lock()
+        .unwrap()
+        .sender
+        .as_ref()
+        .is_some_and(|sender| sender.covered())
+    {
+        n0_future::task::spawn(privacy::cover_traffic(app_state.clone()));
+    }
+    n0_future::task::spawn(scheduled::watch(app_state.clone(), chat_handle.clone()));
+    n0_future::task::spawn(presence::watch_idle(app_state.clone(), chat_handle.clone()));
+    paths::watch_relay(endpoint.clone(), &app_state, &chat_handle);
+    paths::watch_addresses(endpoint.clone(), &app_state, &chat_handle);
+    println!("DEBUG: Session started for room {}", entry.topic);
 
     update_online_users(&chat_handle, &app_state);
+    update_contacts(&chat_handle, &app_state);
 
     let chat_handle_for_ui = chat_handle.clone();
-    let join_handle_for_ui = join_handle.clone();
-    let username_for_ui = username.clone();
     let app_state_for_ui = app_state.clone();
+    let (username, greeting) = (entry.username, entry.greeting);
 
-    match slint::invoke_from_event_loop(move || {
-        if let Some(chat) = chat_handle_for_ui.upgrade() {
-            chat.set_current_username(SharedString::from(username_for_ui.clone()));
-            chat.set_connection_status(SharedString::from("Connected"));
-
-            let ticket_message = create_room_joined_message();
-            let system_message = types::ChatMessage {
-                username: SharedString::from("System"),
-                content: SharedString::from(ticket_message),
-                timestamp: SharedString::from(chrono::Local::now().format("%H:%M").to_string()),
-                is_own: false,
-                is_system: true,
-            };
-
-            {
-                let state = app_state_for_ui.lock().unwrap();
-                let mut messages = state.messages.lock().unwrap();
-                messages.push(system_message.clone());
-            }
+    slint::invoke_from_event_loop(move || {
+        let Some(chat) = chat_handle_for_ui.upgrade() else {
+            println!("ERROR: Chat window handle is invalid");
+            return;
+        };
+        chat.set_current_username(SharedString::from(username));
+        chat.set_connection_status(SharedString::from("Connected"));
+        {
+            let state = app_state_for_ui.lock().unwrap();
+            let can_post = state
+                .current_node_id
+                .is_none_or(|node_id| state.can_post(&node_id));
+            chat.set_can_post(can_post);
+            // Rejoining a room we created, e.g. after a lost connection, keeps us its owner.
+            chat.set_is_room_owner(
+                state.room_owner.is_some() && state.room_owner == state.current_node_id,
+            );
+            apply_room_info(&chat, &state.room_info);
+            chat.set_notifications_muted(notifications::is_muted(&state));
+            chat.set_room_incognito(state.room_incognito);
+            chat.set_translation_available(
+                state.settings.lock().unwrap().translation.is_available(),
+            );
+            chat.set_auto_translate(translate::is_auto(&state));
+            chat.set_unread_count(0);
+            chat.set_connection_lost(false);
+            chat.set_scheduled_messages(ModelRc::new(VecModel::from(state.scheduled.items())));
+        }
 
-            let current_messages = {
-                let state = app_state_for_ui.lock().unwrap();
-                let messages = state.messages.lock().unwrap();
-                messages.clone()
-            };
-            let messages_model = VecModel::from(current_messages);
-            chat.set_messages(ModelRc::new(messages_model));
+        let system_message = types::ChatMessage {
+            username: SharedString::from("System"),
+            content: SharedString::from(greeting),
+            timestamp: SharedString::from(chrono::Local::now().format("%H:%M").to_string()),
+            sender_id: SharedString::new(),
+            burst_id: 0,
+            message_id: SharedString::new(),
+            delivery: SharedString::new(),
+            is_own: false,
+            is_system: true,
+            event: types::SystemEvent::Ticket,
+            is_announcement: false,
+            is_action: false,
+            image_index: -1,
+            code_index: -1,
+            translation: Default::default(),
+            name_color: Default::default(),
+        };
+        {
+            let state = app_state_for_ui.lock().unwrap();
+            state.messages.lock().unwrap().push(system_message);
+            render_messages(&chat, &state);
+        }
 
-            if let Some(join) = join_handle_for_ui.upgrade() {
+        switch(&chat);
+    })
+    .map_err(|e| anyhow::anyhow!("Failed to update UI: {:?}", e))
+}
+
+pub async fn join_room(
+    username: String,
+    ticket_str: String,
+    password: String,
+    peer_address: String,
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+    join_handle: Weak<types::JoinWindow>,
+) -> Result<()> {
+    let mut ticket = Ticket::from_str(&ticket_str)?;
+    if ticket.is_locked() {
+        if password.is_empty() {
+            bail!("This room is password protected, enter its password");
+        }
+        ticket.unlock(&password)?;
+    }
+    let nodes = ticket.nodes.iter().map(|node| node.node_id).collect();
+    if !peer_address.trim().is_empty() {
+        add_peer_address(&mut ticket, &peer_address)?;
+    }
+    let entry = Entry {
+        username: username.clone(),
+        token: ticket_str,
+        topic: ticket.topic,
+        moderators: ticket.moderators.clone(),
+        owner: ticket.owner,
+        nodes,
+        info: ticket.info.clone(),
+        capacity: ticket.capacity,
+        knock: ticket.knock,
+        incognito: ticket.incognito,
+        invite: ticket.invite,
+        invites_required: ticket.invite.is_some(),
+        password: (!password.is_empty()).then_some(password),
+        peer_address: (!peer_address.trim().is_empty()).then_some(peer_address),
+        last_read: saved_chats::last_read(&ticket.topic.to_string()),
+        greeting: create_room_joined_message(),
+    };
+    if entry.knock {
+        set_join_status(
+            &join_handle,
+            tr("🚪 Knocking… waiting for the room owner to let you in"),
+        );
+    }
+    let (sender, receiver, endpoint, router, _ticket) = setup_networking(
+        Some(ticket),
+        username,
+        app_state.clone(),
+        chat_handle.clone(),
+    )
+    .await?;
+
+    enter_room(
+        entry,
+        (sender, receiver, endpoint, router),
+        app_state,
+        chat_handle,
+        move |chat| {
+            if let Some(join) = join_handle.upgrade() {
+                join.set_join_status(SharedString::new());
                 let _ = chat.show();
                 let _ = join.hide();
             }
-        }
-    }) {
-        Ok(_) => {}
-        Err(e) => {
-            return Err(anyhow::anyhow!("Failed to update UI: {:?}", e));
-        }
-    }
+        },
+    )
+    .await
+}
 
-    Ok(())
+// Everything picked in the Create window besides the username.
+#[derive(Debug, Default)]
+pub struct RoomOptions {
+    pub announcement: bool,
+    pub info: RoomInfo,
+    pub capacity: Option<u32>,
+    pub knock: bool,
+    pub incognito: bool,
+    // Locks the ticket, so joining takes this password as well as the ticket.
+    pub password: Option<String>,
 }
 
 pub async fn create_room(
     username: String,
+    options: RoomOptions,
     app_state: Arc<Mutex<AppState>>,
     chat_handle: Weak<types::ChatWindow>,
     create_handle: Weak<types::CreateWindow>,
 ) -> Result<()> {
     println!("Creating room for username: {}", username);
-    let (sender, receiver, endpoint, router, room_ticket) =
-        setup_networking(None, username.clone()).await?;
+    let (sender, receiver, endpoint, router, mut room_ticket) = setup_networking(
+        None,
+        username.clone(),
+        app_state.clone(),
+        chat_handle.clone(),
+    )
+    .await?;
     println!("DEBUG: setup_networking returned successfully");
-    println!("Networking setup complete");
 
-    {
-        let mut state = app_state.lock().unwrap();
-        state.sender = Some(sender);
-        state.current_username = username.clone();
-        state.current_node_id = Some(endpoint.node_id());
-        state.current_session_token = Some(room_ticket.to_string());
-        state.endpoint = Some(endpoint.clone());
-        state.router = Some(router);
+    if options.announcement {
+        room_ticket.moderators = vec![endpoint.node_id()];
+        println!("DEBUG: Created announcement room");
+    }
+    room_ticket.owner = Some(endpoint.node_id());
+    room_ticket.info = options.info;
+    room_ticket.capacity = options.capacity;
+    room_ticket.knock = options.knock;
+    room_ticket.incognito = options.incognito;
+    room_ticket.invite = Some(Invite::new(
+        endpoint.secret_key(),
+        room_ticket.topic.as_bytes(),
+    ));
+    // Locking takes the nodes, moderators and owner out of the ticket, so they're kept first.
+    let moderators = room_ticket.moderators.clone();
+    let owner = room_ticket.owner;
+    let nodes = room_ticket.nodes.iter().map(|node| node.node_id).collect();
+    if let Some(password) = &options.password {
+        room_ticket.lock(password)?;
+        println!("DEBUG: Locked the room ticket with a password");
     }
-    println!("App state updated");
-
-    let chat_handle_clone = chat_handle.clone();
-    let app_state_clone = app_state.clone();
-    tokio::spawn(async move {
-        if let Err(e) = handle_messages(receiver, chat_handle_clone, app_state_clone).await {
-            eprintln!("Error handling messages: {}", e);
-        }
-    });
-    println!("Message handler started");
-
-    update_online_users(&chat_handle, &app_state);
-
-    let chat_handle_for_ui = chat_handle.clone();
-    let create_handle_for_ui = create_handle.clone();
-    let username_for_ui = username.clone();
-    let app_state_for_ui = app_state.clone();
-
-    match slint::invoke_from_event_loop(move || {
-        println!("Attempting to switch to chat window...");
-        if let Some(chat) = chat_handle_for_ui.upgrade() {
-            println!("Chat window found, updating UI");
-            chat.set_current_username(SharedString::from(username_for_ui.clone()));
-            chat.set_connection_status(SharedString::from("Connected"));
-
-            let ticket_message = create_room_created_message();
-            let system_message = types::ChatMessage {
-                username: SharedString::from("System"),
-                content: SharedString::from(ticket_message),
-                timestamp: SharedString::from(chrono::Local::now().format("%H:%M").to_string()),
-                is_own: false,
-                is_system: true,
-            };
-
-            {
-                let state = app_state_for_ui.lock().unwrap();
-                let mut messages = state.messages.lock().unwrap();
-                messages.push(system_message.clone());
-            }
 
-            let current_messages = {
-                let state = app_state_for_ui.lock().unwrap();
-                let messages = state.messages.lock().unwrap();
-                messages.clone()
-            };
-            let messages_model = VecModel::from(current_messages);
-            chat.set_messages(ModelRc::new(messages_model));
-
-            println!("Added system message with room token");
-
-            if let Some(create) = create_handle_for_ui.upgrade() {
-                println!("Create window found, switching to chat");
-                match chat.show() {
-                    Ok(_) => println!("Chat window shown successfully"),
-                    Err(e) => println!("Error showing chat window: {:?}", e),
+    let entry = Entry {
+        username: username.clone(),
+        token: room_ticket.to_string(),
+        topic: room_ticket.topic,
+        moderators,
+        owner,
+        nodes,
+        info: room_ticket.info,
+        capacity: room_ticket.capacity,
+        knock: room_ticket.knock,
+        incognito: room_ticket.incognito,
+        invite: None,
+        invites_required: true,
+        password: options.password,
+        peer_address: None,
+        last_read: None,
+        greeting: create_room_created_message(),
+    };
+    enter_room(
+        entry,
+        (sender, receiver, endpoint, router),
+        app_state,
+        chat_handle,
+        move |chat| {
+            if let Some(create) = create_handle.upgrade() {
+                if let Err(e) = chat.show() {
+                    println!("Error showing chat window: {:?}", e);
                 }
-                match create.hide() {
-                    Ok(_) => println!("Create window hidden successfully"),
-                    Err(e) => println!("Error hiding create window: {:?}", e),
+                if let Err(e) = create.hide() {
+                    println!("Error hiding create window: {:?}", e);
                 }
-                println!("Window switch completed");
             } else {
                 println!("ERROR: Create window handle is invalid");
             }
-        } else {
-            println!("ERROR: Chat window handle is invalid");
-        }
-    }) {
-        Ok(_) => println!("Event loop invocation successful"),
-        Err(e) => {
-            println!("ERROR: Failed to invoke on event loop: {:?}", e);
-            return Err(anyhow::anyhow!("Failed to update UI: {:?}", e));
-        }
-    }
+        },
+    )
+    .await?;
 
     println!("Create room completed successfully for: {}", username);
     Ok(())
 }
+
+// Revokes the invite in the ticket we've been handing out and puts a fresh one in its
+// place, so a leaked link stops working without having to move the room.
+pub async fn renew_invite(
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) -> Result<()> {
+    let (token, me, secret_key, sender) = {
+        let state = app_state.lock().unwrap();
+        (
+            state.current_session_token.clone(),
+            state
+                .current_node_id
+                .filter(|me| state.room_owner == Some(*me)),
+            state.secret_key(),
+            state.sender.clone(),
+        )
+    };
+    let (Some(token), Some(me), Some(secret_key), Some(sender)) = (token, me, secret_key, sender)
+    else {
+        bail!("Only the room owner can hand out new invites");
+    };
+    let mut ticket = Ticket::from_str(&token)?;
+    let Some(old) = ticket.invite else {
+        bail!("This room's ticket has no invite to revoke");
+    };
+    ticket.invite = Some(Invite::new(&secret_key, ticket.topic.as_bytes()));
+
+    let revoke = Message::new(MessageBody::RevokeInvite {
+        from: me,
+        invite: old.id,
+    });
+    sender
+        .broadcast(revoke.to_signed_vec(&secret_key, sender.topic()))
+        .await?;
+    {
+        let mut state = app_state.lock().unwrap();
+        state.revoked_invites.insert(old.id);
+        state.current_session_token = Some(ticket.to_string());
+    }
+    println!("> Revoked invite {:016x}", old.id);
+    push_system_message(
+        &app_state,
+        tr("🔗 The old invite link no longer works. Copy Token now gives you the new one.")
+            .to_string(),
+    );
+    update_messages(&chat_handle, &app_state);
+    Ok(())
+}
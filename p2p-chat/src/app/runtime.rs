@@ -0,0 +1,37 @@
+use std::future::Future;
+
+// Native builds drive async work on a tokio runtime owned by `App::run`; in the browser
+// there is no runtime, so futures go straight onto the JS event loop.
+#[derive(Clone)]
+pub struct Spawner {
+    #[cfg(not(target_arch = "wasm32"))]
+    handle: tokio::runtime::Handle,
+}
+
+impl Spawner {
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn new(handle: tokio::runtime::Handle) -> Self {
+        Self { handle }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.handle.spawn(future);
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + 'static,
+    {
+        n0_future::task::spawn(future);
+    }
+}
@@ -1,9 +1,12 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
 use std::fs::{self, File};
-use std::io::{BufReader, BufWriter};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use chrono::{DateTime, Utc};
-use anyhow::{Result, Context};
+
+use crate::app::types;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
@@ -11,13 +14,55 @@ pub struct ChatMessage {
     pub content: String,
     pub timestamp: DateTime<Utc>,
     pub message_type: MessageType,
+    // The content in the reader's language, when it was translated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub translation: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum MessageType {
     Text,
     File,
+    // A notice saved before events had kinds.
     System,
+    Action,
+    Event(SystemEvent),
+}
+
+// Something that happened in the room rather than something someone said.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SystemEvent {
+    Joined,
+    Left,
+    Ticket,
+    History,
+    Notice,
+}
+
+impl SystemEvent {
+    pub fn from_ui(event: types::SystemEvent) -> Option<Self> {
+        match event {
+            types::SystemEvent::None => None,
+            types::SystemEvent::Joined => Some(Self::Joined),
+            types::SystemEvent::Left => Some(Self::Left),
+            types::SystemEvent::Ticket => Some(Self::Ticket),
+            types::SystemEvent::History => Some(Self::History),
+            types::SystemEvent::Notice => Some(Self::Notice),
+            // Only ever drawn, never saved.
+            types::SystemEvent::Unread => None,
+        }
+    }
+
+    pub fn to_ui(event: Option<Self>) -> types::SystemEvent {
+        match event {
+            None => types::SystemEvent::None,
+            Some(Self::Joined) => types::SystemEvent::Joined,
+            Some(Self::Left) => types::SystemEvent::Left,
+            Some(Self::Ticket) => types::SystemEvent::Ticket,
+            Some(Self::History) => types::SystemEvent::History,
+            Some(Self::Notice) => types::SystemEvent::Notice,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,6 +72,129 @@ pub struct ChatSession {
     pub messages: Vec<ChatMessage>,
     pub created_at: DateTime<Utc>,
     pub last_updated: DateTime<Utc>,
+    // The ticket the room was joined or created with, so the saved chat can rejoin it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ticket: Option<String>,
+    // The name we went by in the room.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    // How far we'd read when we were last in the room. Anything newer sits under a
+    // "New messages" divider when the chat is opened or the room rejoined.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_read: Option<DateTime<Utc>>,
+}
+
+impl ChatSession {
+    // Adds what another copy of the same chat has and this one doesn't.
+    pub fn merge(&mut self, other: ChatSession) {
+        // The copy that was in the room last knows the ticket that works now.
+        if other.last_updated > self.last_updated || self.ticket.is_none() {
+            self.ticket = other.ticket.or(self.ticket.take());
+            self.username = other.username.or(self.username.take());
+        }
+        for sender in other.participants {
+            if !self.participants.contains(&sender) {
+                self.participants.push(sender);
+            }
+        }
+        let mut known: HashSet<(DateTime<Utc>, String, String)> = self
+            .messages
+            .iter()
+            .map(|m| (m.timestamp, m.sender.clone(), m.content.clone()))
+            .collect();
+        for message in other.messages {
+            if known.insert((
+                message.timestamp,
+                message.sender.clone(),
+                message.content.clone(),
+            )) {
+                self.messages.push(message);
+            }
+        }
+        self.messages.sort_by_key(|m| m.timestamp);
+        self.last_read = self.last_read.max(other.last_read);
+        self.created_at = self.created_at.min(other.created_at);
+        self.last_updated = self.last_updated.max(other.last_updated);
+    }
+}
+
+// A chat window row pushed out of memory during a long session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpilledMessage {
+    pub username: String,
+    pub content: String,
+    pub timestamp: String,
+    pub sender_id: String,
+    pub message_id: String,
+    pub delivery: String,
+    pub is_own: bool,
+    pub is_system: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event: Option<SystemEvent>,
+    #[serde(default)]
+    pub is_announcement: bool,
+    #[serde(default)]
+    pub is_action: bool,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub translation: String,
+}
+
+// Spilled messages as JSON lines used like a stack: whatever is evicted next is always
+// newer than everything already here, so scrolling back pops pages off the end.
+pub struct MessageSpill {
+    path: PathBuf,
+    file: File,
+    // Where each line starts, so popping is a truncate instead of a rewrite.
+    offsets: Vec<u64>,
+}
+
+impl MessageSpill {
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    pub fn push(&mut self, messages: &[SpilledMessage]) -> Result<()> {
+        let mut end = self.file.seek(SeekFrom::End(0))?;
+        let mut writer = BufWriter::new(&self.file);
+        for message in messages {
+            let mut line = serde_json::to_vec(message)?;
+            line.push(b'\n');
+            writer.write_all(&line)?;
+            self.offsets.push(end);
+            end += line.len() as u64;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Takes the newest `count` spilled messages back, oldest first.
+    pub fn pop(&mut self, count: usize) -> Result<Vec<SpilledMessage>> {
+        let keep = self.offsets.len().saturating_sub(count);
+        let Some(&start) = self.offsets.get(keep) else {
+            return Ok(Vec::new());
+        };
+        self.file.seek(SeekFrom::Start(start))?;
+        let messages = BufReader::new(&self.file)
+            .lines()
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect::<Result<Vec<_>>>()
+            .with_context(|| format!("Failed to read {}", self.path.display()))?;
+        self.file.set_len(start)?;
+        self.offsets.truncate(keep);
+        Ok(messages)
+    }
+}
+
+impl Drop for MessageSpill {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            eprintln!("Failed to remove {}: {}", self.path.display(), e);
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -46,6 +214,26 @@ impl Default for Config {
     }
 }
 
+// Kept next to the chats, and never counted as one of them.
+const INDEX_FILE: &str = "index.json";
+
+// What the user has added to a saved chat, keyed by its file name in the index.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChatMeta {
+    pub favorite: bool,
+    pub tags: Vec<String>,
+    // Shown instead of the room id.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl ChatMeta {
+    fn is_empty(&self) -> bool {
+        !self.favorite && self.tags.is_empty() && self.name.is_none()
+    }
+}
+
 pub struct ChatSaveManager {
     config: Config,
     save_dir: PathBuf,
@@ -54,107 +242,238 @@ pub struct ChatSaveManager {
 impl ChatSaveManager {
     pub fn new(config: Config) -> Result<Self> {
         let save_dir = PathBuf::from(&config.save_directory);
-        
+
         if !save_dir.exists() {
-            fs::create_dir_all(&save_dir)
-                .context("Failed to create save directory")?;
+            fs::create_dir_all(&save_dir).context("Failed to create save directory")?;
+        }
+
+        Ok(Self { config, save_dir })
+    }
+
+    pub fn open_spill(&self, session_id: &str) -> Result<MessageSpill> {
+        let dir = self.save_dir.join("spill");
+        fs::create_dir_all(&dir).context("Failed to create spill directory")?;
+        let path = dir.join(format!("{}_{}.jsonl", session_id, std::process::id()));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .with_context(|| format!("Failed to create file: {}", path.display()))?;
+        Ok(MessageSpill {
+            path,
+            file,
+            offsets: Vec::new(),
+        })
+    }
+
+    // One file per room, rewritten as the conversation grows, so rejoining a room carries
+    // on where its saved chat left off.
+    pub fn session_path(&self, session_id: &str) -> PathBuf {
+        self.save_dir.join(format!("chat_{}.json", session_id))
+    }
+
+    pub fn load_session(&self, session_id: &str) -> Result<Option<ChatSession>> {
+        let path = self.session_path(session_id);
+        if !path.exists() && !backup_path(&path).exists() {
+            return Ok(None);
+        }
+        self.load_chat_from_file(&path).map(Some)
+    }
+
+    pub fn load_index(&self) -> BTreeMap<String, ChatMeta> {
+        let path = self.save_dir.join(INDEX_FILE);
+        match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+                eprintln!("Failed to parse {}: {}", path.display(), e);
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
         }
-        
-        Ok(Self {
-            config,
-            save_dir,
+    }
+
+    fn save_index(&self, index: &BTreeMap<String, ChatMeta>) -> Result<()> {
+        let path = self.save_dir.join(INDEX_FILE);
+        fs::write(&path, serde_json::to_string_pretty(index)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn update_meta(&self, filename: &str, update: impl FnOnce(&mut ChatMeta)) -> Result<()> {
+        let mut index = self.load_index();
+        let meta = index.entry(filename.to_string()).or_default();
+        update(meta);
+        if meta.is_empty() {
+            index.remove(filename);
+        }
+        self.save_index(&index)
+    }
+
+    // The file keeps its name, since it's what ties the chat to its room. An empty name
+    // goes back to showing the room id.
+    pub fn rename_session(&self, filename: &str, name: &str) -> Result<()> {
+        let name = name.trim();
+        self.update_meta(filename, |meta| {
+            meta.name = (!name.is_empty()).then(|| name.to_string());
         })
     }
 
+    pub fn delete_session(&self, filename: &str) -> Result<()> {
+        let path = self.save_dir.join(filename);
+        fs::remove_file(&path)
+            .with_context(|| format!("Failed to delete file: {}", path.display()))?;
+        let _ = fs::remove_file(backup_path(&path));
+
+        let mut index = self.load_index();
+        if index.remove(filename).is_some() {
+            self.save_index(&index)?;
+        }
+        println!("Deleted saved chat: {}", path.display());
+        Ok(())
+    }
+
+    // Each room's current saved chat, leaving out files from before there was one per room.
+    pub fn load_sessions(&self) -> Result<Vec<ChatSession>> {
+        let mut sessions = Vec::new();
+        for info in self.get_saved_chats()? {
+            match self.load_chat_from_file(&info.path) {
+                Ok(session) if info.path == self.session_path(&session.session_id) => {
+                    sessions.push(session)
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Skipping {}: {:#}", info.filename, e),
+            }
+        }
+        Ok(sessions)
+    }
+
     pub fn auto_save_chat(&self, session: &ChatSession) -> Result<()> {
         if !self.config.auto_save_enabled {
             return Ok(());
         }
 
-        let filename = format!("chat_{}_{}.json", 
-            session.session_id, 
-            session.created_at.format("%Y%m%d_%H%M%S")
-        );
-        let file_path = self.save_dir.join(filename);
-        
-        self.save_chat_to_file(session, &file_path)?;
+        self.save_chat_to_file(session, &self.session_path(&session.session_id))?;
         self.cleanup_old_chats()?;
-        
+
         Ok(())
     }
 
+    // Writes next to the target and renames over it, so a crash mid-write leaves the
+    // last save intact. The save before that is kept as a `.bak` in case the new one
+    // can't be read back.
     pub fn save_chat_to_file(&self, session: &ChatSession, path: &Path) -> Result<()> {
-        let file = File::create(path)
-            .with_context(|| format!("Failed to create file: {}", path.display()))?;
-        
-        let writer = BufWriter::new(file);
-        serde_json::to_writer_pretty(writer, session)
+        let tmp_path = path.with_extension("json.tmp");
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create file: {}", tmp_path.display()))?;
+
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, session)
             .context("Failed to serialize chat session")?;
-        
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|file| file.sync_all())
+            .with_context(|| format!("Failed to write file: {}", tmp_path.display()))?;
+
+        if path.exists()
+            && let Err(e) = fs::copy(path, backup_path(path))
+        {
+            eprintln!("Failed to back up {}: {}", path.display(), e);
+        }
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to replace file: {}", path.display()))?;
+
         println!("Chat saved to: {}", path.display());
         Ok(())
     }
 
     pub fn load_chat_from_file(&self, path: &Path) -> Result<ChatSession> {
-        let file = File::open(path)
-            .with_context(|| format!("Failed to open file: {}", path.display()))?;
-        
-        let reader = BufReader::new(file);
-        let session: ChatSession = serde_json::from_reader(reader)
-            .context("Failed to deserialize chat session")?;
-        
-        Ok(session)
+        let error = match read_session(path) {
+            Ok(session) => return Ok(session),
+            Err(e) => e,
+        };
+        let backup = backup_path(path);
+        if !backup.exists() {
+            return Err(error);
+        }
+        eprintln!("{:#}, loading the backup instead", error);
+        read_session(&backup).map_err(|_| error.context("The backup could not be loaded either"))
     }
 
     pub fn get_saved_chats(&self) -> Result<Vec<ChatFileInfo>> {
         let mut chat_files = Vec::new();
-        
+
         if !self.save_dir.exists() {
             return Ok(chat_files);
         }
 
-        let entries = fs::read_dir(&self.save_dir)
-            .context("Failed to read save directory")?;
+        let entries = fs::read_dir(&self.save_dir).context("Failed to read save directory")?;
 
         for entry in entries {
             let entry = entry.context("Failed to read directory entry")?;
             let path = entry.path();
-            
-            if path.extension().and_then(|s| s.to_str()) == Some("json") {
-                if let Ok(metadata) = entry.metadata() {
-                    if let Ok(modified) = metadata.modified() {
-                        let file_info = ChatFileInfo {
-                            path: path.clone(),
-                            filename: path.file_name()
-                                .and_then(|n| n.to_str())
-                                .unwrap_or("Unknown")
-                                .to_string(),
-                            last_modified: modified.into(),
-                            size: metadata.len(),
-                        };
-                        chat_files.push(file_info);
-                    }
-                }
+
+            if path.extension().and_then(|s| s.to_str()) == Some("json")
+                && path.file_name().and_then(|n| n.to_str()) != Some(INDEX_FILE)
+                && let Ok(metadata) = entry.metadata()
+                && let Ok(modified) = metadata.modified()
+            {
+                let file_info = ChatFileInfo {
+                    path: path.clone(),
+                    filename: path
+                        .file_name()
+                        .and_then(|n| n.to_str())
+                        .unwrap_or("Unknown")
+                        .to_string(),
+                    last_modified: modified.into(),
+                    size: metadata.len(),
+                };
+                chat_files.push(file_info);
             }
         }
 
-        chat_files.sort_by(|a, b| b.last_modified.cmp(&a.last_modified));
-        
+        chat_files.sort_by_key(|b| std::cmp::Reverse(b.last_modified));
+
         Ok(chat_files)
     }
 
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn open_file_explorer_for_restore(&self) -> Result<Option<PathBuf>> {
         use rfd::FileDialog;
-        
+
         let file = FileDialog::new()
             .add_filter("JSON Chat Files", &["json"])
             .set_directory(&self.save_dir)
             .set_title("Select Chat File to Restore")
             .pick_file();
-            
+
+        Ok(file)
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn open_file_explorer_for_restore(&self) -> Result<Option<PathBuf>> {
+        Ok(None)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn open_file_explorer_for_save(&self, file_name: &str) -> Result<Option<PathBuf>> {
+        use rfd::FileDialog;
+
+        let file = FileDialog::new()
+            .add_filter("JSON Chat Files", &["json"])
+            .set_directory(&self.save_dir)
+            .set_file_name(file_name)
+            .set_title("Save Chat As")
+            .save_file();
+
         Ok(file)
     }
 
+    #[cfg(target_arch = "wasm32")]
+    pub fn open_file_explorer_for_save(&self, _file_name: &str) -> Result<Option<PathBuf>> {
+        Ok(None)
+    }
+
     pub fn restore_chat_interactive(&self) -> Result<Option<ChatSession>> {
         if let Some(path) = self.open_file_explorer_for_restore()? {
             let session = self.load_chat_from_file(&path)?;
@@ -165,29 +484,54 @@ impl ChatSaveManager {
         }
     }
 
+    // Favourites are never cleaned up, and don't count towards the limit.
     fn cleanup_old_chats(&self) -> Result<()> {
-        let mut chat_files = self.get_saved_chats()?;
-        
+        let mut index = self.load_index();
+        let mut chat_files: Vec<ChatFileInfo> = self
+            .get_saved_chats()?
+            .into_iter()
+            .filter(|info| !index.get(&info.filename).is_some_and(|meta| meta.favorite))
+            .collect();
+
         if chat_files.len() > self.config.max_saved_chats {
-            chat_files.sort_by(|a, b| a.last_modified.cmp(&b.last_modified));
-            
+            chat_files.sort_by_key(|a| a.last_modified);
+
             let files_to_remove = chat_files.len() - self.config.max_saved_chats;
             for file_info in chat_files.iter().take(files_to_remove) {
                 if let Err(e) = fs::remove_file(&file_info.path) {
-                    eprintln!("Failed to remove old chat file {}: {}", 
-                        file_info.path.display(), e);
+                    eprintln!(
+                        "Failed to remove old chat file {}: {}",
+                        file_info.path.display(),
+                        e
+                    );
                 }
+                let _ = fs::remove_file(backup_path(&file_info.path));
+                index.remove(&file_info.filename);
             }
+            self.save_index(&index)?;
         }
-        
+
         Ok(())
     }
 }
 
+fn backup_path(path: &Path) -> PathBuf {
+    path.with_extension("json.bak")
+}
+
+fn read_session(path: &Path) -> Result<ChatSession> {
+    let file =
+        File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?;
+
+    let reader = BufReader::new(file);
+    serde_json::from_reader(reader)
+        .with_context(|| format!("Failed to deserialize chat session {}", path.display()))
+}
+
 #[derive(Debug, Clone)]
 pub struct ChatFileInfo {
     pub path: PathBuf,
     pub filename: String,
     pub last_modified: DateTime<Utc>,
     pub size: u64,
-}
\ No newline at end of file
+}
@@ -0,0 +1,264 @@
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+use slint::{ModelRc, SharedString, VecModel, Weak};
+use std::{
+    collections::BTreeSet,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use ticket::Ticket;
+
+use crate::app::{
+    app_state::AppState,
+    notifications,
+    save::{
+        ChatFileInfo, ChatMessage, ChatMeta, ChatSaveManager, Config, MessageType, SystemEvent,
+    },
+    types,
+    ui_handlers::update_messages,
+};
+
+// The filter chip that shows only starred chats.
+pub const FAVORITES_FILTER: &str = "★";
+
+fn manager() -> Result<ChatSaveManager> {
+    ChatSaveManager::new(Config::default())
+}
+
+fn filename(path: &str) -> Result<String> {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("Not a saved chat: {}", path))
+}
+
+// Lowercased and without repeats, so "Work, work" is one tag.
+pub fn parse_tags(text: &str) -> Vec<String> {
+    let mut tags = Vec::new();
+    for tag in text.split(',') {
+        let tag = tag.trim().to_lowercase();
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    tags
+}
+
+fn chat_file_item(
+    manager: &ChatSaveManager,
+    info: ChatFileInfo,
+    meta: ChatMeta,
+) -> types::ChatFileItem {
+    let (display_name, summary) = match manager.load_chat_from_file(&info.path) {
+        Ok(session) => {
+            let room: String = session.session_id.chars().take(10).collect();
+            let mut people = session
+                .participants
+                .iter()
+                .take(3)
+                .cloned()
+                .collect::<Vec<_>>();
+            if session.participants.len() > 3 {
+                people.push(format!("{} more", session.participants.len() - 3));
+            }
+            (
+                format!("Room {}", room),
+                if people.is_empty() {
+                    format!("{} messages", session.messages.len())
+                } else {
+                    format!(
+                        "{} messages with {}",
+                        session.messages.len(),
+                        people.join(", ")
+                    )
+                },
+            )
+        }
+        Err(e) => (info.filename.clone(), format!("Can't be read: {}", e)),
+    };
+    types::ChatFileItem {
+        file_path: SharedString::from(info.path.display().to_string()),
+        display_name: SharedString::from(meta.name.unwrap_or(display_name)),
+        last_modified: SharedString::from(
+            info.last_modified
+                .with_timezone(&chrono::Local)
+                .format("%d/%m/%Y %H:%M")
+                .to_string(),
+        ),
+        size_display: SharedString::from(format!("{:.1} KB", info.size as f64 / 1024.0)),
+        selected: false,
+        summary: SharedString::from(summary),
+        favorite: meta.favorite,
+        tags: SharedString::from(meta.tags.join(", ")),
+    }
+}
+
+// Runs on the UI thread from StartWindow callbacks, so no event loop hop is needed.
+pub fn refresh_start_saved_chats(main: &types::StartWindow) {
+    let manager = match manager() {
+        Ok(manager) => manager,
+        Err(e) => {
+            main.set_saved_chats_error(format!("{:#}", e).into());
+            return;
+        }
+    };
+    let index = manager.load_index();
+    let tags: BTreeSet<String> = index
+        .values()
+        .flat_map(|meta| meta.tags.iter().cloned())
+        .collect();
+
+    // A tag that's no longer on any chat can't stay selected.
+    let mut filter = main.get_saved_chat_filter().to_string();
+    if !filter.is_empty() && filter != FAVORITES_FILTER && !tags.contains(&filter) {
+        filter.clear();
+        main.set_saved_chat_filter(SharedString::new());
+    }
+
+    let files = manager.get_saved_chats().unwrap_or_else(|e| {
+        eprintln!("Error listing saved chats: {}", e);
+        Vec::new()
+    });
+    let items: Vec<types::ChatFileItem> = files
+        .into_iter()
+        .filter_map(|info| {
+            let meta = index.get(&info.filename).cloned().unwrap_or_default();
+            let shown = match filter.as_str() {
+                "" => true,
+                FAVORITES_FILTER => meta.favorite,
+                tag => meta.tags.iter().any(|t| t == tag),
+            };
+            shown.then(|| chat_file_item(&manager, info, meta))
+        })
+        .collect();
+
+    main.set_saved_chats(ModelRc::new(VecModel::from(items)));
+    main.set_selected_chats(0);
+    main.set_saved_chat_tags(ModelRc::new(VecModel::from(
+        tags.into_iter().map(SharedString::from).collect::<Vec<_>>(),
+    )));
+}
+
+pub fn toggle_favorite(path: &str) -> Result<()> {
+    manager()?.update_meta(&filename(path)?, |meta| meta.favorite = !meta.favorite)
+}
+
+pub fn set_tags(path: &str, text: &str) -> Result<()> {
+    let tags = parse_tags(text);
+    manager()?.update_meta(&filename(path)?, |meta| meta.tags = tags)
+}
+
+pub fn rename(path: &str, name: &str) -> Result<()> {
+    manager()?.rename_session(&filename(path)?, name)
+}
+
+// Carries on past a chat that can't be deleted, and reports the first failure.
+pub fn delete(paths: &[SharedString]) -> Result<()> {
+    let manager = manager()?;
+    let mut first_error = None;
+    for path in paths {
+        if let Err(e) = filename(path).and_then(|name| manager.delete_session(&name)) {
+            eprintln!("Error deleting saved chat: {:#}", e);
+            first_error.get_or_insert(e);
+        }
+    }
+    first_error.map_or(Ok(()), Err)
+}
+
+fn to_chat_message(message: &ChatMessage, me: Option<&str>) -> types::ChatMessage {
+    types::ChatMessage {
+        username: SharedString::from(message.sender.as_str()),
+        content: SharedString::from(message.content.as_str()),
+        timestamp: SharedString::from(
+            message
+                .timestamp
+                .with_timezone(&chrono::Local)
+                .format("%d/%m/%Y %H:%M:%S")
+                .to_string(),
+        ),
+        sender_id: SharedString::new(),
+        burst_id: 0,
+        message_id: SharedString::new(),
+        delivery: SharedString::new(),
+        is_own: me == Some(message.sender.as_str()),
+        is_system: matches!(
+            message.message_type,
+            MessageType::System | MessageType::Event(_)
+        ),
+        event: match message.message_type {
+            MessageType::Event(event) => SystemEvent::to_ui(Some(event)),
+            MessageType::System => types::SystemEvent::Notice,
+            _ => types::SystemEvent::None,
+        },
+        is_announcement: false,
+        is_action: matches!(message.message_type, MessageType::Action),
+        image_index: -1,
+        code_index: -1,
+        translation: SharedString::from(message.translation.as_deref().unwrap_or_default()),
+        name_color: Default::default(),
+    }
+}
+
+// How far we'd read the room with this topic, if it has a saved chat.
+pub fn last_read(topic: &str) -> Option<DateTime<Utc>> {
+    match manager().and_then(|manager| manager.load_session(topic)) {
+        Ok(session) => session.and_then(|session| session.last_read),
+        Err(e) => {
+            eprintln!("Failed to read the saved chat: {:#}", e);
+            None
+        }
+    }
+}
+
+// What the chat window needs to show a saved chat and offer to rejoin its room.
+pub struct Opened {
+    pub name: String,
+    pub ticket: Option<String>,
+    pub username: Option<String>,
+    pub locked: bool,
+}
+
+// Puts a saved chat in the chat window for reading. Rejoining its room later keeps these
+// messages and adds the live ones after them.
+pub fn open(
+    path: &str,
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+) -> Result<Opened> {
+    let manager = manager()?;
+    let info_name = filename(path)?;
+    let session = manager.load_chat_from_file(Path::new(path))?;
+    let room: String = session.session_id.chars().take(10).collect();
+    {
+        let state = app_state.lock().unwrap();
+        *state.messages.lock().unwrap() = session
+            .messages
+            .iter()
+            .map(|message| to_chat_message(message, session.username.as_deref()))
+            .collect();
+        notifications::place_divider(
+            &mut state.messages.lock().unwrap(),
+            session.messages.iter().map(|message| message.timestamp),
+            session.last_read,
+        );
+    }
+    update_messages(chat_handle, app_state);
+    println!(
+        "DEBUG: Opened saved chat {} ({} messages)",
+        info_name,
+        session.messages.len()
+    );
+    let locked = session
+        .ticket
+        .as_deref()
+        .and_then(|ticket| Ticket::from_str(ticket).ok())
+        .is_some_and(|ticket| ticket.is_locked());
+    Ok(Opened {
+        name: format!("Saved chat · Room {}", room),
+        ticket: session.ticket,
+        username: session.username,
+        locked,
+    })
+}
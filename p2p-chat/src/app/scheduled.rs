@@ -0,0 +1,105 @@
+use chrono::{DateTime, Local, TimeDelta, Utc};
+use n0_future::time::{Duration, sleep};
+use slint::{ModelRc, SharedString, VecModel, Weak};
+use std::sync::{Arc, Mutex};
+
+use crate::app::{
+    app_state::AppState, networking::send_message, types, ui_handlers::update_messages,
+};
+
+const SCHEDULE_CHECK: Duration = Duration::from_secs(5);
+
+// A message the user asked to send later.
+#[derive(Debug, Clone)]
+struct ScheduledMessage {
+    id: u64,
+    text: String,
+    due: DateTime<Utc>,
+}
+
+// Messages waiting to be sent in the current room, soonest first. They live only in
+// memory: leaving the room or quitting drops whatever hasn't gone out yet, while a
+// reconnect keeps them.
+#[derive(Debug, Clone, Default)]
+pub struct Schedule {
+    messages: Vec<ScheduledMessage>,
+}
+
+impl Schedule {
+    pub fn add(&mut self, text: String, minutes: u32) {
+        let due = Utc::now() + TimeDelta::minutes(i64::from(minutes.max(1)));
+        let at = self.messages.partition_point(|message| message.due <= due);
+        self.messages.insert(
+            at,
+            ScheduledMessage {
+                id: rand::random(),
+                text,
+                due,
+            },
+        );
+    }
+
+    pub fn cancel(&mut self, id: u64) {
+        self.messages.retain(|message| message.id != id);
+    }
+
+    // Takes out everything that's due, in the order it was scheduled for.
+    pub fn take_due(&mut self) -> Vec<String> {
+        let now = Utc::now();
+        let due = self.messages.partition_point(|message| message.due <= now);
+        self.messages
+            .drain(..due)
+            .map(|message| message.text)
+            .collect()
+    }
+
+    pub fn items(&self) -> Vec<types::ScheduledItem> {
+        self.messages
+            .iter()
+            .map(|message| types::ScheduledItem {
+                id: SharedString::from(message.id.to_string()),
+                text: SharedString::from(message.text.replace('\n', " ")),
+                due: SharedString::from(
+                    message
+                        .due
+                        .with_timezone(&Local)
+                        .format("%H:%M")
+                        .to_string(),
+                ),
+            })
+            .collect()
+    }
+}
+
+pub fn show(chat_handle: &Weak<types::ChatWindow>, app_state: &Arc<Mutex<AppState>>) {
+    let items = app_state.lock().unwrap().scheduled.items();
+    let chat_handle = chat_handle.clone();
+    if let Err(e) = slint::invoke_from_event_loop(move || {
+        if let Some(chat) = chat_handle.upgrade() {
+            chat.set_scheduled_messages(ModelRc::new(VecModel::from(items)));
+        }
+    }) {
+        eprintln!("Failed to update scheduled messages: {:?}", e);
+    }
+}
+
+// Sends whatever is due every SCHEDULE_CHECK. Runs until we leave the room.
+pub async fn watch(app_state: Arc<Mutex<AppState>>, chat_handle: Weak<types::ChatWindow>) {
+    let room = app_state.lock().unwrap().room_generation;
+    loop {
+        sleep(SCHEDULE_CHECK).await;
+        if app_state.lock().unwrap().room_generation != room {
+            break;
+        }
+        let due = app_state.lock().unwrap().scheduled.take_due();
+        if !due.is_empty() {
+            show(&chat_handle, &app_state);
+        }
+        for text in due {
+            match send_message(text, app_state.clone(), chat_handle.clone()).await {
+                Ok(_) => update_messages(&chat_handle, &app_state),
+                Err(e) => eprintln!("ERROR: Failed to send scheduled message: {}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,451 @@
+use anyhow::{Result, anyhow};
+use image::{ImageFormat, codecs::jpeg::JpegEncoder, imageops::FilterType};
+use iroh::{
+    NodeId,
+    endpoint::{Connection, RecvStream},
+    protocol::{AcceptError, ProtocolHandler},
+};
+use messaging::{Message, MessageBody};
+use slint::{Rgba8Pixel, SharedPixelBuffer, SharedString, Weak};
+use std::{
+    fmt,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::watch;
+
+use crate::app::{app_state::AppState, types};
+
+pub const SCREEN_ALPN: &[u8] = b"chast/screen/0";
+const MAX_FRAME_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ShareQuality {
+    Low,
+    Medium,
+    High,
+}
+
+impl ShareQuality {
+    pub fn from_index(index: i32) -> Self {
+        match index {
+            0 => ShareQuality::Low,
+            2 => ShareQuality::High,
+            _ => ShareQuality::Medium,
+        }
+    }
+
+    fn scale(self) -> f32 {
+        match self {
+            ShareQuality::Low => 0.5,
+            ShareQuality::Medium => 0.75,
+            ShareQuality::High => 1.0,
+        }
+    }
+
+    fn jpeg_quality(self) -> u8 {
+        match self {
+            ShareQuality::Low => 50,
+            ShareQuality::Medium => 70,
+            ShareQuality::High => 85,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ShareSettings {
+    pub fps: u32,
+    pub quality: ShareQuality,
+}
+
+type Frame = Option<Arc<Vec<u8>>>;
+
+// Our outgoing share. Viewers subscribe to `frames`; aborting the capture task drops the
+// sender, which ends every viewer stream.
+#[derive(Debug, Clone)]
+pub struct ScreenShare {
+    frames: watch::Receiver<Frame>,
+    capture_task: tokio::task::AbortHandle,
+}
+
+impl ScreenShare {
+    pub fn stop(&self) {
+        self.capture_task.abort();
+    }
+}
+
+#[derive(Clone)]
+pub struct ScreenShareProtocol {
+    app_state: Arc<Mutex<AppState>>,
+}
+
+impl ScreenShareProtocol {
+    pub fn new(app_state: Arc<Mutex<AppState>>) -> Self {
+        Self { app_state }
+    }
+}
+
+impl fmt::Debug for ScreenShareProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScreenShareProtocol")
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProtocolHandler for ScreenShareProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let viewer = connection.remote_node_id()?;
+        let frames = {
+            let state = self.app_state.lock().unwrap();
+            if state.blocklist.lock().unwrap().is_blocked(&viewer) {
+                return Err(AcceptError::NotAllowed {});
+            }
+            state
+                .screen_share
+                .as_ref()
+                .map(|share| share.frames.clone())
+        };
+        let Some(frames) = frames else {
+            return Err(AcceptError::NotAllowed {});
+        };
+
+        println!("DEBUG: {} started watching our screen", viewer.fmt_short());
+        if let Err(e) = serve_viewer(&connection, frames).await {
+            println!(
+                "DEBUG: Screen share stream to {} ended: {}",
+                viewer.fmt_short(),
+                e
+            );
+        }
+        Ok(())
+    }
+}
+
+async fn serve_viewer(connection: &Connection, mut frames: watch::Receiver<Frame>) -> Result<()> {
+    let mut send = connection.open_uni().await?;
+    loop {
+        let frame = frames.borrow_and_update().clone();
+        if let Some(frame) = frame {
+            send.write_all(&(frame.len() as u32).to_be_bytes()).await?;
+            send.write_all(&frame).await?;
+        }
+        frames.changed().await?;
+    }
+}
+
+async fn broadcast(app_state: &Arc<Mutex<AppState>>, body: MessageBody) -> Result<()> {
+    let (sender, secret_key) = {
+        let state = app_state.lock().unwrap();
+        (state.sender.clone(), state.secret_key())
+    };
+    let (Some(sender), Some(secret_key)) = (sender, secret_key) else {
+        return Err(anyhow!("Not connected to a room"));
+    };
+    sender
+        .broadcast(Message::new(body).to_signed_vec(&secret_key, sender.topic()))
+        .await?;
+    Ok(())
+}
+
+pub async fn start_sharing(
+    settings: ShareSettings,
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) -> Result<()> {
+    let from = {
+        let state = app_state.lock().unwrap();
+        if state.screen_share.is_some() {
+            return Err(anyhow!("Already sharing the screen"));
+        }
+        state
+            .current_node_id
+            .ok_or_else(|| anyhow!("Not connected to a room"))?
+    };
+
+    let (frames_tx, frames_rx) = watch::channel(None);
+    let capture_task = tokio::spawn(capture_loop(
+        settings,
+        frames_tx,
+        app_state.clone(),
+        chat_handle.clone(),
+    ));
+
+    app_state.lock().unwrap().screen_share = Some(ScreenShare {
+        frames: frames_rx,
+        capture_task: capture_task.abort_handle(),
+    });
+    update_screen_share_ui(&chat_handle, &app_state);
+
+    broadcast(&app_state, MessageBody::ScreenShareStarted { from }).await?;
+    println!(
+        "DEBUG: Screen sharing started at {} fps ({:?})",
+        settings.fps, settings.quality
+    );
+    Ok(())
+}
+
+pub async fn stop_sharing(
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) -> Result<()> {
+    let (share, from) = {
+        let mut state = app_state.lock().unwrap();
+        (state.screen_share.take(), state.current_node_id)
+    };
+    let Some(share) = share else {
+        return Ok(());
+    };
+    share.stop();
+    update_screen_share_ui(&chat_handle, &app_state);
+
+    if let Some(from) = from {
+        broadcast(&app_state, MessageBody::ScreenShareStopped { from }).await?;
+    }
+    println!("DEBUG: Screen sharing stopped");
+    Ok(())
+}
+
+async fn capture_loop(
+    settings: ShareSettings,
+    frames: watch::Sender<Frame>,
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) {
+    let interval = Duration::from_millis(1000 / settings.fps.clamp(1, 30) as u64);
+    let path = std::env::temp_dir().join(format!("chast-screen-{}.png", std::process::id()));
+
+    loop {
+        let started = tokio::time::Instant::now();
+        let capture_path = path.clone();
+        match tokio::task::spawn_blocking(move || capture_frame(&capture_path, settings)).await {
+            Ok(Ok(frame)) => {
+                frames.send_replace(Some(Arc::new(frame)));
+            }
+            Ok(Err(e)) => {
+                eprintln!("Screen capture failed: {}", e);
+                // Stopping from inside the task would abort ourselves mid-cleanup.
+                tokio::spawn(async move {
+                    if let Err(e) = stop_sharing(app_state, chat_handle).await {
+                        eprintln!("Error stopping screen share: {}", e);
+                    }
+                });
+                return;
+            }
+            Err(e) => {
+                eprintln!("Screen capture task panicked: {}", e);
+                return;
+            }
+        }
+        tokio::time::sleep_until(started + interval).await;
+    }
+}
+
+fn capture_frame(path: &Path, settings: ShareSettings) -> Result<Vec<u8>> {
+    capture_screen(path)?;
+
+    let screenshot = image::open(path)?;
+    let (width, height) = (screenshot.width(), screenshot.height());
+    let scale = settings.quality.scale();
+    let screenshot = if scale < 1.0 {
+        screenshot.resize(
+            (width as f32 * scale) as u32,
+            (height as f32 * scale) as u32,
+            FilterType::Triangle,
+        )
+    } else {
+        screenshot
+    };
+
+    let mut encoded = Vec::new();
+    JpegEncoder::new_with_quality(&mut encoded, settings.quality.jpeg_quality())
+        .encode_image(&screenshot.to_rgb8())?;
+    Ok(encoded)
+}
+
+// Shells out to the platform screenshot tool, the same way copying the token uses pbcopy.
+#[cfg(target_os = "macos")]
+fn capture_screen(path: &Path) -> Result<()> {
+    let status = std::process::Command::new("screencapture")
+        .args(["-x", "-t", "png"])
+        .arg(path)
+        .status()?;
+    if !status.success() {
+        return Err(anyhow!("screencapture exited with {}", status));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn capture_screen(path: &Path) -> Result<()> {
+    let status = match std::process::Command::new("grim").arg(path).status() {
+        Ok(status) if status.success() => return Ok(()),
+        _ => std::process::Command::new("import")
+            .args(["-window", "root"])
+            .arg(path)
+            .status()
+            .map_err(|e| anyhow!("Neither grim nor ImageMagick import is available: {}", e))?,
+    };
+    if !status.success() {
+        return Err(anyhow!("import exited with {}", status));
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+fn capture_screen(_path: &Path) -> Result<()> {
+    Err(anyhow!(
+        "Screen sharing is not supported on this platform yet"
+    ))
+}
+
+pub async fn watch_share(
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) -> Result<()> {
+    let (endpoint, sharer) = {
+        let state = app_state.lock().unwrap();
+        if state.screen_viewer.is_some() {
+            return Ok(());
+        }
+        (
+            state.endpoint.clone(),
+            state.screen_sharers.first().copied(),
+        )
+    };
+    let endpoint = endpoint.ok_or_else(|| anyhow!("Not connected to a room"))?;
+    let sharer = sharer.ok_or_else(|| anyhow!("Nobody is sharing their screen"))?;
+
+    let connection = endpoint.connect(sharer, SCREEN_ALPN).await?;
+    app_state.lock().unwrap().screen_viewer = Some((sharer, connection.clone()));
+    update_screen_share_ui(&chat_handle, &app_state);
+
+    let result = async {
+        let mut recv = connection.accept_uni().await?;
+        loop {
+            let frame = read_frame(&mut recv).await?;
+            let pixels = tokio::task::spawn_blocking(move || decode_frame(&frame)).await??;
+            show_frame(&chat_handle, pixels);
+        }
+    }
+    .await;
+
+    stop_watching(&app_state, &chat_handle, sharer);
+    result
+}
+
+pub fn stop_watching(
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+    sharer: NodeId,
+) {
+    let viewer = {
+        let mut state = app_state.lock().unwrap();
+        match &state.screen_viewer {
+            Some((id, _)) if *id == sharer => state.screen_viewer.take(),
+            _ => None,
+        }
+    };
+    if let Some((_, connection)) = viewer {
+        connection.close(0u32.into(), b"stopped watching");
+        update_screen_share_ui(chat_handle, app_state);
+    }
+}
+
+async fn read_frame(recv: &mut RecvStream) -> Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    recv.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_BYTES {
+        return Err(anyhow!("Screen frame too large ({} bytes)", len));
+    }
+    let mut frame = vec![0u8; len];
+    recv.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+fn decode_frame(frame: &[u8]) -> Result<SharedPixelBuffer<Rgba8Pixel>> {
+    let rgba = image::load_from_memory_with_format(frame, ImageFormat::Jpeg)?.to_rgba8();
+    Ok(SharedPixelBuffer::clone_from_slice(
+        rgba.as_raw(),
+        rgba.width(),
+        rgba.height(),
+    ))
+}
+
+fn show_frame(chat_handle: &Weak<types::ChatWindow>, pixels: SharedPixelBuffer<Rgba8Pixel>) {
+    let chat_handle_clone = chat_handle.clone();
+    if let Err(e) = slint::invoke_from_event_loop(move || {
+        if let Some(chat) = chat_handle_clone.upgrade() {
+            chat.set_screen_frame(slint::Image::from_rgba8(pixels));
+        }
+    }) {
+        println!("ERROR: Failed to show screen frame: {:?}", e);
+    }
+}
+
+pub fn handle_share_signal(
+    body: MessageBody,
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+) {
+    match body {
+        MessageBody::ScreenShareStarted { from } => {
+            let mut state = app_state.lock().unwrap();
+            if state.current_node_id == Some(from) || state.screen_sharers.contains(&from) {
+                return;
+            }
+            state.screen_sharers.push(from);
+        }
+        MessageBody::ScreenShareStopped { from } => {
+            app_state
+                .lock()
+                .unwrap()
+                .screen_sharers
+                .retain(|id| *id != from);
+            stop_watching(app_state, chat_handle, from);
+        }
+        _ => return,
+    }
+    update_screen_share_ui(chat_handle, app_state);
+}
+
+pub fn update_screen_share_ui(
+    chat_handle: &Weak<types::ChatWindow>,
+    app_state: &Arc<Mutex<AppState>>,
+) {
+    let (sharing, sharer_name, viewing) = {
+        let state = app_state.lock().unwrap();
+        let names = state.names.lock().unwrap();
+        let sharer_name = state
+            .screen_sharers
+            .first()
+            .map(|id| names.get(id).cloned().unwrap_or_else(|| id.fmt_short()))
+            .unwrap_or_default();
+        (
+            state.screen_share.is_some(),
+            sharer_name,
+            state.screen_viewer.is_some(),
+        )
+    };
+
+    let chat_handle_clone = chat_handle.clone();
+    match slint::invoke_from_event_loop(move || {
+        if let Some(chat) = chat_handle_clone.upgrade() {
+            chat.set_screen_sharing(sharing);
+            chat.set_screen_sharer(SharedString::from(sharer_name));
+            chat.set_screen_viewing(viewing);
+            if !viewing {
+                chat.set_screen_frame(slint::Image::default());
+            }
+        } else {
+            println!("DEBUG: Chat window handle is invalid, cannot update screen share");
+        }
+    }) {
+        Ok(_) => {}
+        Err(e) => println!(
+            "ERROR: Failed to update screen share UI from event loop: {:?}",
+            e
+        ),
+    }
+}
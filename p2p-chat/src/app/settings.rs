@@ -0,0 +1,161 @@
+use anyhow::{Context, Result};
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::app::{
+    appearance::Appearance, i18n::Language, network_settings::NetworkSettings,
+    persistence::AutoSave, presence::AutoAway, speech::Speech, spellcheck::SpellCheck,
+    translate::Translation, word_filter::WordFilter,
+};
+
+const SETTINGS_FILE: &str = "settings.json";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub word_filter: WordFilter,
+    pub network: NetworkSettings,
+    pub auto_save: AutoSave,
+    pub auto_away: AutoAway,
+    pub device_sync: DeviceSyncSettings,
+    pub language: Language,
+    pub appearance: Appearance,
+    pub spell_check: SpellCheck,
+    pub profile: Profile,
+    pub notifications: NotificationSettings,
+    pub chat_events: ChatEvents,
+    pub translation: Translation,
+    pub speech: Speech,
+    pub files: FileTransferSettings,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+// Copies saved chats between devices set up with the same identity.key. Off unless
+// turned on, since it keeps a second endpoint running outside of rooms.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceSyncSettings {
+    pub enabled: bool,
+    // The other devices' sync ids, as shown in their settings.
+    pub devices: Vec<NodeId>,
+}
+
+// Who we were last time, so the join and create windows start filled in.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    pub username: String,
+}
+
+// Rooms whose messages shouldn't pop up or count as unread, by gossip topic.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotificationSettings {
+    pub muted_rooms: Vec<String>,
+}
+
+// Join and leave lines in the chat. Other notices always show.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChatEvents {
+    pub show_joins: bool,
+}
+
+impl Default for ChatEvents {
+    fn default() -> Self {
+        Self { show_joins: true }
+    }
+}
+
+// What happens to files others offer. Nothing is saved without asking unless a limit
+// is set here.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FileTransferSettings {
+    // Offers up to this many MiB are saved without asking; 0 asks about every file.
+    pub auto_accept_mb: u32,
+    // Extensions, without the dot, of the files that are taken at all. Empty takes any.
+    pub allowed_types: Vec<String>,
+    pub download_dir: String,
+}
+
+impl Default for FileTransferSettings {
+    fn default() -> Self {
+        Self {
+            auto_accept_mb: 0,
+            allowed_types: Vec::new(),
+            download_dir: "downloads".to_string(),
+        }
+    }
+}
+
+impl FileTransferSettings {
+    pub fn allows(&self, name: &str) -> bool {
+        if self.allowed_types.is_empty() {
+            return true;
+        }
+        Path::new(name)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| {
+                self.allowed_types
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(extension))
+            })
+    }
+
+    pub fn auto_accepts(&self, size: u64) -> bool {
+        self.auto_accept_mb > 0 && size <= self.auto_accept_mb as u64 * 1024 * 1024
+    }
+
+    pub fn types_text(&self) -> String {
+        self.allowed_types.join(", ")
+    }
+
+    // Takes "pdf, .png jpg" and the like.
+    pub fn set_types(&mut self, text: &str) {
+        self.allowed_types = text
+            .split([',', ' '])
+            .map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+            .filter(|extension| !extension.is_empty())
+            .collect();
+    }
+}
+
+impl Settings {
+    pub fn load() -> Self {
+        Self::load_from(SETTINGS_FILE)
+    }
+
+    pub fn load_from(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let mut settings: Settings = match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+                eprintln!("Failed to parse {}: {}", path.display(), e);
+                Settings::default()
+            }),
+            Err(_) => Settings::default(),
+        };
+        settings.path = path;
+        settings
+    }
+
+    // Saves only when the name changed, since this runs on every join.
+    pub fn remember_username(&mut self, username: &str) -> Result<()> {
+        if self.profile.username == username {
+            return Ok(());
+        }
+        self.profile.username = username.to_string();
+        self.save()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        fs::write(&self.path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
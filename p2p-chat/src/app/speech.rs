@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+use crate::app::{app_state::AppState, i18n::trf, notifications};
+
+// Long messages are cut off here, so a pasted log doesn't keep talking for minutes.
+const MAX_SPOKEN_CHARS: usize = 300;
+
+// Reading the chat aloud. Both are off unless turned on in the settings.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Speech {
+    pub messages: bool,
+    pub joins: bool,
+}
+
+// Someone else's message. Muted rooms stay quiet here too.
+pub fn on_message(
+    sender_name: &str,
+    text: &str,
+    is_action: bool,
+    app_state: &Arc<Mutex<AppState>>,
+) {
+    {
+        let state = app_state.lock().unwrap();
+        if !state.settings.lock().unwrap().speech.messages || notifications::is_muted(&state) {
+            return;
+        }
+    }
+    let text: String = text.chars().take(MAX_SPOKEN_CHARS).collect();
+    if is_action {
+        say(format!("{} {}", sender_name, text));
+    } else {
+        say(trf("{} says: {}", &[&sender_name, &text]));
+    }
+}
+
+pub fn on_join(username: &str, joined: bool, app_state: &Arc<Mutex<AppState>>) {
+    {
+        let state = app_state.lock().unwrap();
+        if !state.settings.lock().unwrap().speech.joins || notifications::is_muted(&state) {
+            return;
+        }
+    }
+    if joined {
+        say(trf("{} joined", &[&username]));
+    } else {
+        say(trf("{} left", &[&username]));
+    }
+}
+
+// Lines are spoken one after another from a thread of their own, so they never talk over
+// each other and the chat never waits on the speech tool.
+#[cfg(not(target_arch = "wasm32"))]
+fn say(text: String) {
+    use std::sync::{OnceLock, mpsc};
+
+    static QUEUE: OnceLock<mpsc::Sender<String>> = OnceLock::new();
+    let queue = QUEUE.get_or_init(|| {
+        let (tx, rx) = mpsc::channel::<String>();
+        std::thread::spawn(move || {
+            for text in rx {
+                if let Err(e) = speak(&text) {
+                    eprintln!("Failed to read message aloud: {}", e);
+                }
+            }
+        });
+        tx
+    });
+    let _ = queue.send(text);
+}
+
+// The browser has no speech tool to run.
+#[cfg(target_arch = "wasm32")]
+fn say(_text: String) {}
+
+// Uses the platform's own voice: `say` on macOS, speech-dispatcher or eSpeak on Linux and
+// System.Speech through PowerShell on Windows. Each runs until it's done talking.
+#[cfg(not(target_arch = "wasm32"))]
+fn speak(text: &str) -> std::io::Result<()> {
+    use std::{
+        io::Write,
+        process::{Command, Stdio},
+    };
+
+    if cfg!(target_os = "windows") {
+        let mut child = Command::new("powershell")
+            .args([
+                "-NoProfile",
+                "-Command",
+                "Add-Type -AssemblyName System.Speech; \
+                 (New-Object System.Speech.Synthesis.SpeechSynthesizer).Speak([Console]::In.ReadToEnd())",
+            ])
+            .stdin(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes())?;
+        }
+        child.wait()?;
+        return Ok(());
+    }
+    if cfg!(target_os = "macos") {
+        Command::new("say").arg(text).status()?;
+        return Ok(());
+    }
+    match Command::new("spd-say").arg("--wait").arg(text).status() {
+        Ok(_) => Ok(()),
+        Err(_) => Command::new("espeak").arg(text).status().map(|_| ()),
+    }
+}
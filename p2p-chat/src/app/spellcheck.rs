@@ -0,0 +1,453 @@
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+// Hunspell dictionaries (en_US.dic with its en_US.aff, and so on) are read from here.
+// Words added from the composer go to PERSONAL_FILE in the same folder.
+const DICTIONARY_DIR: &str = "dictionaries";
+const PERSONAL_FILE: &str = "personal.txt";
+const MAX_SUGGESTIONS: usize = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpellCheck {
+    pub enabled: bool,
+}
+
+impl Default for SpellCheck {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+// A word in the composer that isn't in any dictionary, by byte offsets into its text.
+#[derive(Debug, Clone)]
+pub struct Misspelling {
+    pub start: usize,
+    pub end: usize,
+    pub word: String,
+    pub suggestions: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum FlagMode {
+    Char,
+    Long,
+    Num,
+}
+
+impl FlagMode {
+    fn split(self, flags: &str) -> Vec<String> {
+        match self {
+            FlagMode::Char => flags.chars().map(String::from).collect(),
+            FlagMode::Long => flags
+                .chars()
+                .collect::<Vec<_>>()
+                .chunks(2)
+                .map(|pair| pair.iter().collect())
+                .collect(),
+            FlagMode::Num => flags.split(',').map(str::to_string).collect(),
+        }
+    }
+}
+
+// One position of an affix condition: `.`, a letter, or a [bracketed] set.
+#[derive(Debug)]
+enum CharClass {
+    Any,
+    Set { chars: Vec<char>, negated: bool },
+}
+
+impl CharClass {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            CharClass::Any => true,
+            CharClass::Set { chars, negated } => chars.contains(&c) != *negated,
+        }
+    }
+}
+
+fn parse_condition(condition: &str) -> Vec<CharClass> {
+    let mut classes = Vec::new();
+    let mut chars = condition.chars();
+    while let Some(c) = chars.next() {
+        classes.push(match c {
+            '.' => CharClass::Any,
+            '[' => {
+                let mut set: Vec<char> = chars.by_ref().take_while(|c| *c != ']').collect();
+                let negated = set.first() == Some(&'^');
+                if negated {
+                    set.remove(0);
+                }
+                CharClass::Set {
+                    chars: set,
+                    negated,
+                }
+            }
+            c => CharClass::Set {
+                chars: vec![c],
+                negated: false,
+            },
+        });
+    }
+    classes
+}
+
+#[derive(Debug)]
+struct AffixRule {
+    strip: String,
+    add: String,
+    condition: Vec<CharClass>,
+}
+
+#[derive(Debug)]
+struct Affix {
+    prefix: bool,
+    // Whether a word can take this together with an affix from the other side.
+    cross: bool,
+    rules: Vec<AffixRule>,
+}
+
+impl Affix {
+    fn apply(&self, word: &str) -> Vec<String> {
+        let chars: Vec<char> = word.chars().collect();
+        self.rules
+            .iter()
+            .filter_map(|rule| {
+                let n = rule.condition.len();
+                if n > chars.len() {
+                    return None;
+                }
+                let part = if self.prefix {
+                    &chars[..n]
+                } else {
+                    &chars[chars.len() - n..]
+                };
+                if !rule
+                    .condition
+                    .iter()
+                    .zip(part)
+                    .all(|(cc, c)| cc.matches(*c))
+                {
+                    return None;
+                }
+                if self.prefix {
+                    let rest = word.strip_prefix(rule.strip.as_str())?;
+                    Some(format!("{}{}", rule.add, rest))
+                } else {
+                    let rest = word.strip_suffix(rule.strip.as_str())?;
+                    Some(format!("{}{}", rest, rule.add))
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct AffixFile {
+    flags: FlagMode,
+    affixes: HashMap<String, Affix>,
+    latin1: bool,
+    try_chars: Vec<char>,
+}
+
+fn decode(bytes: &[u8], latin1: bool) -> String {
+    if latin1 {
+        bytes.iter().map(|b| *b as char).collect()
+    } else {
+        String::from_utf8_lossy(bytes).into_owned()
+    }
+}
+
+fn parse_aff(bytes: &[u8]) -> AffixFile {
+    // SET comes first in practice, but look for it before decoding anything else.
+    let latin1 = String::from_utf8_lossy(bytes).lines().any(|line| {
+        let mut parts = line.split_whitespace();
+        parts.next() == Some("SET")
+            && parts
+                .next()
+                .is_some_and(|set| set.eq_ignore_ascii_case("ISO8859-1"))
+    });
+    let mut aff = AffixFile {
+        flags: FlagMode::Char,
+        affixes: HashMap::new(),
+        latin1,
+        try_chars: Vec::new(),
+    };
+    for line in decode(bytes, latin1).lines() {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        match parts.as_slice() {
+            ["FLAG", "long", ..] => aff.flags = FlagMode::Long,
+            ["FLAG", "num", ..] => aff.flags = FlagMode::Num,
+            ["TRY", chars, ..] => aff.try_chars = chars.chars().collect(),
+            [kind @ ("PFX" | "SFX"), flag, cross, _count] if !aff.affixes.contains_key(*flag) => {
+                aff.affixes.insert(
+                    flag.to_string(),
+                    Affix {
+                        prefix: *kind == "PFX",
+                        cross: *cross == "Y",
+                        rules: Vec::new(),
+                    },
+                );
+            }
+            ["PFX" | "SFX", flag, strip, add, rest @ ..] => {
+                let Some(affix) = aff.affixes.get_mut(*flag) else {
+                    continue;
+                };
+                let zero = |s: &str| {
+                    if s == "0" {
+                        String::new()
+                    } else {
+                        s.to_string()
+                    }
+                };
+                // Anything after a slash is a continuation flag, which we don't follow.
+                let add = add.split('/').next().unwrap_or_default();
+                affix.rules.push(AffixRule {
+                    strip: zero(strip),
+                    add: zero(add),
+                    condition: parse_condition(rest.first().copied().unwrap_or(".")),
+                });
+            }
+            _ => {}
+        }
+    }
+    aff
+}
+
+// Words in the composer, skipping links, @mentions and anything with digits in it.
+fn words(text: &str) -> Vec<(usize, &str)> {
+    let mut found = Vec::new();
+    let mut offset = 0;
+    for chunk in text.split_inclusive(char::is_whitespace) {
+        let start = offset;
+        offset += chunk.len();
+        let chunk = chunk.trim_end();
+        if chunk.contains("://")
+            || chunk.starts_with("www.")
+            || chunk.starts_with('@')
+            || chunk.chars().any(|c| c.is_ascii_digit())
+        {
+            continue;
+        }
+        let mut word_start = None;
+        for (i, c) in chunk.char_indices().chain([(chunk.len(), ' ')]) {
+            let in_word = c.is_alphabetic() || (c == '\'' && word_start.is_some());
+            match (in_word, word_start) {
+                (true, None) => word_start = Some(i),
+                (false, Some(s)) => {
+                    let word = chunk[s..i].trim_end_matches('\'');
+                    if word.chars().count() > 1 {
+                        found.push((start + s, word));
+                    }
+                    word_start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+    found
+}
+
+// Only the dictionaries' own words and what affixes make of them. Hunspell's compounding
+// isn't supported, so languages that build long words from parts get more false alarms.
+#[derive(Debug, Default)]
+pub struct SpellChecker {
+    words: HashSet<String>,
+    alphabet: Vec<char>,
+    dictionaries: Vec<String>,
+    personal: PathBuf,
+}
+
+impl SpellChecker {
+    pub fn load() -> Self {
+        Self::load_from(DICTIONARY_DIR)
+    }
+
+    pub fn load_from(dir: impl AsRef<Path>) -> Self {
+        let dir = dir.as_ref();
+        let mut checker = SpellChecker {
+            personal: dir.join(PERSONAL_FILE),
+            ..Default::default()
+        };
+        let mut alphabet = HashSet::new();
+        if let Ok(entries) = fs::read_dir(dir) {
+            let mut dics: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "dic"))
+                .collect();
+            dics.sort();
+            for dic in dics {
+                match checker.add_dictionary(&dic, &mut alphabet) {
+                    Ok(count) => println!("> spell check: {} words from {}", count, dic.display()),
+                    Err(e) => eprintln!("Failed to load {}: {:#}", dic.display(), e),
+                }
+            }
+        }
+        if let Ok(data) = fs::read_to_string(&checker.personal) {
+            checker
+                .words
+                .extend(data.lines().map(|line| line.trim().to_lowercase()));
+        }
+        checker.alphabet = if alphabet.is_empty() {
+            ('a'..='z').collect()
+        } else {
+            alphabet.into_iter().collect()
+        };
+        checker
+    }
+
+    fn add_dictionary(&mut self, dic: &Path, alphabet: &mut HashSet<char>) -> Result<usize> {
+        let aff = match fs::read(dic.with_extension("aff")) {
+            Ok(bytes) => parse_aff(&bytes),
+            Err(_) => parse_aff(b""),
+        };
+        let bytes = fs::read(dic).context("Failed to read the dictionary")?;
+        let before = self.words.len();
+        // The first line is the word count.
+        for line in decode(&bytes, aff.latin1).lines().skip(1) {
+            let entry = line.split(['\t', ' ']).next().unwrap_or_default();
+            let (word, flags) = entry.split_once('/').unwrap_or((entry, ""));
+            if word.is_empty() {
+                continue;
+            }
+            let affixes: Vec<&Affix> = aff
+                .flags
+                .split(flags)
+                .iter()
+                .filter_map(|flag| aff.affixes.get(flag))
+                .collect();
+            let mut forms = vec![word.to_string()];
+            let mut crossable = Vec::new();
+            for suffix in affixes.iter().filter(|affix| !affix.prefix) {
+                let made = suffix.apply(word);
+                if suffix.cross {
+                    crossable.extend(made.iter().cloned());
+                }
+                forms.extend(made);
+            }
+            for prefix in affixes.iter().filter(|affix| affix.prefix) {
+                forms.extend(prefix.apply(word));
+                if prefix.cross {
+                    for form in &crossable {
+                        forms.extend(prefix.apply(form));
+                    }
+                }
+            }
+            for form in forms {
+                self.words.insert(form.to_lowercase());
+            }
+        }
+        alphabet.extend(aff.try_chars.iter().flat_map(|c| c.to_lowercase()));
+        self.dictionaries.push(
+            dic.file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        );
+        Ok(self.words.len() - before)
+    }
+
+    // Without a dictionary every word would look wrong, so checking is off.
+    pub fn is_available(&self) -> bool {
+        !self.dictionaries.is_empty()
+    }
+
+    pub fn is_known(&self, word: &str) -> bool {
+        self.words.contains(&word.to_lowercase())
+    }
+
+    pub fn check(&self, text: &str) -> Vec<Misspelling> {
+        if !self.is_available() {
+            return Vec::new();
+        }
+        words(text)
+            .into_iter()
+            .filter(|(_, word)| !self.is_known(word))
+            .map(|(start, word)| Misspelling {
+                start,
+                end: start + word.len(),
+                word: word.to_string(),
+                suggestions: self.suggest(word),
+            })
+            .collect()
+    }
+
+    // Known words one edit away, in the original word's capitalisation.
+    pub fn suggest(&self, word: &str) -> Vec<String> {
+        let lower: Vec<char> = word.to_lowercase().chars().collect();
+        let mut candidates = Vec::new();
+        for i in 0..=lower.len() {
+            if i < lower.len() {
+                let mut replaced = lower.clone();
+                for c in &self.alphabet {
+                    replaced[i] = *c;
+                    candidates.push(replaced.iter().collect::<String>());
+                }
+                let mut deleted = lower.clone();
+                deleted.remove(i);
+                candidates.push(deleted.into_iter().collect());
+            }
+            if i + 1 < lower.len() {
+                let mut swapped = lower.clone();
+                swapped.swap(i, i + 1);
+                candidates.push(swapped.into_iter().collect());
+            }
+            for c in &self.alphabet {
+                let mut inserted = lower.clone();
+                inserted.insert(i, *c);
+                candidates.push(inserted.into_iter().collect());
+            }
+        }
+        let capitalised = word.chars().next().is_some_and(char::is_uppercase);
+        let mut suggestions: Vec<String> = Vec::new();
+        for candidate in candidates {
+            if suggestions.len() == MAX_SUGGESTIONS {
+                break;
+            }
+            if !self.words.contains(&candidate) || suggestions.contains(&candidate) {
+                continue;
+            }
+            suggestions.push(candidate);
+        }
+        if capitalised {
+            for suggestion in &mut suggestions {
+                let mut chars = suggestion.chars();
+                if let Some(first) = chars.next() {
+                    *suggestion = first.to_uppercase().chain(chars).collect();
+                }
+            }
+        }
+        suggestions
+    }
+
+    pub fn add_word(&mut self, word: &str) -> Result<()> {
+        let word = word.trim().to_lowercase();
+        if word.is_empty() || !self.words.insert(word.clone()) {
+            return Ok(());
+        }
+        if let Some(dir) = self.personal.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.personal)
+            .with_context(|| format!("Failed to open {}", self.personal.display()))?;
+        writeln!(file, "{}", word)?;
+        Ok(())
+    }
+}
+
+// Swaps the word at start..end for a suggestion. The offsets come from an earlier check
+// of the same text, so anything that no longer lines up is refused.
+pub fn replace(text: &str, start: usize, end: usize, with: &str) -> Result<String> {
+    if start > end || !text.is_char_boundary(start) || !text.is_char_boundary(end) {
+        bail!("The message changed since it was checked");
+    }
+    Ok(format!("{}{}{}", &text[..start], with, &text[end..]))
+}
@@ -0,0 +1,116 @@
+use anyhow::{Context, Result, bail};
+use image::imageops::FilterType;
+use slint::{ModelRc, SharedString, VecModel, Weak};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::app::{app_state::AppState, images, types};
+
+// Stickers are plain PNG, JPEG or GIF files in this folder, so a pack can also be
+// shared by copying the folder.
+const STICKERS_DIR: &str = "stickers";
+const EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif"];
+const THUMBNAIL_SIDE: u32 = 96;
+
+fn is_sticker(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+}
+
+// Only a bare file name from the picker is accepted, never a path out of the folder.
+fn sticker_path(name: &str) -> Result<PathBuf> {
+    if Path::new(name).file_name().and_then(|file| file.to_str()) != Some(name) {
+        bail!("Not a sticker name: {}", name);
+    }
+    Ok(Path::new(STICKERS_DIR).join(name))
+}
+
+fn thumbnail(path: &Path) -> Result<slint::Image> {
+    let image = image::ImageReader::open(path)?
+        .with_guessed_format()?
+        .decode()?
+        .resize(THUMBNAIL_SIDE, THUMBNAIL_SIDE, FilterType::Triangle)
+        .to_rgba8();
+    Ok(slint::Image::from_rgba8(
+        slint::SharedPixelBuffer::clone_from_slice(image.as_raw(), image.width(), image.height()),
+    ))
+}
+
+// The pack, by file name. Files that don't decode are left out.
+pub fn list() -> Vec<types::Sticker> {
+    let Ok(entries) = fs::read_dir(STICKERS_DIR) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| is_sticker(path))
+        .collect();
+    paths.sort();
+
+    paths
+        .iter()
+        .filter_map(|path| {
+            let preview = thumbnail(path)
+                .inspect_err(|e| eprintln!("Skipping sticker {}: {}", path.display(), e))
+                .ok()?;
+            Some(types::Sticker {
+                name: SharedString::from(path.file_name()?.to_string_lossy().as_ref()),
+                preview,
+            })
+        })
+        .collect()
+}
+
+pub fn show(chat: &types::ChatWindow) {
+    chat.set_stickers(ModelRc::new(VecModel::from(list())));
+}
+
+// Copies a picked picture into the pack. Returns false when the picker was cancelled.
+pub fn add() -> Result<bool> {
+    let Some(source) = rfd::FileDialog::new()
+        .add_filter("Stickers", EXTENSIONS)
+        .set_title("Add a Sticker")
+        .pick_file()
+    else {
+        return Ok(false);
+    };
+    // Checked here rather than on send, so a broken file never shows up in the pack.
+    images::decode(&fs::read(&source)?)
+        .with_context(|| format!("{} isn't a picture that can be sent", source.display()))?;
+
+    fs::create_dir_all(STICKERS_DIR).context("Failed to create the stickers folder")?;
+    let name = source
+        .file_name()
+        .context("The picked file has no name")?
+        .to_string_lossy()
+        .to_string();
+    let mut target = Path::new(STICKERS_DIR).join(&name);
+    let mut copy = 1;
+    while target.exists() {
+        copy += 1;
+        target = Path::new(STICKERS_DIR).join(format!("{}-{}", copy, name));
+    }
+    fs::copy(&source, &target).with_context(|| format!("Failed to copy {}", source.display()))?;
+    println!("DEBUG: Added sticker {}", target.display());
+    Ok(true)
+}
+
+pub fn remove(name: &str) -> Result<()> {
+    fs::remove_file(sticker_path(name)?)
+        .with_context(|| format!("Failed to remove sticker {}", name))
+}
+
+pub async fn send(
+    name: String,
+    app_state: Arc<Mutex<AppState>>,
+    chat_handle: Weak<types::ChatWindow>,
+) -> Result<()> {
+    let bytes = tokio::fs::read(sticker_path(&name)?)
+        .await
+        .with_context(|| format!("Failed to read sticker {}", name))?;
+    images::send_file(bytes, app_state, chat_handle).await
+}
@@ -0,0 +1,213 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use slint::{SharedString, Weak};
+use std::sync::{Arc, Mutex};
+
+use crate::app::{app_state::AppState, types, ui_handlers::update_messages};
+
+// Long enough for a local model on a slow machine, short enough that a command stuck
+// waiting on something doesn't hang around for good.
+#[cfg(not(target_arch = "wasm32"))]
+const TRANSLATE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+// Translation runs a command of the user's choosing, so it can be translate-shell, a local
+// model or a small script around a web service. Nothing is sent anywhere unless one is set.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Translation {
+    // Gets the message on stdin and prints the translation. `{lang}` in it is replaced
+    // with the language to translate into.
+    pub command: String,
+    // A code like "en" or "es". Empty means the system's language.
+    pub language: String,
+    // Rooms whose messages are translated as they arrive, by gossip topic.
+    pub auto_rooms: Vec<String>,
+}
+
+impl Translation {
+    pub fn is_available(&self) -> bool {
+        !self.command.trim().is_empty()
+    }
+
+    fn language(&self) -> String {
+        if !self.language.trim().is_empty() {
+            return self.language.trim().to_string();
+        }
+        sys_locale::get_locale()
+            .and_then(|locale| locale.split(['-', '_']).next().map(str::to_lowercase))
+            .unwrap_or_else(|| "en".to_string())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn run(&self, text: &str) -> Result<String> {
+        use anyhow::{Context, bail};
+        use std::{io::ErrorKind, process::Stdio};
+        use tokio::{io::AsyncWriteExt, process::Command};
+
+        let language = self.language();
+        let mut words = self
+            .command
+            .split_whitespace()
+            .map(|word| word.replace("{lang}", &language));
+        let program = words.next().context("No translation command is set")?;
+        let mut child = Command::new(&program)
+            .args(words)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // A command that runs past the timeout is killed with it.
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Failed to run {}", program))?;
+        let mut stdin = child.stdin.take().context("No stdin for the command")?;
+        // Written while the output is read, or a command that prints as it goes could
+        // fill its stdout pipe and wait on us while we wait on it.
+        let write = async move {
+            stdin.write_all(text.as_bytes()).await?;
+            // Dropping stdin once written tells the command the message is complete.
+            drop(stdin);
+            Ok::<_, std::io::Error>(())
+        };
+        let (written, output) = tokio::time::timeout(TRANSLATE_TIMEOUT, async {
+            tokio::join!(write, child.wait_with_output())
+        })
+        .await
+        .with_context(|| format!("{} didn't finish in time", program))?;
+        let output = output?;
+        // A command that doesn't read all of its input is fine, as long as it answers.
+        if let Err(e) = written
+            && e.kind() != ErrorKind::BrokenPipe
+        {
+            return Err(e.into());
+        }
+        if !output.status.success() {
+            bail!(
+                "{} failed: {}",
+                program,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let translation = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if translation.is_empty() {
+            bail!("{} printed no translation", program);
+        }
+        Ok(translation)
+    }
+}
+
+pub fn is_auto(state: &AppState) -> bool {
+    let Some(topic) = state.room_topic else {
+        return false;
+    };
+    let settings = state.settings.lock().unwrap();
+    settings.translation.is_available()
+        && settings.translation.auto_rooms.contains(&topic.to_string())
+}
+
+// Flips auto-translate for the current room.
+pub fn toggle_auto(
+    chat_handle: &Weak<types::ChatWindow>,
+    app_state: &Arc<Mutex<AppState>>,
+) -> Result<()> {
+    {
+        let state = app_state.lock().unwrap();
+        let Some(topic) = state.room_topic else {
+            return Ok(());
+        };
+        let topic = topic.to_string();
+        let mut settings = state.settings.lock().unwrap();
+        let auto_rooms = &mut settings.translation.auto_rooms;
+        if auto_rooms.contains(&topic) {
+            auto_rooms.retain(|room| *room != topic);
+        } else {
+            auto_rooms.push(topic);
+        }
+        settings.save()?;
+    }
+    show(chat_handle, app_state);
+    Ok(())
+}
+
+pub fn show(chat_handle: &Weak<types::ChatWindow>, app_state: &Arc<Mutex<AppState>>) {
+    let (available, auto) = {
+        let state = app_state.lock().unwrap();
+        let available = state.settings.lock().unwrap().translation.is_available();
+        (available, is_auto(&state))
+    };
+    let chat_handle = chat_handle.clone();
+    if let Err(e) = slint::invoke_from_event_loop(move || {
+        if let Some(chat) = chat_handle.upgrade() {
+            chat.set_translation_available(available);
+            chat.set_auto_translate(auto);
+        }
+    }) {
+        eprintln!("Failed to update translation state: {:?}", e);
+    }
+}
+
+// Translates a message in the chat by id. The translation is shown under the original
+// and saved along with it.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn translate(
+    message_id: &str,
+    app_state: &Arc<Mutex<AppState>>,
+    chat_handle: &Weak<types::ChatWindow>,
+) {
+    let (translation, original) = {
+        let state = app_state.lock().unwrap();
+        let translation = state.settings.lock().unwrap().translation.clone();
+        let original = state
+            .messages
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|message| message.message_id == message_id)
+            .map(|message| (message.username.to_string(), message.content.to_string()));
+        (translation, original)
+    };
+    let Some((sender, content)) = original else {
+        return;
+    };
+    if !translation.is_available() {
+        return;
+    }
+
+    let message_id = message_id.to_string();
+    let app_state = app_state.clone();
+    let chat_handle = chat_handle.clone();
+    tokio::spawn(async move {
+        let translated = match translation.run(&content).await {
+            Ok(translated) => translated,
+            Err(e) => {
+                eprintln!("Failed to translate message: {:#}", e);
+                return;
+            }
+        };
+        println!("DEBUG: Translated message {}", message_id);
+        {
+            let state = app_state.lock().unwrap();
+            let mut messages = state.messages.lock().unwrap();
+            if let Some(message) = messages
+                .iter_mut()
+                .rev()
+                .find(|message| message.message_id == message_id)
+            {
+                message.translation = SharedString::from(translated.as_str());
+            }
+            state
+                .persister
+                .record_translation(&sender, &content, &translated);
+        }
+        update_messages(&chat_handle, &app_state);
+    });
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn translate(
+    _message_id: &str,
+    _app_state: &Arc<Mutex<AppState>>,
+    _chat_handle: &Weak<types::ChatWindow>,
+) {
+    eprintln!("Translation isn't available in the browser build");
+}
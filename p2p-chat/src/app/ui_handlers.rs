@@ -1,7 +1,25 @@
-use slint::{ModelRc, SharedString, VecModel, Weak};
-use std::sync::{Arc, Mutex};
+use iroh::NodeId;
+use messaging::{Capabilities, ClientInfo};
+use slint::{Color, Model, ModelRc, SharedString, VecModel, Weak};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, atomic::Ordering},
+};
 
-use crate::app::{app_state::AppState, types};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::app::images;
+use crate::app::{
+    app_state::AppState, code_blocks, history, i18n::trf, last_seen::format_ago, save::SystemEvent,
+    speech, types,
+};
+
+// Message times are kept as UTC instants and only turned into the viewer's local time
+// for display.
+pub fn local_time(at: chrono::DateTime<chrono::Utc>) -> String {
+    at.with_timezone(&chrono::Local)
+        .format("%d/%m/%Y %H:%M:%S")
+        .to_string()
+}
 
 pub fn handle_user_connect(
     chat_handle: &Weak<types::ChatWindow>,
@@ -10,21 +28,36 @@ pub fn handle_user_connect(
 ) {
     let connect_message = types::ChatMessage {
         username: SharedString::from("System"),
-        content: SharedString::from(format!("🎉 {} joined the room", username)),
+        content: SharedString::from(trf("🎉 {} joined the room", &[&username])),
         timestamp: SharedString::from(chrono::Local::now().format("%H:%M").to_string()),
+        sender_id: SharedString::new(),
+        burst_id: 0,
+        message_id: SharedString::new(),
+        delivery: SharedString::new(),
         is_own: false,
         is_system: true,
+        event: types::SystemEvent::Joined,
+        is_announcement: false,
+        is_action: false,
+        image_index: -1,
+        code_index: -1,
+        translation: Default::default(),
+        name_color: Default::default(),
     };
 
     {
         let state = app_state.lock().unwrap();
+        state
+            .persister
+            .record_event(SystemEvent::Joined, &connect_message.content);
         let mut messages = state.messages.lock().unwrap();
         messages.push(connect_message);
     }
 
     update_messages(chat_handle, app_state);
     update_online_users(chat_handle, app_state);
-    
+    speech::on_join(username, true, app_state);
+
     println!("DEBUG: User {} connected, UI updated", username);
 }
 
@@ -35,24 +68,64 @@ pub fn handle_user_disconnect(
 ) {
     let disconnect_message = types::ChatMessage {
         username: SharedString::from("System"),
-        content: SharedString::from(format!("👋 {} disconnected", username)),
+        content: SharedString::from(trf("👋 {} disconnected", &[&username])),
         timestamp: SharedString::from(chrono::Local::now().format("%H:%M").to_string()),
+        sender_id: SharedString::new(),
+        burst_id: 0,
+        message_id: SharedString::new(),
+        delivery: SharedString::new(),
         is_own: false,
         is_system: true,
+        event: types::SystemEvent::Left,
+        is_announcement: false,
+        is_action: false,
+        image_index: -1,
+        code_index: -1,
+        translation: Default::default(),
+        name_color: Default::default(),
     };
 
     {
         let state = app_state.lock().unwrap();
+        state
+            .persister
+            .record_event(SystemEvent::Left, &disconnect_message.content);
         let mut messages = state.messages.lock().unwrap();
         messages.push(disconnect_message);
     }
 
     update_messages(chat_handle, app_state);
     update_online_users(chat_handle, app_state);
-    
+    speech::on_join(username, false, app_state);
+
     println!("DEBUG: User {} disconnected, UI updated", username);
 }
 
+pub fn push_system_message(app_state: &Arc<Mutex<AppState>>, content: String) {
+    let system_message = types::ChatMessage {
+        username: SharedString::from("System"),
+        content: SharedString::from(content),
+        timestamp: SharedString::from(chrono::Local::now().format("%H:%M").to_string()),
+        sender_id: SharedString::new(),
+        burst_id: 0,
+        message_id: SharedString::new(),
+        delivery: SharedString::new(),
+        is_own: false,
+        is_system: true,
+        event: types::SystemEvent::Notice,
+        is_announcement: false,
+        is_action: false,
+        image_index: -1,
+        code_index: -1,
+        translation: Default::default(),
+        name_color: Default::default(),
+    };
+
+    let state = app_state.lock().unwrap();
+    let mut messages = state.messages.lock().unwrap();
+    messages.push(system_message);
+}
+
 pub fn update_messages_and_clear_input(
     chat_handle: &Weak<types::ChatWindow>,
     app_state: &Arc<Mutex<AppState>>,
@@ -74,6 +147,53 @@ pub fn update_messages_and_clear_input(
     }
 }
 
+// Bright enough to read on the dark bubbles, and clear of our own green and the system orange.
+const USER_COLORS: [(u8, u8, u8); 12] = [
+    (0x4d, 0xa6, 0xff),
+    (0xff, 0x6b, 0x9d),
+    (0xc7, 0x8b, 0xff),
+    (0x5c, 0xe1, 0xe6),
+    (0xff, 0xd9, 0x4d),
+    (0xff, 0x8a, 0x65),
+    (0x9c, 0xcc, 0x65),
+    (0xf0, 0x6e, 0xe8),
+    (0x7e, 0x9c, 0xff),
+    (0x4d, 0xd0, 0xa0),
+    (0xe5, 0x73, 0x73),
+    (0xb0, 0xbe, 0xc5),
+];
+
+// Node ids are random, so their first bytes spread people evenly over the palette, and a
+// name change doesn't change the colour.
+pub fn user_color(node_id: &NodeId) -> Color {
+    let bytes = node_id.as_bytes();
+    let index = u16::from_be_bytes([bytes[0], bytes[1]]) as usize % USER_COLORS.len();
+    let (r, g, b) = USER_COLORS[index];
+    Color::from_rgb_u8(r, g, b)
+}
+
+fn describe_client(client: Option<&ClientInfo>) -> String {
+    match client {
+        Some(client) if !client.version.is_empty() => {
+            let mut features: Vec<String> = client
+                .capabilities
+                .names()
+                .into_iter()
+                .map(String::from)
+                .collect();
+            if client.protocol > 0 {
+                features.insert(0, format!("protocol {}", client.protocol));
+            }
+            if features.is_empty() {
+                client.version.clone()
+            } else {
+                format!("{} · {}", client.version, features.join(", "))
+            }
+        }
+        _ => "Older client".to_string(),
+    }
+}
+
 pub fn update_online_users(
     chat_handle: &Weak<types::ChatWindow>,
     app_state: &Arc<Mutex<AppState>>,
@@ -85,17 +205,65 @@ pub fn update_online_users(
         if let Some(chat) = chat_handle_clone.upgrade() {
             let state = app_state_clone.lock().unwrap();
             let names = state.names.lock().unwrap();
+            let blocklist = state.blocklist.lock().unwrap();
+            let clients = state.clients.lock().unwrap();
+            let presence = state.presence.lock().unwrap();
+            let paths = state.paths.lock().unwrap();
 
-            let mut users: Vec<SharedString> = names
-                .values()
-                .map(|name| SharedString::from(name.clone()))
+            let mut users: Vec<types::OnlineUser> = names
+                .iter()
+                .filter(|(id, _)| state.current_node_id != Some(**id))
+                .map(|(id, name)| {
+                    let client = clients.get(id);
+                    types::OnlineUser {
+                        name: SharedString::from(name.clone()),
+                        muted: blocklist.is_muted(id),
+                        blocked: blocklist.is_blocked(id),
+                        client: SharedString::from(describe_client(client)),
+                        can_call: client.is_none_or(|client| client.supports(Capabilities::VOICE)),
+                        status: SharedString::from(presence.peer_status(id).as_str()),
+                        path: SharedString::from(
+                            paths
+                                .get(id)
+                                .copied()
+                                .flatten()
+                                .map_or("", |path| path.as_str()),
+                        ),
+                        color: user_color(id),
+                    }
+                })
                 .collect();
-            users.push(SharedString::from(state.current_username.clone()));
-            users.sort();
-            users.dedup();
+            users.push(types::OnlineUser {
+                name: SharedString::from(state.current_username.clone()),
+                muted: false,
+                blocked: false,
+                client: SharedString::new(),
+                can_call: false,
+                status: SharedString::from(presence.status().as_str()),
+                path: SharedString::new(),
+                color: Color::from_rgb_u8(0x00, 0xff, 0x88),
+            });
+            users.sort_by(|a, b| a.name.cmp(&b.name));
+            users.dedup_by(|a, b| a.name == b.name);
 
             let users_model = VecModel::from(users);
             chat.set_online_users(ModelRc::new(users_model));
+
+            let offline: Vec<types::OfflineUser> = match state.room_topic {
+                Some(topic) => state
+                    .last_seen
+                    .lock()
+                    .unwrap()
+                    .offline_in(&topic.to_string(), &names)
+                    .into_iter()
+                    .map(|(_, sighting)| types::OfflineUser {
+                        name: SharedString::from(sighting.name.clone()),
+                        last_seen: SharedString::from(format_ago(sighting.at)),
+                    })
+                    .collect(),
+                None => Vec::new(),
+            };
+            chat.set_offline_users(ModelRc::new(VecModel::from(offline)));
             println!("DEBUG: Online users updated in GUI");
         } else {
             println!("DEBUG: Chat window handle is invalid, cannot update online users");
@@ -109,24 +277,198 @@ pub fn update_online_users(
     }
 }
 
+// Past this many changed rows a single reset is cheaper than row-by-row notifications.
+const MAX_ROW_EDITS: usize = 64;
+
+// Brings the chat's persistent model in line with `wanted`, touching only the rows in
+// between the unchanged start and end. New messages end up as a single push.
+fn sync_rows(rows: &VecModel<types::ChatMessage>, mut wanted: Vec<types::ChatMessage>) {
+    let old_len = rows.row_count();
+    let shared = old_len.min(wanted.len());
+    let prefix = (0..shared)
+        .take_while(|&i| rows.row_data(i).as_ref() == Some(&wanted[i]))
+        .count();
+    let suffix = (0..shared - prefix)
+        .take_while(|&i| {
+            rows.row_data(old_len - 1 - i).as_ref() == Some(&wanted[wanted.len() - 1 - i])
+        })
+        .count();
+
+    let old_middle = old_len - prefix - suffix;
+    let new_middle = wanted.len() - prefix - suffix;
+    if old_middle + new_middle > MAX_ROW_EDITS {
+        rows.set_vec(wanted);
+        return;
+    }
+
+    let replacement: Vec<_> = wanted.drain(prefix..prefix + new_middle).collect();
+    for (offset, row) in replacement.into_iter().enumerate() {
+        if offset < old_middle {
+            rows.set_row_data(prefix + offset, row);
+        } else {
+            rows.insert(prefix + offset, row);
+        }
+    }
+    for _ in new_middle..old_middle {
+        rows.remove(prefix + new_middle);
+    }
+}
+
+// Runs on the UI thread. Muted peers, filtered words and collapsed bursts only affect
+// the view; the stored messages stay intact.
+pub fn render_messages(chat: &types::ChatWindow, state: &AppState) {
+    let messages = state.messages.lock().unwrap();
+    let blocklist = state.blocklist.lock().unwrap();
+    let flood = state.flood.lock().unwrap();
+    let (word_filter, show_joins) = {
+        let settings = state.settings.lock().unwrap();
+        (
+            settings.word_filter.clone(),
+            settings.chat_events.show_joins,
+        )
+    };
+    chat.set_has_older_history(history::has_older(state));
+
+    let visible: Vec<types::ChatMessage> = messages
+        .iter()
+        .filter(|message| {
+            show_joins
+                || !matches!(
+                    message.event,
+                    types::SystemEvent::Joined | types::SystemEvent::Left
+                )
+        })
+        .filter(|message| {
+            message
+                .sender_id
+                .parse()
+                .map_or(true, |id| !blocklist.is_muted(&id))
+        })
+        .filter_map(|message| {
+            if message.is_own || message.is_system {
+                return Some(message.clone());
+            }
+            let content = word_filter.apply(&message.content)?;
+            Some(types::ChatMessage {
+                content: SharedString::from(content),
+                ..message.clone()
+            })
+        })
+        .collect();
+
+    let mut burst_sizes: HashMap<i32, usize> = HashMap::new();
+    for message in &visible {
+        if flood.is_collapsed(message.burst_id) {
+            *burst_sizes.entry(message.burst_id).or_default() += 1;
+        }
+    }
+
+    let mut collapsed = Vec::with_capacity(visible.len());
+    for message in visible {
+        if !flood.is_collapsed(message.burst_id) {
+            collapsed.push(message);
+        } else if let Some(count) = burst_sizes.remove(&message.burst_id) {
+            collapsed.push(types::ChatMessage {
+                content: SharedString::from(format!(
+                    "⚠ {} messages from {} (click to expand)",
+                    count, message.username
+                )),
+                is_system: true,
+                event: types::SystemEvent::Notice,
+                is_announcement: false,
+                is_action: false,
+                image_index: -1,
+                code_index: -1,
+                translation: Default::default(),
+                ..message
+            });
+        }
+    }
+
+    for message in collapsed.iter_mut() {
+        if let Ok(id) = message.sender_id.parse::<NodeId>() {
+            message.name_color = user_color(&id);
+        }
+    }
+
+    let mut code = Vec::new();
+    for message in collapsed
+        .iter_mut()
+        .filter(|message| !message.is_system && !message.is_action)
+    {
+        if let Some(parts) = code_blocks::split(&message.content) {
+            message.code_index = code.len() as i32;
+            code.push(code_blocks::to_ui(parts));
+        }
+    }
+    chat.set_code_messages(ModelRc::new(VecModel::from(code)));
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let mut pictures = state.pictures.lock().unwrap();
+        let mut images = Vec::new();
+        let mut playing = Vec::new();
+        for message in collapsed.iter_mut().filter(|message| !message.is_system) {
+            let Some(picture) = pictures.get(&message.message_id) else {
+                continue;
+            };
+            match picture.first_frame() {
+                Some(pixels) => {
+                    // Animations start on their first frame and the player takes it from there.
+                    if let images::Picture::Animated(_) = picture {
+                        let id = message.message_id.parse().unwrap_or_default();
+                        playing.push((images.len(), id, 0));
+                    }
+                    message.image_index = images.len() as i32;
+                    images.push(slint::Image::from_rgba8(pixels.clone()));
+                }
+                None => {
+                    if let Some(caption) = images::caption(picture) {
+                        message.content = SharedString::from(caption);
+                    }
+                }
+            }
+        }
+        pictures.set_playing(playing);
+        chat.set_images(ModelRc::new(VecModel::from(images)));
+    }
+
+    println!("DEBUG: Updating GUI with {} messages", collapsed.len());
+    let model = chat.get_messages();
+    match model
+        .as_any()
+        .downcast_ref::<VecModel<types::ChatMessage>>()
+    {
+        Some(rows) => sync_rows(rows, collapsed),
+        None => chat.set_messages(ModelRc::new(VecModel::from(collapsed))),
+    }
+}
+
 pub fn update_messages(chat_handle: &Weak<types::ChatWindow>, app_state: &Arc<Mutex<AppState>>) {
+    // Every incoming message asks for a redraw; while one is already queued on the event
+    // loop it will pick up the newer state anyway, so floods don't pile up redraws.
+    let render_pending = app_state.lock().unwrap().render_pending.clone();
+    if render_pending.swap(true, Ordering::AcqRel) {
+        return;
+    }
+
     let chat_handle_clone = chat_handle.clone();
     let app_state_clone = app_state.clone();
+    let render_pending_clone = render_pending.clone();
 
     match slint::invoke_from_event_loop(move || {
+        render_pending_clone.store(false, Ordering::Release);
         if let Some(chat) = chat_handle_clone.upgrade() {
-            let state = app_state_clone.lock().unwrap();
-            let messages = state.messages.lock().unwrap();
-
-            println!("DEBUG: Updating GUI with {} messages", messages.len());
-            let messages_model = VecModel::from(messages.clone());
-            chat.set_messages(ModelRc::new(messages_model));
+            render_messages(&chat, &app_state_clone.lock().unwrap());
             println!("DEBUG: Messages updated in GUI successfully");
         } else {
             println!("DEBUG: Chat window handle is invalid, cannot update messages");
         }
     }) {
         Ok(_) => {}
-        Err(e) => println!("ERROR: Failed to invoke UI update from event loop: {:?}", e),
+        Err(e) => {
+            render_pending.store(false, Ordering::Release);
+            println!("ERROR: Failed to invoke UI update from event loop: {:?}", e)
+        }
     }
 }
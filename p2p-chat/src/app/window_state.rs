@@ -0,0 +1,201 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use slint::{CloseRequestResponse, ComponentHandle, Weak};
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use crate::app::{app_state::AppState, contacts, saved_chats, types};
+
+const WINDOW_STATE_FILE: &str = "window_state.json";
+
+// In physical pixels, as the windowing system reports them.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Geometry {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Geometry {
+    fn of(window: &slint::Window) -> Option<Self> {
+        let size = window.size();
+        let position = window.position();
+        // Minimised windows report no size; keep whatever was there before.
+        (size.width > 0 && size.height > 0).then_some(Self {
+            x: position.x,
+            y: position.y,
+            width: size.width,
+            height: size.height,
+        })
+    }
+
+    // The browser build's window is the page's canvas, which sizes itself.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn apply(&self, window: &slint::Window) {
+        window.set_size(slint::PhysicalSize::new(self.width, self.height));
+        window.set_position(slint::PhysicalPosition::new(self.x, self.y));
+    }
+}
+
+// Which panel the start window was showing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StartPage {
+    #[default]
+    Home,
+    Contacts,
+    SavedChats,
+}
+
+// Each window's geometry is taken when it's closed, so it opens the same way next time.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WindowState {
+    pub start: Option<Geometry>,
+    pub join: Option<Geometry>,
+    pub create: Option<Geometry>,
+    pub chat: Option<Geometry>,
+    pub start_page: StartPage,
+    pub sidebar_hidden: bool,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl WindowState {
+    pub fn load() -> Self {
+        Self::load_from(WINDOW_STATE_FILE)
+    }
+
+    pub fn load_from(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let mut state: WindowState = match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+                eprintln!("Failed to parse {}: {}", path.display(), e);
+                WindowState::default()
+            }),
+            Err(_) => WindowState::default(),
+        };
+        state.path = path;
+        state
+    }
+
+    pub fn save(&self) -> Result<()> {
+        fs::write(&self.path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+#[derive(Clone)]
+pub struct Windows {
+    pub main: Weak<types::StartWindow>,
+    pub join: Weak<types::JoinWindow>,
+    pub create: Weak<types::CreateWindow>,
+    pub chat: Weak<types::ChatWindow>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Which {
+    Start,
+    Join,
+    Create,
+    Chat,
+}
+
+// Puts every window back the way it was left. Runs before the first window is shown.
+pub fn restore(windows: &Windows, app_state: &Arc<Mutex<AppState>>) -> Arc<Mutex<WindowState>> {
+    let state = WindowState::load();
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        if let (Some(geometry), Some(main)) = (state.start, windows.main.upgrade()) {
+            geometry.apply(main.window());
+        }
+        if let (Some(geometry), Some(join)) = (state.join, windows.join.upgrade()) {
+            geometry.apply(join.window());
+        }
+        if let (Some(geometry), Some(create)) = (state.create, windows.create.upgrade()) {
+            geometry.apply(create.window());
+        }
+        if let (Some(geometry), Some(chat)) = (state.chat, windows.chat.upgrade()) {
+            geometry.apply(chat.window());
+        }
+    }
+    if let Some(chat) = windows.chat.upgrade() {
+        chat.set_show_sidebar(!state.sidebar_hidden);
+    }
+    if let Some(main) = windows.main.upgrade() {
+        match state.start_page {
+            StartPage::Home => {}
+            StartPage::Contacts => {
+                contacts::refresh_start_contacts(&main, app_state);
+                main.set_show_contacts(true);
+            }
+            StartPage::SavedChats => {
+                saved_chats::refresh_start_saved_chats(&main);
+                main.set_show_saved_chats(true);
+            }
+        }
+    }
+    Arc::new(Mutex::new(state))
+}
+
+// Saves the state whenever one of the windows is closed.
+pub fn watch(windows: &Windows, state: Arc<Mutex<WindowState>>) {
+    let on_close = |which: Which| {
+        let windows = windows.clone();
+        let state = state.clone();
+        move || {
+            remember(&windows, &state, which);
+            CloseRequestResponse::HideWindow
+        }
+    };
+    if let Some(main) = windows.main.upgrade() {
+        main.window().on_close_requested(on_close(Which::Start));
+    }
+    if let Some(join) = windows.join.upgrade() {
+        join.window().on_close_requested(on_close(Which::Join));
+    }
+    if let Some(create) = windows.create.upgrade() {
+        create.window().on_close_requested(on_close(Which::Create));
+    }
+    if let Some(chat) = windows.chat.upgrade() {
+        chat.window().on_close_requested(on_close(Which::Chat));
+    }
+}
+
+fn remember(windows: &Windows, state: &Mutex<WindowState>, which: Which) {
+    let mut state = state.lock().unwrap();
+    let geometry = match which {
+        Which::Start => windows.main.upgrade().map(|w| Geometry::of(w.window())),
+        Which::Join => windows.join.upgrade().map(|w| Geometry::of(w.window())),
+        Which::Create => windows.create.upgrade().map(|w| Geometry::of(w.window())),
+        Which::Chat => windows.chat.upgrade().map(|w| Geometry::of(w.window())),
+    }
+    .flatten();
+    if let Some(geometry) = geometry {
+        match which {
+            Which::Start => state.start = Some(geometry),
+            Which::Join => state.join = Some(geometry),
+            Which::Create => state.create = Some(geometry),
+            Which::Chat => state.chat = Some(geometry),
+        }
+    }
+    if let Some(main) = windows.main.upgrade() {
+        state.start_page = if main.get_show_contacts() {
+            StartPage::Contacts
+        } else if main.get_show_saved_chats() {
+            StartPage::SavedChats
+        } else {
+            StartPage::Home
+        };
+    }
+    if let Some(chat) = windows.chat.upgrade() {
+        state.sidebar_hidden = !chat.get_show_sidebar();
+    }
+    if let Err(e) = state.save() {
+        eprintln!("Error saving window state: {}", e);
+    }
+}
@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilterMode {
+    #[default]
+    Mask,
+    Hide,
+}
+
+impl FilterMode {
+    pub fn from_index(index: i32) -> Self {
+        match index {
+            1 => FilterMode::Hide,
+            _ => FilterMode::Mask,
+        }
+    }
+
+    pub fn index(self) -> i32 {
+        match self {
+            FilterMode::Mask => 0,
+            FilterMode::Hide => 1,
+        }
+    }
+}
+
+// Words are matched whole and case-insensitively, so "ass" doesn't catch "class".
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WordFilter {
+    pub enabled: bool,
+    pub mode: FilterMode,
+    pub words: Vec<String>,
+}
+
+impl WordFilter {
+    pub fn set_words(&mut self, list: &str) {
+        self.words = list
+            .split([',', '\n'])
+            .map(|word| word.trim().to_lowercase())
+            .filter(|word| !word.is_empty())
+            .collect();
+        self.words.sort();
+        self.words.dedup();
+    }
+
+    pub fn words_text(&self) -> String {
+        self.words.join("\n")
+    }
+
+    fn is_blocked_word(&self, word: &str) -> bool {
+        self.words
+            .iter()
+            .any(|blocked| *blocked == word.to_lowercase())
+    }
+
+    /// Returns `None` when the message should be hidden, otherwise the text to show.
+    pub fn apply(&self, text: &str) -> Option<String> {
+        if !self.enabled || self.words.is_empty() {
+            return Some(text.to_string());
+        }
+
+        let mut filtered = String::with_capacity(text.len());
+        let mut word = String::new();
+        let mut matched = false;
+
+        let mut flush = |word: &mut String, filtered: &mut String| {
+            if self.is_blocked_word(word) {
+                matched = true;
+                filtered.extend(word.chars().map(|_| '*'));
+            } else {
+                filtered.push_str(word);
+            }
+            word.clear();
+        };
+
+        for c in text.chars() {
+            if c.is_alphanumeric() || c == '\'' {
+                word.push(c);
+            } else {
+                flush(&mut word, &mut filtered);
+                filtered.push(c);
+            }
+        }
+        flush(&mut word, &mut filtered);
+
+        if matched && self.mode == FilterMode::Hide {
+            None
+        } else {
+            Some(filtered)
+        }
+    }
+}
@@ -3,9 +3,18 @@ use anyhow::Result;
 
 pub mod app;
 
+#[cfg(not(target_arch = "wasm32"))]
 fn main() -> Result<()> {
     match App::run() {
         Ok(()) => Ok(()),
         Err(e) => Err(anyhow::anyhow!("GUI error: {}", e)),
     }
 }
+
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen::prelude::wasm_bindgen(start)]
+pub fn main() {
+    if let Err(e) = App::run() {
+        eprintln!("GUI error: {}", e);
+    }
+}
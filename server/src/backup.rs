@@ -0,0 +1,137 @@
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use iroh::{NodeId, SecretKey};
+use iroh_gossip::proto::TopicId;
+use messaging::StoredMessage;
+use serde::{Deserialize, Serialize};
+use std::{fs, io::Write, path::Path};
+
+use crate::{retention::Retention, schedule::Scheduled};
+
+const IDENTITY_FILE: &str = "server_identity.key";
+const CONFIG_FILE: &str = "server_config.json";
+const BACKUP_VERSION: u32 = 1;
+
+// Which room this server runs, so a restart (or a restore on another host) comes back
+// to the same one instead of opening a fresh topic.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerConfig {
+    pub topic: TopicId,
+    // Set when this server joined someone else's room as an extra history node.
+    #[serde(default)]
+    pub joined: Option<String>,
+    #[serde(default)]
+    pub retention: Retention,
+    // Kept out of the room for good; see /ban.
+    #[serde(default)]
+    pub banned: Vec<NodeId>,
+    // Announcements the server posts by itself; see /schedule.
+    #[serde(default)]
+    pub schedule: Vec<Scheduled>,
+    // Invites nobody can join with any more; see /revoke.
+    #[serde(default)]
+    pub revoked_invites: Vec<u64>,
+    // When not empty, only these node ids get through; see --allow.
+    #[serde(default)]
+    pub allowed: Vec<NodeId>,
+    // Other servers whose archives and announcements we take; see --peer.
+    #[serde(default)]
+    pub peers: Vec<NodeId>,
+    // The room this server opened keeps no history; see --incognito.
+    #[serde(default)]
+    pub incognito: bool,
+}
+
+impl ServerConfig {
+    pub fn load() -> Option<Self> {
+        let data = fs::read_to_string(CONFIG_FILE).ok()?;
+        serde_json::from_str(&data)
+            .map_err(|e| eprintln!("Failed to parse {}: {}", CONFIG_FILE, e))
+            .ok()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        fs::write(CONFIG_FILE, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", CONFIG_FILE))
+    }
+}
+
+// The server keeps its node id between runs so tickets handed out earlier still reach it.
+pub fn load_or_create_identity() -> Result<SecretKey> {
+    if let Ok(bytes) = fs::read(IDENTITY_FILE)
+        && let Ok(bytes) = <[u8; 32]>::try_from(bytes.as_slice())
+    {
+        return Ok(SecretKey::from_bytes(&bytes));
+    }
+
+    let secret_key = SecretKey::from_bytes(&rand::random());
+    write_private(IDENTITY_FILE, secret_key.to_bytes())
+        .with_context(|| format!("Failed to write {}", IDENTITY_FILE))?;
+    println!("Created new server identity in {}", IDENTITY_FILE);
+    Ok(secret_key)
+}
+
+// Everything needed to bring the room up on another machine, in one file.
+#[derive(Serialize, Deserialize)]
+struct Backup {
+    version: u32,
+    created_at: DateTime<Utc>,
+    secret_key: SecretKey,
+    config: ServerConfig,
+    history: Vec<StoredMessage>,
+}
+
+pub fn write_backup(
+    path: &Path,
+    secret_key: &SecretKey,
+    config: &ServerConfig,
+    history: &[StoredMessage],
+) -> Result<()> {
+    let backup = Backup {
+        version: BACKUP_VERSION,
+        created_at: Utc::now(),
+        secret_key: secret_key.clone(),
+        config: config.clone(),
+        history: history.to_vec(),
+    };
+    write_private(path, serde_json::to_string_pretty(&backup)?)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+// Writes a file only we can read, for secret keys and whatever holds one.
+fn write_private(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    // The mode only applies to a new file, so one written before is tightened as well.
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(contents.as_ref())
+}
+
+// Puts the identity and config from a backup in place for the next start and returns
+// its history.
+pub fn restore_backup(path: &Path) -> Result<Vec<StoredMessage>> {
+    let data =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let backup: Backup = serde_json::from_str(&data)
+        .with_context(|| format!("{} is not a server backup", path.display()))?;
+    if backup.version > BACKUP_VERSION {
+        bail!(
+            "Backup version {} is newer than this server understands",
+            backup.version
+        );
+    }
+
+    write_private(IDENTITY_FILE, backup.secret_key.to_bytes())
+        .with_context(|| format!("Failed to write {}", IDENTITY_FILE))?;
+    backup.config.save()?;
+    println!(
+        "Restored backup from {} with {} messages",
+        backup.created_at.format("%d/%m/%Y %H:%M:%S"),
+        backup.history.len()
+    );
+    Ok(backup.history)
+}
@@ -0,0 +1,37 @@
+use chrono::{DateTime, Utc};
+use iroh::NodeId;
+use messaging::{MessageId, StoredMessage};
+use std::collections::HashSet;
+
+// How two history nodes decide they hold the same message. Entries written before
+// messages had ids fall back to sender, time and text.
+#[derive(PartialEq, Eq, Hash)]
+enum HistoryKey {
+    Id(MessageId),
+    Legacy(NodeId, DateTime<Utc>, String),
+}
+
+fn history_key(message: &StoredMessage) -> HistoryKey {
+    match message.id {
+        Some(id) => HistoryKey::Id(id),
+        None => HistoryKey::Legacy(message.from, message.timestamp, message.text.clone()),
+    }
+}
+
+// Adds the messages another history node has that we don't, keeping the archive in time
+// order. Returns how many were new.
+pub fn merge_history(ours: &mut Vec<StoredMessage>, theirs: Vec<StoredMessage>) -> usize {
+    let mut known: HashSet<HistoryKey> = ours.iter().map(history_key).collect();
+    let before = ours.len();
+    ours.extend(
+        theirs
+            .into_iter()
+            .filter(|message| known.insert(history_key(message))),
+    );
+    let added = ours.len() - before;
+    if added > 0 {
+        // Stable, so messages stamped in the same second keep the order we had them in.
+        ours.sort_by_key(|message| message.timestamp);
+    }
+    added
+}
@@ -0,0 +1,91 @@
+use iroh::{
+    NodeId,
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler},
+};
+use messaging::{StoredMessage, history};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+// A newcomer can ask before its AboutMe has reached us, so give it a moment to show up.
+const MEMBER_WAIT: Duration = Duration::from_secs(5);
+const MEMBER_POLL: Duration = Duration::from_millis(200);
+
+// Hands the archive to one member at a time, instead of gossiping it to the whole room on
+// every join.
+#[derive(Debug, Clone)]
+pub struct HistoryProtocol {
+    node_id: NodeId,
+    users: Arc<Mutex<HashMap<NodeId, String>>>,
+    message_history: Arc<Mutex<Vec<StoredMessage>>>,
+}
+
+impl HistoryProtocol {
+    pub fn new(
+        node_id: NodeId,
+        users: Arc<Mutex<HashMap<NodeId, String>>>,
+        message_history: Arc<Mutex<Vec<StoredMessage>>>,
+    ) -> Self {
+        Self {
+            node_id,
+            users,
+            message_history,
+        }
+    }
+
+    // Only people who announced themselves in the room get its history, which keeps out
+    // anyone banned, not allowed, or turned away at the door.
+    async fn member_name(&self, node_id: &NodeId) -> Option<String> {
+        let mut waited = Duration::ZERO;
+        loop {
+            if let Some(name) = self.users.lock().unwrap().get(node_id) {
+                return Some(name.clone());
+            }
+            if waited >= MEMBER_WAIT {
+                return None;
+            }
+            tokio::time::sleep(MEMBER_POLL).await;
+            waited += MEMBER_POLL;
+        }
+    }
+}
+
+impl ProtocolHandler for HistoryProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let remote = connection.remote_node_id()?;
+        let (mut send, mut recv) = connection.accept_bi().await?;
+        let from = history::read_request(&mut recv)
+            .await
+            .map_err(|_| AcceptError::NotAllowed {})?;
+        if from != remote {
+            return Err(AcceptError::NotAllowed {});
+        }
+        let Some(name) = self.member_name(&from).await else {
+            println!(
+                "Refused history to {}, who isn't in the room",
+                from.fmt_short()
+            );
+            return Err(AcceptError::NotAllowed {});
+        };
+
+        let messages = self.message_history.lock().unwrap().clone();
+        let count = messages.len();
+        let chunks = match history::send_history(&mut send, self.node_id, messages).await {
+            Ok(chunks) => chunks,
+            Err(e) => {
+                eprintln!("Failed to send history to {}: {}", name, e);
+                return Ok(());
+            }
+        };
+        println!(
+            "Sent {} messages from history to {} in {} chunks",
+            count, name, chunks
+        );
+        // The requester closes the connection once it has read the whole archive.
+        connection.closed().await;
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+use anyhow::{Result, anyhow, bail};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+// A client that trickles its request in, or never finishes it, is dropped after this.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Just enough HTTP for curl and a Prometheus scraper: reads the request head and returns
+// the target of a GET, or an empty string for anything else.
+pub async fn read_get_target(stream: &mut TcpStream) -> Result<String> {
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_head(stream))
+        .await
+        .map_err(|_| anyhow!("Request not received within {:?}", REQUEST_TIMEOUT))??;
+
+    let request = String::from_utf8_lossy(&request);
+    Ok(request
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("GET "))
+        .and_then(|rest| rest.split(' ').next())
+        .unwrap_or("")
+        .to_string())
+}
+
+async fn read_head(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        if request.len() + read > MAX_REQUEST_BYTES {
+            bail!("Request head is over {} bytes", MAX_REQUEST_BYTES);
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    Ok(request)
+}
+
+pub async fn respond(
+    mut stream: TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
@@ -1,32 +1,150 @@
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Local, Timelike, Utc};
 use futures_lite::StreamExt;
-use iroh::{Endpoint, protocol::Router};
-use iroh_gossip::{api::Event, net::Gossip, proto::TopicId};
-use messaging::{Message, MessageBody, StoredMessage};
+use iroh::{
+    Endpoint, NodeId, Watcher,
+    endpoint::{DirectAddr, DirectAddrType},
+    protocol::{AccessLimit, Router},
+};
+use iroh_gossip::{
+    api::{Event, GossipSender},
+    net::Gossip,
+    proto::TopicId,
+};
+use messaging::{
+    Capabilities, ClientInfo, Invite, JoinVerdict, Message, MessageBody, PROTOCOL_VERSION,
+    SeenWindow, StoredMessage,
+    history::{HISTORY_ALPN, fetch_history},
+    message_id,
+    receipts::send_acks,
+};
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet},
     fs,
     path::Path,
+    str::FromStr,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 use ticket::Ticket;
 
+use crate::{
+    backup::ServerConfig, history::HistoryProtocol, metrics::RoomMetrics, search::SearchIndex,
+};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+mod backup;
+mod cluster;
+mod history;
+mod http;
+mod metrics;
+mod retention;
+mod schedule;
+mod search;
+
 const DEFAULT_RELAY_URL: &str = "https://relay.iroh.link";
 const MESSAGE_HISTORY_FILE: &str = "server_message_history.json";
+const SERVER_VERSION: &str = concat!("chast-server/", env!("CARGO_PKG_VERSION"));
+const HISTORY_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+const ACK_SEND_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[tokio::main]
 async fn main() -> Result<()> {
     println!("Starting Chat Server...");
 
-    let topic = TopicId::from_bytes(rand::random());
-    let endpoint = Endpoint::builder().discovery_n0().bind().await?;
+    // Given a ticket, this server joins that room as an extra history node instead of
+    // opening a new one. Without one it goes back to the room it ran last time.
+    let saved = ServerConfig::load();
+    let args = Args::parse(std::env::args().skip(1))?;
+    let joined = args
+        .ticket
+        .or_else(|| saved.as_ref().and_then(|config| config.joined.clone()));
+    let joining = match &joined {
+        Some(ticket) => Some(Ticket::from_str(ticket)?),
+        None => None,
+    };
+    if joining.as_ref().is_some_and(Ticket::is_locked) {
+        bail!("Password-protected tickets can't be joined by the server");
+    }
+    let topic = match (&joining, &saved) {
+        (Some(ticket), _) => ticket.topic,
+        (None, Some(config)) => config.topic,
+        (None, None) => TopicId::from_bytes(rand::random()),
+    };
+    let config = ServerConfig {
+        topic,
+        joined,
+        retention: saved
+            .as_ref()
+            .map(|config| config.retention)
+            .unwrap_or_default(),
+        banned: saved
+            .as_ref()
+            .map(|config| config.banned.clone())
+            .unwrap_or_default(),
+        schedule: saved
+            .as_ref()
+            .map(|config| config.schedule.clone())
+            .unwrap_or_default(),
+        revoked_invites: saved
+            .as_ref()
+            .map(|config| config.revoked_invites.clone())
+            .unwrap_or_default(),
+        // Passing --allow replaces the saved list; otherwise it carries over.
+        allowed: match args.allowed {
+            Some(allowed) => allowed,
+            None => saved
+                .as_ref()
+                .map(|config| config.allowed.clone())
+                .unwrap_or_default(),
+        },
+        // Passing --peer replaces the saved list too.
+        peers: match args.peers {
+            Some(peers) => peers,
+            None => saved
+                .as_ref()
+                .map(|config| config.peers.clone())
+                .unwrap_or_default(),
+        },
+        // Only means something for a room we open; a joined room's ticket says for itself.
+        incognito: args.incognito || saved.as_ref().is_some_and(|config| config.incognito),
+    };
+    if let Err(e) = config.save() {
+        eprintln!("Failed to save server config: {}", e);
+    }
+
+    let endpoint = Endpoint::builder()
+        .secret_key(backup::load_or_create_identity()?)
+        .discovery_n0()
+        .bind()
+        .await?;
     let node_id = endpoint.node_id();
 
     println!("Server Node ID: {}", node_id);
+    tokio::spawn(print_reachable_at(endpoint.direct_addresses().stream()));
+
+    let users: Arc<Mutex<HashMap<iroh::NodeId, String>>> = Arc::new(Mutex::new(HashMap::new()));
+    users.lock().unwrap().insert(node_id, "Server".to_string());
+
+    let message_history: Arc<Mutex<Vec<StoredMessage>>> =
+        Arc::new(Mutex::new(load_message_history()));
 
     let gossip = Gossip::builder().spawn(endpoint.clone());
-    let _router = Router::builder(endpoint.clone())
-        .accept(iroh_gossip::ALPN, gossip.clone())
+    let allowed: HashSet<NodeId> = config.allowed.iter().copied().collect();
+    if !allowed.is_empty() {
+        println!("Only accepting {} allowed node ids", allowed.len());
+    }
+    let router = Router::builder(endpoint.clone())
+        .accept(
+            iroh_gossip::ALPN,
+            AccessLimit::new(gossip.clone(), move |peer| {
+                allowed.is_empty() || allowed.contains(&peer)
+            }),
+        )
+        .accept(
+            HISTORY_ALPN,
+            HistoryProtocol::new(node_id, users.clone(), message_history.clone()),
+        )
         .spawn();
 
     tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
@@ -37,26 +155,55 @@ async fn main() -> Result<()> {
         node_addr = node_addr.with_relay_url(relay_url);
     }
 
-    let ticket = Ticket {
-        topic,
-        nodes: vec![node_addr],
+    let (ticket, subscription) = match joining {
+        Some(joined) => {
+            let node_ids = joined.nodes.iter().map(|node| node.node_id).collect();
+            for node in joined.nodes.iter().cloned() {
+                endpoint.add_node_addr(node)?;
+            }
+            println!("Joining room as an additional history node...");
+            let subscription = gossip.subscribe_and_join(topic, node_ids).await?;
+            println!("Joined room successfully!");
+            (
+                Ticket {
+                    nodes: vec![node_addr],
+                    ..joined
+                },
+                subscription,
+            )
+        }
+        None => {
+            let ticket = Ticket {
+                topic,
+                nodes: vec![node_addr],
+                moderators: Vec::new(),
+                // Clients only take kicks and bans from the room's owner.
+                owner: Some(node_id),
+                info: Default::default(),
+                capacity: None,
+                knock: false,
+                incognito: config.incognito,
+                locked: None,
+                // Joining takes an invite signed by us; see /invite and /revoke.
+                invite: Some(Invite::new(endpoint.secret_key(), topic.as_bytes())),
+            };
+            println!("Room created successfully!");
+            (ticket, gossip.subscribe(topic, vec![]).await?)
+        }
     };
 
-    println!("Room created successfully!");
     println!("Share this ticket with others to join:");
-    println!("{}", "=".repeat(60));
-    println!("{}", ticket);
-    println!("{}", "=".repeat(60));
+    print_ticket(&ticket);
     println!("Server is running. Others can join using the p2p-chat app with this ticket.");
+    if ticket.incognito {
+        println!("Incognito room: messages are relayed but not stored");
+    }
 
-    let subscription = gossip.subscribe(topic, vec![]).await?;
-    let (sender, mut receiver) = subscription.split();
-
-    let users: Arc<Mutex<HashMap<iroh::NodeId, String>>> = Arc::new(Mutex::new(HashMap::new()));
-    users.lock().unwrap().insert(node_id, "Server".to_string());
-
-    let message_history: Arc<Mutex<Vec<StoredMessage>>> =
-        Arc::new(Mutex::new(load_message_history()));
+    let (sender, receiver) = subscription.split();
+    // Built with the chaos feature, CHAST_CHAOS makes the room's gossip unreliable.
+    #[cfg(feature = "chaos")]
+    let receiver = messaging::chaos::stream(receiver);
+    let mut receiver = std::pin::pin!(receiver);
 
     println!("Chat log will appear below:");
     println!("{}", "-".repeat(60));
@@ -66,93 +213,1046 @@ async fn main() -> Result<()> {
         println!("Loaded {} existing messages from history", existing_count);
     }
 
+    let search = Arc::new(Mutex::new(SearchIndex::build(
+        &message_history.lock().unwrap(),
+    )));
+    if let Ok(addr) = std::env::var(search::SEARCH_ADDR_VAR) {
+        let history = message_history.clone();
+        let search = search.clone();
+        tokio::spawn(async move {
+            if let Err(e) = search::serve(addr, history, search).await {
+                eprintln!("Search endpoint stopped: {}", e);
+            }
+        });
+    }
+
+    let room_metrics = Arc::new(RoomMetrics::default());
+    if let Ok(addr) = std::env::var(metrics::METRICS_ADDR_VAR) {
+        let mut registry = iroh_metrics::Registry::default();
+        registry.register_all_prefixed(endpoint.metrics());
+        registry.register(gossip.metrics().clone());
+        registry.register(room_metrics.clone());
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(addr, Arc::new(registry)).await {
+                eprintln!("Metrics endpoint stopped: {}", e);
+            }
+        });
+    }
+
+    let (archive_sender, mut archives) = tokio::sync::mpsc::unbounded_channel();
+    let mut server = Server {
+        endpoint: endpoint.clone(),
+        sender,
+        users,
+        message_history,
+        search,
+        seen: SeenWindow::default(),
+        history_peers: HashSet::new(),
+        admitted: HashSet::new(),
+        direct_acks: HashSet::new(),
+        archives: archive_sender,
+        room_owner: ticket.owner,
+        ticket,
+        last_scheduled: None,
+        metrics: room_metrics,
+        config,
+    };
+    // Other history nodes in the room only find out about us from this.
+    if let Err(e) = server.announce().await {
+        eprintln!("Failed to announce server: {}", e);
+    }
+
+    let mut console = BufReader::new(tokio::io::stdin()).lines();
+    // Running without a terminal closes stdin straight away; the room keeps going regardless.
+    let mut console_open = true;
+    println!("Type /help for console commands.");
+    let mut prune_timer = tokio::time::interval(retention::PRUNE_EVERY);
+    let mut schedule_timer = tokio::time::interval(schedule::CHECK_EVERY);
+
     loop {
-        if let Ok(Some(event)) = receiver.try_next().await {
-            if let Event::Received(msg) = event {
-                if let Ok(message) = Message::from_bytes(&msg.content) {
-                    match message.body {
-                        MessageBody::AboutMe { from, name } => {
-                            let is_new_user = !users.lock().unwrap().contains_key(&from);
-                            users.lock().unwrap().insert(from, name.clone());
-
-                            if is_new_user {
-                                println!(
-                                    "{} joined the room ({})",
-                                    name,
-                                    from.to_string().chars().take(8).collect::<String>()
-                                );
-
-                                let user_count = users.lock().unwrap().len();
-                                println!("{} users online", user_count);
-
-                                let history = message_history.lock().unwrap().clone();
-                                if !history.is_empty() {
-                                    let history_message =
-                                        Message::new(MessageBody::MessageHistory {
-                                            messages: history,
-                                        });
-
-                                    if let Err(e) =
-                                        sender.broadcast(history_message.to_vec().into()).await
-                                    {
-                                        eprintln!("Failed to send message history: {}", e);
-                                    } else {
-                                        println!(
-                                            "Sent {} messages from history to {}",
-                                            message_history.lock().unwrap().len(),
-                                            name
-                                        );
-                                    }
-                                }
-                            }
-                        }
-                        MessageBody::Message { from, text } => {
-                            let sender_name = users
-                                .lock()
-                                .unwrap()
-                                .get(&from)
-                                .cloned()
-                                .unwrap_or_else(|| {
-                                    format!(
-                                        "User-{}",
-                                        from.to_string().chars().take(8).collect::<String>()
-                                    )
-                                });
-
-                            let timestamp =
-                                chrono::Local::now().format("%d/%m/%Y %H:%M:%S").to_string();
-                            println!("[{}] {}: {}", timestamp, sender_name, text);
-
-                            let stored_message = StoredMessage {
-                                from,
-                                sender_name: sender_name.clone(),
-                                text: text.clone(),
-                                timestamp: timestamp.clone(),
-                            };
-
-                            message_history.lock().unwrap().push(stored_message);
-
-                            if let Err(e) = save_message_history(&message_history.lock().unwrap()) {
-                                eprintln!("Failed to save message history: {}", e);
-                            }
+        server.update_metrics();
+        tokio::select! {
+            _ = prune_timer.tick() => server.prune(),
+            _ = schedule_timer.tick() => server.run_schedule().await,
+            Some((from, messages)) = archives.recv() => server.merge_archive(from, messages),
+            event = receiver.next() => match event {
+                Some(Ok(Event::Received(msg))) => match Message::from_signed_bytes(&msg.content, topic.as_bytes()) {
+                    Ok(message) => server.handle_message(message).await,
+                    Err(e) => eprintln!("Dropping unreadable message: {}", e),
+                },
+                Some(Ok(Event::NeighborUp(_))) => {
+                    server.metrics.gossip_neighbors.inc();
+                }
+                Some(Ok(Event::NeighborDown(_))) => {
+                    server.metrics.gossip_neighbors.dec();
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => eprintln!("Gossip error: {}", e),
+                None => {
+                    println!("Gossip subscription closed");
+                    break;
+                }
+            },
+            line = console.next_line(), if console_open => match line {
+                Ok(Some(line)) => {
+                    if !server.handle_command(line.trim()).await {
+                        break;
+                    }
+                }
+                Ok(None) => console_open = false,
+                Err(e) => {
+                    eprintln!("Failed to read console input: {}", e);
+                    console_open = false;
+                }
+            },
+            _ = tokio::signal::ctrl_c() => {
+                println!("Shutting down...");
+                break;
+            }
+        }
+    }
+
+    server.shutdown().await;
+    if let Err(e) = router.shutdown().await {
+        eprintln!("Error shutting down router: {}", e);
+    }
+    Ok(())
+}
+
+// `server [ticket] [--allow <node-id>]... [--allow-file <path>] [--peer <node-id>]...
+// [--incognito]`. An allowlist file has one node id per line; blank lines and lines
+// starting with # are skipped. A peer is another server we trust with history; nothing
+// a node says about itself makes it one.
+struct Args {
+    ticket: Option<String>,
+    // None when no --allow flags were given, so the saved list is kept.
+    allowed: Option<Vec<NodeId>>,
+    // None when no --peer flags were given.
+    peers: Option<Vec<NodeId>>,
+    incognito: bool,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut ticket = None;
+        let mut allowed: Option<Vec<NodeId>> = None;
+        let mut peers: Option<Vec<NodeId>> = None;
+        let mut incognito = false;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--incognito" => incognito = true,
+                "--allow" => {
+                    let Some(id) = args.next() else {
+                        bail!("--allow needs a node id");
+                    };
+                    let id =
+                        NodeId::from_str(&id).with_context(|| format!("Invalid node id {}", id))?;
+                    allowed.get_or_insert_default().push(id);
+                }
+                "--peer" => {
+                    let Some(id) = args.next() else {
+                        bail!("--peer needs a node id");
+                    };
+                    let id =
+                        NodeId::from_str(&id).with_context(|| format!("Invalid node id {}", id))?;
+                    peers.get_or_insert_default().push(id);
+                }
+                "--allow-file" => {
+                    let Some(path) = args.next() else {
+                        bail!("--allow-file needs a path");
+                    };
+                    let list = allowed.get_or_insert_default();
+                    let data = fs::read_to_string(&path)
+                        .with_context(|| format!("Failed to read {}", path))?;
+                    for line in data.lines() {
+                        let line = line.trim();
+                        if !line.is_empty() && !line.starts_with('#') {
+                            let id = NodeId::from_str(line)
+                                .with_context(|| format!("Invalid node id {} in {}", line, path))?;
+                            list.push(id);
                         }
-                        MessageBody::Disconnect { from, name } => {
-                            users.lock().unwrap().remove(&from);
-                            println!("{} left the room", name);
+                    }
+                }
+                _ if ticket.is_none() => ticket = Some(arg),
+                _ => bail!("Unexpected argument {}", arg),
+            }
+        }
+        Ok(Self {
+            ticket,
+            allowed,
+            peers,
+            incognito,
+        })
+    }
+}
+
+fn print_ticket(ticket: &Ticket) {
+    println!("{}", "=".repeat(60));
+    println!("{}", ticket);
+    println!("{}", "=".repeat(60));
+    if let Some(invite) = ticket.invite {
+        println!("Invite {:016x}", invite.id);
+    }
+    match ticket.to_qr_terminal() {
+        Ok(qr) => print!("{}", qr),
+        Err(e) => eprintln!("Failed to draw the ticket as a QR code: {}", e),
+    }
+}
+
+struct Server {
+    endpoint: Endpoint,
+    sender: GossipSender,
+    users: Arc<Mutex<HashMap<iroh::NodeId, String>>>,
+    message_history: Arc<Mutex<Vec<StoredMessage>>>,
+    // Always locked after message_history, never before.
+    search: Arc<Mutex<SearchIndex>>,
+    // Replayed or duplicated messages must not end up in the history twice.
+    seen: SeenWindow,
+    // Configured peers (--peer) seen in the room, whose archives we merge into ours.
+    history_peers: HashSet<iroh::NodeId>,
+    // Archives fetched from history_peers, merged back on the main loop.
+    archives: tokio::sync::mpsc::UnboundedSender<(iroh::NodeId, Vec<StoredMessage>)>,
+    // In a knock room, who the owner let in, from the JoinReplies it sends to the room.
+    admitted: HashSet<iroh::NodeId>,
+    // Clients that take acks over a direct connection rather than from the gossip.
+    direct_acks: HashSet<iroh::NodeId>,
+    // Only the owner's kicks and bans are honoured by clients.
+    room_owner: Option<iroh::NodeId>,
+    // The last minute the schedule was checked for, so nothing is posted twice.
+    last_scheduled: Option<chrono::NaiveDateTime>,
+    metrics: Arc<RoomMetrics>,
+    // The ticket we hand out; /invite and /revoke swap in a fresh invite.
+    ticket: Ticket,
+    config: ServerConfig,
+}
 
-                            let user_count = users.lock().unwrap().len();
-                            println!("{} users online", user_count);
+impl Server {
+    // Announcements are kept from configured peers and whoever the ticket puts in charge.
+    fn can_announce(&self, node_id: &NodeId) -> bool {
+        self.config.peers.contains(node_id)
+            || self.ticket.owner == Some(*node_id)
+            || self.ticket.moderators.contains(node_id)
+    }
+
+    // Knock rooms only let in those the owner said yes to. Nobody else gets into `users`,
+    // so they get no history either.
+    fn is_admitted(&self, node_id: &NodeId) -> bool {
+        !self.ticket.knock
+            || *node_id == self.endpoint.node_id()
+            || self.ticket.owner == Some(*node_id)
+            || self.ticket.moderators.contains(node_id)
+            || self.config.peers.contains(node_id)
+            || self.admitted.contains(node_id)
+    }
+
+    fn is_allowed(&self, node_id: &NodeId) -> bool {
+        self.config.allowed.is_empty()
+            || self.config.allowed.contains(node_id)
+            || *node_id == self.endpoint.node_id()
+    }
+
+    async fn broadcast(&self, message: Message) -> Result<()> {
+        let frame = message.to_signed_vec(self.endpoint.secret_key(), self.ticket.topic.as_bytes());
+        #[cfg(feature = "chaos")]
+        let copies = messaging::chaos::copies().await;
+        #[cfg(not(feature = "chaos"))]
+        let copies = 1;
+        for _ in 0..copies {
+            self.sender.broadcast(frame.clone().into()).await?;
+        }
+        Ok(())
+    }
+
+    async fn announce(&self) -> Result<()> {
+        self.broadcast(Message::new(MessageBody::AboutMe {
+            from: self.endpoint.node_id(),
+            name: "Server".to_string(),
+            client: ClientInfo {
+                version: SERVER_VERSION.to_string(),
+                capabilities: Capabilities::HISTORY.union(Capabilities::COMPACT),
+                protocol: PROTOCOL_VERSION,
+            },
+            // As a history node in someone else's room we show the invite we joined with.
+            invite: self.ticket.invite.filter(|_| !self.is_owner()),
+        }))
+        .await
+    }
+
+    async fn handle_message(&mut self, message: Message) {
+        if !self.seen.admit(&message) {
+            return;
+        }
+        // Gossip passes on messages from people who connected to someone else, so the
+        // allowlist is checked again here and not just on incoming connections.
+        if let Some(from) = message.body.sender()
+            && !self.is_allowed(&from)
+        {
+            println!(
+                "Dropped a message from {}, who isn't allowed",
+                from.fmt_short()
+            );
+            return;
+        }
+        if let Some(from) = message.body.sender()
+            && self.config.banned.contains(&from)
+        {
+            // Someone banned coming back gets told again, so the room drops them too.
+            if let MessageBody::AboutMe { .. } = message.body
+                && self.room_owner == Some(self.endpoint.node_id())
+                && let Err(e) = self
+                    .broadcast(Message::new(MessageBody::Ban {
+                        from: self.endpoint.node_id(),
+                        target: from,
+                    }))
+                    .await
+            {
+                eprintln!("Failed to repeat ban: {}", e);
+            }
+            return;
+        }
+        if let Some(from) = message.body.sender()
+            && !self.is_admitted(&from)
+        {
+            println!("Ignored {}, who wasn't let in", from.fmt_short());
+            return;
+        }
+        let users = &self.users;
+        match message.body {
+            MessageBody::AboutMe {
+                from,
+                name,
+                invite,
+                client,
+                ..
+            } => {
+                let is_new_user = !users.lock().unwrap().contains_key(&from);
+                let problem = self
+                    .invite_problem(&from, invite.as_ref())
+                    .or_else(|| self.capacity_problem(&from));
+                if is_new_user && let Some(problem) = problem {
+                    if self.is_owner() {
+                        self.turn_away(from, &name, invite, problem).await;
+                    } else {
+                        // Only the owner's kicks count, but nobody over the limit gets
+                        // our history either.
+                        println!(
+                            "Ignored {} ({}) who joined with {}",
+                            name,
+                            from.fmt_short(),
+                            problem
+                        );
+                    }
+                    return;
+                }
+                users.lock().unwrap().insert(from, name.clone());
+                if client.capabilities.contains(Capabilities::ACKS) {
+                    self.direct_acks.insert(from);
+                } else {
+                    self.direct_acks.remove(&from);
+                }
+                if self.config.peers.contains(&from)
+                    && from != self.endpoint.node_id()
+                    && self.history_peers.insert(from)
+                {
+                    println!("History node {} joined the room", from.fmt_short());
+                    self.fetch_archive(from);
+                }
+
+                if is_new_user {
+                    if let Err(e) = self.announce().await {
+                        eprintln!("Failed to announce server: {}", e);
+                    }
+                    println!(
+                        "{} joined the room ({})",
+                        name,
+                        from.to_string().chars().take(8).collect::<String>()
+                    );
+
+                    let user_count = users.lock().unwrap().len();
+                    println!("{} users online", user_count);
+                }
+            }
+            body @ (MessageBody::Message { .. } | MessageBody::Action { .. }) => {
+                let (from, text, id, seq, action) = match body {
+                    MessageBody::Message {
+                        from,
+                        text,
+                        id,
+                        seq,
+                    } => (from, text, id, seq, false),
+                    MessageBody::Action { from, text, id } => (from, text, id, 0, true),
+                    _ => unreachable!(),
+                };
+                // In an announcement channel only the moderators' posts are kept.
+                if !self.ticket.can_post(&from) {
+                    println!(
+                        "Dropped a post from {}, who can't post here",
+                        from.fmt_short()
+                    );
+                    return;
+                }
+                let content_id = message_id(&from, seq, id, &text);
+                let sender_name = users
+                    .lock()
+                    .unwrap()
+                    .get(&from)
+                    .cloned()
+                    .unwrap_or_else(|| {
+                        format!(
+                            "User-{}",
+                            from.to_string().chars().take(8).collect::<String>()
+                        )
+                    });
+
+                let timestamp = Utc::now();
+                if action {
+                    println!("[{}] * {} {}", local_time(timestamp), sender_name, text);
+                } else {
+                    println!("[{}] {}: {}", local_time(timestamp), sender_name, text);
+                }
+
+                let stored_message = StoredMessage {
+                    from,
+                    sender_name: sender_name.clone(),
+                    text: text.clone(),
+                    timestamp,
+                    id: Some(content_id),
+                    action,
+                };
+
+                self.store(stored_message);
+
+                if id != 0 {
+                    self.ack(from, id).await;
+                }
+            }
+            MessageBody::Announcement { from, text, id } if self.can_announce(&from) => {
+                let timestamp = Utc::now();
+                println!("[{}] 📢 {}", local_time(timestamp), text);
+                self.store(StoredMessage {
+                    from,
+                    sender_name: "Server".to_string(),
+                    text: text.clone(),
+                    timestamp,
+                    id: Some(message_id(&from, 0, id, &text)),
+                    action: false,
+                });
+            }
+            MessageBody::JoinReply {
+                from,
+                to,
+                verdict: JoinVerdict::Admitted,
+            } if self.ticket.owner == Some(from) => {
+                self.admitted.insert(to);
+            }
+            MessageBody::Kick { from, target } | MessageBody::Ban { from, target }
+                if self.ticket.owner == Some(from) =>
+            {
+                // Let in once isn't let in again after the owner threw them out.
+                self.admitted.remove(&target);
+                if users.lock().unwrap().remove(&target).is_some() {
+                    println!("{} was removed by the room owner", target.fmt_short());
+                }
+            }
+            MessageBody::Disconnect { from, name } => {
+                users.lock().unwrap().remove(&from);
+                self.direct_acks.remove(&from);
+                println!("{} left the room", name);
+
+                let user_count = users.lock().unwrap().len();
+                println!("{} users online", user_count);
+            }
+            // Older servers still gossip their archive whenever someone joins.
+            MessageBody::MessageHistory {
+                from: Some(from),
+                messages,
+                ..
+            } if self.config.peers.contains(&from) => self.merge_archive(from, messages),
+            MessageBody::MessageHistory { .. } | MessageBody::HistoryRequest { .. } => {
+                // Archives from anyone who isn't a configured peer; requests only come in
+                // over HISTORY_ALPN
+            }
+            MessageBody::CallRequest { .. }
+            | MessageBody::CallRinging { .. }
+            | MessageBody::CallAccept { .. }
+            | MessageBody::CallHangUp { .. }
+            | MessageBody::ScreenShareStarted { .. }
+            | MessageBody::ScreenShareStopped { .. }
+            | MessageBody::RoomInvite { .. }
+            | MessageBody::Ack { .. }
+            | MessageBody::RoomInfo { .. }
+            | MessageBody::JoinRequest { .. }
+            | MessageBody::KnockRequest { .. }
+            | MessageBody::ResendRequest { .. }
+            | MessageBody::Resend { .. }
+            | MessageBody::Presence { .. }
+            | MessageBody::JoinReply { .. }
+            | MessageBody::Kick { .. }
+            | MessageBody::Ban { .. }
+            | MessageBody::Announcement { .. }
+            | MessageBody::RevokeInvite { .. }
+            | MessageBody::Image { .. }
+            | MessageBody::File { .. }
+            | MessageBody::Cover { .. }
+            | MessageBody::Unknown => {
+                // Signaling, presence, moderation and room metadata, nothing to log or store
+                // Images and files are fetched from whoever posted them, so there's nothing to keep.
+            }
+        }
+    }
+
+    // Tells the author we have its message, straight back to it when its client takes
+    // that, so the room doesn't carry an ack per message.
+    async fn ack(&self, to: iroh::NodeId, id: u64) {
+        if !self.direct_acks.contains(&to) {
+            let ack = Message::new(MessageBody::Ack {
+                from: self.endpoint.node_id(),
+                to,
+                id,
+            });
+            if let Err(e) = self.broadcast(ack).await {
+                eprintln!("Failed to send ack: {}", e);
+            }
+            return;
+        }
+        let endpoint = self.endpoint.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(ACK_SEND_TIMEOUT, send_acks(&endpoint, to, &[id])).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => eprintln!("Failed to send ack to {}: {}", to.fmt_short(), e),
+                Err(_) => eprintln!("Timed out sending ack to {}", to.fmt_short()),
+            }
+        });
+    }
+
+    // Pulls another history node's archive over a direct connection.
+    fn fetch_archive(&self, from: iroh::NodeId) {
+        let endpoint = self.endpoint.clone();
+        let archives = self.archives.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(
+                HISTORY_FETCH_TIMEOUT,
+                fetch_history(&endpoint, from, |_, _| {}),
+            )
+            .await
+            {
+                Ok(Ok(messages)) => {
+                    let _ = archives.send((from, messages));
+                }
+                Ok(Err(e)) => eprintln!("Failed to fetch history from {}: {}", from.fmt_short(), e),
+                Err(_) => eprintln!("Timed out fetching history from {}", from.fmt_short()),
+            }
+        });
+    }
+
+    fn merge_archive(&mut self, from: iroh::NodeId, messages: Vec<StoredMessage>) {
+        if self.ticket.incognito {
+            return;
+        }
+        for id in messages.iter().filter_map(|message| message.id) {
+            self.seen.insert(id);
+        }
+        let mut history = self.message_history.lock().unwrap();
+        let added = cluster::merge_history(&mut history, messages);
+        if added > 0 {
+            println!(
+                "Merged {} messages from history node {}",
+                added,
+                from.fmt_short()
+            );
+            *self.search.lock().unwrap() = SearchIndex::build(&history);
+            if let Err(e) = save_message_history(&history) {
+                eprintln!("Failed to save message history: {}", e);
+            }
+        }
+    }
+
+    // Incognito rooms are relayed and logged to the console, but nothing is kept.
+    fn store(&self, stored_message: StoredMessage) {
+        if self.ticket.incognito {
+            return;
+        }
+        let mut history = self.message_history.lock().unwrap();
+        self.search
+            .lock()
+            .unwrap()
+            .add(history.len(), &stored_message);
+        history.push(stored_message);
+
+        if let Err(e) = save_message_history(&history) {
+            eprintln!("Failed to save message history: {}", e);
+        }
+    }
+
+    // Sends an operator notice to the room. It's stored like a chat message so people
+    // joining later still see it in the history.
+    async fn announce_text(&self, text: String) -> Result<()> {
+        let from = self.endpoint.node_id();
+        let id = rand::random::<u64>().max(1);
+        let timestamp = Utc::now();
+        println!("[{}] 📢 {}", local_time(timestamp), text);
+        self.store(StoredMessage {
+            from,
+            sender_name: "Server".to_string(),
+            text: text.clone(),
+            timestamp,
+            id: Some(message_id(&from, 0, id, &text)),
+            action: false,
+        });
+        self.broadcast(Message::new(MessageBody::Announcement { from, text, id }))
+            .await
+    }
+
+    // Finds someone in the room by name, node id, or the start of their node id.
+    fn find_user(&self, query: &str) -> Result<(iroh::NodeId, String), String> {
+        let users = self.users.lock().unwrap();
+        let matches: Vec<_> = users
+            .iter()
+            .filter(|(id, name)| {
+                **id != self.endpoint.node_id()
+                    && (name.eq_ignore_ascii_case(query) || id.to_string().starts_with(query))
+            })
+            .map(|(id, name)| (*id, name.clone()))
+            .collect();
+        match matches.as_slice() {
+            [found] => Ok(found.clone()),
+            [] => match iroh::NodeId::from_str(query) {
+                Ok(id) => Ok((id, id.fmt_short())),
+                Err(_) => Err(format!("Nobody called {} is in the room", query)),
+            },
+            _ => Err(format!(
+                "{} matches {} people, use more of their node id",
+                query,
+                matches.len()
+            )),
+        }
+    }
+
+    fn is_owner(&self) -> bool {
+        self.room_owner == Some(self.endpoint.node_id())
+    }
+
+    // Why a newcomer can't join with `invite`, or None if they can. Only the owner's
+    // server checks, since it's the one that signed the invites.
+    fn invite_problem(&self, from: &iroh::NodeId, invite: Option<&Invite>) -> Option<&'static str> {
+        let me = self.endpoint.node_id();
+        if !self.is_owner() || *from == me {
+            return None;
+        }
+        match invite {
+            None => Some("no invite"),
+            Some(invite) if !invite.verify(&me, self.ticket.topic.as_bytes()) => {
+                Some("a forged invite")
+            }
+            Some(invite) if self.config.revoked_invites.contains(&invite.id) => {
+                Some("a revoked invite")
+            }
+            Some(_) => None,
+        }
+    }
+
+    // Why a newcomer doesn't fit in the room, or None if there's still a seat.
+    fn capacity_problem(&self, from: &iroh::NodeId) -> Option<&'static str> {
+        let capacity = self.ticket.capacity?;
+        let users = self.users.lock().unwrap();
+        (!users.contains_key(from) && users.len() >= capacity as usize)
+            .then_some("the room already full")
+    }
+
+    // Asks a newcomer we can't let in to leave, and repeats an invite's revocation for
+    // clients that joined after it went out.
+    async fn turn_away(
+        &self,
+        target: iroh::NodeId,
+        name: &str,
+        invite: Option<Invite>,
+        problem: &str,
+    ) {
+        println!(
+            "Turned away {} ({}) who joined with {}",
+            name,
+            target.fmt_short(),
+            problem
+        );
+        let from = self.endpoint.node_id();
+        let mut bodies = Vec::new();
+        if let Some(invite) =
+            invite.filter(|invite| self.config.revoked_invites.contains(&invite.id))
+        {
+            bodies.push(MessageBody::RevokeInvite {
+                from,
+                invite: invite.id,
+            });
+        }
+        bodies.push(MessageBody::Kick { from, target });
+        for body in bodies {
+            if let Err(e) = self.broadcast(Message::new(body)).await {
+                eprintln!("Failed to turn away {}: {}", name, e);
+            }
+        }
+    }
+
+    // Prints a ticket with a fresh invite. Invites handed out before keep working until
+    // they're revoked.
+    fn new_invite(&mut self) {
+        self.ticket.invite = Some(Invite::new(
+            self.endpoint.secret_key(),
+            self.ticket.topic.as_bytes(),
+        ));
+        print_ticket(&self.ticket);
+    }
+
+    async fn revoke_invite(&mut self, invite: u64) {
+        if !self.config.revoked_invites.contains(&invite) {
+            self.config.revoked_invites.push(invite);
+            if let Err(e) = self.config.save() {
+                eprintln!("Failed to save server config: {}", e);
+            }
+        }
+        let body = MessageBody::RevokeInvite {
+            from: self.endpoint.node_id(),
+            invite,
+        };
+        if let Err(e) = self.broadcast(Message::new(body)).await {
+            eprintln!("Failed to send to the room: {}", e);
+        }
+        println!("Revoked invite {:016x}", invite);
+    }
+
+    async fn moderate(&mut self, query: &str, ban: bool) {
+        if self.room_owner != Some(self.endpoint.node_id()) {
+            println!("Only the server that opened the room can kick or ban people.");
+            return;
+        }
+        let (target, name) = match self.find_user(query) {
+            Ok(found) => found,
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        };
+        let from = self.endpoint.node_id();
+        let body = if ban {
+            if !self.config.banned.contains(&target) {
+                self.config.banned.push(target);
+                if let Err(e) = self.config.save() {
+                    eprintln!("Failed to save server config: {}", e);
+                }
+            }
+            MessageBody::Ban { from, target }
+        } else {
+            MessageBody::Kick { from, target }
+        };
+        if let Err(e) = self.broadcast(Message::new(body)).await {
+            eprintln!("Failed to send to the room: {}", e);
+            return;
+        }
+        self.users.lock().unwrap().remove(&target);
+        println!(
+            "{} {} ({})",
+            if ban { "Banned" } else { "Kicked" },
+            name,
+            target.fmt_short()
+        );
+    }
+
+    // Returns false when the operator asked the server to stop.
+    async fn handle_command(&mut self, line: &str) -> bool {
+        let (command, arg) = line
+            .split_once(' ')
+            .map_or((line, ""), |(command, arg)| (command, arg.trim()));
+        match (command, arg) {
+            ("", _) => {}
+            // Anything that isn't a command goes out to the room as an announcement.
+            _ if !line.starts_with('/') => {
+                if let Err(e) = self.announce_text(line.to_string()).await {
+                    eprintln!("Failed to send announcement: {}", e);
+                }
+            }
+            ("/help", _) => {
+                println!("<text>           announce something to the room");
+                println!("/users           list who is in the room");
+                println!("/kick <who>      ask someone to leave, by name or node id");
+                println!("/ban <who>       kick someone and keep them out");
+                println!("/unban <id>      let a banned node id back in");
+                println!("/announce <text> the same, for text starting with a slash");
+                println!("/invite          print a ticket with a new invite");
+                println!(
+                    "/revoke [invite] revoke an invite, or the current ticket's and print a new one"
+                );
+                println!("/clear-history   delete every stored message");
+                println!("/schedule        list announcements the server posts by itself");
+                println!(
+                    "/schedule add <minute> <hour> <day> <month> <weekday> <text>  post text on a cron schedule"
+                );
+                println!("/schedule remove <n>  drop a scheduled announcement");
+                println!("/history         show how many messages are stored");
+                println!("/search <words>  find stored messages containing all the words");
+                println!(
+                    "/retention [forever|<days>d|<messages>]  show or set how long history is kept"
+                );
+                println!("/backup <path>   save history, identity and config to one file");
+                println!("/restore <path>  load a backup; restart to run as its identity");
+                println!("/quit            leave the room and stop the server");
+            }
+            ("/users", _) => {
+                let users = self.users.lock().unwrap();
+                println!("{} users online", users.len());
+                for (id, name) in users.iter() {
+                    println!("  {} ({})", name, id.fmt_short());
+                }
+            }
+            ("/history", _) => {
+                println!(
+                    "{} messages in history",
+                    self.message_history.lock().unwrap().len()
+                );
+            }
+            ("/kick" | "/ban" | "/unban", "") => println!("Usage: {} <who>", command),
+            ("/kick", who) => self.moderate(who, false).await,
+            ("/ban", who) => self.moderate(who, true).await,
+            ("/unban", who) => {
+                let before = self.config.banned.len();
+                self.config
+                    .banned
+                    .retain(|id| !id.to_string().starts_with(who));
+                if self.config.banned.len() == before {
+                    println!("Nobody banned matches {}", who);
+                } else if let Err(e) = self.config.save() {
+                    eprintln!("Failed to save server config: {}", e);
+                } else {
+                    println!("Unbanned {}", who);
+                }
+            }
+            ("/invite" | "/revoke", _) if !self.is_owner() => {
+                println!("Only the server that opened the room hands out invites.");
+            }
+            ("/invite", _) => self.new_invite(),
+            ("/revoke", "") => {
+                if let Some(invite) = self.ticket.invite {
+                    self.revoke_invite(invite.id).await;
+                }
+                self.new_invite();
+            }
+            ("/revoke", invite) => match u64::from_str_radix(invite, 16) {
+                Ok(invite) => self.revoke_invite(invite).await,
+                Err(_) => println!("Usage: /revoke [invite], with the invite in hex as printed"),
+            },
+            ("/announce", "") => println!("Usage: /announce <text>"),
+            ("/announce", text) => {
+                if let Err(e) = self.announce_text(text.to_string()).await {
+                    eprintln!("Failed to send announcement: {}", e);
+                }
+            }
+            ("/clear-history", _) => {
+                {
+                    let mut history = self.message_history.lock().unwrap();
+                    let count = history.len();
+                    history.clear();
+                    *self.search.lock().unwrap() = SearchIndex::default();
+                    if let Err(e) = save_message_history(&history) {
+                        eprintln!("Failed to save message history: {}", e);
+                    }
+                    println!("Deleted {} stored messages", count);
+                }
+                if let Err(e) = self
+                    .announce_text("The room's stored history was cleared.".to_string())
+                    .await
+                {
+                    eprintln!("Failed to tell the room: {}", e);
+                }
+            }
+            ("/schedule", "") => {
+                if self.config.schedule.is_empty() {
+                    println!("Nothing scheduled");
+                }
+                for (n, scheduled) in self.config.schedule.iter().enumerate() {
+                    println!("  {}. [{}] {}", n + 1, scheduled.when, scheduled.text);
+                }
+            }
+            ("/schedule", arg) => {
+                let changed = match arg.split_once(' ').unwrap_or((arg, "")) {
+                    ("add", entry) => match entry.parse::<schedule::Scheduled>() {
+                        Ok(scheduled) => {
+                            println!("Scheduled [{}] {}", scheduled.when, scheduled.text);
+                            self.config.schedule.push(scheduled);
+                            true
                         }
-                        MessageBody::MessageHistory { .. } => {
-                            // Server doesn't need to process history messages sent by itself
+                        Err(e) => {
+                            println!("{}", e);
+                            false
                         }
+                    },
+                    ("remove", n) => match n.trim().parse::<usize>() {
+                        Ok(n) if (1..=self.config.schedule.len()).contains(&n) => {
+                            let scheduled = self.config.schedule.remove(n - 1);
+                            println!("Removed [{}] {}", scheduled.when, scheduled.text);
+                            true
+                        }
+                        _ => {
+                            println!("No scheduled announcement {}, see /schedule", n);
+                            false
+                        }
+                    },
+                    _ => {
+                        println!("Usage: /schedule [add <schedule> <text> | remove <n>]");
+                        false
+                    }
+                };
+                if changed && let Err(e) = self.config.save() {
+                    eprintln!("Failed to save server config: {}", e);
+                }
+            }
+            ("/retention", "") => println!("History is kept {}", self.config.retention),
+            ("/retention", policy) => match policy.parse() {
+                Ok(retention) => {
+                    self.config.retention = retention;
+                    if let Err(e) = self.config.save() {
+                        eprintln!("Failed to save server config: {}", e);
                     }
+                    println!("History is now kept {}", retention);
+                    self.prune();
+                }
+                Err(e) => println!("{}", e),
+            },
+            ("/search", "") => println!("Usage: /search <words>"),
+            ("/search", query) => {
+                let history = self.message_history.lock().unwrap();
+                search::print_hits(&self.search.lock().unwrap().search(&history, query));
+            }
+            ("/backup" | "/restore", "") => println!("Usage: {} <path>", command),
+            ("/backup", path) => {
+                let history = self.message_history.lock().unwrap();
+                match backup::write_backup(
+                    Path::new(path),
+                    self.endpoint.secret_key(),
+                    &self.config,
+                    &history,
+                ) {
+                    Ok(()) => println!(
+                        "Backed up {} messages to {}. The file holds the server's secret key, keep it safe.",
+                        history.len(),
+                        path
+                    ),
+                    Err(e) => eprintln!("Backup failed: {:#}", e),
                 }
             }
+            ("/restore", path) => match backup::restore_backup(Path::new(path)) {
+                Ok(history) => {
+                    if let Err(e) = save_message_history(&history) {
+                        eprintln!("Failed to save message history: {}", e);
+                    }
+                    let mut current = self.message_history.lock().unwrap();
+                    *self.search.lock().unwrap() = SearchIndex::build(&history);
+                    *current = history;
+                    println!("Restart the server to take over the restored identity and room.");
+                }
+                Err(e) => eprintln!("Restore failed: {:#}", e),
+            },
+            ("/quit", _) => {
+                println!("Shutting down...");
+                return false;
+            }
+            (other, _) => println!("Unknown command {}, try /help", other),
+        }
+        true
+    }
+
+    async fn run_schedule(&mut self) {
+        let now = chrono::Local::now().naive_local();
+        let Some(minute) = now.with_second(0).and_then(|now| now.with_nanosecond(0)) else {
+            return;
+        };
+        if self.last_scheduled.replace(minute) == Some(minute) {
+            return;
         }
+        let due: Vec<String> = self
+            .config
+            .schedule
+            .iter()
+            .filter(|scheduled| scheduled.when.matches(minute))
+            .map(|scheduled| scheduled.text.clone())
+            .collect();
+        for text in due {
+            if let Err(e) = self.announce_text(text).await {
+                eprintln!("Failed to send scheduled announcement: {}", e);
+            }
+        }
+    }
 
-        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    fn update_metrics(&self) {
+        let users = self.users.lock().unwrap().len();
+        let stored = self.message_history.lock().unwrap().len();
+        self.metrics.users_online.set(users as i64);
+        self.metrics.stored_messages.set(stored as i64);
+        self.metrics
+            .history_peers
+            .set(self.history_peers.len() as i64);
     }
+
+    fn prune(&self) {
+        let mut history = self.message_history.lock().unwrap();
+        let pruned = self.config.retention.prune(&mut history);
+        if pruned == 0 {
+            return;
+        }
+        println!(
+            "Pruned {} messages past the {} retention",
+            pruned, self.config.retention
+        );
+        *self.search.lock().unwrap() = SearchIndex::build(&history);
+        if let Err(e) = save_message_history(&history) {
+            eprintln!("Failed to save message history: {}", e);
+        }
+    }
+
+    async fn shutdown(&self) {
+        let goodbye = Message::new(MessageBody::Disconnect {
+            from: self.endpoint.node_id(),
+            name: "Server".to_string(),
+        });
+        if let Err(e) = self.broadcast(goodbye).await {
+            eprintln!("Failed to announce shutdown: {}", e);
+        }
+        if let Err(e) = save_message_history(&self.message_history.lock().unwrap()) {
+            eprintln!("Failed to save message history: {}", e);
+        }
+    }
+}
+
+// Prints the public addresses the relays or the router's port mapping found for us, so
+// the operator knows whether the server can be reached directly.
+async fn print_reachable_at(
+    mut updates: impl futures_lite::Stream<Item = Option<BTreeSet<DirectAddr>>> + Unpin,
+) {
+    let mut shown = String::new();
+    while let Some(addrs) = updates.next().await {
+        let external = addrs
+            .iter()
+            .flatten()
+            .filter(|addr| {
+                matches!(
+                    addr.typ,
+                    DirectAddrType::Qad
+                        | DirectAddrType::Portmapped
+                        | DirectAddrType::Qad4LocalPort
+                )
+            })
+            .map(|addr| addr.addr.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        if external != shown && !external.is_empty() {
+            println!("Reachable at: {}", external);
+        }
+        shown = external;
+    }
+}
+
+// Stored times are UTC; the console shows them in the server's own timezone.
+pub fn local_time(at: DateTime<Utc>) -> String {
+    at.with_timezone(&Local)
+        .format("%d/%m/%Y %H:%M:%S")
+        .to_string()
 }
 
 fn load_message_history() -> Vec<StoredMessage> {
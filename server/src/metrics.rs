@@ -0,0 +1,69 @@
+use anyhow::Result;
+use iroh_metrics::{Gauge, MetricsGroup, Registry, encoding::encode_openmetrics_eof};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+use crate::http;
+
+// Set to an address like 127.0.0.1:9100 to serve GET /metrics for Prometheus.
+pub const METRICS_ADDR_VAR: &str = "CHAST_METRICS_ADDR";
+
+/// The room as this server sees it.
+#[derive(Debug, Default, MetricsGroup)]
+#[metrics(name = "room")]
+pub struct RoomMetrics {
+    /// People in the room, the server included
+    pub users_online: Gauge,
+    /// Messages in the stored history
+    pub stored_messages: Gauge,
+    /// Peers this server exchanges gossip with directly
+    pub gossip_neighbors: Gauge,
+    /// Other history nodes merging archives with this one
+    pub history_peers: Gauge,
+}
+
+// The registry holds the endpoint's connection metrics, gossip traffic and the room
+// gauges above, all encoded in the OpenMetrics text format.
+pub async fn serve(addr: String, registry: Arc<Registry>) -> Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    println!("Metrics available at http://{}/metrics", addr);
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            let result = match http::read_get_target(&mut stream).await {
+                Ok(target) if target == "/metrics" => {
+                    let mut body = String::new();
+                    let encoded = registry
+                        .encode_openmetrics_to_writer(&mut body)
+                        .and_then(|()| encode_openmetrics_eof(&mut body));
+                    match encoded {
+                        Ok(()) => {
+                            http::respond(
+                                stream,
+                                "200 OK",
+                                "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                                &body,
+                            )
+                            .await
+                        }
+                        Err(e) => {
+                            http::respond(
+                                stream,
+                                "500 Internal Server Error",
+                                "text/plain",
+                                &e.to_string(),
+                            )
+                            .await
+                        }
+                    }
+                }
+                Ok(_) => http::respond(stream, "404 Not Found", "text/plain", "use /metrics").await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                eprintln!("Metrics request failed: {}", e);
+            }
+        });
+    }
+}
@@ -0,0 +1,73 @@
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, TimeDelta, Utc};
+use messaging::StoredMessage;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+use tokio::time::Duration;
+
+pub const PRUNE_EVERY: Duration = Duration::from_secs(60 * 60);
+
+// How long this room's history is kept. Lives in the server config, so each room a
+// server runs carries its own policy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Retention {
+    #[default]
+    Forever,
+    Days(u32),
+    Messages(usize),
+}
+
+impl Retention {
+    // Drops whatever the policy no longer covers. Returns how many messages went.
+    pub fn prune(self, history: &mut Vec<StoredMessage>) -> usize {
+        let before = history.len();
+        match self {
+            Retention::Forever => {}
+            Retention::Days(days) => {
+                let cutoff = Utc::now() - TimeDelta::days(days.into());
+                // Entries whose timestamp couldn't be read load as the epoch; they're kept
+                // rather than guessed at.
+                history.retain(|message| {
+                    message.timestamp == DateTime::UNIX_EPOCH || message.timestamp >= cutoff
+                });
+            }
+            Retention::Messages(limit) => {
+                let excess = history.len().saturating_sub(limit);
+                history.drain(..excess);
+            }
+        }
+        before - history.len()
+    }
+}
+
+impl fmt::Display for Retention {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Retention::Forever => write!(f, "forever"),
+            Retention::Days(days) => write!(f, "{}d", days),
+            Retention::Messages(limit) => write!(f, "{}", limit),
+        }
+    }
+}
+
+// `forever`, `30d` for a number of days, or a bare number of messages.
+impl FromStr for Retention {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s == "forever" {
+            return Ok(Retention::Forever);
+        }
+        let invalid = || anyhow!("Expected forever, <days>d or <messages>, got {}", s);
+        let retention = match s.strip_suffix('d') {
+            Some(days) => Retention::Days(days.parse().map_err(|_| invalid())?),
+            None => Retention::Messages(s.parse().map_err(|_| invalid())?),
+        };
+        match retention {
+            Retention::Days(0) | Retention::Messages(0) => Err(invalid()),
+            retention => Ok(retention),
+        }
+    }
+}
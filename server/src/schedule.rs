@@ -0,0 +1,258 @@
+use anyhow::{Result, anyhow, bail};
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+use tokio::time::Duration;
+
+// Well under a minute, so no minute is skipped even when a tick runs late.
+pub const CHECK_EVERY: Duration = Duration::from_secs(20);
+
+// An announcement the server posts on its own whenever `when` matches the local time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scheduled {
+    pub when: Cron,
+    pub text: String,
+}
+
+// The usual five cron fields: minute, hour, day of month, month and day of week
+// (0 or 7 is Sunday). Each field takes `*`, numbers, ranges like `1-5`, lists like
+// `1,15` and steps like `*/10`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cron {
+    source: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // Cron matches either day field when both are restricted, not both of them. A field
+    // counts as unrestricted when it covers its whole range, however it's written.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+// Every value from `min` to `max`.
+fn full_range(min: u32, max: u32) -> u64 {
+    (min..=max).fold(0, |bits, value| bits | 1 << value)
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let mut bits = 0u64;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>()?),
+            None => (item, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (start.parse()?, end.parse()?),
+                None => {
+                    let value = range.parse()?;
+                    // `5/15` means from 5 to the end in steps of 15.
+                    (value, if item.contains('/') { max } else { value })
+                }
+            },
+        };
+        if step == 0 || start < min || end > max || start > end {
+            bail!("{} is out of range {}-{}", item, min, max);
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl Cron {
+    pub fn matches(&self, at: NaiveDateTime) -> bool {
+        let day = self.days & (1 << at.day()) != 0;
+        let weekday = self.weekdays & (1 << at.weekday().num_days_from_sunday()) != 0;
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        self.minutes & (1 << at.minute()) != 0
+            && self.hours & (1 << at.hour()) != 0
+            && self.months & (1 << at.month()) != 0
+            && day_matches
+    }
+}
+
+impl FromStr for Cron {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            bail!(
+                "Expected 5 fields (minute hour day month weekday), got {}",
+                s
+            );
+        };
+        let invalid = |e: anyhow::Error| anyhow!("Invalid schedule {}: {}", s, e);
+        let mut weekdays = parse_field(weekday, 0, 7).map_err(invalid)?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        let days = parse_field(day, 1, 31).map_err(invalid)?;
+        Ok(Cron {
+            source: fields.join(" "),
+            minutes: parse_field(minute, 0, 59).map_err(invalid)?,
+            hours: parse_field(hour, 0, 23).map_err(invalid)?,
+            days,
+            months: parse_field(month, 1, 12).map_err(invalid)?,
+            weekdays,
+            any_day: days == full_range(1, 31),
+            any_weekday: weekdays & full_range(0, 6) == full_range(0, 6),
+        })
+    }
+}
+
+impl TryFrom<String> for Cron {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Cron> for String {
+    fn from(cron: Cron) -> Self {
+        cron.source
+    }
+}
+
+impl fmt::Display for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+// `<minute> <hour> <day> <month> <weekday> <text>`, as typed after /schedule add.
+impl FromStr for Scheduled {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut rest = s.trim_start();
+        for _ in 0..5 {
+            rest = rest
+                .split_once(char::is_whitespace)
+                .map(|(_, rest)| rest.trim_start())
+                .ok_or_else(|| anyhow!("Expected a schedule followed by the text to post"))?;
+        }
+        let when = s[..s.len() - rest.len()].parse()?;
+        if rest.is_empty() {
+            bail!("Expected the text to post after the schedule");
+        }
+        Ok(Scheduled {
+            when,
+            text: rest.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    // 2025-06-02 is a Monday.
+    fn at(month: u32, day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2025, month, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    fn values(bits: u64) -> Vec<u32> {
+        (0..64).filter(|value| bits & (1 << value) != 0).collect()
+    }
+
+    #[test]
+    fn fields_parse() {
+        let cases: &[(&str, u32, u32, &[u32])] = &[
+            ("*", 0, 5, &[0, 1, 2, 3, 4, 5]),
+            ("3", 0, 59, &[3]),
+            ("1-4", 0, 59, &[1, 2, 3, 4]),
+            ("1,15,30", 0, 59, &[1, 15, 30]),
+            ("*/15", 0, 59, &[0, 15, 30, 45]),
+            ("5/20", 0, 59, &[5, 25, 45]),
+            ("10-20/5", 0, 59, &[10, 15, 20]),
+            ("1-3,10,20-30/5", 0, 59, &[1, 2, 3, 10, 20, 25, 30]),
+            ("*/5", 1, 12, &[1, 6, 11]),
+        ];
+        for (field, min, max, expected) in cases {
+            let bits = parse_field(field, *min, *max).unwrap();
+            assert_eq!(values(bits), *expected, "{}", field);
+        }
+    }
+
+    #[test]
+    fn invalid_schedules_are_refused() {
+        for source in [
+            "",
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * 32 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "1,,2 * * * *",
+            "a * * * *",
+            "-1 * * * *",
+            "1-2-3 * * * *",
+        ] {
+            assert!(source.parse::<Cron>().is_err(), "{:?} parsed", source);
+        }
+    }
+
+    #[test]
+    fn schedules_match() {
+        let cases = [
+            // Every weekday at 9:30.
+            ("30 9 * * 1-5", at(6, 2, 9, 30), true),
+            ("30 9 * * 1-5", at(6, 1, 9, 30), false),
+            ("30 9 * * 1-5", at(6, 2, 9, 31), false),
+            // Every quarter of an hour.
+            ("*/15 * * * *", at(6, 2, 13, 45), true),
+            ("*/15 * * * *", at(6, 2, 13, 46), false),
+            // Sunday is 0 and 7.
+            ("0 12 * * 7", at(6, 1, 12, 0), true),
+            ("0 12 * * 0", at(6, 1, 12, 0), true),
+            // Only in June.
+            ("0 0 1 6 *", at(6, 1, 0, 0), true),
+            ("0 0 1 6 *", at(7, 1, 0, 0), false),
+            // Both day fields restricted: either one will do.
+            ("0 8 15 * 1", at(6, 15, 8, 0), true),
+            ("0 8 15 * 1", at(6, 2, 8, 0), true),
+            ("0 8 15 * 1", at(6, 3, 8, 0), false),
+            // A day field covering its whole range counts as `*`, so only the other one
+            // has to match.
+            ("0 8 1-31 * 1", at(6, 2, 8, 0), true),
+            ("0 8 1-31 * 1", at(6, 3, 8, 0), false),
+            ("0 8 15 * 0-6", at(6, 15, 8, 0), true),
+            ("0 8 15 * 0-6", at(6, 2, 8, 0), false),
+            ("0 8 15 * */1", at(6, 2, 8, 0), false),
+            ("0 8 15 * 1-7", at(6, 2, 8, 0), false),
+        ];
+        for (source, at, expected) in cases {
+            let cron: Cron = source.parse().unwrap();
+            assert_eq!(cron.matches(at), expected, "{} at {}", source, at);
+        }
+    }
+
+    #[test]
+    fn scheduled_text_follows_the_fields() {
+        let scheduled: Scheduled = "0  9 * * 1   Stand-up in five".parse().unwrap();
+        assert_eq!(scheduled.when.to_string(), "0 9 * * 1");
+        assert_eq!(scheduled.text, "Stand-up in five");
+        assert!("0 9 * * 1".parse::<Scheduled>().is_err());
+        assert!("0 9 * * 1 ".parse::<Scheduled>().is_err());
+    }
+}
@@ -0,0 +1,277 @@
+use anyhow::Result;
+use messaging::StoredMessage;
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex},
+};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{http, local_time};
+
+// Set to an address like 127.0.0.1:8080 to serve GET /search?q=... over HTTP.
+pub const SEARCH_ADDR_VAR: &str = "CHAST_SEARCH_ADDR";
+const MAX_HITS: usize = 20;
+// Messages shown either side of a hit.
+const CONTEXT: usize = 2;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchHit {
+    pub before: Vec<StoredMessage>,
+    pub message: StoredMessage,
+    pub after: Vec<StoredMessage>,
+}
+
+// Word -> positions in the history that contain it. Positions shift when history from
+// another node is merged in, so merges and restores rebuild the whole thing.
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    postings: HashMap<String, BTreeSet<usize>>,
+}
+
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+impl SearchIndex {
+    pub fn build(history: &[StoredMessage]) -> Self {
+        let mut index = Self::default();
+        for (position, message) in history.iter().enumerate() {
+            index.add(position, message);
+        }
+        index
+    }
+
+    pub fn add(&mut self, position: usize, message: &StoredMessage) {
+        for word in words(&message.text).chain(words(&message.sender_name)) {
+            self.postings.entry(word).or_default().insert(position);
+        }
+    }
+
+    // Messages containing every word of the query, newest first.
+    pub fn search(&self, history: &[StoredMessage], query: &str) -> Vec<SearchHit> {
+        let mut matches: Option<BTreeSet<usize>> = None;
+        for word in words(query) {
+            let positions = self.postings.get(&word).cloned().unwrap_or_default();
+            matches = Some(match matches {
+                Some(found) => found.intersection(&positions).copied().collect(),
+                None => positions,
+            });
+        }
+        matches
+            .unwrap_or_default()
+            .into_iter()
+            .rev()
+            .filter(|position| *position < history.len())
+            .take(MAX_HITS)
+            .map(|position| SearchHit {
+                before: history[position.saturating_sub(CONTEXT)..position].to_vec(),
+                message: history[position].clone(),
+                after: history[position + 1..(position + 1 + CONTEXT).min(history.len())].to_vec(),
+            })
+            .collect()
+    }
+}
+
+pub fn print_hits(hits: &[SearchHit]) {
+    if hits.is_empty() {
+        println!("No matches");
+        return;
+    }
+    for hit in hits.iter().rev() {
+        println!("{}", "-".repeat(60));
+        for message in &hit.before {
+            println!(
+                "  [{}] {}: {}",
+                local_time(message.timestamp),
+                message.sender_name,
+                message.text
+            );
+        }
+        println!(
+            "> [{}] {}: {}",
+            local_time(hit.message.timestamp),
+            hit.message.sender_name,
+            hit.message.text
+        );
+        for message in &hit.after {
+            println!(
+                "  [{}] {}: {}",
+                local_time(message.timestamp),
+                message.sender_name,
+                message.text
+            );
+        }
+    }
+    println!("{}", "-".repeat(60));
+    println!("{} matches", hits.len());
+}
+
+// Just enough HTTP for `curl 'http://addr/search?q=hello'`; anything else gets a 404.
+pub async fn serve(
+    addr: String,
+    history: Arc<Mutex<Vec<StoredMessage>>>,
+    index: Arc<Mutex<SearchIndex>>,
+) -> Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    println!("Search available at http://{}/search?q=...", addr);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let history = history.clone();
+        let index = index.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer(stream, &history, &index).await {
+                eprintln!("Search request failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn answer(
+    mut stream: TcpStream,
+    history: &Arc<Mutex<Vec<StoredMessage>>>,
+    index: &Arc<Mutex<SearchIndex>>,
+) -> Result<()> {
+    let target = http::read_get_target(&mut stream).await?;
+    let query = target.strip_prefix("/search?").and_then(|params| {
+        params
+            .split('&')
+            .find_map(|param| param.strip_prefix("q="))
+            .map(decode_query)
+    });
+
+    let (status, body) = match query {
+        Some(query) => {
+            let hits = {
+                let history = history.lock().unwrap();
+                index.lock().unwrap().search(&history, &query)
+            };
+            ("200 OK", serde_json::to_string(&hits)?)
+        }
+        None => (
+            "404 Not Found",
+            r#"{"error":"use /search?q=..."}"#.to_string(),
+        ),
+    };
+    http::respond(stream, status, "application/json", &body).await
+}
+
+fn decode_query(raw: &str) -> String {
+    let bytes = raw.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => match raw
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 2;
+                }
+                None => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn history(texts: &[&str]) -> Vec<StoredMessage> {
+        texts
+            .iter()
+            .map(|text| StoredMessage {
+                from: iroh::SecretKey::from_bytes(&[7; 32]).public(),
+                sender_name: "Ada".to_string(),
+                text: text.to_string(),
+                timestamp: Utc::now(),
+                id: None,
+                action: false,
+            })
+            .collect()
+    }
+
+    fn texts(hits: &[SearchHit]) -> Vec<&str> {
+        hits.iter().map(|hit| hit.message.text.as_str()).collect()
+    }
+
+    #[test]
+    fn words_split_on_punctuation_and_ignore_case() {
+        let history = history(&["Hello, WORLD!", "hello-world again", "CAFÉ au lait"]);
+        let index = SearchIndex::build(&history);
+        assert_eq!(
+            texts(&index.search(&history, "world hello")),
+            ["hello-world again", "Hello, WORLD!"]
+        );
+        assert_eq!(texts(&index.search(&history, "café")), ["CAFÉ au lait"]);
+        // Every word has to be there, not just one of them.
+        assert!(index.search(&history, "hello lait").is_empty());
+        // Parts of a word don't match.
+        assert!(index.search(&history, "hell").is_empty());
+    }
+
+    #[test]
+    fn empty_or_punctuation_only_query_finds_nothing() {
+        let history = history(&["hello"]);
+        let index = SearchIndex::build(&history);
+        assert!(index.search(&history, "").is_empty());
+        assert!(index.search(&history, "?!, ...").is_empty());
+    }
+
+    #[test]
+    fn sender_names_are_searchable() {
+        let history = history(&["hi"]);
+        let index = SearchIndex::build(&history);
+        assert_eq!(texts(&index.search(&history, "ada")), ["hi"]);
+    }
+
+    #[test]
+    fn newest_hits_come_first_and_are_capped() {
+        let many: Vec<String> = (0..MAX_HITS + 5).map(|i| format!("ping {}", i)).collect();
+        let many: Vec<&str> = many.iter().map(String::as_str).collect();
+        let history = history(&many);
+        let index = SearchIndex::build(&history);
+        let hits = index.search(&history, "ping");
+        assert_eq!(hits.len(), MAX_HITS);
+        assert_eq!(hits[0].message.text, format!("ping {}", MAX_HITS + 4));
+        assert_eq!(hits[MAX_HITS - 1].message.text, "ping 5");
+    }
+
+    #[test]
+    fn context_stops_at_the_ends_of_history() {
+        let history = history(&["first", "second", "third"]);
+        let index = SearchIndex::build(&history);
+        let hit = &index.search(&history, "first")[0];
+        assert!(hit.before.is_empty());
+        assert_eq!(hit.after.len(), 2);
+        let hit = &index.search(&history, "third")[0];
+        assert_eq!(hit.before.len(), 2);
+        assert!(hit.after.is_empty());
+    }
+
+    #[test]
+    fn positions_past_the_history_are_skipped() {
+        let long = history(&["old news", "more news"]);
+        let index = SearchIndex::build(&long);
+        // History shrank without the index being rebuilt.
+        assert_eq!(texts(&index.search(&long[..1], "news")), ["old news"]);
+    }
+
+    #[test]
+    fn query_is_url_decoded() {
+        assert_eq!(decode_query("hello+world"), "hello world");
+        assert_eq!(decode_query("caf%C3%A9"), "café");
+        assert_eq!(decode_query("100%"), "100%");
+        assert_eq!(decode_query("%zz"), "%zz");
+    }
+}
@@ -11,6 +11,7 @@ pkgs.mkShell {
     pkgs.atk
     pkgs.gdk-pixbuf
     pkgs.zlib
+    pkgs.alsa-lib
     pkgs.qt6.full
   ];
 }
@@ -1,13 +1,102 @@
-use anyhow::Result;
-use iroh::NodeAddr;
+use anyhow::{Context, Result, anyhow, bail};
+use crypto_secretbox::{KeyInit, XSalsa20Poly1305, aead::Aead};
+use image::{ImageFormat, Luma};
+use iroh::{NodeAddr, NodeId, RelayUrl};
 use iroh_gossip::proto::TopicId;
+use messaging::{Invite, RoomInfo};
+use qrcode::{QrCode, render::svg, render::unicode::Dense1x2};
 use serde::{Deserialize, Serialize};
-use std::{fmt, str::FromStr};
+use std::{fmt, io::Cursor, net::SocketAddr, str::FromStr};
+
+mod words;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// How hard scrypt works to turn a password into a key. Locking and unlocking must use
+/// the same cost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfCost {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+
+impl Default for KdfCost {
+    // Every guess needs 2^15 * 8 * 128 bytes = 32 MiB of memory and around a tenth of a
+    // second, so a password can't be tried cheaply on thousands of GPU cores.
+    fn default() -> Self {
+        Self {
+            log_n: 15,
+            r: 8,
+            p: 1,
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Ticket {
     pub topic: TopicId,
     pub nodes: Vec<NodeAddr>,
+    /// Nodes allowed to post. Empty means everyone can; otherwise the room is an
+    /// announcement channel and clients drop messages from anyone else.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub moderators: Vec<NodeId>,
+    /// The node that created the room; only its `RoomInfo` broadcasts are trusted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<NodeId>,
+    #[serde(default, skip_serializing_if = "RoomInfo::is_empty")]
+    pub info: RoomInfo,
+    /// Maximum number of members, the owner included. The owner turns away anyone
+    /// past it when they ask to join.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capacity: Option<u32>,
+    /// New members have to knock and wait for the owner to let them in.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub knock: bool,
+    /// Nobody keeps the room's messages: clients don't save them and history servers
+    /// don't store them.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub incognito: bool,
+    /// Signed by the owner. Rooms whose tickets carry one only let in newcomers with a
+    /// valid invite the owner hasn't revoked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invite: Option<Invite>,
+    /// Set on password-protected tickets: `nodes`, `moderators` and `owner`, encrypted
+    /// with the passphrase. Those fields stay empty until `unlock` fills them back in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locked: Option<String>,
+}
+
+// The fields that name a node, which is everything needed to reach the room.
+#[derive(Serialize, Deserialize)]
+struct Hidden {
+    nodes: Vec<NodeAddr>,
+    #[serde(default)]
+    moderators: Vec<NodeId>,
+    #[serde(default)]
+    owner: Option<NodeId>,
+}
+
+// The word form of a ticket, in postcard rather than JSON so it takes as few words as
+// it can. A node with a relay keeps its id and relay but not its direct addresses,
+// which are most of a ticket and get found again through the relay and discovery
+// anyway; a node without one keeps its direct addresses, since nothing else reaches it.
+// The owner is usually also a node and a moderator, so every id is written once and
+// referred to by its place in `ids`.
+#[derive(Serialize, Deserialize)]
+struct Compact {
+    topic: TopicId,
+    ids: Vec<NodeId>,
+    nodes: Vec<(usize, Option<RelayUrl>, Vec<SocketAddr>)>,
+    moderators: Vec<usize>,
+    owner: Option<usize>,
+    info: RoomInfo,
+    capacity: Option<u32>,
+    knock: bool,
+    incognito: bool,
+    invite: Option<Invite>,
+    locked: Option<String>,
 }
 
 impl Ticket {
@@ -18,6 +107,203 @@ impl Ticket {
     pub fn to_bytes(&self) -> Vec<u8> {
         serde_json::to_vec(self).expect("serde_json::to_vec is infallible")
     }
+
+    /// The ticket as dash-separated words, for reading out loud. `from_str` takes
+    /// either form. Direct addresses are left out to keep it short, unless a node has no
+    /// relay to be found through.
+    pub fn to_words(&self) -> String {
+        let mut ids = Vec::new();
+        let mut index_of = |id: NodeId| {
+            ids.iter()
+                .position(|known| *known == id)
+                .unwrap_or_else(|| {
+                    ids.push(id);
+                    ids.len() - 1
+                })
+        };
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|addr| {
+                let direct = match addr.relay_url {
+                    Some(_) => Vec::new(),
+                    None => addr.direct_addresses.iter().copied().collect(),
+                };
+                (index_of(addr.node_id), addr.relay_url.clone(), direct)
+            })
+            .collect();
+        let moderators = self.moderators.iter().map(|id| index_of(*id)).collect();
+        let owner = self.owner.map(&mut index_of);
+        let compact = Compact {
+            topic: self.topic,
+            ids,
+            nodes,
+            moderators,
+            owner,
+            info: self.info.clone(),
+            capacity: self.capacity,
+            knock: self.knock,
+            incognito: self.incognito,
+            invite: self.invite,
+            locked: self.locked.clone(),
+        };
+        words::encode(&postcard::to_allocvec(&compact).expect("postcard encoding is infallible"))
+    }
+
+    fn from_words(s: &str) -> Result<Self> {
+        // Trailing padding from the words is left unread.
+        let compact: Compact =
+            postcard::from_bytes(&words::decode(s)?).context("Ticket is damaged")?;
+        let id = |index: usize| compact.ids.get(index).copied().context("Ticket is damaged");
+        Ok(Self {
+            topic: compact.topic,
+            nodes: compact
+                .nodes
+                .into_iter()
+                .map(|(index, relay_url, direct)| {
+                    Ok(NodeAddr::from_parts(id(index)?, relay_url, direct))
+                })
+                .collect::<Result<_>>()?,
+            moderators: compact
+                .moderators
+                .into_iter()
+                .map(id)
+                .collect::<Result<_>>()?,
+            owner: compact.owner.map(id).transpose()?,
+            info: compact.info,
+            capacity: compact.capacity,
+            knock: compact.knock,
+            incognito: compact.incognito,
+            invite: compact.invite,
+            locked: compact.locked,
+        })
+    }
+
+    /// The ticket as a QR code in PNG format, `scale` pixels per module.
+    pub fn to_qr_png(&self, scale: u32) -> Result<Vec<u8>> {
+        let scale = scale.max(1);
+        let image = self
+            .to_qr()?
+            .render::<Luma<u8>>()
+            .module_dimensions(scale, scale)
+            .build();
+        let mut png = Vec::new();
+        image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+        Ok(png)
+    }
+
+    pub fn to_qr_svg(&self) -> Result<String> {
+        Ok(self
+            .to_qr()?
+            .render::<svg::Color>()
+            .module_dimensions(1, 1)
+            .build())
+    }
+
+    /// The ticket as a QR code drawn with block characters, for printing to a console.
+    pub fn to_qr_terminal(&self) -> Result<String> {
+        // Light modules are drawn, so it reads right on the usual dark background.
+        let mut text = self
+            .to_qr()?
+            .render::<Dense1x2>()
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark)
+            .build();
+        text.push('\n');
+        Ok(text)
+    }
+
+    // Upper case base32 fits the QR alphanumeric mode, which packs it tighter than
+    // bytes. `from_str` takes it in either case.
+    fn to_qr(&self) -> Result<QrCode> {
+        QrCode::new(self.to_string().to_ascii_uppercase())
+            .context("Ticket is too long for a QR code")
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.is_some()
+    }
+
+    /// Encrypts the node addresses so the ticket is useless without `passphrase`.
+    pub fn lock(&mut self, passphrase: &str) -> Result<()> {
+        self.lock_with_cost(passphrase, KdfCost::default())
+    }
+
+    pub fn lock_with_cost(&mut self, passphrase: &str, cost: KdfCost) -> Result<()> {
+        if self.is_locked() {
+            bail!("Ticket is already password protected");
+        }
+        let hidden = Hidden {
+            nodes: std::mem::take(&mut self.nodes),
+            moderators: std::mem::take(&mut self.moderators),
+            owner: self.owner.take(),
+        };
+        let salt: [u8; SALT_LEN] = rand::random();
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let cipher = XSalsa20Poly1305::new(&derive_key_with_cost(passphrase, &salt, cost).into());
+        let ciphertext = cipher
+            .encrypt(&nonce.into(), serde_json::to_vec(&hidden)?.as_slice())
+            .map_err(|_| anyhow!("Failed to encrypt ticket"))?;
+        self.locked = Some(
+            data_encoding::BASE64_NOPAD.encode(&[&salt[..], &nonce[..], &ciphertext[..]].concat()),
+        );
+        Ok(())
+    }
+
+    /// Restores the node addresses of a locked ticket. Does nothing to an unlocked one.
+    pub fn unlock(&mut self, passphrase: &str) -> Result<()> {
+        self.unlock_with_cost(passphrase, KdfCost::default())
+    }
+
+    pub fn unlock_with_cost(&mut self, passphrase: &str, cost: KdfCost) -> Result<()> {
+        let Some(locked) = &self.locked else {
+            return Ok(());
+        };
+        let bytes = data_encoding::BASE64_NOPAD
+            .decode(locked.as_bytes())
+            .context("Ticket is damaged")?;
+        if bytes.len() < SALT_LEN + NONCE_LEN {
+            bail!("Ticket is damaged");
+        }
+        let (salt, rest) = bytes.split_at(SALT_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let cipher = XSalsa20Poly1305::new(&derive_key_with_cost(passphrase, salt, cost).into());
+        let plaintext = cipher
+            .decrypt(nonce.into(), ciphertext)
+            .map_err(|_| anyhow!("Wrong password for this ticket"))?;
+        let hidden: Hidden = serde_json::from_slice(&plaintext)?;
+        self.nodes = hidden.nodes;
+        self.moderators = hidden.moderators;
+        self.owner = hidden.owner;
+        self.locked = None;
+        Ok(())
+    }
+
+    /// Parses a ticket and unlocks it if it's password protected.
+    pub fn from_str_with_passphrase(s: &str, passphrase: &str) -> Result<Self> {
+        let mut ticket = Self::from_str(s)?;
+        ticket.unlock(passphrase)?;
+        Ok(ticket)
+    }
+
+    pub fn can_post(&self, node_id: &NodeId) -> bool {
+        self.moderators.is_empty() || self.moderators.contains(node_id)
+    }
+}
+
+/// Turns a password and a random salt into a key for sealing a ticket or an exported
+/// identity.
+pub fn derive_key(password: &str, salt: &[u8]) -> [u8; 32] {
+    derive_key_with_cost(password, salt, KdfCost::default())
+}
+
+pub fn derive_key_with_cost(password: &str, salt: &[u8], cost: KdfCost) -> [u8; 32] {
+    let params =
+        scrypt::Params::new(cost.log_n, cost.r, cost.p, 32).expect("scrypt parameters are valid");
+    let mut key = [0u8; 32];
+    scrypt::scrypt(password.as_bytes(), salt, &params, &mut key)
+        .expect("32 bytes is a valid scrypt output length");
+    key
 }
 
 impl fmt::Display for Ticket {
@@ -31,6 +317,9 @@ impl fmt::Display for Ticket {
 impl FromStr for Ticket {
     type Err = anyhow::Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if words::looks_like_words(s) {
+            return Self::from_words(s);
+        }
         let bytes = data_encoding::BASE32_NOPAD.decode(s.to_ascii_uppercase().as_bytes())?;
         Self::from_bytes(&bytes)
     }
@@ -0,0 +1,150 @@
+use anyhow::{Result, bail};
+use std::sync::OnceLock;
+
+// Eleven bits per word, from the BIP39 English list: 2048 short, common words, no two
+// sharing their first four letters. Two checksum bytes at the end catch a misheard
+// word before anyone tries to join with it.
+const WORD_LIST: &str = include_str!("words.txt");
+const BITS: usize = 11;
+const CHECKSUM_LEN: usize = 2;
+
+fn words() -> &'static [&'static str] {
+    static WORDS: OnceLock<Vec<&str>> = OnceLock::new();
+    WORDS.get_or_init(|| WORD_LIST.lines().collect())
+}
+
+fn checksum(bytes: &[u8]) -> [u8; CHECKSUM_LEN] {
+    blake3::hash(bytes).as_bytes()[..CHECKSUM_LEN]
+        .try_into()
+        .unwrap()
+}
+
+// Bits left unused in the last word when `len` bytes are written out.
+fn spare_bits(len: usize) -> usize {
+    (len * 8).div_ceil(BITS) * BITS - len * 8
+}
+
+pub fn encode(bytes: &[u8]) -> String {
+    // The decoder reads as many whole bytes as the words hold, so zeros are added
+    // until less than a byte is left over. The checksum is always the last two.
+    let mut data = bytes.to_vec();
+    while spare_bits(data.len() + CHECKSUM_LEN) >= 8 {
+        data.push(0);
+    }
+    let sum = checksum(&data);
+    data.extend_from_slice(&sum);
+
+    let mut out = Vec::with_capacity(data.len() * 8 / BITS + 1);
+    let (mut acc, mut bits) = (0u32, 0);
+    for byte in data {
+        acc = (acc << 8) | byte as u32;
+        bits += 8;
+        while bits >= BITS {
+            bits -= BITS;
+            out.push(words()[(acc >> bits) as usize & 0x7ff]);
+        }
+        acc &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        out.push(words()[(acc << (BITS - bits)) as usize & 0x7ff]);
+    }
+    out.join("-")
+}
+
+// Takes words separated by dashes or whitespace, in any case. What comes back may end
+// in a few zero bytes of padding.
+pub fn decode(s: &str) -> Result<Vec<u8>> {
+    let indices = s
+        .split(|c: char| c == '-' || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let word = word.to_ascii_lowercase();
+            match words().binary_search(&word.as_str()) {
+                Ok(index) => Ok(index as u32),
+                Err(_) => bail!("\"{}\" is not a ticket word", word),
+            }
+        })
+        .collect::<Result<Vec<u32>>>()?;
+
+    let mut bytes = Vec::with_capacity(indices.len() * BITS / 8);
+    let (mut acc, mut bits) = (0u32, 0);
+    for index in indices {
+        acc = (acc << BITS) | index;
+        bits += BITS;
+        while bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+        }
+        acc &= (1 << bits) - 1;
+    }
+    if bytes.len() < CHECKSUM_LEN {
+        bail!("Ticket has no words");
+    }
+    let sum = bytes.split_off(bytes.len() - CHECKSUM_LEN);
+    if acc != 0 || checksum(&bytes) != sum.as_slice() {
+        bail!("Ticket words don't add up, one of them is probably wrong");
+    }
+    Ok(bytes)
+}
+
+// Word tickets always have a separator; base32 ones never do.
+pub fn looks_like_words(s: &str) -> bool {
+    s.trim().contains(|c: char| c == '-' || c.is_whitespace())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn word_list_is_sorted_and_complete() {
+        assert_eq!(words().len(), 1 << BITS);
+        assert!(words().windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn bytes_round_trip() -> Result<()> {
+        for len in 0..64 {
+            let bytes: Vec<u8> = (0..len).map(|_| rand::random()).collect();
+            let decoded = decode(&encode(&bytes))?;
+            assert_eq!(&decoded[..len], &bytes[..]);
+            assert!(decoded[len..].iter().all(|byte| *byte == 0));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn eleven_bits_per_word() {
+        let words = encode(&[0xAB; 100]);
+        assert_eq!(words.split('-').count(), (102 * 8usize).div_ceil(BITS));
+    }
+
+    #[test]
+    fn misheard_word_is_caught() {
+        let encoded = encode(b"a room ticket");
+        let mut words: Vec<&str> = encoded.split('-').collect();
+        words[3] = if words[3] == "zoo" { "zone" } else { "zoo" };
+        let error = decode(&words.join("-")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Ticket words don't add up, one of them is probably wrong"
+        );
+    }
+
+    #[test]
+    fn unknown_word_is_named() {
+        let encoded = encode(b"a room ticket").replacen('-', "-flibbertigibbet-", 1);
+        let error = decode(&encoded).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "\"flibbertigibbet\" is not a ticket word"
+        );
+    }
+
+    #[test]
+    fn takes_spaces_and_any_case() -> Result<()> {
+        let encoded = encode(b"hello").replace('-', " ").to_ascii_uppercase();
+        assert_eq!(&decode(&encoded)?[..5], b"hello");
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+use anyhow::Result;
+use iroh::{NodeAddr, SecretKey};
+use iroh_gossip::proto::TopicId;
+use ticket::{KdfCost, Ticket};
+
+// Far below the real cost, so the round trips below don't each spend seconds in scrypt.
+const CHEAP: KdfCost = KdfCost {
+    log_n: 4,
+    r: 1,
+    p: 1,
+};
+
+fn ticket() -> Ticket {
+    let owner = SecretKey::from_bytes(&rand::random()).public();
+    Ticket {
+        topic: TopicId::from_bytes(rand::random()),
+        nodes: vec![NodeAddr::new(owner)],
+        moderators: vec![owner],
+        owner: Some(owner),
+        info: Default::default(),
+        capacity: None,
+        knock: false,
+        incognito: false,
+        invite: None,
+        locked: None,
+    }
+}
+
+#[test]
+fn locked_ticket_unlocks_with_its_password() -> Result<()> {
+    let original = ticket();
+    let mut locked = Ticket::from_bytes(&original.to_bytes())?;
+    locked.lock_with_cost("correct horse", CHEAP)?;
+    assert!(locked.is_locked());
+    assert!(locked.nodes.is_empty() && locked.moderators.is_empty());
+    assert_eq!(locked.owner, None);
+
+    let mut unlocked: Ticket = locked.to_string().parse()?;
+    unlocked.unlock_with_cost("correct horse", CHEAP)?;
+    assert!(!unlocked.is_locked());
+    assert_eq!(unlocked.nodes, original.nodes);
+    assert_eq!(unlocked.moderators, original.moderators);
+    assert_eq!(unlocked.owner, original.owner);
+    Ok(())
+}
+
+#[test]
+fn wrong_password_is_refused() -> Result<()> {
+    let mut locked = ticket();
+    locked.lock_with_cost("correct horse", CHEAP)?;
+    let error = locked
+        .unlock_with_cost("battery staple", CHEAP)
+        .unwrap_err();
+    assert_eq!(error.to_string(), "Wrong password for this ticket");
+    assert!(locked.is_locked());
+    Ok(())
+}
+
+#[test]
+fn tampered_ticket_is_refused() -> Result<()> {
+    let mut locked = ticket();
+    locked.lock_with_cost("correct horse", CHEAP)?;
+    let mut bytes =
+        data_encoding::BASE64_NOPAD.decode(locked.locked.as_ref().unwrap().as_bytes())?;
+    *bytes.last_mut().unwrap() ^= 1;
+    locked.locked = Some(data_encoding::BASE64_NOPAD.encode(&bytes));
+    assert!(locked.unlock_with_cost("correct horse", CHEAP).is_err());
+    assert!(locked.nodes.is_empty());
+    Ok(())
+}
+
+#[test]
+fn ticket_round_trips_through_words() -> Result<()> {
+    let mut original = ticket();
+    let relay: iroh::RelayUrl = "https://relay.iroh.link".parse()?;
+    original.nodes = vec![
+        NodeAddr::new(original.owner.unwrap())
+            .with_relay_url(relay.clone())
+            .with_direct_addresses(["192.168.1.20:4433".parse()?]),
+    ];
+    original.info.name = "Book club".to_string();
+    original.knock = true;
+
+    let words = original.to_words();
+    let parsed: Ticket = words.parse()?;
+    assert_eq!(parsed.topic, original.topic);
+    assert_eq!(
+        parsed.nodes,
+        vec![NodeAddr::new(original.owner.unwrap()).with_relay_url(relay)]
+    );
+    assert_eq!(parsed.owner, original.owner);
+    assert_eq!(parsed.moderators, original.moderators);
+    assert_eq!(parsed.info, original.info);
+    assert!(parsed.knock);
+    // The topic and one node id with its relay, which is also the owner and the
+    // moderator: under a hundred words, where a byte per word of the JSON took
+    // several hundred.
+    assert!(
+        words.split('-').count() < 100,
+        "{} words",
+        words.split('-').count()
+    );
+    Ok(())
+}
+
+#[test]
+fn direct_only_ticket_round_trips_through_words() -> Result<()> {
+    let mut original = ticket();
+    original.nodes = vec![
+        NodeAddr::new(original.owner.unwrap())
+            .with_direct_addresses(["192.168.1.20:4433".parse()?, "[fe80::1]:4433".parse()?]),
+    ];
+
+    let parsed: Ticket = original.to_words().parse()?;
+    assert_eq!(parsed.nodes, original.nodes);
+    Ok(())
+}
+
+#[test]
+fn qr_code_renders_square_with_a_quiet_zone() -> Result<()> {
+    let ticket = ticket();
+    let png = image::load_from_memory(&ticket.to_qr_png(3)?)?;
+    assert_eq!(png.width(), png.height());
+    assert_eq!(png.width() % 3, 0);
+    assert_eq!(png.to_luma8().get_pixel(0, 0).0, [255]);
+    assert!(ticket.to_qr_svg()?.starts_with("<?xml"));
+    assert!(ticket.to_qr_terminal()?.ends_with('\n'));
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Tickets locked and identities exported before keep opening with the same key.
+#[test]
+fn derived_key_uses_the_same_parameters() {
+    let salt: Vec<u8> = (0..16).collect();
+    assert_eq!(
+        hex(&ticket::derive_key("chast", &salt)),
+        "20f4c19f93c29586cb4585764a390f26f62e672e4d9349480df366b048a683c2"
+    );
+}